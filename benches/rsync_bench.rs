@@ -8,7 +8,7 @@ mod crc;
 
 use crate::crc::Crc;
 use criterion::{black_box, BenchmarkId, Criterion, Throughput};
-use superfast_rsync::{apply_limited, diff, Signature, SignatureOptions};
+use superfast_rsync::{apply_limited, diff, Crc32c, RollingHash, Signature, SignatureOptions};
 use std::io;
 
 fn random_block(len: usize) -> Vec<u8> {
@@ -31,6 +31,9 @@ fn crc_update(c: &mut Criterion) {
             &data,
             |b, data| b.iter(|| Crc::new().basic_update(black_box(data))),
         );
+        group.bench_with_input(BenchmarkId::new("Crc32c::update", len), &data, |b, data| {
+            b.iter(|| Crc32c::default().update(black_box(data)))
+        });
     }
     group.finish();
 }
@@ -49,11 +52,7 @@ fn calculate_signature(c: &mut Criterion) {
             b.iter(|| {
                 Signature::calculate(
                     black_box(data),
-                    SignatureOptions {
-                        block_size: 4096,
-                        crypto_hash_size: 8,
-                        hash_algorithm: superfast_rsync::HashAlgorithm::Blake3,
-                    },
+                    SignatureOptions::new(4096, 8, superfast_rsync::HashAlgorithm::Blake3),
                 )
                 .into_serialized();
             })
@@ -89,11 +88,7 @@ fn bench_diff(
 ) {
     let signature = Signature::calculate(
         data,
-        SignatureOptions {
-            block_size: 4096,
-            crypto_hash_size: 8,
-            hash_algorithm: superfast_rsync::HashAlgorithm::Blake3,
-        },
+        SignatureOptions::new(4096, 8, superfast_rsync::HashAlgorithm::Blake3),
     )
     .into_serialized();
     let mut group = c.benchmark_group(name);
@@ -162,11 +157,7 @@ fn apply_delta(c: &mut Criterion) {
     diff(
         &Signature::calculate(
             &data,
-            SignatureOptions {
-                block_size: 4096,
-                crypto_hash_size: 8,
-                hash_algorithm: superfast_rsync::HashAlgorithm::Blake3,
-            },
+            SignatureOptions::new(4096, 8, superfast_rsync::HashAlgorithm::Blake3),
         )
         .index(),
         &new_data,
@@ -1,10 +1,15 @@
-use superfast_rsync::{Signature, SignatureOptions, diff, apply, HashAlgorithm};
+use superfast_rsync::{Signature, SignatureOptions, diff, apply, CompactOptions, HashAlgorithm};
+use superfast_rsync::analysis::heatmap;
 #[cfg(feature = "parallel")]
 use superfast_rsync::diff_parallel;
+use std::collections::HashMap;
 use std::fs;
+use std::path::Path;
 use std::time::Instant;
 use std::io;
-use clap::{Command, Arg};
+use clap::{Arg, ArgAction, Command};
+use clap::parser::ValueSource;
+use serde::Deserialize;
 
 #[derive(Debug)]
 struct Config {
@@ -13,6 +18,63 @@ struct Config {
     hash_algorithm: HashAlgorithm,
     block_size: u32,
     hash_size: u32,
+    compression: Option<i32>,
+    parallel: bool,
+    json: bool,
+}
+
+/// Settings that a profile can set, globally or per file type.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Settings {
+    hash: Option<String>,
+    block_size: Option<u32>,
+    hash_size: Option<u32>,
+    /// zstd level for compressing the signature
+    compression: Option<i32>,
+    parallel: Option<bool>,
+}
+
+impl Settings {
+    /// Take every setting that `other` sets.
+    fn merge(&mut self, other: &Settings) {
+        self.hash = other.hash.clone().or(self.hash.take());
+        self.block_size = other.block_size.or(self.block_size);
+        self.hash_size = other.hash_size.or(self.hash_size);
+        self.compression = other.compression.or(self.compression);
+        self.parallel = other.parallel.or(self.parallel);
+    }
+}
+
+/// A TOML profile, e.g.:
+///
+/// ```toml
+/// hash = "blake3"
+/// block_size = 4096
+/// hash_size = 16
+/// compression = 3
+/// parallel = true
+///
+/// # keyed by file extension
+/// [overrides.log]
+/// block_size = 1024
+/// ```
+#[derive(Debug, Default, Deserialize)]
+struct Profile {
+    #[serde(flatten)]
+    defaults: Settings,
+    #[serde(default)]
+    overrides: HashMap<String, Settings>,
+}
+
+fn load_profile(path: &str) -> Profile {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Error: can't read profile {}: {}", path, e);
+        std::process::exit(2);
+    });
+    toml::from_str(&text).unwrap_or_else(|e| {
+        eprintln!("Error: invalid profile {}: {}", path, e);
+        std::process::exit(2);
+    })
 }
 
 fn parse_args() -> Config {
@@ -35,8 +97,8 @@ fn parse_args() -> Config {
             .long("hash")
             .short('a')
             .value_name("ALGORITHM")
-            .help("Hash algorithm: md4, blake3")
-            .value_parser(["md4", "blake3"])
+            .help("Hash algorithm: md4, blake2, blake3, sha256, md5")
+            .value_parser(clap::value_parser!(HashAlgorithm))
             .default_value("blake3"))
         .arg(Arg::new("block-size")
             .long("block-size")
@@ -50,24 +112,57 @@ fn parse_args() -> Config {
             .value_name("BYTES")
             .help("Hash size in bytes (max: 16 for MD4, 32 for BLAKE3)")
             .default_value("16"))
+        .arg(Arg::new("profile")
+            .long("profile")
+            .short('p')
+            .value_name("FILE")
+            .help("TOML profile with default settings and per-file-type overrides; explicit flags take precedence"))
+        .arg(Arg::new("json")
+            .long("json")
+            .action(ArgAction::SetTrue)
+            .help("Print the statistics as a single JSON object instead of text"))
         .get_matches();
     
     let original_file = matches.get_one::<String>("original").unwrap().clone();
     let modified_file = matches.get_one::<String>("modified").unwrap().clone();
-    
-    let hash_algo = match matches.get_one::<String>("hash").unwrap().as_str() {
-        "blake3" => HashAlgorithm::Blake3,
-        "md4" => HashAlgorithm::Md4,
-        _ => unreachable!(), // clap ensures valid values
+
+    // Profile defaults, then the override for the modified file's extension
+    let mut settings = Settings::default();
+    if let Some(path) = matches.get_one::<String>("profile") {
+        let profile = load_profile(path);
+        settings.merge(&profile.defaults);
+        let extension = Path::new(&modified_file).extension().and_then(|e| e.to_str());
+        if let Some(settings_override) = extension.and_then(|e| profile.overrides.get(e)) {
+            settings.merge(settings_override);
+        }
+    }
+    let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    let hash_algo = match &settings.hash {
+        Some(name) if !explicit("hash") => name.parse().unwrap_or_else(|e| {
+            eprintln!("Error: invalid profile: {}", e);
+            std::process::exit(2);
+        }),
+        _ => *matches.get_one::<HashAlgorithm>("hash").unwrap(),
     };
-    
-    let block_size: u32 = matches.get_one::<String>("block-size").unwrap().parse().unwrap();
-    let hash_size: u32 = matches.get_one::<String>("hash-size").unwrap().parse().unwrap();
+
+    let block_size: u32 = match settings.block_size {
+        Some(block_size) if !explicit("block-size") => block_size,
+        _ => matches.get_one::<String>("block-size").unwrap().parse().unwrap(),
+    };
+    let hash_size: u32 = match settings.hash_size {
+        Some(hash_size) if !explicit("hash-size") => hash_size,
+        _ => matches.get_one::<String>("hash-size").unwrap().parse().unwrap(),
+    };
+    let parallel = settings.parallel.unwrap_or(cfg!(feature = "parallel"));
+    if parallel && !cfg!(feature = "parallel") {
+        eprintln!("Warning: profile requests parallel processing, but the parallel feature is disabled");
+    }
     
     // Validate hash size
     let max_hash_size = hash_algo.max_hash_size();
     if hash_size > max_hash_size as u32 {
-        eprintln!("Warning: hash size {} exceeds maximum {} for algorithm {:?}, using maximum", 
+        eprintln!("Warning: hash size {} exceeds maximum {} for algorithm {}, using maximum", 
                   hash_size, max_hash_size, hash_algo);
     }
     let hash_size = hash_size.min(max_hash_size as u32);
@@ -78,6 +173,9 @@ fn parse_args() -> Config {
         hash_algorithm: hash_algo,
         block_size,
         hash_size,
+        compression: settings.compression,
+        parallel,
+        json: matches.get_flag("json"),
     }
 }
 
@@ -88,31 +186,46 @@ pub fn main() -> io::Result<()> {
     let original = fs::read(&config.original_file)?;
     let modified = fs::read(&config.modified_file)?;
     
-    println!("🔧 Configuration:");
-    println!("   Original file: {}", config.original_file);
-    println!("   Modified file: {}", config.modified_file);
-    println!("   Hash algorithm: {:?}", config.hash_algorithm);
-    println!("   Block size: {} bytes", config.block_size);
-    println!("   Hash size: {} bytes", config.hash_size);
-    println!();
-    
-    println!("📄 File Statistics:");
-    println!("   Original size: {} bytes ({:.2} MB)", original.len(), original.len() as f64 / 1024.0 / 1024.0);
-    println!("   Modified size: {} bytes ({:.2} MB)", modified.len(), modified.len() as f64 / 1024.0 / 1024.0);
-    println!("   Size difference: {} bytes", (original.len() as i64 - modified.len() as i64).abs());
+    if !config.json {
+        println!("🔧 Configuration:");
+        println!("   Original file: {}", config.original_file);
+        println!("   Modified file: {}", config.modified_file);
+        println!("   Hash algorithm: {}", config.hash_algorithm);
+        println!("   Block size: {} bytes", config.block_size);
+        println!("   Hash size: {} bytes", config.hash_size);
+        if let Some(level) = config.compression {
+            println!("   Signature compression: zstd level {}", level);
+        }
+        println!("   Parallel: {}", config.parallel);
+        println!();
+
+        println!("📄 File Statistics:");
+        println!("   Original size: {} bytes ({:.2} MB)", original.len(), original.len() as f64 / 1024.0 / 1024.0);
+        println!("   Modified size: {} bytes ({:.2} MB)", modified.len(), modified.len() as f64 / 1024.0 / 1024.0);
+        println!("   Size difference: {} bytes", (original.len() as i64 - modified.len() as i64).abs());
+    }
 
     // Step 1: Create SignatureOptions with CLI parameters
-    let sig_opts = SignatureOptions {
-        block_size: config.block_size,
-        crypto_hash_size: config.hash_size,
-        hash_algorithm: config.hash_algorithm,
-    };
+    let sig_opts =
+        SignatureOptions::new(config.block_size, config.hash_size, config.hash_algorithm);
 
     // Step 2: Generate signature from original
     let t0 = Instant::now();
     let signature = Signature::calculate(&original, sig_opts);
     let t_sig = t0.elapsed();
 
+    // Optionally compress the signature, as it would be sent over the wire
+    let compact_signature = config.compression.map(|_level| {
+        let options = CompactOptions {
+            #[cfg(feature = "zstd")]
+            zstd_level: Some(_level),
+            delta_crcs: true,
+        };
+        #[cfg(not(feature = "zstd"))]
+        eprintln!("Warning: the zstd feature is disabled, only delta-encoding the signature CRCs");
+        signature.serialize_compact(options)
+    });
+
     // Step 3: Index the signature for fast lookup
     let indexed = signature.index();
 
@@ -120,13 +233,11 @@ pub fn main() -> io::Result<()> {
     let mut delta = Vec::new();
     let t1 = Instant::now();
     
-    #[cfg(feature = "parallel")]
-    {
+    if config.parallel && cfg!(feature = "parallel") {
         // Use parallel diff if feature is enabled
+        #[cfg(feature = "parallel")]
         diff_parallel(&indexed, &modified, &mut delta).expect("parallel diff failed");
-    }
-    #[cfg(not(feature = "parallel"))]
-    {
+    } else {
         // Use sequential diff
         diff(&indexed, &modified, &mut delta).expect("diff failed");
     }
@@ -141,7 +252,9 @@ pub fn main() -> io::Result<()> {
 
     // Step 6: Verify exact match
     assert_eq!(reconstructed, modified);
-    println!("✅ Reconstructed matches modified input.");
+    if !config.json {
+        println!("✅ Reconstructed matches modified input.");
+    }
 
     // Calculate detailed statistics
     let delta_ratio = delta.len() as f64 * 100.0 / modified.len() as f64;
@@ -160,27 +273,81 @@ pub fn main() -> io::Result<()> {
     let cycles_per_byte_diff = (t_diff.as_nanos() as f64 * cpu_freq_ghz) / modified.len() as f64;
     let cycles_per_byte_apply = (t_apply.as_nanos() as f64 * cpu_freq_ghz) / original.len() as f64;
 
-    println!("\n📊 Performance Statistics:");
-    println!("   Delta size: {} bytes ({:.2} MB)", delta.len(), delta.len() as f64 / 1024.0 / 1024.0);
-    println!("   Delta ratio: {:.2}%", delta_ratio);
-    println!("   Compression ratio: {:.2}%", compression_ratio);
-    
-    println!("\n⏱ Timing Statistics:");
-    println!("   Signature generation: {:.2?} ({:.2} MB/s)", t_sig, sig_throughput);
-    println!("   Delta generation: {:.2?} ({:.2} MB/s)", t_diff, diff_throughput);
-    println!("   Delta application: {:.2?} ({:.2} MB/s)", t_apply, apply_throughput);
-    println!("   Total processing time: {:.2?}", t_sig + t_diff + t_apply);
-    
-    println!("\n🖥️ CPU Statistics (estimated @ {:.1} GHz):", cpu_freq_ghz);
-    println!("   Signature cycles/byte: {:.2}", cycles_per_byte_sig);
-    println!("   Delta generation cycles/byte: {:.2}", cycles_per_byte_diff);
-    println!("   Delta application cycles/byte: {:.2}", cycles_per_byte_apply);
-    println!("   Total CPU cycles: {:.0}", (t_sig + t_diff + t_apply).as_nanos() as f64 * cpu_freq_ghz);
-    
-    println!("\n🧠 Memory Statistics:");
-    println!("   Estimated total memory: {:.2} MB", total_memory as f64 / 1024.0 / 1024.0);
-    println!("   Peak memory usage: {:.2} MB", peak_memory as f64 / 1024.0 / 1024.0);
-    println!("   Signature memory: {:.2} MB", signature.serialized().len() as f64 / 1024.0 / 1024.0);
+    // Bytes reconstructed from literals rather than copied from the original
+    let literal_bytes = heatmap(&delta, 1).expect("invalid delta")[0].changed;
+
+    if config.json {
+        let stats = serde_json::json!({
+            "config": {
+                "original_file": config.original_file,
+                "modified_file": config.modified_file,
+                "hash_algorithm": config.hash_algorithm.to_string(),
+                "block_size": config.block_size,
+                "hash_size": config.hash_size,
+                "compression": config.compression,
+                "parallel": config.parallel,
+            },
+            "sizes": {
+                "original": original.len(),
+                "modified": modified.len(),
+                "signature": signature.serialized().len(),
+                "compressed_signature": compact_signature.as_ref().map(Vec::len),
+                "delta": delta.len(),
+            },
+            "matches": {
+                "copied_bytes": modified.len() as u64 - literal_bytes,
+                "literal_bytes": literal_bytes,
+                "delta_ratio_percent": delta_ratio,
+            },
+            "timings_seconds": {
+                "signature": t_sig.as_secs_f64(),
+                "diff": t_diff.as_secs_f64(),
+                "apply": t_apply.as_secs_f64(),
+            },
+            "throughput_mb_per_second": {
+                "signature": sig_throughput,
+                "diff": diff_throughput,
+                "apply": apply_throughput,
+            },
+            "cycles_per_byte": {
+                "assumed_ghz": cpu_freq_ghz,
+                "signature": cycles_per_byte_sig,
+                "diff": cycles_per_byte_diff,
+                "apply": cycles_per_byte_apply,
+            },
+            "memory_bytes": {
+                "estimated_total": total_memory,
+                "peak": peak_memory,
+            },
+        });
+        println!("{}", stats);
+    } else {
+        println!("\n📊 Performance Statistics:");
+        println!("   Delta size: {} bytes ({:.2} MB)", delta.len(), delta.len() as f64 / 1024.0 / 1024.0);
+        println!("   Delta ratio: {:.2}%", delta_ratio);
+        println!("   Compression ratio: {:.2}%", compression_ratio);
+        println!("   Literal bytes: {} (copied: {})", literal_bytes, modified.len() as u64 - literal_bytes);
+
+        println!("\n⏱ Timing Statistics:");
+        println!("   Signature generation: {:.2?} ({:.2} MB/s)", t_sig, sig_throughput);
+        println!("   Delta generation: {:.2?} ({:.2} MB/s)", t_diff, diff_throughput);
+        println!("   Delta application: {:.2?} ({:.2} MB/s)", t_apply, apply_throughput);
+        println!("   Total processing time: {:.2?}", t_sig + t_diff + t_apply);
+
+        println!("\n🖥️ CPU Statistics (estimated @ {:.1} GHz):", cpu_freq_ghz);
+        println!("   Signature cycles/byte: {:.2}", cycles_per_byte_sig);
+        println!("   Delta generation cycles/byte: {:.2}", cycles_per_byte_diff);
+        println!("   Delta application cycles/byte: {:.2}", cycles_per_byte_apply);
+        println!("   Total CPU cycles: {:.0}", (t_sig + t_diff + t_apply).as_nanos() as f64 * cpu_freq_ghz);
+
+        println!("\n🧠 Memory Statistics:");
+        println!("   Estimated total memory: {:.2} MB", total_memory as f64 / 1024.0 / 1024.0);
+        println!("   Peak memory usage: {:.2} MB", peak_memory as f64 / 1024.0 / 1024.0);
+        println!("   Signature memory: {:.2} MB", signature.serialized().len() as f64 / 1024.0 / 1024.0);
+        if let Some(compact) = &compact_signature {
+            println!("   Compressed signature: {:.2} MB", compact.len() as f64 / 1024.0 / 1024.0);
+        }
+    }
 
     // Optional output
     fs::write("patch_output.bin", &delta)?;
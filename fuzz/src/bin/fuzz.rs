@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate honggfuzz;
 
+use superfast_rsync::consts::DELTA_MAGIC;
 use superfast_rsync::{apply_limited, ApplyError};
 use rand::rngs::SmallRng;
 use rand::{RngCore, SeedableRng};
@@ -25,6 +26,14 @@ fn main() {
             let base_data = &base_data[..base_len];
             out_data.clear();
 
+            // librsync only knows plain v1 deltas; the other formats just mustn't misbehave.
+            if !delta.starts_with(&DELTA_MAGIC.to_be_bytes()) {
+                if apply_limited(base_data, delta, &mut out_data, MAX_OUT).is_ok() {
+                    assert!(out_data.len() <= MAX_OUT);
+                }
+                return;
+            }
+
             let mut librsync_data_cursor = Cursor::new(&mut librsync_data[..]);
             let mut librsync_delta_cursor = &delta[..];
             let fast_rsync_result = apply_limited(base_data, delta, &mut out_data, MAX_OUT);
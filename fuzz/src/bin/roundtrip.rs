@@ -0,0 +1,39 @@
+#[macro_use]
+extern crate honggfuzz;
+
+use superfast_rsync::compress::{pack_delta, Capabilities};
+use superfast_rsync::fingerprint::diff_fingerprinted;
+use superfast_rsync::{apply_limited, diff_v2, HashAlgorithm, Signature, SignatureOptions};
+
+/// Every delta format this crate produces must apply back to the data it was calculated from.
+fn main() {
+    const MAX_OUT: usize = 1 << 24;
+    let mut out = Vec::with_capacity(MAX_OUT);
+    loop {
+        fuzz!(|data: &[u8]| {
+            if data.len() < 4 {
+                return;
+            }
+            // The first bytes pick the block size and where the input splits into base and new
+            // data, so that both share content often enough to produce copies.
+            let block_size = 16 + u16::from_be_bytes([data[0], data[1]]) as u32 % 1024;
+            let rest = &data[4..];
+            let split = u16::from_be_bytes([data[2], data[3]]) as usize % (rest.len() + 1);
+            let (base, new) = (&rest[..split], [&rest[split..], &rest[..split / 2]].concat());
+            let signature =
+                Signature::calculate(base, SignatureOptions::new(block_size, 8, HashAlgorithm::Md4));
+            let signature = signature.index();
+
+            let mut v2 = vec![];
+            diff_v2(&signature, &new, &mut v2).unwrap();
+            let mut fingerprinted = vec![];
+            diff_fingerprinted(&signature, &new, &mut fingerprinted).unwrap();
+            let packed = pack_delta(&v2, Some(base), 1, Capabilities::supported()).unwrap();
+            for delta in [&v2, &fingerprinted, &packed] {
+                out.clear();
+                apply_limited(base, delta, &mut out, MAX_OUT).unwrap();
+                assert_eq!(out, new);
+            }
+        });
+    }
+}
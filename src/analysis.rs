@@ -0,0 +1,539 @@
+//! Summaries of what a delta does, for tools that display or debug changes rather than apply them.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::{Range, RangeInclusive};
+
+use crate::ops::OpHeader;
+use crate::patch::{apply, ApplyError, Op, OpReader};
+use crate::signature::{Signature, SignatureOptions};
+use crate::strict::require;
+
+/// A region of the base data that appears at a different position in the output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Move {
+    /// Where the region starts in the base data.
+    pub from: u64,
+    /// Where the region starts in the output.
+    pub to: u64,
+    /// The length of the region, including any bytes changed inside it.
+    pub len: u64,
+}
+
+/// Find the regions of the base data that `delta` moves to another position.
+///
+/// Copies are clustered by their displacement (position in the output minus offset in the base
+/// data): consecutive copies with the same displacement, possibly with literals in between, form
+/// one region. Regions that stay in place aren't reported, and neither are regions shorter than
+/// `min_len`, which filters out single blocks that merely happen to occur elsewhere in the base
+/// data.
+pub fn moves(delta: &[u8], min_len: u64) -> Result<Vec<Move>, ApplyError> {
+    struct Cluster {
+        displacement: i128,
+        to: u64,
+        end: u64,
+    }
+
+    let mut moves = Vec::new();
+    let mut finish = |cluster: Cluster| {
+        let len = cluster.end - cluster.to;
+        if cluster.displacement != 0 && len >= min_len {
+            moves.push(Move {
+                from: (cluster.to as i128 - cluster.displacement) as u64,
+                to: cluster.to,
+                len,
+            });
+        }
+    };
+    let mut ops = OpReader::new(delta)?;
+    let mut position = 0u64;
+    let mut current: Option<Cluster> = None;
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Literal(_) | Op::Repeat { .. } => {
+                position = position.saturating_add(op.output_len())
+            }
+            Op::Copy { offset, len } => {
+                let displacement = position as i128 - offset as i128;
+                let end = position.saturating_add(len);
+                match &mut current {
+                    Some(cluster) if cluster.displacement == displacement => cluster.end = end,
+                    _ => {
+                        if let Some(cluster) = current.take() {
+                            finish(cluster);
+                        }
+                        current = Some(Cluster {
+                            displacement,
+                            to: position,
+                            end,
+                        });
+                    }
+                }
+                position = end;
+            }
+        }
+    }
+    if let Some(cluster) = current {
+        finish(cluster);
+    }
+    Ok(moves)
+}
+
+/// A region of the output and how many of its bytes are new, i.e. not copied from the base data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeatmapBucket {
+    /// The region of the output.
+    pub range: Range<u64>,
+    /// How many bytes of the region come from literals.
+    pub changed: u64,
+}
+
+impl HeatmapBucket {
+    /// The percentage of changed bytes in the region, or 0 if it is empty.
+    pub fn percent_changed(&self) -> f64 {
+        let len = self.range.end - self.range.start;
+        if len == 0 {
+            return 0.0;
+        }
+        self.changed as f64 * 100.0 / len as f64
+    }
+}
+
+/// Summarize where `delta` changes its output, for UIs that visualize the changes in a file.
+///
+/// The output is split into `buckets` regions of (nearly) equal size, and each region reports how
+/// many of its bytes come from literals rather than copies.
+///
+/// # Panics
+/// Panics if `buckets` is zero, unless the `strict` feature turns that into
+/// [ApplyError::InvalidArgument].
+pub fn heatmap(delta: &[u8], buckets: usize) -> Result<Vec<HeatmapBucket>, ApplyError> {
+    require!(buckets > 0, ApplyError::InvalidArgument { name: "buckets" });
+    let mut len = 0u64;
+    let mut ops = OpReader::new(delta)?;
+    while let Some(op) = ops.next_op()? {
+        len = len.saturating_add(op.output_len());
+    }
+
+    let boundary = |i: usize| (len as u128 * i as u128 / buckets as u128) as u64;
+    let mut heatmap: Vec<_> = (0..buckets)
+        .map(|i| HeatmapBucket {
+            range: boundary(i)..boundary(i + 1),
+            changed: 0,
+        })
+        .collect();
+    let mut ops = OpReader::new(delta)?;
+    let mut position = 0u64;
+    let mut bucket = 0;
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Literal(_) | Op::Repeat { .. } => {
+                let end = position.saturating_add(op.output_len());
+                while position < end {
+                    while heatmap[bucket].range.end <= position {
+                        bucket += 1;
+                    }
+                    let overlap = end.min(heatmap[bucket].range.end) - position;
+                    heatmap[bucket].changed += overlap;
+                    position += overlap;
+                }
+            }
+            Op::Copy { len, .. } => position = position.saturating_add(len),
+        }
+    }
+    Ok(heatmap)
+}
+
+/// The outcome of [diagnose].
+#[derive(Debug)]
+pub enum Diagnosis {
+    /// Applying the delta reproduces the target.
+    Match,
+    /// The delta couldn't be applied.
+    Invalid(ApplyError),
+    /// Applying the delta produced different data than the target.
+    Diverged(Divergence),
+}
+
+/// Where the output of a delta first differs from the expected data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Divergence {
+    /// The position of the first differing byte.
+    pub position: u64,
+    /// The length of the output of the delta.
+    pub output_len: u64,
+    /// The length of the expected data.
+    pub target_len: u64,
+    /// The index of the op that wrote the differing byte among the ops of the delta, or `None`
+    /// if the output ended before it.
+    pub op_index: Option<usize>,
+    /// The op that wrote the differing byte, if any.
+    pub op: Option<OpHeader>,
+    /// Where the differing byte was copied from in the base data, if it was copied.
+    pub base_offset: Option<u64>,
+}
+
+impl Divergence {
+    /// The index of the signature block the differing byte was copied from, for signatures with
+    /// blocks of `block_size` bytes.
+    ///
+    /// A copy of the wrong data from a block that matched by CRC and crypto hash points at a hash
+    /// collision; a copy of the right block points at a base that changed after its signature was
+    /// calculated.
+    pub fn base_block(&self, block_size: u32) -> Option<u64> {
+        self.base_offset.map(|offset| offset / block_size as u64)
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "output diverges from target (position={}, output_len={}, target_len={}",
+            self.position, self.output_len, self.target_len
+        )?;
+        match self.op {
+            Some(OpHeader::Copy { offset, len }) => write!(
+                f,
+                ", op_index={}, copy_offset={offset}, copy_len={len}",
+                self.op_index.unwrap_or_default()
+            )?,
+            Some(OpHeader::Literal(len)) => write!(
+                f,
+                ", op_index={}, literal_len={len}",
+                self.op_index.unwrap_or_default()
+            )?,
+            Some(OpHeader::Repeat { count, pattern_len }) => write!(
+                f,
+                ", op_index={}, repeat_count={count}, pattern_len={pattern_len}",
+                self.op_index.unwrap_or_default()
+            )?,
+            Some(OpHeader::End) | None => {}
+        }
+        if let Some(base_offset) = self.base_offset {
+            write!(f, ", base_offset={base_offset}")?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// Apply `delta` to `base` and compare the output to `target`, locating the first difference and
+/// the op responsible for it.
+///
+/// This turns a failed round-trip (e.g. a hash mismatch after syncing) into the position, op and
+/// base offset to investigate.
+pub fn diagnose(base: &[u8], target: &[u8], delta: &[u8]) -> Diagnosis {
+    let mut output = Vec::with_capacity(target.len());
+    if let Err(e) = apply(base, delta, &mut output) {
+        return Diagnosis::Invalid(e);
+    }
+    let position = match output.iter().zip(target).position(|(a, b)| a != b) {
+        Some(position) => position,
+        None if output.len() == target.len() => return Diagnosis::Match,
+        None => output.len().min(target.len()),
+    } as u64;
+
+    let mut divergence = Divergence {
+        position,
+        output_len: output.len() as u64,
+        target_len: target.len() as u64,
+        op_index: None,
+        op: None,
+        base_offset: None,
+    };
+    // The delta was applied successfully above, so it decodes.
+    let mut ops = OpReader::new(delta).expect("delta was applied");
+    let mut start = 0u64;
+    let mut index = 0;
+    while let Some(op) = ops.next_op().expect("delta was applied") {
+        let len = op.output_len();
+        let header = match op {
+            Op::Literal(literal) => OpHeader::Literal(literal.len() as u64),
+            Op::Copy { offset, len } => OpHeader::Copy { offset, len },
+            Op::Repeat { pattern, count } => OpHeader::Repeat {
+                count,
+                pattern_len: pattern.len() as u8,
+            },
+        };
+        if position < start + len {
+            divergence.op_index = Some(index);
+            divergence.op = Some(header);
+            if let OpHeader::Copy { offset, .. } = header {
+                divergence.base_offset = Some(offset + (position - start));
+            }
+            break;
+        }
+        start += len;
+        index += 1;
+    }
+    Diagnosis::Diverged(divergence)
+}
+
+/// Indicates that two versions of some data couldn't be compared block by block.
+#[derive(Debug)]
+pub enum CompareError {
+    /// A signature uses a hash algorithm this crate can't calculate.
+    UnsupportedSignature,
+    /// The signatures were calculated with different options, so their blocks don't line up.
+    OptionsMismatch {
+        /// The options of the first signature.
+        old: SignatureOptions,
+        /// The options of the second signature.
+        new: SignatureOptions,
+    },
+}
+
+impl fmt::Display for CompareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompareError::UnsupportedSignature => f.write_str("unsupported signature"),
+            CompareError::OptionsMismatch { old, new } => write!(
+                f,
+                "signatures have different options (old_block_size={}, new_block_size={}, \
+                 old_crypto_hash_size={}, new_crypto_hash_size={}, old_hash={}, new_hash={})",
+                old.block_size,
+                new.block_size,
+                old.crypto_hash_size,
+                new.crypto_hash_size,
+                old.hash_algorithm,
+                new.hash_algorithm,
+            ),
+        }
+    }
+}
+
+impl Error for CompareError {}
+
+/// Find the first and last block at which the data behind two signatures differs, or `None` if
+/// the signatures are equal.
+///
+/// Only block hashes are compared, so this is cheap even for very large files, which makes it a
+/// first step in triaging corruption of replicated data: the data between the two blocks is all
+/// that needs closer inspection. If the data has different lengths, the last differing block is
+/// the last block of the longer one.
+pub fn differing_blocks(
+    old: &Signature,
+    new: &Signature,
+) -> Result<Option<RangeInclusive<u64>>, CompareError> {
+    let (old_options, new_options) = match (old.options(), new.options()) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return Err(CompareError::UnsupportedSignature),
+    };
+    if old_options != new_options {
+        return Err(CompareError::OptionsMismatch {
+            old: old_options,
+            new: new_options,
+        });
+    }
+    let old: Vec<_> = old.block_entries().collect();
+    let new: Vec<_> = new.block_entries().collect();
+    Ok(differing_range(old.len(), new.len(), |i| old[i] != new[i]))
+}
+
+/// Find the first and last block at which `data` differs from the data behind `signature`, or
+/// `None` if it doesn't differ.
+///
+/// Blocks of `data` are hashed from either end only until a difference is found, so this is
+/// cheap when the differences are near the ends. See [differing_blocks].
+pub fn differing_blocks_from_data(
+    signature: &Signature,
+    data: &[u8],
+) -> Result<Option<RangeInclusive<u64>>, CompareError> {
+    let options = signature.options().ok_or(CompareError::UnsupportedSignature)?;
+    let block_size = options.block_size as usize;
+    let entries: Vec<_> = signature.block_entries().collect();
+    let mut entry = Vec::new();
+    Ok(differing_range(entries.len(), data.len().div_ceil(block_size), |i| {
+        let block = &data[i * block_size..data.len().min((i + 1) * block_size)];
+        entry.clear();
+        Signature::hash_blocks(block, &options, &mut entry);
+        entries[i] != entry
+    }))
+}
+
+/// The range of block indexes between the first and last block for which `differs` is true,
+/// comparing `old_len` blocks with `new_len` blocks.
+fn differing_range(
+    old_len: usize,
+    new_len: usize,
+    mut differs: impl FnMut(usize) -> bool,
+) -> Option<RangeInclusive<u64>> {
+    let common = old_len.min(new_len);
+    let first = (0..common).find(|&i| differs(i)).unwrap_or(common);
+    let last = if old_len != new_len {
+        old_len.max(new_len) - 1
+    } else if first == common {
+        return None;
+    } else {
+        // `first` differs, so this finds a block.
+        (first..common).rev().find(|&i| differs(i)).unwrap()
+    };
+    Some(first as u64..=last as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{
+        diagnose, differing_blocks, differing_blocks_from_data, heatmap, moves, CompareError,
+        Diagnosis, Divergence, HeatmapBucket, Move,
+    };
+    use crate::ops::OpHeader;
+    use crate::{diff, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_moves() {
+        let mut base = vec![0; 4096];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut data = base[..1024].to_vec();
+        data.extend_from_slice(&base[2048..3072]);
+        data.extend_from_slice(&base[1024..2048]);
+        data.extend_from_slice(&base[3072..]);
+        data[1500] ^= 1;
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(16, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+        assert_eq!(
+            moves(&delta, 64).unwrap(),
+            vec![
+                Move {
+                    from: 2048,
+                    to: 1024,
+                    len: 1024,
+                },
+                Move {
+                    from: 1024,
+                    to: 2048,
+                    len: 1024,
+                },
+            ],
+        );
+        assert!(moves(&delta[..10], 0).is_err());
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let base = vec![0; 1000];
+        let mut data = base.clone();
+        data[100..150].fill(1);
+        data.extend_from_slice(&[2; 200]);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(50, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+        let buckets = heatmap(&delta, 4).unwrap();
+        assert_eq!(
+            buckets,
+            vec![
+                HeatmapBucket {
+                    range: 0..300,
+                    changed: 50,
+                },
+                HeatmapBucket {
+                    range: 300..600,
+                    changed: 0,
+                },
+                HeatmapBucket {
+                    range: 600..900,
+                    changed: 0,
+                },
+                HeatmapBucket {
+                    range: 900..1200,
+                    changed: 200,
+                },
+            ],
+        );
+        assert_eq!(buckets[3].percent_changed(), 200.0 * 100.0 / 300.0);
+        assert!(heatmap(b"", 4).is_err());
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let mut base = vec![0; 1000];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut target = base.clone();
+        target[10] ^= 1;
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(100, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &target, &mut delta).unwrap();
+        assert!(matches!(diagnose(&base, &target, &delta), Diagnosis::Match));
+
+        // The base changed after the signature was calculated.
+        let mut changed = base.clone();
+        changed[450] ^= 1;
+        let Diagnosis::Diverged(divergence) = diagnose(&changed, &target, &delta) else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(
+            divergence,
+            Divergence {
+                position: 450,
+                output_len: 1000,
+                target_len: 1000,
+                op_index: Some(1),
+                op: Some(OpHeader::Copy {
+                    offset: 100,
+                    len: 900,
+                }),
+                base_offset: Some(450),
+            },
+        );
+        assert_eq!(divergence.base_block(100), Some(4));
+
+        let Diagnosis::Diverged(divergence) = diagnose(&base, &target[..999], &delta) else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.position, 999);
+        assert_eq!(divergence.base_offset, Some(999));
+        assert!(matches!(
+            diagnose(&base, &target, &delta[..10]),
+            Diagnosis::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn test_differing_blocks() {
+        let options = SignatureOptions::new(100, 8, HashAlgorithm::Blake3);
+        let mut base = vec![0; 1050];
+        rand::thread_rng().fill(&mut base[..]);
+        let old = Signature::calculate(&base, options);
+        let mut data = base.clone();
+        assert_eq!(differing_blocks(&old, &Signature::calculate(&data, options)).unwrap(), None);
+        assert_eq!(differing_blocks_from_data(&old, &data).unwrap(), None);
+
+        data[250] ^= 1;
+        data[720] ^= 1;
+        let new = Signature::calculate(&data, options);
+        assert_eq!(differing_blocks(&old, &new).unwrap(), Some(2..=7));
+        assert_eq!(differing_blocks_from_data(&old, &data).unwrap(), Some(2..=7));
+
+        // Truncated data differs in its last, partial block and is missing the rest.
+        data.truncate(950);
+        assert_eq!(differing_blocks_from_data(&old, &data).unwrap(), Some(2..=10));
+        assert_eq!(differing_blocks_from_data(&old, &base[..1000]).unwrap(), Some(10..=10));
+
+        let md4 = Signature::calculate(
+            &base,
+            SignatureOptions {
+                hash_algorithm: HashAlgorithm::Md4,
+                ..options
+            },
+        );
+        assert!(matches!(
+            differing_blocks(&old, &md4),
+            Err(CompareError::OptionsMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,247 @@
+//! Counterparts of the main entry points for tokio's [AsyncRead] and [AsyncWrite].
+//!
+//! The work is done in pieces of 256 KiB, yielding to the runtime after each one, so long
+//! signatures, deltas and patches share executor threads with other tasks instead of blocking
+//! them or needing `spawn_blocking`.
+//!
+//! Data already in memory can be passed as a `&[u8]`, which is an [AsyncRead], e.g. to stream a
+//! delta straight onto a network connection with [diff_async]. The output is written in pieces
+//! of up to 256 KiB as it is produced, never buffered as a whole. For sinks implementing
+//! `futures::io::AsyncWrite` instead, `tokio_util::compat` converts between the two traits.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::yield_now;
+
+use crate::compress::unpack_delta;
+use crate::diff::{DiffError, DiffSession};
+use crate::fingerprint::check_fingerprint;
+use crate::patch::{fill_repeated, ApplyError, Op, OpReader};
+use crate::signature::{IndexedSignature, Signature, SignatureOptions};
+use crate::strict::{invalid_input, require};
+
+/// How much data is processed between yields to the runtime.
+const ASYNC_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Compute the signature of everything read from `reader`, like
+/// [Signature::calculate_from_reader].
+///
+/// Panics if the provided options are invalid, unless the `strict` feature turns that into an
+/// [io::ErrorKind::InvalidInput] error.
+pub async fn calculate_async(
+    mut reader: impl AsyncRead + Unpin,
+    options: SignatureOptions,
+) -> io::Result<Signature> {
+    require!(options.is_valid(), invalid_input("options"));
+    // Every chunk but the last must be a whole number of blocks.
+    let block_size = options.block_size as usize;
+    let chunk_size = ASYNC_CHUNK_SIZE.div_ceil(block_size) * block_size;
+    let mut signature = Vec::new();
+    Signature::write_header(&options, &mut signature);
+    let mut chunk = Vec::with_capacity(chunk_size);
+    loop {
+        chunk.clear();
+        (&mut reader).take(chunk_size as u64).read_to_end(&mut chunk).await?;
+        Signature::hash_blocks(&chunk, &options, &mut signature);
+        if chunk.len() < chunk_size {
+            return Ok(Signature::from_serialized_parts(&options, signature));
+        }
+        yield_now().await;
+    }
+}
+
+/// Calculate a delta from `signature` to everything read from `reader`, writing it to `out`, like
+/// [diff_from_reader](crate::diff_from_reader()).
+///
+/// # Security
+/// See [diff()](crate::diff()).
+pub async fn diff_async(
+    signature: &IndexedSignature<'_>,
+    mut reader: impl AsyncRead + Unpin,
+    mut out: impl AsyncWrite + Unpin,
+) -> Result<(), DiffError> {
+    let mut buf = Vec::with_capacity(ASYNC_CHUNK_SIZE);
+    let mut session = DiffSession::with_max_literal(signature, ASYNC_CHUNK_SIZE, &mut buf)?;
+    let mut chunk = Vec::with_capacity(ASYNC_CHUNK_SIZE);
+    loop {
+        chunk.clear();
+        (&mut reader).take(ASYNC_CHUNK_SIZE as u64).read_to_end(&mut chunk).await?;
+        if chunk.is_empty() {
+            break;
+        }
+        session.push(&chunk, &mut buf)?;
+        out.write_all(&buf).await?;
+        buf.clear();
+        yield_now().await;
+    }
+    session.finish(&mut buf)?;
+    out.write_all(&buf).await?;
+    out.flush().await?;
+    Ok(())
+}
+
+/// Apply the delta read from `delta` to the base data `base`, writing the result to `out`, like
+/// [apply()](crate::apply()). Returns the length of the output.
+///
+/// The delta is read into memory first, so that compressed containers and fingerprinted deltas
+/// are handled like [apply()](crate::apply()) does, but the output is written as it is
+/// reconstructed.
+///
+/// # Security
+/// See [apply()](crate::apply()): the output of a malicious delta is unbounded.
+pub async fn apply_async(
+    base: &[u8],
+    mut delta: impl AsyncRead + Unpin,
+    mut out: impl AsyncWrite + Unpin,
+) -> Result<u64, ApplyError> {
+    let mut packed = Vec::new();
+    delta.read_to_end(&mut packed).await?;
+    let delta = unpack_delta(&packed, base, usize::MAX)?;
+    let mut ops = OpReader::new(check_fingerprint(&delta, base)?)?;
+    let mut written = 0u64;
+    // The output since the last yield.
+    let mut pending = 0u64;
+    let mut buf = vec![0; ASYNC_CHUNK_SIZE];
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Literal(literal) => out.write_all(literal).await?,
+            Op::Copy { offset, len } => {
+                if len == 0 {
+                    return Err(ApplyError::CopyZero);
+                }
+                let data = usize::try_from(offset)
+                    .ok()
+                    .zip(usize::try_from(len).ok())
+                    .and_then(|(offset, len)| base.get(offset..offset.checked_add(len)?))
+                    .ok_or(ApplyError::CopyOutOfBounds {
+                        offset,
+                        len,
+                        data_len: base.len(),
+                    })?;
+                out.write_all(data).await?;
+            }
+            Op::Repeat { pattern, .. } => {
+                let len = op.output_len();
+                let mut done = 0;
+                while done < len {
+                    let n = (len - done).min(buf.len() as u64) as usize;
+                    fill_repeated(pattern, done, &mut buf[..n]);
+                    out.write_all(&buf[..n]).await?;
+                    done += n as u64;
+                    yield_now().await;
+                }
+            }
+        }
+        written = written.saturating_add(op.output_len());
+        pending = pending.saturating_add(op.output_len());
+        if pending >= ASYNC_CHUNK_SIZE as u64 {
+            pending = 0;
+            yield_now().await;
+        }
+    }
+    out.flush().await?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use rand::Rng;
+    use tokio::io::AsyncWrite;
+    use tokio::runtime::Builder;
+
+    use super::{apply_async, calculate_async, diff_async};
+    use crate::{apply, HashAlgorithm, Signature, SignatureOptions};
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    /// A slow connection: every other write is pending, and at most 1000 bytes are taken at once.
+    #[derive(Default)]
+    struct Throttled {
+        received: Vec<u8>,
+        ready: bool,
+        writes: usize,
+    }
+
+    impl AsyncWrite for Throttled {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(1000);
+            self.received.extend_from_slice(&buf[..n]);
+            self.writes += 1;
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_async() {
+        let mut base = vec![0; 1 << 20];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut data = base.clone();
+        data[1000..2000].fill(0);
+        data.splice(500_000..500_000, vec![3; 300_000]);
+        let options = SignatureOptions::new(1000, 8, HashAlgorithm::Blake3);
+        let signature = Signature::calculate(&base, options);
+        let indexed = signature.index();
+        // Servers spawn these onto multi-threaded runtimes.
+        assert_send(&calculate_async(&base[..], options));
+        assert_send(&diff_async(&indexed, &data[..], vec![]));
+        assert_send(&apply_async(&base, &data[..], vec![]));
+
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let signature = calculate_async(&base[..], options).await.unwrap();
+            assert_eq!(signature, Signature::calculate(&base, options));
+
+            let mut delta = vec![];
+            diff_async(&signature.index(), &data[..], &mut delta).await.unwrap();
+            let mut out = vec![];
+            apply(&base, &delta, &mut out).unwrap();
+            assert_eq!(out, data);
+
+            let mut out = vec![];
+            let len = apply_async(&base, &delta[..], &mut out).await.unwrap();
+            assert_eq!(len, data.len() as u64);
+            assert_eq!(out, data);
+        });
+    }
+
+    #[test]
+    fn test_async_writer() {
+        let base: Vec<u8> = (0..300_000u32).map(|i| (i * 17 % 257) as u8).collect();
+        let data = [&base[..100_000], &[5; 50_000], &base[100_000..]].concat();
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(512, 8, HashAlgorithm::Md4),
+        );
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut delta = Throttled::default();
+            diff_async(&signature.index(), &data[..], &mut delta).await.unwrap();
+            assert!(delta.writes > 1);
+            let mut out = Throttled::default();
+            apply_async(&base, &delta.received[..], &mut out).await.unwrap();
+            assert_eq!(out.received, data);
+        });
+    }
+}
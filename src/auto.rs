@@ -0,0 +1,109 @@
+//! Choosing a delta calculation strategy from the shape of the input.
+
+use std::io::Write;
+
+use crate::diff::{diff, DiffError};
+use crate::pipeline::Pipeline;
+use crate::signature::IndexedSignature;
+#[cfg(feature = "parallel")]
+use crate::signature::SignatureType;
+
+/// Inputs at least this large are worth spreading across threads.
+const LARGE_INPUT: usize = 16 << 20;
+/// Parallel matching needs enough blocks per thread to amortize scheduling.
+#[cfg(feature = "parallel")]
+const MIN_BLOCKS_PER_THREAD: usize = 64;
+
+/// A way of calculating a delta, as picked by [DiffStrategy::choose].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DiffStrategy {
+    /// [diff()](crate::diff()) on the calling thread.
+    Sequential,
+    /// [Pipeline::diff], which writes the delta on a thread of its own while matching.
+    Pipelined,
+    /// [diff_parallel](crate::diff_parallel), which matches blocks on all of rayon's threads.
+    #[cfg(feature = "parallel")]
+    Parallel,
+}
+
+impl DiffStrategy {
+    /// Pick the strategy for a delta of `data_len` bytes against `signature`.
+    ///
+    /// Small inputs are diffed sequentially, since threads wouldn't pay for themselves. Large
+    /// inputs are matched in parallel if the `parallel` feature is enabled, the signature uses
+    /// BLAKE3 and more than one thread is available, and are pipelined otherwise.
+    #[cfg_attr(not(feature = "parallel"), allow(unused_variables))]
+    pub fn choose(signature: &IndexedSignature<'_>, data_len: usize) -> Self {
+        if data_len < LARGE_INPUT {
+            return DiffStrategy::Sequential;
+        }
+        #[cfg(feature = "parallel")]
+        {
+            let threads = rayon::current_num_threads();
+            let blocks = data_len / signature.block_size.max(1) as usize;
+            if let SignatureType::Blake3 = signature.signature_type {
+                if threads > 1 && blocks >= threads * MIN_BLOCKS_PER_THREAD {
+                    return DiffStrategy::Parallel;
+                }
+            }
+        }
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if threads > 1 {
+            DiffStrategy::Pipelined
+        } else {
+            DiffStrategy::Sequential
+        }
+    }
+}
+
+/// Calculate a delta with the strategy [DiffStrategy::choose] picks for `data`, and write it to
+/// `out`.
+///
+/// The delta always reconstructs `data`. Parallel matching writes the same delta as [diff()],
+/// while pipelining splits long literals into several ops.
+///
+/// # Security
+/// See [diff()].
+pub fn diff_auto(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    out: impl Write + Send,
+) -> Result<(), DiffError> {
+    match DiffStrategy::choose(signature, data.len()) {
+        DiffStrategy::Sequential => diff(signature, data, out),
+        DiffStrategy::Pipelined => Pipeline::new().diff(signature, data, out),
+        #[cfg(feature = "parallel")]
+        DiffStrategy::Parallel => crate::diff::diff_parallel(signature, data, out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_auto, DiffStrategy};
+    use crate::{apply, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_diff_auto() {
+        let base = vec![5; 100_000];
+        let mut data = base.clone();
+        data[50_000] = 6;
+        for hash_algorithm in [HashAlgorithm::Md4, HashAlgorithm::Blake3] {
+            let signature = Signature::calculate(
+                &base,
+                SignatureOptions::new(1024, 8, hash_algorithm),
+            );
+            let indexed = signature.index();
+            assert_eq!(DiffStrategy::choose(&indexed, data.len()), DiffStrategy::Sequential);
+            // MD4 is never matched in parallel
+            #[cfg(feature = "parallel")]
+            if hash_algorithm == HashAlgorithm::Md4 {
+                assert_ne!(DiffStrategy::choose(&indexed, 1 << 30), DiffStrategy::Parallel);
+            }
+            let mut delta = vec![];
+            diff_auto(&indexed, &data, &mut delta).unwrap();
+            let mut out = vec![];
+            apply(&base, &delta, &mut out).unwrap();
+            assert_eq!(out, data);
+        }
+    }
+}
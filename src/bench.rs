@@ -0,0 +1,133 @@
+//! Measuring the speed of this crate on the hardware it runs on.
+//!
+//! Applications can call these at startup on a sample of their own data and pick options (block
+//! size, hash algorithm, whether to diff in parallel) by the results, instead of relying on
+//! numbers measured elsewhere.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use crate::diff::DiffError;
+use crate::patch::ApplyError;
+use crate::signature::{IndexedSignature, Signature, SignatureOptions};
+
+/// How much work was done in how much time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Measurement {
+    /// The amount of work: bytes, or blocks for [index].
+    pub units: u64,
+    /// How long the work took.
+    pub elapsed: Duration,
+}
+
+impl Measurement {
+    /// The rate of work per second.
+    pub fn per_second(&self) -> f64 {
+        self.units as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    fn time<T>(units: u64, f: impl FnOnce() -> T) -> (Self, T) {
+        let start = Instant::now();
+        let result = black_box(f());
+        let elapsed = start.elapsed();
+        (Measurement { units, elapsed }, result)
+    }
+}
+
+/// The results of [run].
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Report {
+    /// Signature calculation over the base data, in bytes.
+    pub signature: Measurement,
+    /// Index building, in blocks.
+    pub index: Measurement,
+    /// Delta calculation over the new data, in bytes.
+    pub diff: Measurement,
+    /// Delta application, in bytes of output.
+    pub apply: Measurement,
+    /// The size of the delta.
+    pub delta_len: usize,
+}
+
+/// Measure calculating the signature of `data` with `options`, i.e. hashing every block.
+///
+/// Panics if the provided options are invalid.
+pub fn signature(data: &[u8], options: SignatureOptions) -> (Measurement, Signature) {
+    Measurement::time(data.len() as u64, || Signature::calculate(black_box(data), options))
+}
+
+/// Measure building the block index of `signature`.
+pub fn index(signature: &Signature) -> (Measurement, IndexedSignature<'_>) {
+    let blocks = signature.block_entries().len() as u64;
+    Measurement::time(blocks, || signature.index())
+}
+
+/// Measure calculating the delta from `signature` to `data`.
+pub fn diff(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+) -> Result<(Measurement, Vec<u8>), DiffError> {
+    let mut delta = Vec::new();
+    let (measurement, result) = Measurement::time(data.len() as u64, || {
+        crate::diff::diff(signature, black_box(data), &mut delta)
+    });
+    result?;
+    Ok((measurement, delta))
+}
+
+/// Measure applying `delta` to `base`.
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Measurement, ApplyError> {
+    let mut out = Vec::new();
+    let start = Instant::now();
+    crate::patch::apply(black_box(base), black_box(delta), &mut out)?;
+    let elapsed = start.elapsed();
+    black_box(&out);
+    Ok(Measurement {
+        units: out.len() as u64,
+        elapsed,
+    })
+}
+
+/// Measure every step of syncing `base` to `data` with `options`.
+///
+/// Panics if the provided options are invalid.
+pub fn run(base: &[u8], data: &[u8], options: SignatureOptions) -> Result<Report, DiffError> {
+    let (signature_time, signature) = signature(base, options);
+    let (index_time, indexed) = index(&signature);
+    let (diff_time, delta) = diff(&indexed, data)?;
+    let apply_time = apply(base, &delta).expect("diff produced an invalid delta");
+    Ok(Report {
+        signature: signature_time,
+        index: index_time,
+        diff: diff_time,
+        apply: apply_time,
+        delta_len: delta.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use crate::{HashAlgorithm, SignatureOptions};
+
+    #[test]
+    fn test_bench_run() {
+        let base = vec![1; 100_000];
+        let mut data = base.clone();
+        data.extend_from_slice(b"tail");
+        let report = run(
+            &base,
+            &data,
+            SignatureOptions::new(1000, 8, HashAlgorithm::Blake3),
+        )
+        .unwrap();
+        assert_eq!(report.signature.units, 100_000);
+        assert_eq!(report.index.units, 100);
+        assert_eq!(report.diff.units, 100_004);
+        assert_eq!(report.apply.units, 100_004);
+        assert!(report.delta_len < 100);
+        assert!(report.diff.per_second() > 0.0);
+    }
+}
@@ -0,0 +1,66 @@
+//! BLAKE2b block hashes, as librsync computes them for its BLAKE2 signatures.
+//!
+//! librsync configures BLAKE2b for a 32-byte output, which is a different hash than the first
+//! 32 bytes of the usual 64-byte BLAKE2b.
+
+use blake2b_simd::many::{hash_many, HashManyJob, MAX_DEGREE};
+use blake2b_simd::Params;
+
+pub const BLAKE2_SIZE: usize = 32;
+
+fn params() -> Params {
+    let mut params = Params::new();
+    params.hash_length(BLAKE2_SIZE);
+    params
+}
+
+/// Compute the BLAKE2 hash of a single block of data
+pub fn blake2(data: &[u8]) -> [u8; BLAKE2_SIZE] {
+    params().hash(data).as_bytes().try_into().unwrap()
+}
+
+/// Compute BLAKE2 hashes of multiple blocks of data, up to [MAX_DEGREE] of them at once with SIMD
+pub fn blake2_many<'a>(
+    datas: impl ExactSizeIterator<Item = &'a [u8]>,
+) -> impl ExactSizeIterator<Item = (&'a [u8], [u8; BLAKE2_SIZE])> {
+    let params = params();
+    let datas: Vec<&[u8]> = datas.collect();
+    let mut hashes = Vec::with_capacity(datas.len());
+    for batch in datas.chunks(MAX_DEGREE) {
+        let mut jobs: Vec<_> = batch.iter().map(|data| HashManyJob::new(&params, data)).collect();
+        hash_many(jobs.iter_mut());
+        hashes.extend(
+            jobs.iter()
+                .map(|job| -> [u8; BLAKE2_SIZE] { job.to_hash().as_bytes().try_into().unwrap() }),
+        );
+    }
+    datas.into_iter().zip(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blake2() {
+        // BLAKE2b-256 of the empty string and of "abc".
+        assert_eq!(
+            blake2(b""),
+            *b"\x0e\x57\x51\xc0\x26\xe5\x43\xb2\xe8\xab\x2e\xb0\x60\x99\xda\xa1\
+               \xd1\xe5\xdf\x47\x77\x8f\x77\x87\xfa\xab\x45\xcd\xf1\x2f\xe3\xa8"
+        );
+        assert_eq!(
+            blake2(b"abc"),
+            *b"\xbd\xdd\x81\x3c\x63\x42\x39\x72\x31\x71\xef\x3f\xee\x98\x57\x9b\
+               \x94\x96\x4e\x3b\xb1\xcb\x3e\x42\x72\x62\xc8\xc0\x68\xd5\x23\x19"
+        );
+
+        let datas: Vec<Vec<u8>> = (0..10).map(|i| vec![i; i as usize * 100]).collect();
+        let results: Vec<_> = blake2_many(datas.iter().map(|x| &x[..])).collect();
+        assert_eq!(results.len(), datas.len());
+        for ((block, hash), data) in results.into_iter().zip(&datas) {
+            assert_eq!(block, &data[..]);
+            assert_eq!(hash, blake2(data));
+        }
+    }
+}
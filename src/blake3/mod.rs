@@ -1,38 +1,14 @@
 //! A BLAKE3 implementation for fast_rsync with parallel processing support.
 //! BLAKE3 is a cryptographic hash function that is both fast and secure.
 
-use std::iter::Iterator;
-
 pub const BLAKE3_SIZE: usize = 32;  // Default output size
+/// The longest output requested from the BLAKE3 XOF.
+pub const BLAKE3_MAX_SIZE: usize = 64;
 
-/// Compute BLAKE3 hash of a single block of data
-pub fn blake3(data: &[u8]) -> [u8; 32] {
-    blake3::hash(data).into()
-}
-
-/// Compute BLAKE3 hashes for multiple blocks of data in parallel
-pub fn blake3_many<'a>(
-    datas: impl ExactSizeIterator<Item = &'a [u8]>,
-) -> impl ExactSizeIterator<Item = (&'a [u8], [u8; 32])> {
-    struct Blake3Iterator<'a, I: Iterator<Item = &'a [u8]>> {
-        inner: I,
-    }
-
-    impl<'a, I: Iterator<Item = &'a [u8]>> Iterator for Blake3Iterator<'a, I> {
-        type Item = (&'a [u8], [u8; 32]);
-
-        fn next(&mut self) -> Option<Self::Item> {
-            self.inner.next().map(|data| (data, blake3(data)))
-        }
-
-        fn size_hint(&self) -> (usize, Option<usize>) {
-            self.inner.size_hint()
-        }
-    }
-
-    impl<'a, I: ExactSizeIterator<Item = &'a [u8]>> ExactSizeIterator for Blake3Iterator<'a, I> {}
-
-    Blake3Iterator { inner: datas }
+/// Fill `out` with the BLAKE3 hash of a single block of data, extended with the XOF beyond
+/// [BLAKE3_SIZE] bytes. Shorter outputs are prefixes of longer ones.
+pub fn blake3_xof(data: &[u8], out: &mut [u8]) {
+    blake3::Hasher::new().update(data).finalize_xof().fill(out);
 }
 
 #[cfg(test)]
@@ -42,31 +18,23 @@ mod tests {
     #[test]
     fn test_blake3_basic() {
         let data = b"hello world";
-        let hash = blake3(data);
-        assert_eq!(hash.len(), 32);
+        let mut hash = [0; BLAKE3_SIZE];
+        blake3_xof(data, &mut hash);
+        assert_eq!(hash, *blake3::hash(data).as_bytes());
         
         // Test that same input produces same output
-        let hash2 = blake3(data);
+        let mut hash2 = [0; BLAKE3_SIZE];
+        blake3_xof(data, &mut hash2);
         assert_eq!(hash, hash2);
     }
 
     #[test]
-    fn test_blake3_many() {
-        let data1 = b"block1";
-        let data2 = b"block2";
-        let data3 = b"block3";
-        
-        let datas = vec![data1, data2, data3];
-        let results: Vec<_> = blake3_many(datas.iter().map(|x| &x[..])).collect();
-        
-        assert_eq!(results.len(), 3);
-        assert_eq!(results[0].0, b"block1");
-        assert_eq!(results[1].0, b"block2");
-        assert_eq!(results[2].0, b"block3");
-        
-        // Verify each hash is 32 bytes
-        for (_, hash) in &results {
-            assert_eq!(hash.len(), 32);
-        }
+    fn test_blake3_xof() {
+        let mut hash = [0; BLAKE3_SIZE];
+        blake3_xof(b"hello world", &mut hash);
+        let mut out = [0; BLAKE3_MAX_SIZE];
+        blake3_xof(b"hello world", &mut out);
+        assert_eq!(out[..BLAKE3_SIZE], hash);
+        assert_ne!(out[BLAKE3_SIZE..], [0; BLAKE3_MAX_SIZE - BLAKE3_SIZE]);
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,274 @@
+//! Syncing raw block devices and VM images in place.
+//!
+//! Unlike [diff()][crate::diff()], the block-device mode compares the new data to the basis block
+//! for block at the same position, without searching for shifted matches. Blocks of a device
+//! don't move around, and skipping the search keeps every op aligned to the block size, so the
+//! delta can be written straight onto the basis device with direct IO: unchanged blocks become
+//! copies of themselves, which [apply] skips, and only changed blocks are written.
+//!
+//! Direct IO bypasses the page cache, which keeps a sync of a multi-TB device from evicting
+//! everything else. It requires the block size to be a multiple of the device's logical block
+//! size (usually 512 or 4096 bytes), and the device or image size to be a multiple of the block
+//! size.
+
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::consts::{DELTA_MAGIC, RS_OP_END};
+use crate::crc::Crc;
+use crate::diff::DiffError;
+use crate::ops::{encode_copy, encode_literal_header};
+use crate::patch::{fill_repeated, ApplyError, Op, OpReader};
+use crate::signature::{HashAlgorithm, Signature, SignatureOptions};
+use crate::strict::{invalid_input, require};
+
+/// The block size used by [signature_options].
+pub const BLOCK_SIZE: u32 = 4096;
+
+/// The alignment of the buffers used for direct IO.
+const ALIGNMENT: usize = 4096;
+
+/// How many bytes are read from the device at a time, in blocks.
+const CHUNK_BLOCKS: usize = 256;
+
+/// Signature options for syncing a block device: 4 KiB blocks and the full hash.
+pub fn signature_options(hash_algorithm: HashAlgorithm) -> SignatureOptions {
+    SignatureOptions::new(BLOCK_SIZE, hash_algorithm.max_hash_size() as u32, hash_algorithm)
+}
+
+/// Open a device or image for direct IO (`O_DIRECT` on Linux; a regular open elsewhere).
+pub fn open(path: impl AsRef<Path>, writable: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(writable);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_DIRECT);
+    }
+    options.open(path)
+}
+
+/// Compute the signature of `device`.
+///
+/// The result is identical to [Signature::calculate] over the device's contents.
+/// Panics if the provided options are invalid, unless the `strict` feature turns that into an
+/// [io::ErrorKind::InvalidInput] error.
+pub fn signature(device: &File, options: SignatureOptions) -> io::Result<Signature> {
+    require!(options.is_valid(), invalid_input("options"));
+
+    let mut signature = Vec::new();
+    Signature::write_header(&options, &mut signature);
+    let chunk_size = CHUNK_BLOCKS * options.block_size as usize;
+    let mut buf = AlignedBuf::new(chunk_size);
+    let mut offset = 0;
+    loop {
+        let n = read_chunk(device, offset, &mut buf[..chunk_size])?;
+        Signature::hash_blocks(&buf[..n], &options, &mut signature);
+        if n < chunk_size {
+            return Ok(Signature::from_serialized_parts(&options, signature));
+        }
+        offset += n as u64;
+    }
+}
+
+/// Calculate a delta from `signature` to the contents of `device`, comparing block for block.
+///
+/// Every op in the resulting delta starts at a multiple of the signature's block size, and every
+/// copy is a copy of the block at the same position. It can be applied with [apply] as well as
+/// with the regular [apply()][crate::apply()].
+pub fn diff(signature: &Signature, device: &File, mut out: impl Write) -> Result<(), DiffError> {
+    let options = signature.options().ok_or(DiffError::InvalidSignature)?;
+    let block_size = options.block_size as usize;
+    let entry_size = Crc::SIZE + options.crypto_hash_size as usize;
+    let mut old_entries = signature.block_entries();
+    let mut new_entries = Vec::new();
+
+    out.write_all(&DELTA_MAGIC.to_be_bytes())?;
+    let chunk_size = CHUNK_BLOCKS * block_size;
+    let mut buf = AlignedBuf::new(chunk_size);
+    let mut offset = 0;
+    // The unchanged blocks seen so far that have not been written as a copy yet.
+    let mut queued_copy: Option<(u64, u64)> = None;
+    loop {
+        let n = read_chunk(device, offset, &mut buf[..chunk_size])?;
+        new_entries.clear();
+        Signature::hash_blocks(&buf[..n], &options, &mut new_entries);
+        for (block, new_entry) in buf[..n].chunks(block_size).zip(new_entries.chunks(entry_size)) {
+            if old_entries.next() == Some(new_entry) {
+                queued_copy = match queued_copy {
+                    Some((start, len)) => Some((start, len + block.len() as u64)),
+                    None => Some((offset, block.len() as u64)),
+                };
+            } else {
+                if let Some((start, len)) = queued_copy.take() {
+                    encode_copy(start, len, &mut out)?;
+                }
+                encode_literal_header(block.len() as u64, &mut out)?;
+                out.write_all(block)?;
+            }
+            offset += block.len() as u64;
+        }
+        if n < chunk_size {
+            break;
+        }
+    }
+    if let Some((start, len)) = queued_copy {
+        encode_copy(start, len, &mut out)?;
+    }
+    out.write_all(&[RS_OP_END])?;
+    Ok(())
+}
+
+/// Apply `delta` onto `device` in place.
+///
+/// `device` must hold the basis data, and every copy in `delta` must be a copy of the data at its
+/// own position (as produced by [diff]), which is skipped. Literals are written at their position
+/// in the output. A regular file is truncated to the length of the output afterwards.
+pub fn apply(device: &File, delta: &[u8]) -> Result<(), ApplyError> {
+    let mut ops = OpReader::new(delta)?;
+    let mut position = 0;
+    let mut buf = AlignedBuf::new(0);
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Copy { offset, len } => {
+                if offset != position {
+                    return Err(ApplyError::CopyNotInPlace { offset, position });
+                }
+                position += len;
+            }
+            Op::Literal(literal) => {
+                if buf.len() < literal.len() {
+                    buf = AlignedBuf::new(literal.len());
+                }
+                buf[..literal.len()].copy_from_slice(literal);
+                device.write_all_at(&buf[..literal.len()], position)?;
+                position += literal.len() as u64;
+            }
+            Op::Repeat { pattern, .. } => {
+                let len = op.output_len();
+                let chunk = len.min((CHUNK_BLOCKS * BLOCK_SIZE as usize) as u64) as usize;
+                if buf.len() < chunk {
+                    buf = AlignedBuf::new(chunk);
+                }
+                let mut done = 0;
+                while done < len {
+                    let n = (len - done).min(chunk as u64) as usize;
+                    fill_repeated(pattern, done, &mut buf[..n]);
+                    device.write_all_at(&buf[..n], position + done)?;
+                    done += n as u64;
+                }
+                position += len;
+            }
+        }
+    }
+    let metadata = device.metadata()?;
+    if metadata.is_file() && metadata.len() > position {
+        device.set_len(position)?;
+    }
+    Ok(())
+}
+
+/// Fill `buf` from `device` starting at `offset`, returning how many bytes were read.
+/// Less than `buf.len()` bytes are read only at the end of the device.
+fn read_chunk(device: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match device.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// A zeroed heap buffer aligned for direct IO.
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        // Round up so the full buffer can be used for direct IO as well.
+        let layout = Layout::from_size_align(len.div_ceil(ALIGNMENT).max(1) * ALIGNMENT, ALIGNMENT)
+            .expect("buffer too large");
+        // SAFETY: `layout` has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `layout.size()` initialized bytes owned by `self`.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: `ptr` points to `layout.size()` initialized bytes owned by `self`.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` was allocated with `layout`.
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{apply, HashAlgorithm};
+
+    #[test]
+    fn test_blockdev_sync() {
+        let block = super::BLOCK_SIZE as usize;
+        let base: Vec<u8> = (0..block * 300).map(|i| (i % 253) as u8).collect();
+        let mut data = base.clone();
+        data[block * 3 + 17] ^= 0xff;
+        data[block * 299..].fill(0);
+
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        let base_path = dir.join(format!("superfast_rsync_blockdev_base_{pid}"));
+        let data_path = dir.join(format!("superfast_rsync_blockdev_data_{pid}"));
+        fs::write(&base_path, &base).unwrap();
+        fs::write(&data_path, &data).unwrap();
+
+        // tmpfs doesn't support O_DIRECT, so exercise the logic with buffered files
+        let base_file = fs::OpenOptions::new().read(true).write(true).open(&base_path).unwrap();
+        let data_file = fs::File::open(&data_path).unwrap();
+        let options = super::signature_options(HashAlgorithm::Blake3);
+        let signature = super::signature(&base_file, options).unwrap();
+        assert_eq!(signature, crate::Signature::calculate(&base, options));
+
+        let mut delta = vec![];
+        super::diff(&signature, &data_file, &mut delta).unwrap();
+        // the two changed blocks are the only literals
+        assert!(delta.len() < 3 * block);
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        super::apply(&base_file, &delta).unwrap();
+        let synced = fs::read(&base_path).unwrap();
+        fs::remove_file(base_path).unwrap();
+        fs::remove_file(data_path).unwrap();
+        assert_eq!(synced, data);
+    }
+}
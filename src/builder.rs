@@ -0,0 +1,148 @@
+use crate::crc::Crc;
+use crate::signature::{Signature, SignatureOptions, SignatureParseError};
+
+/// Computes a signature from data fed piece by piece, with a checkpoint that can be persisted to
+/// resume an interrupted calculation.
+///
+/// A checkpoint is the serialized signature of the whole blocks hashed so far. After
+/// [SignatureBuilder::resume], feed the input again from [SignatureBuilder::position]: the bytes
+/// of a trailing partial block aren't part of the checkpoint. The final signature is identical
+/// to [Signature::calculate] over the whole input.
+///
+/// ```
+/// # use superfast_rsync::{HashAlgorithm, Signature, SignatureBuilder, SignatureOptions};
+/// # let options = SignatureOptions::new(16, 8, HashAlgorithm::Blake3);
+/// let data = b"a file that takes hours to read".repeat(100);
+/// let mut builder = SignatureBuilder::new(options);
+/// builder.update(&data[..1000]);
+/// let checkpoint = builder.checkpoint();
+///
+/// // ...interrupted, then later:
+/// let mut builder = SignatureBuilder::resume(checkpoint)?;
+/// builder.update(&data[builder.position() as usize..]);
+/// assert_eq!(builder.finish(), Signature::calculate(&data, options));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct SignatureBuilder {
+    options: SignatureOptions,
+    /// The serialized signature of the whole blocks hashed so far.
+    signature: Vec<u8>,
+    /// The start of the next block, shorter than a block.
+    partial: Vec<u8>,
+}
+
+impl SignatureBuilder {
+    /// Start a signature calculated with `options`.
+    ///
+    /// Panics if the provided options are invalid, unless the `strict` feature replaces them with
+    /// the nearest valid ones.
+    pub fn new(options: SignatureOptions) -> Self {
+        #[cfg(feature = "strict")]
+        let options = options.clamped();
+        assert!(options.is_valid());
+
+        let mut signature = Vec::new();
+        Signature::write_header(&options, &mut signature);
+        SignatureBuilder {
+            options,
+            signature,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Continue from a checkpoint returned by [SignatureBuilder::checkpoint].
+    ///
+    /// Fails if `checkpoint` isn't a signature that this crate can calculate. A finished
+    /// signature is taken as a checkpoint too, but its last block may be incomplete, which the
+    /// signature doesn't record, so the result would be wrong.
+    pub fn resume(checkpoint: Vec<u8>) -> Result<Self, SignatureParseError> {
+        let signature = Signature::deserialize(checkpoint)?.without_index();
+        let options = signature.options().ok_or(SignatureParseError(()))?;
+        Ok(SignatureBuilder {
+            options,
+            signature: signature.into_serialized(),
+            partial: Vec::new(),
+        })
+    }
+
+    /// Hash the next piece of the input.
+    pub fn update(&mut self, mut data: &[u8]) {
+        let block_size = self.options.block_size as usize;
+        if !self.partial.is_empty() {
+            let n = (block_size - self.partial.len()).min(data.len());
+            self.partial.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.partial.len() < block_size {
+                return;
+            }
+            Signature::hash_blocks(&self.partial, &self.options, &mut self.signature);
+            self.partial.clear();
+        }
+        let whole = data.len() - data.len() % block_size;
+        Signature::hash_blocks(&data[..whole], &self.options, &mut self.signature);
+        self.partial.extend_from_slice(&data[whole..]);
+    }
+
+    /// The length of the input covered by [SignatureBuilder::checkpoint], where the input must
+    /// be fed from after resuming.
+    pub fn position(&self) -> u64 {
+        let entry_size = Crc::SIZE + self.options.crypto_hash_size as usize;
+        let blocks = (self.signature.len() - self.options.header_size()) / entry_size;
+        blocks as u64 * self.options.block_size as u64
+    }
+
+    /// The state to persist, which [SignatureBuilder::resume] continues from.
+    pub fn checkpoint(&self) -> Vec<u8> {
+        self.signature.clone()
+    }
+
+    /// Hash the rest of the input and return the signature.
+    pub fn finish(mut self) -> Signature {
+        Signature::hash_blocks(&self.partial, &self.options, &mut self.signature);
+        Signature::from_serialized_parts(&self.options, self.signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::SignatureBuilder;
+    use crate::{HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_signature_builder() {
+        let mut rng = rand::thread_rng();
+        let mut data = vec![0; 10_000];
+        rng.fill(&mut data[..]);
+        for hash_algorithm in [HashAlgorithm::Md4, HashAlgorithm::Blake3] {
+            let options = SignatureOptions::new(100, 8, hash_algorithm);
+            let expected = Signature::calculate(&data, options);
+            for len in [0, 50, 100, 5_050, 10_000] {
+                let data = &data[..len];
+                let expected = Signature::calculate(data, options);
+                let mut builder = SignatureBuilder::new(options);
+                let mut rest = data;
+                while !rest.is_empty() {
+                    let (piece, tail) = rest.split_at(rng.gen_range(1..300).min(rest.len()));
+                    builder.update(piece);
+                    rest = tail;
+                }
+                assert_eq!(builder.finish(), expected, "{len}");
+            }
+
+            // Interrupt in the middle of a block, twice.
+            let mut builder = SignatureBuilder::new(options);
+            builder.update(&data[..3_333]);
+            assert_eq!(builder.position(), 3_300);
+            let mut builder = SignatureBuilder::resume(builder.checkpoint()).unwrap();
+            builder.update(&data[3_300..7_777]);
+            let mut builder = SignatureBuilder::resume(builder.checkpoint()).unwrap();
+            assert_eq!(builder.position(), 7_700);
+            builder.update(&data[7_700..]);
+            assert_eq!(builder.finish(), expected);
+        }
+        assert!(SignatureBuilder::resume(b"garbage".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Caching signatures across syncs of the same basis.
+//!
+//! A sync daemon or a build tool often computes signatures of the same unchanged files over and
+//! over. [IndexCache] keeps them in memory, keyed by the file's identity, and evicts the least
+//! recently used ones once a byte budget is exceeded. Signatures computed by the cache carry a
+//! prebuilt index (see [Signature::with_index]), so [Signature::index] is free on a hit as well.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::signature::{HashAlgorithm, Signature, SignatureOptions};
+
+/// How a cached file is recognized as unchanged.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FileIdentity {
+    /// The modification time reported by the filesystem.
+    Modified(SystemTime),
+    /// A hash of the file contents, for filesystems with coarse or unreliable modification times.
+    ContentHash([u8; 32]),
+}
+
+/// Identifies a version of a file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FileKey {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The length of the file.
+    pub len: u64,
+    /// The modification time or content hash of the file.
+    pub identity: FileIdentity,
+}
+
+impl FileKey {
+    /// Identify the file at `path` by its length and modification time.
+    pub fn from_metadata(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path)?;
+        Ok(FileKey {
+            path: path.to_owned(),
+            len: metadata.len(),
+            identity: FileIdentity::Modified(metadata.modified()?),
+        })
+    }
+
+    /// Identify the file at `path`, whose contents are `data`, by its length and a BLAKE3 hash
+    /// of `data`.
+    pub fn from_contents(path: impl AsRef<Path>, data: &[u8]) -> Self {
+        FileKey {
+            path: path.as_ref().to_owned(),
+            len: data.len() as u64,
+            identity: FileIdentity::ContentHash(*::blake3::hash(data).as_bytes()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    file: FileKey,
+    block_size: u32,
+    crypto_hash_size: u32,
+    hash_algorithm: HashAlgorithm,
+    checksum_seed: u32,
+}
+
+impl Key {
+    fn new(file: FileKey, options: &SignatureOptions) -> Self {
+        Key {
+            file,
+            block_size: options.block_size,
+            crypto_hash_size: options.crypto_hash_size,
+            hash_algorithm: options.hash_algorithm,
+            checksum_seed: options.checksum_seed,
+        }
+    }
+}
+
+struct Entry {
+    signature: Arc<Signature>,
+    last_used: u64,
+}
+
+/// An in-memory cache of signatures with an LRU byte budget.
+///
+/// ```
+/// use superfast_rsync::cache::IndexCache;
+/// use superfast_rsync::{HashAlgorithm, SignatureOptions};
+///
+/// # let path = std::env::temp_dir().join(format!("superfast_rsync_cache_doc_{}", std::process::id()));
+/// # std::fs::write(&path, vec![1; 10_000]).unwrap();
+/// let options = SignatureOptions::new(1024, 8, HashAlgorithm::Blake3);
+/// let mut cache = IndexCache::new(64 << 20);
+/// let first = cache.signature(&path, options).unwrap();
+/// // unchanged since, so this is served from the cache
+/// let second = cache.signature(&path, options).unwrap();
+/// assert!(std::sync::Arc::ptr_eq(&first, &second));
+/// # std::fs::remove_file(path).unwrap();
+/// ```
+pub struct IndexCache {
+    budget: usize,
+    used: usize,
+    clock: u64,
+    entries: HashMap<Key, Entry>,
+    /// last use -> key, for finding the least recently used entry.
+    lru: BTreeMap<u64, Key>,
+}
+
+impl IndexCache {
+    /// Create a cache that holds signatures of up to `budget` bytes in total.
+    pub fn new(budget: usize) -> Self {
+        IndexCache {
+            budget,
+            used: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    /// Get the signature of the file at `path`, computing it only if the file changed since it
+    /// was last cached.
+    ///
+    /// The file is identified by its length and modification time. If those change while the
+    /// file is read, the signature is returned but not cached.
+    pub fn signature(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: SignatureOptions,
+    ) -> io::Result<Arc<Signature>> {
+        self.signature_with(path.as_ref(), options, |path| fs::read(path))
+    }
+
+    /// [IndexCache::signature], reading the file with `read`.
+    fn signature_with(
+        &mut self,
+        path: &Path,
+        options: SignatureOptions,
+        read: impl FnOnce(&Path) -> io::Result<Vec<u8>>,
+    ) -> io::Result<Arc<Signature>> {
+        let before = FileKey::from_metadata(path)?;
+        if let Some(signature) = self.get(&before, &options) {
+            return Ok(signature);
+        }
+        let data = read(path)?;
+        let signature = Arc::new(Signature::calculate_with_index(&data, options));
+        // The file may have changed while it was read, and then `data` is of neither version.
+        let after = FileKey::from_metadata(path)?;
+        if before == after && after.len == data.len() as u64 {
+            self.insert(after, &options, signature.clone());
+        }
+        Ok(signature)
+    }
+
+    /// Look up the signature computed with `options` of the file version `file`.
+    pub fn get(&mut self, file: &FileKey, options: &SignatureOptions) -> Option<Arc<Signature>> {
+        let key = Key::new(file.clone(), options);
+        let entry = self.entries.get_mut(&key)?;
+        self.clock += 1;
+        self.lru.remove(&entry.last_used);
+        entry.last_used = self.clock;
+        self.lru.insert(self.clock, key);
+        Some(entry.signature.clone())
+    }
+
+    /// Cache `signature`, computed with `options`, for the file version `file`.
+    ///
+    /// Older versions of the same file are dropped, and then the least recently used signatures
+    /// are evicted until the cache fits its budget. A signature larger than the whole budget is
+    /// not cached.
+    pub fn insert(&mut self, file: FileKey, options: &SignatureOptions, signature: Arc<Signature>) {
+        let size = signature.serialized().len();
+        let stale: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|key| key.file.path == file.path)
+            .cloned()
+            .collect();
+        for key in stale {
+            if key.file != file || key == Key::new(file.clone(), options) {
+                self.remove(&key);
+            }
+        }
+        if size > self.budget {
+            return;
+        }
+        while self.used + size > self.budget {
+            let (_, key) = self.lru.pop_first().expect("used bytes without entries");
+            let entry = self.entries.remove(&key).unwrap();
+            self.used -= entry.signature.serialized().len();
+        }
+        self.clock += 1;
+        let key = Key::new(file, options);
+        self.lru.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                signature,
+                last_used: self.clock,
+            },
+        );
+        self.used += size;
+    }
+
+    /// Drop all cached signatures of the file at `path`.
+    pub fn invalidate(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let keys: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|key| key.file.path == path)
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// The number of cached signatures.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The total size of the cached signatures in bytes.
+    pub fn used_bytes(&self) -> usize {
+        self.used
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.used -= entry.signature.serialized().len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use super::{FileIdentity, FileKey, IndexCache};
+    use crate::{HashAlgorithm, Signature, SignatureOptions};
+
+    const OPTIONS: SignatureOptions = SignatureOptions::new(64, 8, HashAlgorithm::Blake3);
+
+    fn key(path: &str, modified: u64) -> FileKey {
+        FileKey {
+            path: Path::new(path).to_owned(),
+            len: 1000,
+            identity: FileIdentity::Modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified)),
+        }
+    }
+
+    #[test]
+    fn test_index_cache_lru() {
+        let signature = Arc::new(Signature::calculate(&[0; 1000], OPTIONS));
+        let size = signature.serialized().len();
+        let mut cache = IndexCache::new(size * 2);
+        cache.insert(key("a", 1), &OPTIONS, signature.clone());
+        cache.insert(key("b", 1), &OPTIONS, signature.clone());
+        assert!(cache.get(&key("a", 1), &OPTIONS).is_some());
+        // "b" is the least recently used
+        cache.insert(key("c", 1), &OPTIONS, signature.clone());
+        assert!(cache.get(&key("b", 1), &OPTIONS).is_none());
+        assert!(cache.get(&key("a", 1), &OPTIONS).is_some());
+        assert_eq!(cache.used_bytes(), size * 2);
+
+        // a new version replaces the old one
+        cache.insert(key("a", 2), &OPTIONS, signature.clone());
+        assert!(cache.get(&key("a", 1), &OPTIONS).is_none());
+        assert!(cache.get(&key("a", 2), &OPTIONS).is_some());
+        assert_eq!(cache.len(), 2);
+
+        // other options are a different entry
+        let options = SignatureOptions {
+            block_size: 128,
+            ..OPTIONS
+        };
+        assert!(cache.get(&key("a", 2), &options).is_none());
+
+        cache.invalidate("a");
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.used_bytes(), size);
+    }
+
+    #[test]
+    fn test_index_cache_files() {
+        let path = std::env::temp_dir().join(format!("superfast_rsync_cache_{}", std::process::id()));
+        std::fs::write(&path, vec![3; 5000]).unwrap();
+        let mut cache = IndexCache::new(1 << 20);
+        let first = cache.signature(&path, OPTIONS).unwrap();
+        assert_eq!(Signature::clone(&first).without_index(), Signature::calculate(&[3; 5000], OPTIONS));
+        assert!(Arc::ptr_eq(&first, &cache.signature(&path, OPTIONS).unwrap()));
+
+        std::fs::write(&path, vec![4; 6000]).unwrap();
+        let second = cache.signature(&path, OPTIONS).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Signature::clone(&second).without_index(), Signature::calculate(&[4; 6000], OPTIONS));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_index_cache_modified_while_read() {
+        let path = std::env::temp_dir().join(format!("superfast_rsync_cache_race_{}", std::process::id()));
+        std::fs::write(&path, vec![5; 5000]).unwrap();
+        let mut cache = IndexCache::new(1 << 20);
+        let signature = cache
+            .signature_with(&path, OPTIONS, |path| {
+                let data = std::fs::read(path)?;
+                let file = std::fs::File::options().write(true).open(path)?;
+                file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1))?;
+                Ok(data)
+            })
+            .unwrap();
+        assert_eq!(Signature::clone(&signature).without_index(), Signature::calculate(&[5; 5000], OPTIONS));
+        assert!(cache.is_empty());
+        // unchanged while read this time
+        let signature = cache.signature(&path, OPTIONS).unwrap();
+        assert!(Arc::ptr_eq(&signature, &cache.signature(&path, OPTIONS).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! Cancellable variants of the main entry points.
+//!
+//! Each function takes a flag that another thread sets to stop it. The flag is checked after
+//! every 1 MiB of input or output, so the functions return promptly with [Cancelled],
+//! [DiffError::Cancelled] or [ApplyError::Cancelled], even on pathological inputs.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::diff::{DiffError, DiffSession};
+use crate::patch::{apply_limited, ApplyError};
+use crate::signature::{IndexedSignature, Signature, SignatureOptions};
+
+/// How much data is processed between checks of the flag.
+const CHECK_INTERVAL: usize = 1 << 20;
+
+/// Indicates that an operation was stopped by its cancellation flag.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("operation was cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Compute a signature like [Signature::calculate], unless `cancel` is set first.
+///
+/// Panics if the provided options are invalid, unless the `strict` feature replaces them with
+/// the nearest valid ones.
+pub fn signature(
+    buf: &[u8],
+    options: SignatureOptions,
+    cancel: &AtomicBool,
+) -> Result<Signature, Cancelled> {
+    #[cfg(feature = "strict")]
+    let options = options.clamped();
+    assert!(options.is_valid());
+
+    // Every chunk but the last must be a whole number of blocks.
+    let block_size = options.block_size as usize;
+    let chunk_size = CHECK_INTERVAL.div_ceil(block_size) * block_size;
+    let mut signature = Vec::new();
+    Signature::write_header(&options, &mut signature);
+    for chunk in buf.chunks(chunk_size) {
+        check(cancel)?;
+        Signature::hash_blocks(chunk, &options, &mut signature);
+    }
+    Ok(Signature::from_serialized_parts(&options, signature))
+}
+
+/// Calculate a delta like [DiffSession] over all of `data`, unless `cancel` is set first.
+///
+/// Part of the delta may have been written to `out` when this is cancelled.
+///
+/// # Security
+/// See [diff()](crate::diff()).
+pub fn diff(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    cancel: &AtomicBool,
+    mut out: impl Write,
+) -> Result<(), DiffError> {
+    let mut session = DiffSession::new(signature, &mut out)?;
+    for chunk in data.chunks(CHECK_INTERVAL) {
+        check(cancel)?;
+        session.push(chunk, &mut out)?;
+    }
+    session.finish(&mut out)
+}
+
+/// Apply a delta like [apply()](crate::apply()), unless `cancel` is set first.
+///
+/// Part of the output may have been written to `out` when this is cancelled.
+///
+/// # Security
+/// See [apply()](crate::apply()).
+pub fn apply(
+    base: &[u8],
+    delta: &[u8],
+    out: &mut impl Write,
+    cancel: &AtomicBool,
+) -> Result<(), ApplyError> {
+    let mut out = CancelWriter { out, cancel };
+    match apply_limited(base, delta, &mut out, usize::MAX) {
+        Err(ApplyError::Io(e)) if e.get_ref().is_some_and(|e| e.is::<Cancelled>()) => {
+            Err(ApplyError::Cancelled)
+        }
+        result => result,
+    }
+}
+
+fn check(cancel: &AtomicBool) -> Result<(), Cancelled> {
+    match cancel.load(Ordering::Relaxed) {
+        true => Err(Cancelled),
+        false => Ok(()),
+    }
+}
+
+/// Fails writes with [Cancelled] once the flag is set, taking at most [CHECK_INTERVAL] bytes at
+/// a time.
+struct CancelWriter<'a, W> {
+    out: W,
+    cancel: &'a AtomicBool,
+}
+
+impl<W: Write> Write for CancelWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        check(self.cancel).map_err(io::Error::other)?;
+        self.out.write(&buf[..buf.len().min(CHECK_INTERVAL)])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::{apply, diff, signature, Cancelled};
+    use crate::{ApplyError, DiffError, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_cancel() {
+        let base: Vec<u8> = (0..3_000_000u32).map(|i| (i * 11 % 241) as u8).collect();
+        let data = [&base[1_000_000..], b"new", &base[..1_000_000]].concat();
+        let options = SignatureOptions::new(4096, 8, HashAlgorithm::Blake3);
+        let go = AtomicBool::new(false);
+        let stop = AtomicBool::new(true);
+
+        let sig = signature(&base, options, &go).unwrap();
+        assert_eq!(sig, Signature::calculate(&base, options));
+        assert_eq!(signature(&base, options, &stop), Err(Cancelled));
+
+        let mut delta = vec![];
+        diff(&sig.index(), &data, &go, &mut delta).unwrap();
+        let mut out = vec![];
+        apply(&base, &delta, &mut out, &go).unwrap();
+        assert_eq!(out, data);
+
+        assert!(matches!(
+            diff(&sig.index(), &data, &stop, vec![]),
+            Err(DiffError::Cancelled)
+        ));
+        assert!(matches!(
+            apply(&base, &delta, &mut vec![], &stop),
+            Err(ApplyError::Cancelled)
+        ));
+    }
+}
@@ -0,0 +1,329 @@
+//! A [tokio_util::codec] codec for shipping signatures and deltas over framed transports.
+//!
+//! [RsyncCodec] splits each [Message] into frames of at most
+//! [max_frame_len](RsyncCodec::max_frame_len) bytes and reassembles them on the receiving side.
+//! Every frame starts with a 10-byte header:
+//!
+//! | bytes | field                                                      |
+//! |-------|------------------------------------------------------------|
+//! | 1     | message kind: 1 = signature, 2 = delta                     |
+//! | 1     | flags: bit 0 is set on the last frame of a message         |
+//! | 4     | sequence number, big-endian, counting frames since the start of the stream |
+//! | 4     | payload length, big-endian                                 |
+//!
+//! The sequence number lets the receiver detect dropped or reordered frames on transports that
+//! don't guarantee ordered delivery.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+const HEADER_LEN: usize = 10;
+const FLAG_LAST: u8 = 1;
+
+/// What a [Message] carries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// A serialized [Signature](crate::Signature).
+    Signature,
+    /// A delta as produced by [diff()](crate::diff()).
+    Delta,
+}
+
+impl MessageKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            MessageKind::Signature => 1,
+            MessageKind::Delta => 2,
+        }
+    }
+
+    fn from_byte(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(MessageKind::Signature),
+            2 => Some(MessageKind::Delta),
+            _ => None,
+        }
+    }
+}
+
+/// A complete signature or delta.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// What `data` contains.
+    pub kind: MessageKind,
+    /// The serialized signature or delta.
+    pub data: Bytes,
+}
+
+/// Indicates that a frame stream could not be decoded.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CodecError {
+    /// The frame header has an unknown message kind.
+    UnknownKind {
+        /// The kind byte.
+        kind: u8,
+    },
+    /// The frame header has unknown flags set.
+    UnknownFlags {
+        /// The flags byte.
+        flags: u8,
+    },
+    /// A frame was dropped or arrived out of order.
+    OutOfSequence {
+        /// The sequence number of the next frame.
+        expected: u32,
+        /// The sequence number of the frame that arrived.
+        actual: u32,
+    },
+    /// A message's frames don't all have the same kind.
+    KindChanged,
+    /// A frame's payload is longer than the codec allows.
+    FrameTooLarge {
+        /// The payload length.
+        len: usize,
+    },
+    /// A message is longer than the codec allows.
+    MessageTooLarge,
+    /// An IO error from the underlying transport.
+    Io(io::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownKind { kind } => write!(f, "unknown message kind {kind}"),
+            Self::UnknownFlags { flags } => write!(f, "unknown frame flags {flags:#04x}"),
+            Self::OutOfSequence { expected, actual } => write!(
+                f,
+                "frame out of sequence (expected={expected}, actual={actual})"
+            ),
+            Self::KindChanged => f.write_str("message kind changed in the middle of a message"),
+            Self::FrameTooLarge { len } => write!(f, "frame too large (len={len})"),
+            Self::MessageTooLarge => f.write_str("message too large"),
+            Self::Io(source) => write!(f, "io error in the transport (source={source})"),
+        }
+    }
+}
+
+impl Error for CodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(source: io::Error) -> Self {
+        Self::Io(source)
+    }
+}
+
+/// Encodes [Message]s into frames and decodes frames back into [Message]s.
+#[derive(Debug)]
+pub struct RsyncCodec {
+    max_frame_len: usize,
+    max_message_len: usize,
+    send_sequence: u32,
+    receive_sequence: u32,
+    /// The message being reassembled.
+    partial: Option<(MessageKind, BytesMut)>,
+}
+
+impl Default for RsyncCodec {
+    fn default() -> Self {
+        RsyncCodec {
+            max_frame_len: 64 * 1024,
+            max_message_len: 1 << 30,
+            send_sequence: 0,
+            receive_sequence: 0,
+            partial: None,
+        }
+    }
+}
+
+impl RsyncCodec {
+    /// Create a codec with 64 KiB frames that accepts messages of up to 1 GiB.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the largest frame payload that is sent or accepted.
+    pub fn max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len.clamp(1, u32::MAX as usize);
+        self
+    }
+
+    /// Set the largest message that is accepted.
+    pub fn max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len;
+        self
+    }
+}
+
+impl Encoder<Message> for RsyncCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let frames = message.data.len().div_ceil(self.max_frame_len).max(1);
+        dst.reserve(message.data.len() + frames * HEADER_LEN);
+        let mut chunks = message.data.chunks(self.max_frame_len).peekable();
+        loop {
+            let chunk = chunks.next().unwrap_or_default();
+            let last = chunks.peek().is_none();
+            dst.put_u8(message.kind.to_byte());
+            dst.put_u8(if last { FLAG_LAST } else { 0 });
+            dst.put_u32(self.send_sequence);
+            dst.put_u32(chunk.len() as u32);
+            dst.put_slice(chunk);
+            self.send_sequence = self.send_sequence.wrapping_add(1);
+            if last {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Decoder for RsyncCodec {
+    type Item = Message;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+        loop {
+            if src.len() < HEADER_LEN {
+                return Ok(None);
+            }
+            let kind = src[0];
+            let flags = src[1];
+            let sequence = u32::from_be_bytes(src[2..6].try_into().unwrap());
+            let len = u32::from_be_bytes(src[6..10].try_into().unwrap()) as usize;
+            if len > self.max_frame_len {
+                return Err(CodecError::FrameTooLarge { len });
+            }
+            if src.len() < HEADER_LEN + len {
+                src.reserve(HEADER_LEN + len - src.len());
+                return Ok(None);
+            }
+
+            let kind = MessageKind::from_byte(kind).ok_or(CodecError::UnknownKind { kind })?;
+            if flags & !FLAG_LAST != 0 {
+                return Err(CodecError::UnknownFlags { flags });
+            }
+            if sequence != self.receive_sequence {
+                return Err(CodecError::OutOfSequence {
+                    expected: self.receive_sequence,
+                    actual: sequence,
+                });
+            }
+            self.receive_sequence = self.receive_sequence.wrapping_add(1);
+            src.advance(HEADER_LEN);
+            let payload = src.split_to(len);
+
+            let (partial_kind, data) = self.partial.get_or_insert((kind, BytesMut::new()));
+            if *partial_kind != kind {
+                return Err(CodecError::KindChanged);
+            }
+            if data.len() + payload.len() > self.max_message_len {
+                return Err(CodecError::MessageTooLarge);
+            }
+            data.unsplit(payload);
+            if flags & FLAG_LAST != 0 {
+                let (kind, data) = self.partial.take().unwrap();
+                return Ok(Some(Message {
+                    kind,
+                    data: data.freeze(),
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{CodecError, Message, MessageKind, RsyncCodec};
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let messages = [
+            Message {
+                kind: MessageKind::Signature,
+                data: Bytes::from((0..1000u32).map(|i| i as u8).collect::<Vec<_>>()),
+            },
+            Message {
+                kind: MessageKind::Delta,
+                data: Bytes::new(),
+            },
+            Message {
+                kind: MessageKind::Delta,
+                data: Bytes::from_static(b"exactly 16 bytes"),
+            },
+        ];
+        let mut codec = RsyncCodec::new().max_frame_len(16);
+        let mut wire = BytesMut::new();
+        for message in &messages {
+            codec.encode(message.clone(), &mut wire).unwrap();
+        }
+        // 63 frames for the signature, one for each delta
+        assert_eq!(wire.len(), 1016 + 65 * 10);
+
+        // feed the stream a few bytes at a time
+        let mut src = BytesMut::new();
+        let mut decoded = vec![];
+        for piece in wire.chunks(7) {
+            src.extend_from_slice(piece);
+            while let Some(message) = codec.decode(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, messages);
+    }
+
+    #[test]
+    fn test_codec_errors() {
+        let mut codec = RsyncCodec::new().max_frame_len(4);
+        let mut wire = BytesMut::new();
+        let message = Message {
+            kind: MessageKind::Delta,
+            data: Bytes::from_static(b"12345678"),
+        };
+        codec.encode(message, &mut wire).unwrap();
+
+        // drop the first frame
+        let mut src = BytesMut::from(&wire[14..]);
+        assert!(matches!(
+            RsyncCodec::new().decode(&mut src),
+            Err(CodecError::OutOfSequence {
+                expected: 0,
+                actual: 1
+            })
+        ));
+
+        let mut src = wire.clone();
+        assert!(matches!(
+            RsyncCodec::new().max_frame_len(2).decode(&mut src),
+            Err(CodecError::FrameTooLarge { len: 4 })
+        ));
+
+        let mut src = wire.clone();
+        assert!(matches!(
+            RsyncCodec::new().max_message_len(6).decode(&mut src),
+            Err(CodecError::MessageTooLarge)
+        ));
+
+        let mut src = wire.clone();
+        src[0] = 9;
+        assert!(matches!(
+            RsyncCodec::new().decode(&mut src),
+            Err(CodecError::UnknownKind { kind: 9 })
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+//! A compact container for serialized signatures.
+//!
+//! Signatures of very large bases are big, and their block hashes are incompressible. The CRCs
+//! are not always: repeated blocks (zero-filled regions, for example) have identical CRCs. The
+//! compact form can store the CRCs in a column of their own, delta-encoded, which zstd then
+//! compresses well, and can compress the whole signature with zstd.
+//!
+//! Layout: `COMPACT_SIGNATURE_MAGIC`, one flags byte, the length of the uncompressed body as a
+//! big-endian u64, then the body (zstd-compressed if `FLAG_ZSTD` is set). The body is the
+//! regular serialized signature, or with `FLAG_DELTA_CRCS`, its header followed by all
+//! CRCs (each stored as the wrapping difference to the previous one) and then all hashes.
+
+use crate::consts::{COMPACT_SIGNATURE_HEADER_SIZE, COMPACT_SIGNATURE_MAGIC, SIGNATURE_HEADER_SIZE};
+use crate::crc::Crc;
+use crate::signature::header_size;
+
+const FLAG_ZSTD: u8 = 1;
+const FLAG_DELTA_CRCS: u8 = 2;
+const HEADER_SIZE: usize = COMPACT_SIGNATURE_HEADER_SIZE;
+
+/// Options for [Signature::serialize_compact](crate::Signature::serialize_compact).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CompactOptions {
+    /// Compress the signature with zstd at this level.
+    #[cfg(feature = "zstd")]
+    pub zstd_level: Option<i32>,
+    /// Store the block CRCs delta-encoded, in a column separate from the block hashes.
+    pub delta_crcs: bool,
+}
+
+/// Encode the serialized signature `signature`, whose header is `header_size` bytes, in the
+/// compact form.
+pub(crate) fn encode(
+    signature: &[u8],
+    header_size: usize,
+    crypto_hash_size: usize,
+    options: CompactOptions,
+) -> Vec<u8> {
+    let mut flags = 0;
+    let body = if options.delta_crcs {
+        flags |= FLAG_DELTA_CRCS;
+        let (header, entries) = signature.split_at(header_size);
+        let entries = entries.chunks(Crc::SIZE + crypto_hash_size);
+        let mut body = Vec::with_capacity(signature.len());
+        body.extend_from_slice(header);
+        let mut previous = 0u32;
+        for entry in entries.clone() {
+            let crc = u32::from_be_bytes(entry[..Crc::SIZE].try_into().unwrap());
+            body.extend_from_slice(&crc.wrapping_sub(previous).to_be_bytes());
+            previous = crc;
+        }
+        for entry in entries {
+            body.extend_from_slice(&entry[Crc::SIZE..]);
+        }
+        body
+    } else {
+        signature.to_vec()
+    };
+    let raw_len = body.len() as u64;
+    #[cfg(feature = "zstd")]
+    let body = match options.zstd_level {
+        Some(level) => {
+            flags |= FLAG_ZSTD;
+            zstd::bulk::compress(&body, level).expect("compressing to a Vec can't fail")
+        }
+        None => body,
+    };
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + body.len());
+    out.extend_from_slice(&COMPACT_SIGNATURE_MAGIC.to_be_bytes());
+    out.push(flags);
+    out.extend_from_slice(&raw_len.to_be_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+/// Decode a compact signature (starting with `COMPACT_SIGNATURE_MAGIC`) back into the regular
+/// serialized form. Returns `None` if it's malformed, or compressed and the `zstd` feature is
+/// disabled.
+pub(crate) fn decode(compact: &[u8]) -> Option<Vec<u8>> {
+    if compact.len() < HEADER_SIZE {
+        return None;
+    }
+    let flags = compact[4];
+    let raw_len = u64::from_be_bytes(compact[5..HEADER_SIZE].try_into().unwrap());
+    let body = &compact[HEADER_SIZE..];
+    if flags & !(FLAG_ZSTD | FLAG_DELTA_CRCS) != 0 {
+        return None;
+    }
+    let body = if flags & FLAG_ZSTD != 0 {
+        decompress(body, raw_len)?
+    } else {
+        body.to_vec()
+    };
+    if body.len() as u64 != raw_len || body.len() < SIGNATURE_HEADER_SIZE {
+        return None;
+    }
+    if flags & FLAG_DELTA_CRCS == 0 {
+        return Some(body);
+    }
+
+    let header_size = header_size(body[..4].try_into().unwrap());
+    if body.len() < header_size {
+        return None;
+    }
+    let (header, columns) = body.split_at(header_size);
+    let crypto_hash_size = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+    let entry_size = Crc::SIZE + crypto_hash_size;
+    if columns.len() % entry_size != 0 {
+        return None;
+    }
+    let (crcs, hashes) = columns.split_at(columns.len() / entry_size * Crc::SIZE);
+    let mut signature = Vec::with_capacity(body.len());
+    signature.extend_from_slice(header);
+    let mut crc = 0u32;
+    for (i, delta) in crcs.chunks_exact(Crc::SIZE).enumerate() {
+        crc = crc.wrapping_add(u32::from_be_bytes(delta.try_into().unwrap()));
+        signature.extend_from_slice(&crc.to_be_bytes());
+        signature.extend_from_slice(&hashes[i * crypto_hash_size..(i + 1) * crypto_hash_size]);
+    }
+    Some(signature)
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(body: &[u8], raw_len: u64) -> Option<Vec<u8>> {
+    use std::io::Read;
+    // Don't trust `raw_len` for the allocation; a bogus length just fails the check afterwards.
+    let mut decoder = zstd::stream::read::Decoder::new(body).ok()?;
+    let mut out = Vec::new();
+    (&mut decoder).take(raw_len.saturating_add(1)).read_to_end(&mut out).ok()?;
+    Some(out)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_body: &[u8], _raw_len: u64) -> Option<Vec<u8>> {
+    None
+}
@@ -0,0 +1,440 @@
+//! Compressing deltas with zstd, optionally against the base data.
+//!
+//! Copies take a few bytes each, so what's left to compress in a delta is its literals. Those are
+//! often near-misses of the base data: a block with a few changed bytes doesn't match and ends up
+//! in a literal whole, and so does data that moved by less than a block. A sender that has the
+//! base data as well, like an update server building patches between releases, can pass it to
+//! `compress_delta` as a zstd prefix. zstd then finds such literals in the base data with
+//! long-distance matching, and they compress to little more than their changed bytes.
+//!
+//! The receiver needs the same base data to decompress, which it has anyway to apply the delta.
+//! Only the last `MAX_PREFIX_LEN` bytes of the base data are used.
+//!
+//! # Containers
+//! Transports that don't want to agree on compression out of band can use [pack_delta] instead.
+//! The receiver advertises its [Capabilities], and the sender wraps the delta in a container that
+//! starts with [COMPRESSED_DELTA_MAGIC] and a byte of the capabilities it needs, followed by the
+//! zstd frame. [apply()](crate::apply()) unpacks containers transparently and applies classic
+//! deltas as before, so the receiver doesn't need to tell them apart.
+//!
+//! Sometimes a delta is no good at all, like when a file was recompressed or encrypted and
+//! nothing matches. [diff_or_compress] then falls back to a container holding the new data
+//! compressed as a whole, which [apply()](crate::apply()) outputs without reading the base data.
+//!
+//! `compress_delta` and `decompress_delta` need the `zstd` feature. Without it, receivers advertise
+//! no capabilities and [pack_delta] returns the delta unchanged.
+
+use std::borrow::Cow;
+use std::io;
+#[cfg(feature = "zstd")]
+use std::io::{Read, Write};
+use std::ops::{BitAnd, BitOr};
+
+use crate::consts::{COMPRESSED_DELTA_HEADER_SIZE, COMPRESSED_DELTA_MAGIC, DELTA_HEADER_SIZE};
+#[cfg(feature = "zstd")]
+use crate::consts::{DELTA_MAGIC, RS_OP_END};
+use crate::diff::{diff, DiffError};
+#[cfg(feature = "zstd")]
+use crate::ops::encode_literal_header;
+use crate::patch::ApplyError;
+use crate::signature::IndexedSignature;
+
+/// The longest suffix of the base data used as a prefix.
+#[cfg(feature = "zstd")]
+pub const MAX_PREFIX_LEN: usize = 1 << 29;
+
+/// The largest window zstd supports on all targets.
+#[cfg(feature = "zstd")]
+const MAX_WINDOW_LOG: u32 = 30;
+
+/// The smallest window zstd supports.
+#[cfg(feature = "zstd")]
+const MIN_WINDOW_LOG: u32 = 10;
+
+/// The highest zstd level [diff_or_compress] compresses the whole data at, which bounds the time
+/// spent on a fallback that usually loses.
+pub const MAX_WHOLE_FILE_LEVEL: i32 = 3;
+
+/// A delta that writes `n` bytes is at most this many bytes per output byte long: a copy of one
+/// byte takes 3, a one-byte repeat 4.
+const MAX_DELTA_EXPANSION: usize = 4;
+
+/// The compressed deltas a receiver can decode, as a set of flags.
+///
+/// The same flags in the header of a container say what is needed to decode it.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    /// No compression at all.
+    pub const NONE: Capabilities = Capabilities(0);
+    /// zstd frames.
+    pub const ZSTD: Capabilities = Capabilities(1);
+    /// zstd frames compressed against the base data.
+    pub const BASE_PREFIX: Capabilities = Capabilities(2);
+    /// zstd frames of the new data as a whole instead of a delta.
+    pub const WHOLE_FILE: Capabilities = Capabilities(4);
+
+    /// Everything this build can decode.
+    pub fn supported() -> Self {
+        if cfg!(feature = "zstd") {
+            Self::ZSTD | Self::BASE_PREFIX | Self::WHOLE_FILE
+        } else {
+            Self::NONE
+        }
+    }
+
+    /// The capabilities in `bits`, e.g. as received from a peer, ignoring unknown ones.
+    pub fn from_bits(bits: u8) -> Self {
+        Capabilities(bits & Self::supported().0)
+    }
+
+    /// The flags, e.g. for sending to a peer.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether all of `other` is in `self`.
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Compress `delta` with zstd at `level`, referring to `base` if given.
+///
+/// [Effort::zstd_level](crate::Effort::zstd_level) picks a level to go with a diff effort.
+#[cfg(feature = "zstd")]
+pub fn compress_delta(delta: &[u8], base: Option<&[u8]>, level: i32) -> io::Result<Vec<u8>> {
+    let mut encoder = match base {
+        Some(base) => {
+            let prefix = prefix(base);
+            let mut encoder = zstd::Encoder::with_ref_prefix(Vec::new(), level, prefix)?;
+            encoder.long_distance_matching(true)?;
+            encoder.window_log(window_log(prefix.len() + delta.len()))?;
+            encoder
+        }
+        None => zstd::Encoder::new(Vec::new(), level)?,
+    };
+    // With the size in the frame header, the decoder doesn't allocate a whole window. The
+    // checksum catches a receiver decompressing against different base data.
+    encoder.set_pledged_src_size(Some(delta.len() as u64))?;
+    encoder.include_contentsize(true)?;
+    encoder.include_checksum(true)?;
+    encoder.write_all(delta)?;
+    encoder.finish()
+}
+
+/// Decompress a delta compressed by [compress_delta], with the same `base`.
+///
+/// Fails with [io::ErrorKind::InvalidData] if the delta is longer than `limit` bytes, which
+/// bounds the memory a malicious input can take.
+#[cfg(feature = "zstd")]
+pub fn decompress_delta(
+    compressed: &[u8],
+    base: Option<&[u8]>,
+    limit: usize,
+) -> io::Result<Vec<u8>> {
+    let mut decoder = match base {
+        Some(base) => zstd::Decoder::with_ref_prefix(compressed, prefix(base))?,
+        None => zstd::Decoder::with_buffer(compressed)?,
+    };
+    decoder.window_log_max(MAX_WINDOW_LOG)?;
+    let mut delta = Vec::new();
+    decoder.take((limit as u64).saturating_add(1)).read_to_end(&mut delta)?;
+    if delta.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("decompressed delta exceeds limit (limit={limit})"),
+        ));
+    }
+    Ok(delta)
+}
+
+/// The part of `base` that is used as a prefix.
+#[cfg(feature = "zstd")]
+fn prefix(base: &[u8]) -> &[u8] {
+    &base[base.len().saturating_sub(MAX_PREFIX_LEN)..]
+}
+
+/// The window that reaches back over the whole prefix from the end of the delta, if possible.
+#[cfg(feature = "zstd")]
+fn window_log(len: usize) -> u32 {
+    len.next_power_of_two()
+        .trailing_zeros()
+        .clamp(MIN_WINDOW_LOG, MAX_WINDOW_LOG)
+}
+
+/// Wrap `delta` for a receiver with capabilities `peer`, compressing it at `level` against `base`
+/// if given and supported.
+///
+/// The delta is returned unchanged if the receiver or this build can't decompress it, or if
+/// compressing doesn't make it smaller.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub fn pack_delta(
+    delta: &[u8],
+    base: Option<&[u8]>,
+    level: i32,
+    peer: Capabilities,
+) -> io::Result<Vec<u8>> {
+    let usable = peer & Capabilities::supported();
+    if !usable.contains(Capabilities::ZSTD) {
+        return Ok(delta.to_vec());
+    }
+    #[cfg(feature = "zstd")]
+    {
+        let base = base.filter(|_| usable.contains(Capabilities::BASE_PREFIX));
+        let mut flags = Capabilities::ZSTD;
+        if base.is_some() {
+            flags = flags | Capabilities::BASE_PREFIX;
+        }
+        let compressed = compress_delta(delta, base, level)?;
+        if COMPRESSED_DELTA_HEADER_SIZE + compressed.len() < delta.len() {
+            return Ok(container(flags, &compressed));
+        }
+    }
+    Ok(delta.to_vec())
+}
+
+/// Calculate a delta of `data` against `signature` and pack it like [pack_delta], or else
+/// compress `data` as a whole if the receiver supports [Capabilities::WHOLE_FILE], whichever is
+/// smaller.
+///
+/// The whole data is compressed at `level`, but at most at [MAX_WHOLE_FILE_LEVEL].
+///
+/// # Security
+/// See [diff()](crate::diff()).
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub fn diff_or_compress(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    base: Option<&[u8]>,
+    level: i32,
+    peer: Capabilities,
+) -> Result<Vec<u8>, DiffError> {
+    let mut delta = Vec::new();
+    diff(signature, data, &mut delta)?;
+    let packed = pack_delta(&delta, base, level, peer)?;
+    #[cfg(feature = "zstd")]
+    {
+        let whole_file = Capabilities::ZSTD | Capabilities::WHOLE_FILE;
+        if (peer & Capabilities::supported()).contains(whole_file) {
+            let compressed = compress_delta(data, None, level.min(MAX_WHOLE_FILE_LEVEL))?;
+            if COMPRESSED_DELTA_HEADER_SIZE + compressed.len() < packed.len() {
+                return Ok(container(whole_file, &compressed));
+            }
+        }
+    }
+    Ok(packed)
+}
+
+/// A container of `body` with the header for `flags`.
+#[cfg(feature = "zstd")]
+fn container(flags: Capabilities, body: &[u8]) -> Vec<u8> {
+    let mut container = Vec::with_capacity(COMPRESSED_DELTA_HEADER_SIZE + body.len());
+    container.extend_from_slice(&COMPRESSED_DELTA_MAGIC.to_be_bytes());
+    container.push(flags.0);
+    container.extend_from_slice(body);
+    container
+}
+
+/// Unwrap a delta from [pack_delta] with the base data it will be applied to, or return a classic
+/// delta as it is.
+///
+/// The new data of a [Capabilities::WHOLE_FILE] container is returned as a delta with a single
+/// literal.
+///
+/// Fails if the container is malformed, needs capabilities this build doesn't have, or would
+/// decompress to more than `limit` bytes.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub fn unpack_delta<'a>(
+    delta: &'a [u8],
+    base: &[u8],
+    limit: usize,
+) -> Result<Cow<'a, [u8]>, ApplyError> {
+    if !delta.starts_with(&COMPRESSED_DELTA_MAGIC.to_be_bytes()) {
+        return Ok(Cow::Borrowed(delta));
+    }
+    let Some(&flags) = delta.get(COMPRESSED_DELTA_HEADER_SIZE - 1) else {
+        return Err(ApplyError::UnexpectedEof {
+            reading: "compression flags",
+            expected: 1,
+            available: 0,
+        });
+    };
+    let needed = Capabilities(flags);
+    if !Capabilities::supported().contains(needed) || !needed.contains(Capabilities::ZSTD) {
+        return Err(ApplyError::Decompress(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported compression flags (flags=0x{flags:02x})"),
+        )));
+    }
+    #[cfg(feature = "zstd")]
+    {
+        let base = needed.contains(Capabilities::BASE_PREFIX).then_some(base);
+        let body = &delta[COMPRESSED_DELTA_HEADER_SIZE..];
+        let decompressed = decompress_delta(body, base, limit).map_err(ApplyError::Decompress)?;
+        if !needed.contains(Capabilities::WHOLE_FILE) {
+            return Ok(Cow::Owned(decompressed));
+        }
+        let mut delta = Vec::with_capacity(decompressed.len() + 16);
+        delta.extend_from_slice(&DELTA_MAGIC.to_be_bytes());
+        if !decompressed.is_empty() {
+            encode_literal_header(decompressed.len() as u64, &mut delta)?;
+            delta.extend_from_slice(&decompressed);
+        }
+        delta.push(RS_OP_END);
+        Ok(Cow::Owned(delta))
+    }
+    #[cfg(not(feature = "zstd"))]
+    unreachable!("no capabilities are supported without zstd")
+}
+
+/// The longest delta that can write at most `limit` bytes, for [unpack_delta].
+pub(crate) fn delta_limit(limit: usize) -> usize {
+    limit
+        .saturating_mul(MAX_DELTA_EXPANSION)
+        .saturating_add(DELTA_HEADER_SIZE + 1)
+}
+
+#[cfg(feature = "zstd")]
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{compress_delta, decompress_delta, diff_or_compress, pack_delta, Capabilities};
+    use crate::consts::COMPRESSED_DELTA_MAGIC;
+    use crate::{apply, apply_limited, diff, ApplyError, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_compress_delta() {
+        let mut base = vec![0; 1 << 20];
+        rand::thread_rng().fill(&mut base[..]);
+        // Every block changes slightly, so nothing matches and the delta is one big literal.
+        let mut data = base.clone();
+        for i in (0..data.len()).step_by(1000) {
+            data[i] ^= 1;
+        }
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(2048, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+
+        let plain = compress_delta(&delta, None, 3).unwrap();
+        assert_eq!(decompress_delta(&plain, None, delta.len()).unwrap(), delta);
+        let against_base = compress_delta(&delta, Some(&base), 3).unwrap();
+        assert!(against_base.len() * 20 < plain.len(), "{} {}", against_base.len(), plain.len());
+        let decompressed = decompress_delta(&against_base, Some(&base), delta.len()).unwrap();
+        let mut out = vec![];
+        apply(&base, &decompressed, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        assert!(decompress_delta(&against_base, Some(&base), delta.len() - 1).is_err());
+        let other_base = &base[..base.len() - 1];
+        assert!(decompress_delta(&against_base, Some(other_base), delta.len()).is_err());
+    }
+
+    #[test]
+    fn test_pack_delta() {
+        let base = b"0123456789".repeat(1000);
+        let data = [&b"x"[..], &base.repeat(2)].concat();
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(64, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        crate::diff_v2(&signature.index(), &data, &mut delta).unwrap();
+
+        assert_eq!(pack_delta(&delta, Some(&base), 3, Capabilities::NONE).unwrap(), delta);
+        let peers = [Capabilities::ZSTD, Capabilities::supported()];
+        for (peer, flags) in peers.into_iter().zip([1, 3]) {
+            let packed = pack_delta(&delta, Some(&base), 3, peer).unwrap();
+            assert_eq!(packed[..4], COMPRESSED_DELTA_MAGIC.to_be_bytes());
+            assert_eq!(packed[4], flags);
+            assert!(packed.len() < delta.len());
+            let mut out = vec![];
+            apply(&base, &packed, &mut out).unwrap();
+            assert_eq!(out, data);
+            assert!(matches!(
+                apply_limited(&base, &packed, &mut vec![], 1),
+                Err(ApplyError::Decompress(_) | ApplyError::OutputLimit { .. })
+            ));
+        }
+        // Classic deltas are applied as they are.
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let mut unknown = COMPRESSED_DELTA_MAGIC.to_be_bytes().to_vec();
+        unknown.push(0x81);
+        assert!(matches!(
+            apply(&base, &unknown, &mut vec![]),
+            Err(ApplyError::Decompress(_))
+        ));
+        assert!(matches!(
+            apply(&base, &unknown[..4], &mut vec![]),
+            Err(ApplyError::UnexpectedEof { .. })
+        ));
+        assert_eq!(Capabilities::from_bits(0xff), Capabilities::supported());
+    }
+
+    #[test]
+    fn test_diff_or_compress() {
+        let mut base = vec![0; 64 * 1024];
+        rand::thread_rng().fill(&mut base[..]);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(256, 8, HashAlgorithm::Blake3),
+        );
+        let indexed = signature.index();
+        let whole_file = Capabilities::ZSTD | Capabilities::WHOLE_FILE;
+
+        // Nothing matches, but the new data compresses well.
+        let data = b"fresh text, nothing like the base. ".repeat(2000);
+        let packed = diff_or_compress(&indexed, &data, Some(&base), 19, whole_file).unwrap();
+        assert_eq!(packed[..4], COMPRESSED_DELTA_MAGIC.to_be_bytes());
+        assert_eq!(packed[4], whole_file.bits());
+        assert!(packed.len() < data.len() / 10);
+        let mut out = vec![];
+        apply(&[], &packed, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(matches!(
+            apply_limited(&[], &packed, &mut vec![], data.len() - 1),
+            Err(ApplyError::OutputLimit { .. })
+        ));
+        // A peer without the capability gets a delta.
+        let packed = diff_or_compress(&indexed, &data, None, 3, Capabilities::ZSTD).unwrap();
+        assert_eq!(packed[4], Capabilities::ZSTD.bits());
+
+        // The delta of similar data is smaller.
+        let mut data = base.clone();
+        data[1000] ^= 1;
+        let packed = diff_or_compress(&indexed, &data, Some(&base), 3, whole_file).unwrap();
+        assert!(packed.len() < 1000);
+        let mut out = vec![];
+        apply(&base, &packed, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let packed = diff_or_compress(&indexed, &[], None, 3, whole_file).unwrap();
+        let mut out = vec![];
+        apply(&base, &packed, &mut out).unwrap();
+        assert!(out.is_empty());
+    }
+}
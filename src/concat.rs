@@ -0,0 +1,209 @@
+//! Merging deltas of consecutive parts of the new data into one delta.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::consts::{DELTA_MAGIC, DELTA_V2_MAGIC, RS_OP_END};
+use crate::ops::{encode_copy, encode_literal_header, encode_repeat};
+use crate::patch::{ApplyError, Op, OpReader};
+
+/// A delta that reconstructs part of the new data, as input to [concat_deltas].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkDelta {
+    /// Where the part reconstructed by `delta` starts in the new data.
+    pub start: u64,
+    /// The delta, calculated against the signature of the whole base data.
+    pub delta: Vec<u8>,
+}
+
+/// Indicates that per-chunk deltas could not be merged.
+#[derive(Debug)]
+pub enum ConcatError {
+    /// A chunk contained an invalid delta.
+    InvalidDelta {
+        /// The index of the chunk.
+        chunk: usize,
+        /// Why the delta is invalid.
+        source: ApplyError,
+    },
+    /// A chunk didn't start where the previous one ended, so the chunks leave a gap in the new
+    /// data or overlap.
+    NotContiguous {
+        /// The index of the chunk.
+        chunk: usize,
+        /// Where the chunk starts.
+        start: u64,
+        /// Where the previous chunk ended.
+        expected: u64,
+    },
+}
+
+impl fmt::Display for ConcatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConcatError::InvalidDelta { chunk, source } => {
+                write!(f, "invalid chunk delta (chunk={chunk}, source={source})")
+            }
+            ConcatError::NotContiguous {
+                chunk,
+                start,
+                expected,
+            } => write!(
+                f,
+                "chunks aren't contiguous (chunk={chunk}, start={start}, expected={expected})",
+            ),
+        }
+    }
+}
+
+impl Error for ConcatError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConcatError::InvalidDelta { source, .. } => Some(source),
+            ConcatError::NotContiguous { .. } => None,
+        }
+    }
+}
+
+/// The op being built up from consecutive ops of the input.
+enum Pending {
+    Literal(Vec<u8>),
+    Copy { offset: u64, len: u64 },
+}
+
+/// Merge the deltas of consecutive parts of the new data, in any order, into one delta that
+/// reconstructs all of them.
+///
+/// This lets the new data be split among threads or machines that each diff their part against
+/// the same signature (for example with [diff_range](crate::diff_range())). The chunks must
+/// cover the new data without gaps or overlaps, starting at 0. Consecutive literals are merged,
+/// and so are copies that continue each other, which mends the matches the split cut in two.
+/// The result is a v2 delta if any chunk contains repeats.
+pub fn concat_deltas(chunks: &[ChunkDelta]) -> Result<Vec<u8>, ConcatError> {
+    let mut order: Vec<usize> = (0..chunks.len()).collect();
+    order.sort_by_key(|&i| chunks[i].start);
+
+    let mut out = DELTA_MAGIC.to_be_bytes().to_vec();
+    let mut pending: Option<Pending> = None;
+    let mut position = 0u64;
+    let mut v2 = false;
+    for chunk in order {
+        let invalid = |source| ConcatError::InvalidDelta { chunk, source };
+        if chunks[chunk].start != position {
+            return Err(ConcatError::NotContiguous {
+                chunk,
+                start: chunks[chunk].start,
+                expected: position,
+            });
+        }
+        let mut ops = OpReader::new(&chunks[chunk].delta).map_err(invalid)?;
+        while let Some(op) = ops.next_op().map_err(invalid)? {
+            if let Op::Repeat { pattern, count } = op {
+                if let Some(previous) = pending.take() {
+                    write_pending(previous, &mut out);
+                }
+                // Writing to a Vec can't fail.
+                encode_repeat(pattern, count, &mut out).unwrap();
+                position = position.saturating_add(op.output_len());
+                v2 = true;
+                continue;
+            }
+            pending = Some(match (pending.take(), op) {
+                (Some(Pending::Literal(mut data)), Op::Literal(literal)) => {
+                    data.extend_from_slice(literal);
+                    Pending::Literal(data)
+                }
+                (Some(Pending::Copy { offset, len }), Op::Copy { offset: next, len: more })
+                    if offset.checked_add(len) == Some(next) =>
+                {
+                    Pending::Copy {
+                        offset,
+                        len: len.saturating_add(more),
+                    }
+                }
+                (previous, op) => {
+                    if let Some(previous) = previous {
+                        write_pending(previous, &mut out);
+                    }
+                    match op {
+                        Op::Literal(literal) => Pending::Literal(literal.to_vec()),
+                        Op::Copy { offset, len } => Pending::Copy { offset, len },
+                        Op::Repeat { .. } => unreachable!("handled above"),
+                    }
+                }
+            });
+            position = position.saturating_add(op.output_len());
+        }
+    }
+    if let Some(previous) = pending {
+        write_pending(previous, &mut out);
+    }
+    out.push(RS_OP_END);
+    if v2 {
+        out[..4].copy_from_slice(&DELTA_V2_MAGIC.to_be_bytes());
+    }
+    Ok(out)
+}
+
+fn write_pending(pending: Pending, out: &mut Vec<u8>) {
+    // Writing to a Vec can't fail.
+    match pending {
+        Pending::Literal(data) if data.is_empty() => {}
+        Pending::Literal(data) => {
+            encode_literal_header(data.len() as u64, out).unwrap();
+            out.extend_from_slice(&data);
+        }
+        Pending::Copy { offset, len } => encode_copy(offset, len, out).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{concat_deltas, ChunkDelta, ConcatError};
+    use crate::{apply, diff, diff_range, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_concat_deltas() {
+        let mut base = vec![0; 10_000];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut data = base.clone();
+        data[5000..5003].fill(9);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(100, 8, HashAlgorithm::Blake3),
+        );
+        let indexed = signature.index();
+        let mut chunks: Vec<_> = [0..3333, 3333..5001, 5001..10_000]
+            .into_iter()
+            .map(|range| {
+                let mut delta = vec![];
+                diff_range(&indexed, &data, range.clone(), &mut delta).unwrap();
+                ChunkDelta {
+                    start: range.start as u64,
+                    delta,
+                }
+            })
+            .collect();
+        chunks.reverse();
+        let merged = concat_deltas(&chunks).unwrap();
+        let mut out = vec![];
+        apply(&base, &merged, &mut out).unwrap();
+        assert_eq!(out, data);
+        // The copies cut at the chunk boundaries are mended.
+        let mut whole = vec![];
+        diff(&indexed, &data, &mut whole).unwrap();
+        assert_eq!(merged, whole);
+
+        chunks.remove(1);
+        assert!(matches!(
+            concat_deltas(&chunks),
+            Err(ConcatError::NotContiguous {
+                chunk: 0,
+                start: 5001,
+                expected: 3333,
+            })
+        ));
+    }
+}
@@ -1,31 +1,172 @@
+//! The magic numbers, op codes and header layouts of the signature and delta formats.
+//!
+//! These match librsync where the formats are shared. They're public so that parsers, wire-format
+//! debuggers and implementations in other languages can stay in sync with this crate. All
+//! multi-byte integers in both formats are big-endian.
+//!
+//! # Signatures
+//! A signature starts with a [`SIGNATURE_HEADER_SIZE`]-byte header: the magic of its hash
+//! algorithm ([`MD4_MAGIC`], [`BLAKE2_MAGIC`], [`BLAKE3_MAGIC`], [`SHA256_MAGIC`] or
+//! [`MD5_MAGIC`]), the block size as a u32 and the crypto hash size as a u32. Every block of the
+//! base data follows as its 4-byte CRC and then its truncated crypto hash. Signatures whose CRC
+//! is [Crc32c](crate::Crc32c) have [`CRC32C_MAGIC_FLAG`] set in the magic.
+//!
+//! A signature with a checksum seed has [`SEEDED_MAGIC_FLAG`] set in the magic, and a
+//! [`SEEDED_SIGNATURE_HEADER_SIZE`]-byte header that ends with the nonzero seed as a u32. Like
+//! rsync's `checksum_seed`, the seed is mixed into the crypto hashes: each is the hash of the
+//! block followed by the seed as 4 little-endian bytes, or, for MD5, preceded by them. The CRCs
+//! are unchanged.
+//!
+//! A v2 signature wraps a regular one: [`SIGNATURE_V2_MAGIC`], the length of the regular
+//! signature as a u64 (together [`SIGNATURE_V2_HEADER_SIZE`] bytes), the regular signature, and
+//! then its prebuilt block index.
+//!
+//! An extended signature wraps a regular or v2 one in front of which it puts an extension area:
+//! [`SIGNATURE_EXT_MAGIC`], the length of the area as a u32 (together
+//! [`SIGNATURE_EXT_HEADER_SIZE`] bytes), and the area, a sequence of records that each consist of
+//! a type byte, the length of the value as a u32, and the value. The records are
+//! [`SIGNATURE_EXT_SOURCE_LEN`] and [`SIGNATURE_EXT_SOURCE_BLAKE3`], and readers skip records of
+//! other types.
+//!
+//! A compact signature starts with a
+//! [`COMPACT_SIGNATURE_HEADER_SIZE`]-byte header; see
+//! [Signature::serialize_compact](crate::Signature::serialize_compact).
+//!
+//! # Deltas
+//! A delta starts with [`DELTA_MAGIC`] and is followed by a sequence of ops, each an op code byte
+//! followed by its parameters, ending with [`RS_OP_END`].
+//!
+//! - [`RS_OP_LITERAL_1`] to [`RS_OP_LITERAL_64`] are followed by that many (1 to 64) literal
+//!   bytes.
+//! - [`RS_OP_LITERAL_N1`] to [`RS_OP_LITERAL_N8`] are followed by the literal length as a 1, 2, 4
+//!   or 8 byte integer, and then the literal bytes.
+//! - [`RS_OP_COPY_N1_N1`] to [`RS_OP_COPY_N8_N8`] are followed by the offset into the base data
+//!   and then the length of the copy, each as a 1, 2, 4 or 8 byte integer as named by the op code.
+//!
+//! A v2 delta starts with [`DELTA_V2_MAGIC`] instead, and may also contain [`RS_OP_REPEAT_N1`] to
+//! [`RS_OP_REPEAT_N8`], which are followed by the repeat count as a 1, 2, 4 or 8 byte integer, the
+//! length of the pattern as 1 byte, and then the pattern, which is written `count` times. librsync
+//! can't apply v2 deltas.
+//!
+//! A compressed delta container starts with [`COMPRESSED_DELTA_MAGIC`] and a byte of flags, see
+//! [compress](crate::compress).
+//!
+//! A fingerprinted delta starts with a [`FINGERPRINTED_DELTA_HEADER_SIZE`]-byte header:
+//! [`FINGERPRINTED_DELTA_MAGIC`], the block size as a u32 and the fingerprint of the base data as
+//! a u64, see [fingerprint](crate::fingerprint). The delta itself follows.
+
+/// The magic of signatures using MD4 block hashes.
 pub const MD4_MAGIC: u32 = 0x72730136;
+/// The magic of signatures using BLAKE2 block hashes.
 pub const BLAKE2_MAGIC: u32 = 0x72730137;
+/// The magic of signatures using BLAKE3 block hashes.
 pub const BLAKE3_MAGIC: u32 = 0x72730138;
+/// The magic of signatures using SHA-256 block hashes, which librsync can't read.
+pub const SHA256_MAGIC: u32 = 0x7273013b;
+/// The magic of signatures using MD5 block hashes, which librsync can't read.
+pub const MD5_MAGIC: u32 = 0x7273013c;
+/// Set in the magic of a signature whose rolling hash is [Crc32c](crate::Crc32c) instead of the
+/// rollsum, e.g. `0x72730338` for BLAKE3.
+pub const CRC32C_MAGIC_FLAG: u32 = 0x200;
+/// Set in the magic of a signature with a checksum seed, e.g. `0x72730538` for BLAKE3.
+pub const SEEDED_MAGIC_FLAG: u32 = 0x400;
+/// The magic every delta starts with.
 pub const DELTA_MAGIC: u32 = 0x72730236;
+/// The magic of compact signatures.
+pub const COMPACT_SIGNATURE_MAGIC: u32 = 0x72730139;
+/// The magic of v2 signatures, which carry a prebuilt block index.
+pub const SIGNATURE_V2_MAGIC: u32 = 0x7273013a;
+/// The magic of v2 deltas, which may contain repeat ops.
+pub const DELTA_V2_MAGIC: u32 = 0x72730238;
+/// The magic of compressed delta containers, see [compress](crate::compress).
+pub const COMPRESSED_DELTA_MAGIC: u32 = 0x72730239;
+/// The magic of fingerprinted deltas, see [fingerprint](crate::fingerprint).
+pub const FINGERPRINTED_DELTA_MAGIC: u32 = 0x7273023a;
+/// The magic of extended signatures, which carry information about the data they describe.
+pub const SIGNATURE_EXT_MAGIC: u32 = 0x7273013d;
+/// The extension record holding the length of the data a signature describes, as a u64.
+pub const SIGNATURE_EXT_SOURCE_LEN: u8 = 1;
+/// The extension record holding the BLAKE3 hash of the data a signature describes.
+pub const SIGNATURE_EXT_SOURCE_BLAKE3: u8 = 2;
+/// The magic of seek indexes, which are stored alongside a delta.
+pub const SEEK_INDEX_MAGIC: u32 = 0x72730237;
+
+/// The size of a signature header: magic, block size and crypto hash size.
+pub const SIGNATURE_HEADER_SIZE: usize = 4 + 4 + 4;
+/// The size of the header of a signature with a checksum seed: magic, block size, crypto hash
+/// size and seed.
+pub const SEEDED_SIGNATURE_HEADER_SIZE: usize = 4 + 4 + 4 + 4;
+/// The size of a v2 signature header: magic and the length of the wrapped signature.
+pub const SIGNATURE_V2_HEADER_SIZE: usize = 4 + 8;
+/// The size of an extended signature header: magic and the length of the extension area.
+pub const SIGNATURE_EXT_HEADER_SIZE: usize = 4 + 4;
+/// The size of a compact signature header: magic, flags and the length of the body.
+pub const COMPACT_SIGNATURE_HEADER_SIZE: usize = 4 + 1 + 8;
+/// The size of a delta header: just the magic.
+pub const DELTA_HEADER_SIZE: usize = 4;
+/// The size of a compressed delta container header: magic and capability flags.
+pub const COMPRESSED_DELTA_HEADER_SIZE: usize = 4 + 1;
+/// The size of a fingerprinted delta header: magic, block size and fingerprint.
+pub const FINGERPRINTED_DELTA_HEADER_SIZE: usize = 4 + 4 + 8;
+/// The smallest block size of a fingerprinted delta. Smaller blocks would make checking the
+/// fingerprint hash several times the size of the base data.
+pub const MIN_FINGERPRINT_BLOCK_SIZE: u32 = 16;
 
+/// Ends a delta.
 pub const RS_OP_END: u8 = 0;
 
+/// A literal of 1 byte. Op codes up to [`RS_OP_LITERAL_64`] encode literals of their own length.
 pub const RS_OP_LITERAL_1: u8 = 0x1;
+/// A literal of 64 bytes.
 pub const RS_OP_LITERAL_64: u8 = 0x40;
 
+/// A literal whose length follows as 1 byte.
 pub const RS_OP_LITERAL_N1: u8 = 0x41;
+/// A literal whose length follows as 2 bytes.
 pub const RS_OP_LITERAL_N2: u8 = 0x42;
+/// A literal whose length follows as 4 bytes.
 pub const RS_OP_LITERAL_N4: u8 = 0x43;
+/// A literal whose length follows as 8 bytes.
 pub const RS_OP_LITERAL_N8: u8 = 0x44;
 
+/// A copy with a 1-byte offset and 1-byte length.
 pub const RS_OP_COPY_N1_N1: u8 = 0x45;
-// pub const RS_OP_COPY_N1_N2: u8 = 0x46;
-// pub const RS_OP_COPY_N1_N4: u8 = 0x47;
-// pub const RS_OP_COPY_N1_N8: u8 = 0x48;
-// pub const RS_OP_COPY_N2_N1: u8 = 0x49;
-// pub const RS_OP_COPY_N2_N2: u8 = 0x4a;
-// pub const RS_OP_COPY_N2_N4: u8 = 0x4b;
-// pub const RS_OP_COPY_N2_N8: u8 = 0x4c;
-// pub const RS_OP_COPY_N4_N1: u8 = 0x4d;
-// pub const RS_OP_COPY_N4_N2: u8 = 0x4e;
-// pub const RS_OP_COPY_N4_N4: u8 = 0x4f;
-// pub const RS_OP_COPY_N4_N8: u8 = 0x50;
-// pub const RS_OP_COPY_N8_N1: u8 = 0x51;
-// pub const RS_OP_COPY_N8_N2: u8 = 0x52;
-// pub const RS_OP_COPY_N8_N4: u8 = 0x53;
+/// A copy with a 1-byte offset and 2-byte length.
+pub const RS_OP_COPY_N1_N2: u8 = 0x46;
+/// A copy with a 1-byte offset and 4-byte length.
+pub const RS_OP_COPY_N1_N4: u8 = 0x47;
+/// A copy with a 1-byte offset and 8-byte length.
+pub const RS_OP_COPY_N1_N8: u8 = 0x48;
+/// A copy with a 2-byte offset and 1-byte length.
+pub const RS_OP_COPY_N2_N1: u8 = 0x49;
+/// A copy with a 2-byte offset and 2-byte length.
+pub const RS_OP_COPY_N2_N2: u8 = 0x4a;
+/// A copy with a 2-byte offset and 4-byte length.
+pub const RS_OP_COPY_N2_N4: u8 = 0x4b;
+/// A copy with a 2-byte offset and 8-byte length.
+pub const RS_OP_COPY_N2_N8: u8 = 0x4c;
+/// A copy with a 4-byte offset and 1-byte length.
+pub const RS_OP_COPY_N4_N1: u8 = 0x4d;
+/// A copy with a 4-byte offset and 2-byte length.
+pub const RS_OP_COPY_N4_N2: u8 = 0x4e;
+/// A copy with a 4-byte offset and 4-byte length.
+pub const RS_OP_COPY_N4_N4: u8 = 0x4f;
+/// A copy with a 4-byte offset and 8-byte length.
+pub const RS_OP_COPY_N4_N8: u8 = 0x50;
+/// A copy with an 8-byte offset and 1-byte length.
+pub const RS_OP_COPY_N8_N1: u8 = 0x51;
+/// A copy with an 8-byte offset and 2-byte length.
+pub const RS_OP_COPY_N8_N2: u8 = 0x52;
+/// A copy with an 8-byte offset and 4-byte length.
+pub const RS_OP_COPY_N8_N4: u8 = 0x53;
+/// A copy with an 8-byte offset and 8-byte length.
 pub const RS_OP_COPY_N8_N8: u8 = 0x54;
+
+/// A repeat whose count follows as 1 byte.
+pub const RS_OP_REPEAT_N1: u8 = 0x55;
+/// A repeat whose count follows as 2 bytes.
+pub const RS_OP_REPEAT_N2: u8 = 0x56;
+/// A repeat whose count follows as 4 bytes.
+pub const RS_OP_REPEAT_N4: u8 = 0x57;
+/// A repeat whose count follows as 8 bytes.
+pub const RS_OP_REPEAT_N8: u8 = 0x58;
@@ -1,16 +1,28 @@
 const CRC_MAGIC: u16 = 31;
 
+/// How many window positions [Crc::rotate_many] computes per SIMD kernel call.
+const ROTATE_LANES: usize = 16;
+
+/// The rolling checksum of rsync and librsync (rollsum), the default [RollingHash].
+///
+/// [RollingHash]: crate::RollingHash
 #[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
-pub struct Crc(pub u32);
+pub struct Crc(
+    /// The two 16-bit sums, the second in the high half.
+    pub u32,
+);
 
 impl Crc {
+    /// The length of the serialized checksum.
     pub const SIZE: usize = 4;
 
+    /// The checksum as stored in signatures.
     #[inline]
     pub fn to_bytes(self) -> [u8; Self::SIZE] {
         self.0.to_be_bytes()
     }
 
+    /// Parse a checksum stored in a signature.
     #[inline]
     pub fn from_bytes(b: [u8; Self::SIZE]) -> Self {
         Crc(u32::from_be_bytes(b))
@@ -26,12 +38,13 @@ impl Crc {
         Crc(s1 as u32 | ((s2 as u32) << 16))
     }
 
+    /// The checksum of no bytes.
     #[inline]
     pub fn new() -> Crc {
         Crc(0)
     }
 
-    #[allow(dead_code)]
+    /// The checksum of the `size` bytes checksummed by `self` without `old_byte` at the start.
     pub fn rollout(self, size: u32, old_byte: u8) -> Crc {
         let size = size as u16;
         let old_byte = old_byte as u16;
@@ -41,6 +54,7 @@ impl Crc {
         Crc::combine(s1, s2)
     }
 
+    /// The checksum of the window one byte further than the `size` bytes checksummed by `self`.
     #[inline]
     pub fn rotate(self, size: u32, old_byte: u8, new_byte: u8) -> Crc {
         let size = size as u16;
@@ -54,8 +68,105 @@ impl Crc {
         Crc::combine(s1, s2)
     }
 
+    /// Like [Crc::rotate] for a window of `1 << shift` bytes, with a shift instead of a
+    /// multiplication.
+    #[inline]
+    pub(crate) fn rotate_shift(self, shift: u32, old_byte: u8, new_byte: u8) -> Crc {
+        let old_byte = old_byte as u16;
+        let new_byte = new_byte as u16;
+        let (mut s1, mut s2) = self.split();
+        s1 = s1.wrapping_add(new_byte).wrapping_sub(old_byte);
+        // windows of 64 KiB or more drop out of the 16-bit sum entirely
+        let outgoing = old_byte.wrapping_add(CRC_MAGIC).checked_shl(shift).unwrap_or(0);
+        s2 = s2.wrapping_add(s1).wrapping_sub(outgoing);
+        Crc::combine(s1, s2)
+    }
+
+    /// Like [RollingHash::rotate_many](crate::RollingHash::rotate_many), computing
+    /// [ROTATE_LANES] windows at a time with SIMD.
+    pub(crate) fn rotate_many(self, size: u32, old: &[u8], new: &[u8], out: &mut [Crc]) {
+        // Lane k is the window after k + 1 rotations: its first sum took in the byte differences
+        // up to k, and its second sum the first sums of the windows up to k minus the outgoing
+        // bytes times `size`. Both are prefix sums across the lanes, taken in log2(lanes) steps
+        // of shifted vector additions.
+        macro_rules! imp {
+            ($($x:tt)*) => {$($x)* (
+                mut crc: Crc,
+                size: u16,
+                old: &[u8],
+                new: &[u8],
+                out: &mut [Crc],
+            ) -> Crc {
+                fn prefix_sum(sums: &mut [u16; ROTATE_LANES]) {
+                    let mut shift = 1;
+                    while shift < ROTATE_LANES {
+                        let prev = *sums;
+                        for k in shift..ROTATE_LANES {
+                            sums[k] = sums[k].wrapping_add(prev[k - shift]);
+                        }
+                        shift *= 2;
+                    }
+                }
+                let lanes = old
+                    .chunks_exact(ROTATE_LANES)
+                    .zip(new.chunks_exact(ROTATE_LANES))
+                    .zip(out.chunks_exact_mut(ROTATE_LANES));
+                for ((old, new), out) in lanes {
+                    let (s1, s2) = crc.split();
+                    let mut sum1 = [0u16; ROTATE_LANES];
+                    for k in 0..ROTATE_LANES {
+                        sum1[k] = (new[k] as u16).wrapping_sub(old[k] as u16);
+                    }
+                    prefix_sum(&mut sum1);
+                    let mut sum2 = [0u16; ROTATE_LANES];
+                    for k in 0..ROTATE_LANES {
+                        sum1[k] = s1.wrapping_add(sum1[k]);
+                        let outgoing = size.wrapping_mul((old[k] as u16).wrapping_add(CRC_MAGIC));
+                        sum2[k] = sum1[k].wrapping_sub(outgoing);
+                    }
+                    prefix_sum(&mut sum2);
+                    for k in 0..ROTATE_LANES {
+                        out[k] = Crc::combine(sum1[k], s2.wrapping_add(sum2[k]));
+                    }
+                    crc = out[ROTATE_LANES - 1];
+                }
+                crc
+            }};
+        }
+        assert!(old.len() == out.len() && new.len() == out.len());
+        let size16 = size as u16;
+        let crc = 'simd: {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            {
+                if is_x86_feature_detected!("avx2") {
+                    imp!(#[target_feature(enable = "avx2")] unsafe fn imp_avx2);
+                    break 'simd unsafe { imp_avx2(self, size16, old, new, out) };
+                }
+                if is_x86_feature_detected!("sse2") {
+                    imp!(#[target_feature(enable = "sse2")] unsafe fn imp_sse2);
+                    break 'simd unsafe { imp_sse2(self, size16, old, new, out) };
+                }
+            }
+            #[cfg(target_arch = "aarch64")]
+            {
+                if std::arch::is_aarch64_feature_detected!("neon") {
+                    imp!(#[target_feature(enable = "neon")] unsafe fn imp_neon);
+                    break 'simd unsafe { imp_neon(self, size16, old, new, out) };
+                }
+            }
+            imp!(fn imp_baseline);
+            imp_baseline(self, size16, old, new, out)
+        };
+        let done = out.len() / ROTATE_LANES * ROTATE_LANES;
+        let mut crc = crc;
+        for i in done..out.len() {
+            crc = crc.rotate(size, old[i], new[i]);
+            out[i] = crc;
+        }
+    }
+
     #[allow(dead_code)]
-    pub fn rollin(self, new_byte: u8) -> Crc {
+    pub(crate) fn rollin(self, new_byte: u8) -> Crc {
         let (mut s1, mut s2) = self.split();
         s1 = s1.wrapping_add(new_byte as u16);
         s2 = s2.wrapping_add(s1);
@@ -64,6 +175,7 @@ impl Crc {
         Crc::combine(s1, s2)
     }
 
+    /// Extend the checksum with `buf`.
     pub fn update(self, buf: &[u8]) -> Crc {
         macro_rules! imp {
             ($($x:tt)*) => {$($x)* (init: Crc, buf: &[u8]) -> Crc {
@@ -98,13 +210,22 @@ impl Crc {
                 }
             }
         }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                imp!(#[target_feature(enable = "neon")] unsafe fn imp_neon);
+                unsafe {
+                    return imp_neon(self, buf);
+                }
+            }
+        }
         imp!(fn imp_baseline);
         imp_baseline(self, buf)
     }
 
     /// Like `Crc::update`, but not autovectorizable.
     #[allow(dead_code)]
-    pub fn basic_update(self, buf: &[u8]) -> Crc {
+    pub(crate) fn basic_update(self, buf: &[u8]) -> Crc {
         let (mut s1, mut s2) = self.split();
         for &byte in buf {
             s1 = s1.wrapping_add(byte as u16);
@@ -159,6 +280,25 @@ mod tests {
         sum1 == sum2
     }
 
+    #[quickcheck]
+    fn rotate_shift_matches_rotate(crc: u32, shift: u8, old_byte: u8, new_byte: u8) -> bool {
+        let shift = shift as u32 % 32;
+        let crc = Crc(crc);
+        crc.rotate_shift(shift, old_byte, new_byte) == crc.rotate(1 << shift, old_byte, new_byte)
+    }
+
+    #[quickcheck]
+    fn rotate_many_matches_rotate(crc: u32, size: u32, bytes: Vec<(u8, u8)>) -> bool {
+        let (old, new): (Vec<u8>, Vec<u8>) = bytes.into_iter().unzip();
+        let mut out = vec![Crc::new(); old.len()];
+        Crc(crc).rotate_many(size, &old, &new, &mut out);
+        let mut expected = Crc(crc);
+        out.iter().zip(old.iter().zip(&new)).all(|(&out, (&old, &new))| {
+            expected = expected.rotate(size, old, new);
+            out == expected
+        })
+    }
+
     #[quickcheck]
     fn rollout_one(buf: Vec<u8>) -> bool {
         if buf.is_empty() {
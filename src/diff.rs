@@ -1,32 +1,96 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt;
-use std::io::{self, Write};
-use std::sync::Arc;
+use std::io::{self, Read, Write};
+use std::ops::Range;
 
 #[cfg(feature = "parallel")]
 use rayon::prelude::*;
 
-use crate::consts::{
-    DELTA_MAGIC, RS_OP_COPY_N1_N1, RS_OP_END, RS_OP_LITERAL_1, RS_OP_LITERAL_N1, RS_OP_LITERAL_N2,
-    RS_OP_LITERAL_N4, RS_OP_LITERAL_N8,
-};
+use crate::consts::{DELTA_MAGIC, DELTA_V2_MAGIC, RS_OP_END};
 use crate::crc::Crc;
 use crate::hasher::BuildCrcHasher;
-use crate::md4::{md4, MD4_SIZE};
-use crate::blake3::{blake3, BLAKE3_SIZE};
-use crate::signature::{IndexedSignature, SignatureType};
+use crate::observer::DiffObserver;
+use crate::ops::{encode_copy, encode_literal_header, encode_repeat};
+use crate::patch::{Op, OpReader};
+use crate::rolling_hash::{as_crc, RollingHash};
+use crate::cancel::Cancelled;
+use crate::signature::IndexedSignature;
+#[cfg(feature = "parallel")]
+use crate::signature::SignatureType;
+use crate::strict::require;
+use crate::strong_hash::{StrongHash, MAX_STRONG_HASH_SIZE};
 
 /// This controls how many times we will allow ourselves to fail at matching a
 /// given crc before permanently giving up on it (essentially removing it from
 /// the signature).
-const MAX_CRC_COLLISIONS: u32 = 1024;
+pub(crate) const MAX_CRC_COLLISIONS: u32 = 1024;
+
+/// Tunable limits and heuristics of the block search, for [diff_with_options].
+///
+/// The defaults are what [diff()] uses.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DiffOptions {
+    /// How many times a CRC may match a block of the base data but not its strong hash before
+    /// the CRC is ignored. Lower limits bound the hashing done on adversarial inputs, at the cost
+    /// of missing blocks whose CRC is common.
+    pub max_collisions: u32,
+    /// Whether to look for blocks at every byte offset. If not, the new data is only compared
+    /// block by block from the end of the previous match, which is much faster but only finds
+    /// moved data that stays aligned to it.
+    pub rolling_search: bool,
+    /// How many bytes to search byte by byte after the previous match before falling back to
+    /// comparing block by block, which bounds the cost of large regions without matches.
+    pub max_search_distance: Option<usize>,
+    /// Whether to pick, among duplicate blocks of the base data, the one that continues the
+    /// previous copy.
+    pub lookahead: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            max_collisions: MAX_CRC_COLLISIONS,
+            rolling_search: true,
+            max_search_distance: None,
+            lookahead: true,
+        }
+    }
+}
+
+/// How many candidate positions are hashed together after a CRC collision, see
+/// [Matcher::prefetch].
+const VERIFY_BATCH: usize = 8;
+
+/// How many positions [Matcher::skip_misses] rolls over at a time.
+const ROLL_BATCH: usize = 32;
+
+/// Runs of a repeated pattern at least this long become repeats in v2 deltas, which is always
+/// smaller than leaving them in a literal.
+const MIN_REPEAT_LEN: usize = 32;
+
+/// The longest pattern [find_repeat] looks for.
+const MAX_REPEAT_PERIOD: usize = 16;
+
+/// The size of the longest strong hash a signature can use.
+pub(crate) const DIGEST_SIZE: usize = MAX_STRONG_HASH_SIZE;
+
+/// A strong hash of a block, see [crypto_hash].
+pub(crate) type Digest = [u8; DIGEST_SIZE];
 
 /// Indicates that a delta could not be calculated
 #[derive(Debug)]
 pub enum DiffError {
     /// Indicates the signature is invalid or unsupported
     InvalidSignature,
+    /// Indicates an invalid argument, with the `strict` feature; see
+    /// [the strict module](crate::strict)
+    InvalidArgument {
+        /// The name of the argument.
+        name: &'static str,
+    },
+    /// Indicates the calculation was cancelled; see [the cancel module](crate::cancel)
+    Cancelled,
     /// Indicates an IO error occured when writing the delta
     Io(io::Error),
 }
@@ -35,6 +99,8 @@ impl fmt::Display for DiffError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::InvalidSignature => f.write_str("invalid or unsupported signature for diff"),
+            Self::InvalidArgument { name } => write!(f, "invalid argument (name={name})"),
+            Self::Cancelled => f.write_str("diff was cancelled"),
             Self::Io(source) => write!(f, "Encountered IO error when calculating diff: {source}"),
         }
     }
@@ -48,200 +114,1239 @@ impl From<io::Error> for DiffError {
     }
 }
 
-fn insert_command(len: u64, out: &mut impl Write) -> io::Result<()> {
-    assert!(len != 0);
-    if len <= 64 {
-        out.write_all(&[RS_OP_LITERAL_1 + (len - 1) as u8])?;
-    } else if len <= u8::MAX as u64 {
-        out.write_all(&[RS_OP_LITERAL_N1, len as u8])?;
-    } else if len <= u16::MAX as u64 {
-        let [v1, v2] = (len as u16).to_be_bytes();
-        out.write_all(&[RS_OP_LITERAL_N2, v1, v2])?;
-    } else if len <= u32::MAX as u64 {
-        let [v1, v2, v3, v4] = (len as u32).to_be_bytes();
-        out.write_all(&[RS_OP_LITERAL_N4, v1, v2, v3, v4])?;
-    } else {
-        let [v1, v2, v3, v4, v5, v6, v7, v8] = len.to_be_bytes();
-        out.write_all(&[RS_OP_LITERAL_N8, v1, v2, v3, v4, v5, v6, v7, v8])?;
+impl From<Cancelled> for DiffError {
+    fn from(_: Cancelled) -> Self {
+        Self::Cancelled
     }
+}
 
-    Ok(())
+/// Tracks which part of the new data has been encoded.
+///
+/// Callers pass positions relative to the `data` slice they hold, but the bookkeeping is done in
+/// absolute `u64` offsets so that chunked callers can encode inputs larger than `usize::MAX`
+/// (e.g. >4 GiB on 32-bit targets).
+struct OutputState<O = ()> {
+    /// Absolute position of `data[0]`.
+    base: u64,
+    /// Everything before this absolute position is covered by emitted ops or by `queued_copy`.
+    emitted: u64,
+    queued_copy: Option<(u64, u64)>,
+    /// Whether literals are scanned for runs to write as repeats, in v2 deltas.
+    repeats: bool,
+    /// Told about literals here, and about the rest by [Matcher].
+    observer: O,
 }
 
-fn copy_command(offset: u64, len: u64, out: &mut impl Write) -> io::Result<()> {
-    fn u64_size_class(val: u64) -> u8 {
-        if val <= u8::MAX as u64 {
-            0
-        } else if val <= u16::MAX as u64 {
-            1
-        } else if val <= u32::MAX as u64 {
-            2
-        } else {
-            3
+impl<O: DiffObserver> OutputState<O> {
+    fn with_observer(observer: O) -> Self {
+        OutputState {
+            base: 0,
+            emitted: 0,
+            queued_copy: None,
+            repeats: false,
+            observer,
         }
     }
 
-    fn size_class_marker(offset: u64, len: u64) -> u8 {
-        let offset_len = u64_size_class(offset);
-        let len_len = u64_size_class(len);
+    /// The absolute position of `data[here]`.
+    fn position(&self, here: usize) -> u64 {
+        self.base + here as u64
+    }
 
-        RS_OP_COPY_N1_N1 + offset_len * 4 + len_len
+    /// The position in `data` up to which everything is covered by ops.
+    fn emitted(&self) -> usize {
+        (self.emitted - self.base) as usize
     }
 
-    fn write_varint(val: u64, out: &mut impl Write) -> io::Result<()> {
-        if val <= u8::MAX as u64 {
-            out.write_all(&[val as u8])?;
-        } else if val <= u16::MAX as u64 {
-            out.write_all(&(val as u16).to_be_bytes())?;
-        } else if val <= u32::MAX as u64 {
-            out.write_all(&(val as u32).to_be_bytes())?;
-        } else {
-            out.write_all(&val.to_be_bytes())?;
+    fn emit(&mut self, until: usize, data: &[u8], mut out: impl Write) -> io::Result<()> {
+        if let Some((offset, len)) = self.queued_copy.take() {
+            encode_copy(offset, len, &mut out)?;
+        }
+        if self.emitted() < until {
+            encode_literal(&data[self.emitted()..until], self.repeats, &mut out)?;
+            let len = (until - self.emitted()) as u64;
+            self.observer.literal_flushed(self.emitted, len);
+            self.emitted = self.base + until as u64;
         }
 
         Ok(())
     }
 
-    let marker = size_class_marker(offset, len);
-    out.write_all(&[marker])?;
-    write_varint(offset, out)?;
-    write_varint(len, out)?;
+    fn copy(
+        &mut self,
+        offset: u64,
+        len: u64,
+        here: usize,
+        data: &[u8],
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        if let Some((queued_offset, queued_len)) = self.queued_copy {
+            if self.emitted() == here && queued_offset + queued_len == offset {
+                // just extend the copy
+                self.queued_copy = Some((queued_offset, queued_len + len));
+                self.emitted += len;
+                return Ok(());
+            }
+        }
+        self.emit(here, data, out)?;
+        self.queued_copy = Some((offset, len));
+        self.emitted = self.base + here as u64 + len;
+
+        Ok(())
+    }
 
-    Ok(())
+    /// Shift all positions back by `n` after the first `n` bytes of the data were discarded.
+    fn rebase(&mut self, n: usize) {
+        debug_assert!(n <= self.emitted());
+        self.base += n as u64;
+    }
 }
 
-struct OutputState {
-    emitted: usize,
-    queued_copy: Option<(u64, usize)>,
+/// Converts between blocks of the base data and their offsets, and rolls block-sized windows.
+///
+/// Power-of-two block sizes, the common case, use masks and shifts instead of division and
+/// multiplication, which are measurable in the inner loop at small block sizes.
+#[derive(Clone, Copy, Debug)]
+struct BlockGeometry {
+    size: usize,
+    /// `log2(size)`, if `size` is a power of two.
+    shift: Option<u32>,
 }
 
-impl OutputState {
-    fn emit(&mut self, until: usize, data: &[u8], mut out: impl Write) -> io::Result<()> {
-        if self.emitted == until {
-            return Ok(());
+impl BlockGeometry {
+    fn new(size: usize) -> Self {
+        BlockGeometry {
+            size,
+            shift: size.is_power_of_two().then(|| size.trailing_zeros()),
         }
-        if let Some((offset, len)) = self.queued_copy {
-            copy_command(offset, len as u64, &mut out)?;
-            self.emitted += len;
+    }
+
+    /// The offset of block `idx` in the base data.
+    #[inline]
+    fn offset(self, idx: u32) -> u64 {
+        match self.shift {
+            Some(shift) => (idx as u64) << shift,
+            None => idx as u64 * self.size as u64,
         }
-        if self.emitted < until {
-            let to_emit = &data[self.emitted..until];
-            insert_command(to_emit.len() as u64, &mut out)?;
-            out.write_all(to_emit)?;
-            self.emitted = until;
+    }
+
+    /// The index of the block starting at `offset`, if a block starts there.
+    #[inline]
+    fn index(self, offset: u64) -> Option<usize> {
+        let index = match self.shift {
+            Some(shift) => (offset & (self.size as u64 - 1) == 0).then_some(offset >> shift),
+            None => offset
+                .is_multiple_of(self.size as u64)
+                .then_some(offset / self.size as u64),
+        };
+        index.and_then(|index| usize::try_from(index).ok())
+    }
+
+    /// The CRC of the window one byte further than the one with CRC `crc`.
+    #[inline]
+    fn rotate<R: RollingHash>(self, crc: R, old_byte: u8, new_byte: u8) -> R {
+        match self.shift {
+            Some(shift) => crc.rotate_shift(shift, old_byte, new_byte),
+            None => crc.rotate(self.size as u32, old_byte, new_byte),
         }
+    }
+}
+
+/// The rolling-hash block matcher shared by the one-shot and chunked diff entry points.
+///
+/// Positions are relative to the `data` slice passed to [Matcher::search]; chunked callers that
+/// discard a prefix of their buffer must call [Matcher::rebase] to keep them in sync.
+struct Matcher<'a, 'b, O = (), R = Crc> {
+    signature: &'a IndexedSignature<'b, R>,
+    geometry: BlockGeometry,
+    crypto_hash_size: usize,
+    state: OutputState<O>,
+    here: usize,
+    /// The CRC of the window starting at `here - 1`, if `here` was reached by rolling forward.
+    rolling: Option<R>,
+    collisions: HashMap<Crc, u32, BuildCrcHasher>,
+    /// Anchors found by [Matcher::anchor], by ascending position.
+    anchors: Vec<Anchor>,
+    next_anchor: usize,
+    /// The displacement of the nearest anchor or previous match, if anchoring is enabled.
+    alignment: Option<i64>,
+    /// Strong hashes of upcoming candidate positions from [Matcher::prefetch], by ascending
+    /// position.
+    prefetched: VecDeque<(usize, Digest)>,
+    /// The position right after the previous match and the block after the one it copied.
+    run: Option<(usize, u32)>,
+    /// The CRC of the block at a position [Matcher::prefer_run] looked ahead to.
+    upcoming: Option<(usize, R)>,
+    options: DiffOptions,
+    /// The bytes searched since the previous match.
+    missed: usize,
+}
+
+/// A position in the new data where a block of the base data was found by [Matcher::anchor].
+#[derive(Clone, Copy, Debug)]
+struct Anchor {
+    position: usize,
+    /// `position` minus the offset of the block in the base data.
+    displacement: i64,
+}
+
+/// Check that deltas can be calculated against `signature`.
+pub(crate) fn check_signature<R>(signature: &IndexedSignature<'_, R>) -> Result<(), DiffError> {
+    let hash_size = signature.signature_type.strong_hash().size();
+    if !(1..=DIGEST_SIZE).contains(&hash_size) || signature.crypto_hash_size as usize > hash_size {
+        return Err(DiffError::InvalidSignature);
+    }
+    Ok(())
+}
+
+impl<'a, 'b> Matcher<'a, 'b> {
+    fn new(signature: &'a IndexedSignature<'b>) -> Result<Self, DiffError> {
+        Self::with_observer(signature, ())
+    }
+}
 
+impl<'a, 'b, O: DiffObserver, R: RollingHash> Matcher<'a, 'b, O, R> {
+    fn with_observer(
+        signature: &'a IndexedSignature<'b, R>,
+        observer: O,
+    ) -> Result<Self, DiffError> {
+        check_signature(signature)?;
+        let crypto_hash_size = signature.crypto_hash_size as usize;
+        Ok(Matcher {
+            signature,
+            geometry: BlockGeometry::new(signature.block_size as usize),
+            crypto_hash_size,
+            state: OutputState::with_observer(observer),
+            here: 0,
+            rolling: None,
+            collisions: HashMap::with_hasher(BuildCrcHasher::default()),
+            anchors: Vec::new(),
+            next_anchor: 0,
+            alignment: None,
+            prefetched: VecDeque::new(),
+            run: None,
+            upcoming: None,
+            options: DiffOptions::default(),
+            missed: 0,
+        })
+    }
+
+    /// Look up a block of `data` in the base data every `spacing` bytes of `range`, and make the
+    /// search prefer blocks that continue the alignment of the nearest anchor or previous match.
+    ///
+    /// Anchor positions are relative to `data`, so this can't be combined with [Matcher::rebase].
+    fn anchor(&mut self, data: &[u8], range: Range<usize>, spacing: usize) -> Result<(), DiffError> {
+        let geometry = self.geometry;
+        let block_size = geometry.size;
+        let spacing = spacing.max(block_size);
+        let mut start = range.start;
+        while start + block_size <= range.end {
+            // Try the positions of one block, so that a relocated region is found whatever its
+            // alignment to the blocks of the base data.
+            let last = (start + block_size - 1).min(range.end - block_size);
+            let mut crc = R::default().update(&data[start..start + block_size]);
+            for position in start..=last {
+                if position > start {
+                    crc = geometry.rotate(crc, data[position - 1], data[position + block_size - 1]);
+                }
+                let block = &data[position..position + block_size];
+                if let Some(idx) = find_block(self.signature, crc, block)? {
+                    self.anchors.push(Anchor {
+                        position,
+                        displacement: position as i64 - geometry.offset(idx) as i64,
+                    });
+                    break;
+                }
+            }
+            start += spacing;
+        }
+        self.alignment = Some(0);
         Ok(())
     }
 
-    fn copy(
+    /// The index of the block of the base data at the current alignment, if `block` at `here`,
+    /// whose CRC is `crc`, matches it.
+    fn aligned_block(&self, here: usize, crc: R, block: &[u8]) -> Result<Option<u32>, DiffError> {
+        let Some(displacement) = self.alignment else {
+            return Ok(None);
+        };
+        let Ok(offset) = u64::try_from(here as i64 - displacement) else {
+            return Ok(None);
+        };
+        let Some(idx) = self.geometry.index(offset) else {
+            return Ok(None);
+        };
+        let entry_size = Crc::SIZE + self.crypto_hash_size;
+        let Some(entry) = self.signature.entries.get(idx * entry_size..(idx + 1) * entry_size)
+        else {
+            return Ok(None);
+        };
+        Ok(entry_matches(self.signature, entry, crc, block)?.then_some(idx as u32))
+    }
+
+    /// Match as far into `data` as possible, stopping once fewer than `block_size` bytes remain.
+    fn search(&mut self, data: &[u8], out: &mut impl Write) -> Result<(), DiffError> {
+        let geometry = self.geometry;
+        let block_size = geometry.size;
+        while data.len() - self.here >= block_size {
+            if self.alignment.is_none() {
+                self.skip_misses(data);
+                if data.len() - self.here < block_size {
+                    break;
+                }
+            }
+            let here = self.here;
+            let block = &data[here..here + block_size];
+            let crc = match (self.rolling.take(), self.upcoming.take()) {
+                (Some(crc), _) => geometry.rotate(crc, data[here - 1], block[block_size - 1]),
+                (None, Some((position, crc))) if position == here => crc,
+                (None, _) => R::default().update(block),
+            };
+            let key = as_crc(crc);
+            while let Some(anchor) = self.anchors.get(self.next_anchor) {
+                if anchor.position > here {
+                    break;
+                }
+                self.alignment = Some(anchor.displacement);
+                self.next_anchor += 1;
+            }
+            if let Some(idx) = self.aligned_block(here, crc, block)? {
+                self.matched(here, idx, data, out)?;
+                self.run = Some((here + block_size, idx + 1));
+                self.here += block_size;
+                continue;
+            }
+            // if we detect too many CRC collisions, blacklist the CRC to avoid DoS
+            if self
+                .collisions
+                .get(&key)
+                .is_none_or(|&count| count < self.options.max_collisions)
+            {
+                if let Some(blocks) = self.signature.blocks.get(&key) {
+                    let digest = match self.take_prefetched(here) {
+                        Some(digest) => digest,
+                        None => crypto_hash(self.signature, block)?,
+                    };
+                    if let Some(idx) = blocks.get(&digest[..self.crypto_hash_size]) {
+                        // match found
+                        let idx = if self.options.lookahead {
+                            self.prefer_run(data, here, idx)?
+                        } else {
+                            idx
+                        };
+                        if self.alignment.is_some() {
+                            self.alignment = Some(here as i64 - geometry.offset(idx) as i64);
+                        }
+                        self.matched(here, idx, data, out)?;
+                        self.run = Some((here + block_size, idx + 1));
+                        self.here += block_size;
+                        continue;
+                    }
+                    // CRC collision
+                    let count = self.collisions.entry(key).or_insert(0);
+                    *count += 1;
+                    let blacklisted = *count >= self.options.max_collisions;
+                    self.state
+                        .observer
+                        .collision(self.state.position(here), key.0, blacklisted);
+                    if self.prefetched.is_empty() {
+                        self.prefetch(data, here, crc)?;
+                    }
+                }
+            }
+            // no match, try to extend
+            let rolling = self.options.rolling_search
+                && self.options.max_search_distance.is_none_or(|max| self.missed < max);
+            if rolling {
+                self.rolling = Some(crc);
+                self.here += 1;
+                self.missed += 1;
+            } else {
+                self.here += block_size;
+                self.missed = self.missed.saturating_add(block_size);
+            }
+        }
+        Ok(())
+    }
+
+    /// While rolling forward, skip the positions whose CRCs can't lead to a match, computing
+    /// [ROLL_BATCH] of them at a time with [RollingHash::rotate_many], and stop at the first one
+    /// that can.
+    ///
+    /// Positions are only skipped where [Matcher::search] would roll on without looking anything
+    /// up, so the delta is the same.
+    fn skip_misses(&mut self, data: &[u8]) {
+        let block_size = self.geometry.size;
+        let mut crcs = [R::default(); ROLL_BATCH];
+        while let Some(crc) = self.rolling {
+            let here = self.here;
+            let within_distance = self
+                .options
+                .max_search_distance
+                .is_none_or(|max| max - self.missed.min(max) > ROLL_BATCH);
+            if !within_distance || data.len() - here < block_size + ROLL_BATCH {
+                return;
+            }
+            let old = &data[here - 1..here - 1 + ROLL_BATCH];
+            let new = &data[here + block_size - 1..here + block_size - 1 + ROLL_BATCH];
+            crc.rotate_many(block_size as u32, old, new, &mut crcs);
+            let candidate = crcs.iter().position(|&crc| {
+                let key = as_crc(crc);
+                self.signature.blocks.get(&key).is_some()
+                    && self
+                        .collisions
+                        .get(&key)
+                        .is_none_or(|&count| count < self.options.max_collisions)
+            });
+            let skipped = candidate.unwrap_or(ROLL_BATCH);
+            if skipped == 0 {
+                return;
+            }
+            self.rolling = Some(crcs[skipped - 1]);
+            self.here += skipped;
+            self.missed += skipped;
+            if candidate.is_some() {
+                return;
+            }
+        }
+    }
+
+    /// Copy block `idx` of the base data, which matched at `here`.
+    fn matched(
         &mut self,
-        offset: u64,
-        len: usize,
         here: usize,
+        idx: u32,
         data: &[u8],
         out: &mut impl Write,
     ) -> io::Result<()> {
-        if let Some((queued_offset, queued_len)) = self.queued_copy {
-            if self.emitted + queued_len == here && queued_offset + queued_len as u64 == offset {
-                // just extend the copy
-                self.queued_copy = Some((queued_offset, queued_len + len));
-                return Ok(());
+        let (offset, len) = (self.geometry.offset(idx), self.geometry.size as u64);
+        self.missed = 0;
+        self.state.copy(offset, len, here, data, out)?;
+        self.state.observer.block_matched(self.state.position(here), offset, len);
+        Ok(())
+    }
+
+    /// Among the blocks identical to block `idx`, which matched at `here`, pick one that makes a
+    /// longer copy: the block after the previous match if that ended at `here`, or else one
+    /// whose successor matches the next block of `data`.
+    ///
+    /// This keeps bases with many duplicate blocks (zero padding, repeated records) from
+    /// scattering a contiguous region over copies of unrelated blocks, each costing an op.
+    fn prefer_run(&mut self, data: &[u8], here: usize, idx: u32) -> Result<u32, DiffError> {
+        let block_size = self.geometry.size;
+        let entry_size = Crc::SIZE + self.crypto_hash_size;
+        let entries = self.signature.entries;
+        let entry =
+            |idx: u32| entries.get(idx as usize * entry_size..(idx as usize + 1) * entry_size);
+        if let Some((end, next)) = self.run {
+            if end == here && entry(next).is_some() && entry(next) == entry(idx) {
+                return Ok(next);
             }
         }
-        self.emit(here, data, out)?;
-        self.queued_copy = Some((offset, len));
+        let Some(upcoming) = data.get(here + block_size..here + 2 * block_size) else {
+            return Ok(idx);
+        };
+        let crc = R::default().update(upcoming);
+        self.upcoming = Some((here + block_size, crc));
+        if entry(idx + 1).is_some_and(|entry| entry[..Crc::SIZE] == crc.to_bytes()) {
+            return Ok(idx);
+        }
+        let Some(blocks) = self.signature.blocks.get(&as_crc(crc)) else {
+            return Ok(idx);
+        };
+        let digest = crypto_hash(self.signature, upcoming)?;
+        self.prefetched.push_back((here + block_size, digest));
+        match blocks.get(&digest[..self.crypto_hash_size]) {
+            Some(successor) if successor > 0 && entry(successor - 1) == entry(idx) => {
+                Ok(successor - 1)
+            }
+            _ => Ok(idx),
+        }
+    }
 
+    /// Hash the next few candidate positions after a CRC collision at `here`, whose CRC is `crc`,
+    /// together.
+    ///
+    /// Inputs with one collision tend to have many, and hashing blocks in batches lets MD4 use
+    /// all SIMD lanes. Only positions up to a block ahead are considered, since a match there
+    /// skips the rest, and [Matcher::search] still verifies them in order, so the delta is the
+    /// same as without prefetching.
+    fn prefetch(&mut self, data: &[u8], here: usize, mut crc: R) -> Result<(), DiffError> {
+        let geometry = self.geometry;
+        let block_size = geometry.size;
+        let last = (here + block_size - 1).min(data.len() - block_size);
+        let mut positions = [0; VERIFY_BATCH];
+        let mut count = 0;
+        for position in here + 1..=last {
+            let (old, new) = (data[position - 1], data[position + block_size - 1]);
+            crc = geometry.rotate(crc, old, new);
+            let key = as_crc(crc);
+            let blacklisted = self
+                .collisions
+                .get(&key)
+                .is_some_and(|&count| count >= self.options.max_collisions);
+            if !blacklisted && self.signature.blocks.get(&key).is_some() {
+                positions[count] = position;
+                count += 1;
+                if count == VERIFY_BATCH {
+                    break;
+                }
+            }
+        }
+        let positions = &positions[..count];
+        let mut blocks: [&[u8]; VERIFY_BATCH] = [&[]; VERIFY_BATCH];
+        for (block, &position) in blocks.iter_mut().zip(positions) {
+            *block = &data[position..position + block_size];
+        }
+        let pad = |digest: &[u8]| {
+            let mut padded = [0; DIGEST_SIZE];
+            padded[..digest.len()].copy_from_slice(digest);
+            padded
+        };
+        let hash = self.signature.strong_hash();
+        let size = hash.size();
+        let mut digests = [0; VERIFY_BATCH * DIGEST_SIZE];
+        hash.hash_many(&blocks[..count], &mut digests[..count * size]);
+        self.prefetched
+            .extend(positions.iter().zip(digests.chunks_exact(size)).map(|(&p, d)| (p, pad(d))));
         Ok(())
     }
+
+    /// The prefetched strong hash of the block at `here`, dropping those of positions the search
+    /// skipped.
+    fn take_prefetched(&mut self, here: usize) -> Option<Digest> {
+        while let Some(&(position, digest)) = self.prefetched.front() {
+            if position > here {
+                break;
+            }
+            self.prefetched.pop_front();
+            if position == here {
+                return Some(digest);
+            }
+        }
+        None
+    }
+
+    /// Flush everything up to the current search position, so the caller may discard it.
+    fn flush(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        self.state.emit(self.here, data, out)
+    }
+
+    /// How many leading bytes of `data` are no longer needed by the matcher.
+    fn consumed(&self) -> usize {
+        let needed = if self.rolling.is_some() {
+            self.here - 1
+        } else {
+            self.here
+        };
+        self.state.emitted().min(needed)
+    }
+
+    /// Shift all positions back by `n` after the first `n` bytes of the data were discarded.
+    fn rebase(&mut self, n: usize) {
+        self.state.rebase(n);
+        self.here -= n;
+        for (position, _) in &mut self.prefetched {
+            *position -= n;
+        }
+        self.run = self
+            .run
+            .and_then(|(position, next)| Some((position.checked_sub(n)?, next)));
+        if let Some((position, _)) = &mut self.upcoming {
+            *position -= n;
+        }
+    }
+
+    /// Emit whatever is left of `data` and terminate the delta.
+    fn finish(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        self.state.emit(data.len(), data, &mut *out)?;
+        out.write_all(&[RS_OP_END])
+    }
 }
 
 /// Calculate a delta and write it to `out`.
 /// This delta can be applied to the base data represented by `signature` to
 /// attempt to reconstruct `data`.
 ///
+/// Identical regions at the start and end of `data` and the base data are detected up front and
+/// copied as a whole, so only the part in between is searched. If `data` is the base data with
+/// something appended, or a truncated copy of it, the delta is written without a rolling search.
+///
 /// # Security
 /// Since `fast_rsync` uses the insecure MD4 hash algorithm, the resulting delta must not be
 /// trusted to correctly reconstruct `data`. The delta might fail to apply or produce the wrong
 /// data entirely. Always use another mechanism, like a cryptographic hash function, to validate
 /// the final reconstructed data.
-pub fn diff(
+pub fn diff<R: RollingHash>(
+    signature: &IndexedSignature<'_, R>,
+    data: &[u8],
+    out: impl Write,
+) -> Result<(), DiffError> {
+    diff_with_anchors(signature, data, None, &DiffOptions::default(), false, out)
+}
+
+/// Calculate a delta like [diff()], with an anchoring stage for data that contains large
+/// relocated regions, like reorganized archives.
+///
+/// Every `anchor_spacing` bytes, a block of `data` is looked up in the base data. The search then
+/// prefers the blocks of the base data that continue the alignment of the nearest such anchor or
+/// of the previous match, so relocated regions are copied in long runs even where the base data
+/// contains many identical blocks, and blocks are still found where a CRC is too common to look
+/// up.
+///
+/// # Security
+/// See [diff()].
+pub fn diff_anchored(
     signature: &IndexedSignature<'_>,
     data: &[u8],
+    anchor_spacing: usize,
+    out: impl Write,
+) -> Result<(), DiffError> {
+    let options = DiffOptions::default();
+    diff_with_anchors(signature, data, Some(anchor_spacing), &options, false, out)
+}
+
+/// Calculate a delta like [diff()], with the limits and heuristics of the search set by
+/// `options`, e.g. to trade delta size for speed on large inputs.
+///
+/// # Security
+/// See [diff()].
+pub fn diff_with_options<R: RollingHash>(
+    signature: &IndexedSignature<'_, R>,
+    data: &[u8],
+    options: &DiffOptions,
+    out: impl Write,
+) -> Result<(), DiffError> {
+    diff_with_anchors(signature, data, None, options, false, out)
+}
+
+/// Calculate a delta like [diff()], in the v2 format, which writes runs of a repeated pattern of
+/// up to 16 bytes (zero padding, erased flash) that aren't in the base data as repeat ops instead
+/// of literals.
+///
+/// v2 deltas can be applied by this crate, but not by librsync. See [consts](crate::consts) for
+/// the format.
+///
+/// # Security
+/// See [diff()].
+pub fn diff_v2(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    out: impl Write,
+) -> Result<(), DiffError> {
+    diff_with_anchors(signature, data, None, &DiffOptions::default(), true, out)
+}
+
+/// Calculate a delta that reconstructs only `data[target_range]`, for protocols that update
+/// part of a file.
+///
+/// Copies and literals are clipped to the range, so copies of blocks straddling its ends still
+/// match: only the blocks that overlap the range are searched, i.e. the range widened by one
+/// block on each side.
+///
+/// # Panics
+/// Panics if `target_range` is out of bounds of `data`, unless the `strict` feature turns that
+/// into [DiffError::InvalidArgument].
+///
+/// # Security
+/// See [diff()].
+pub fn diff_range(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    target_range: Range<usize>,
     mut out: impl Write,
 ) -> Result<(), DiffError> {
-    let block_size = signature.block_size;
-    let crypto_hash_size = signature.crypto_hash_size as usize;
-    if let SignatureType::Md4 = signature.signature_type {
-        if crypto_hash_size > MD4_SIZE {
-            return Err(DiffError::InvalidSignature);
+    let Range { start, end } = target_range;
+    require!(
+        start <= end && end <= data.len(),
+        DiffError::InvalidArgument { name: "target_range" }
+    );
+    let margin = (signature.block_size as usize).saturating_sub(1);
+    let window = start.saturating_sub(margin)..end.saturating_add(margin).min(data.len());
+    let mut delta = Vec::new();
+    diff(signature, &data[window.clone()], &mut delta)?;
+
+    // Positions of the range within the window.
+    let (start, end) = ((start - window.start) as u64, (end - window.start) as u64);
+    out.write_all(&DELTA_MAGIC.to_be_bytes())?;
+    let mut position = 0u64;
+    let mut ops = OpReader::new(&delta).expect("diff produced an invalid delta");
+    while let Some(op) = ops.next_op().expect("diff produced an invalid delta") {
+        let len = op.output_len();
+        let (from, to) = (position.max(start), (position + len).min(end));
+        if from < to {
+            let skip = from - position;
+            match op {
+                Op::Literal(literal) => {
+                    encode_literal_header(to - from, &mut out)?;
+                    out.write_all(&literal[skip as usize..(to - position) as usize])?;
+                }
+                Op::Copy { offset, .. } => encode_copy(offset + skip, to - from, &mut out)?,
+                Op::Repeat { .. } => unreachable!("diff writes v1 deltas"),
+            }
         }
-    } else if let SignatureType::Blake3 = signature.signature_type {
-        if crypto_hash_size > BLAKE3_SIZE {
-            return Err(DiffError::InvalidSignature);
+        position += len;
+    }
+    out.write_all(&[RS_OP_END])?;
+    Ok(())
+}
+
+/// Calculate a delta with an optional anchoring stage, with the search tuned by `options`, and as
+/// a v1 or v2 delta (`repeats`).
+pub(crate) fn diff_with_anchors<R: RollingHash>(
+    signature: &IndexedSignature<'_, R>,
+    data: &[u8],
+    anchor_spacing: Option<usize>,
+    options: &DiffOptions,
+    repeats: bool,
+    out: impl Write,
+) -> Result<(), DiffError> {
+    diff_observed_with(signature, data, anchor_spacing, options, repeats, (), out)
+}
+
+/// [diff_with_anchors], reporting to `observer`.
+pub(crate) fn diff_observed_with<R: RollingHash>(
+    signature: &IndexedSignature<'_, R>,
+    data: &[u8],
+    anchor_spacing: Option<usize>,
+    options: &DiffOptions,
+    repeats: bool,
+    mut observer: impl DiffObserver,
+    mut out: impl Write,
+) -> Result<(), DiffError> {
+    check_signature(signature)?;
+    let prefix = common_prefix(signature, data)?;
+    if prefix.append {
+        append_delta(prefix.len, data, repeats, out)?;
+        if prefix.len > 0 {
+            observer.region_skipped(0, prefix.len as u64);
         }
-    } else {
-        return Err(DiffError::InvalidSignature);
+        if prefix.len < data.len() {
+            observer.literal_flushed(prefix.len as u64, (data.len() - prefix.len) as u64);
+        }
+        return Ok(());
     }
-    out.write_all(&DELTA_MAGIC.to_be_bytes())?;
-    let mut state = OutputState {
-        emitted: 0,
-        queued_copy: None,
+    let mut matcher = Matcher::with_observer(signature, observer)?;
+    matcher.options = *options;
+    matcher.state.repeats = repeats;
+    // Only search the part between the identical leading and trailing regions.
+    let (suffix, suffix_offset) = common_suffix(signature, data, &prefix)?;
+    let end = data.len() - suffix;
+    if let Some(spacing) = anchor_spacing {
+        matcher.anchor(data, prefix.len..end, spacing)?;
+    }
+    out.write_all(&delta_magic(repeats).to_be_bytes())?;
+    if prefix.len > 0 {
+        matcher.state.copy(0, prefix.len as u64, 0, data, &mut out)?;
+        matcher.state.observer.region_skipped(0, prefix.len as u64);
+        matcher.here = prefix.len;
+    }
+    matcher.search(&data[..end], &mut out)?;
+    if suffix > 0 {
+        matcher.state.copy(suffix_offset, suffix as u64, end, data, &mut out)?;
+        matcher.state.observer.region_skipped(end as u64, suffix as u64);
+    }
+    matcher.finish(data, &mut out)?;
+    Ok(())
+}
+
+/// The region at the start of the new data that is identical to the start of the base data.
+pub(crate) struct Prefix {
+    /// The length of the region.
+    pub(crate) len: usize,
+    /// How many blocks of the base data the region covers.
+    blocks: usize,
+    /// Whether the new data is the base data with something appended, or a truncated copy of it.
+    pub(crate) append: bool,
+}
+
+/// Find the identical region at the start of `data` and the base data.
+///
+/// This compares `data` block by block against the signature's entries in order, which is much
+/// cheaper than the rolling search.
+pub(crate) fn common_prefix<R: RollingHash>(
+    signature: &IndexedSignature<'_, R>,
+    data: &[u8],
+) -> Result<Prefix, DiffError> {
+    let block_size = signature.block_size as usize;
+    let mut entries = signature
+        .entries
+        .chunks_exact(Crc::SIZE + signature.crypto_hash_size as usize);
+    let mut len = 0;
+    let mut blocks = 0;
+    while let Some(entry) = entries.next() {
+        let rest = &data[len..];
+        let matched = if entries.len() == 0 {
+            // The last block of the base data may be shorter than `block_size`.
+            let mut crc = R::default();
+            let mut matched = None;
+            for (len, &byte) in rest.iter().take(block_size).enumerate() {
+                crc = crc.update(&[byte]);
+                if entry_matches(signature, entry, crc, &rest[..=len])? {
+                    matched = Some(len + 1);
+                    break;
+                }
+            }
+            matched
+        } else if rest.len() >= block_size {
+            let block = &rest[..block_size];
+            let crc = R::default().update(block);
+            entry_matches(signature, entry, crc, block)?.then_some(block_size)
+        } else {
+            None
+        };
+        match matched {
+            Some(block_len) => {
+                len += block_len;
+                blocks += 1;
+            }
+            None => {
+                return Ok(Prefix {
+                    len,
+                    blocks,
+                    // Everything but a partial block at the end matched: the base data was
+                    // truncated.
+                    append: rest.len() < block_size,
+                })
+            }
+        }
+    }
+    Ok(Prefix {
+        len,
+        blocks,
+        append: true,
+    })
+}
+
+/// Find the identical region at the end of `data` and the base data that doesn't overlap
+/// `prefix`. Returns its length and its offset in the base data.
+pub(crate) fn common_suffix<R: RollingHash>(
+    signature: &IndexedSignature<'_, R>,
+    data: &[u8],
+    prefix: &Prefix,
+) -> Result<(usize, u64), DiffError> {
+    let block_size = signature.block_size as usize;
+    let entry_size = Crc::SIZE + signature.crypto_hash_size as usize;
+    let count = signature.entries.len() / entry_size;
+    let mut entries = signature
+        .entries
+        .chunks_exact(entry_size)
+        .skip(prefix.blocks)
+        .rev();
+    let Some(last) = entries.next() else {
+        return Ok((0, 0));
+    };
+    // The last block of the base data may be shorter than `block_size`, so try every length,
+    // longest first.
+    let rest = &data[prefix.len..];
+    let window = &rest[rest.len().saturating_sub(block_size)..];
+    let mut crc = R::default().update(window);
+    let mut len = 0;
+    for start in 0..window.len() {
+        if entry_matches(signature, last, crc, &window[start..])? {
+            len = window.len() - start;
+            break;
+        }
+        crc = crc.rollout((window.len() - start) as u32, window[start]);
+    }
+    if len == 0 {
+        return Ok((0, 0));
+    }
+    let mut blocks = 1;
+    for entry in entries {
+        let rest = &data[prefix.len..data.len() - len];
+        if rest.len() < block_size {
+            break;
+        }
+        let block = &rest[rest.len() - block_size..];
+        if !entry_matches(signature, entry, R::default().update(block), block)? {
+            break;
+        }
+        len += block_size;
+        blocks += 1;
+    }
+    Ok((len, (count - blocks) as u64 * block_size as u64))
+}
+
+/// Check whether `block`, whose CRC is `crc`, matches the signature entry `entry`.
+fn entry_matches<R: RollingHash>(
+    signature: &IndexedSignature<'_, R>,
+    entry: &[u8],
+    crc: R,
+    block: &[u8],
+) -> Result<bool, DiffError> {
+    if entry[..Crc::SIZE] != crc.to_bytes() {
+        return Ok(false);
+    }
+    let digest = crypto_hash(signature, block)?;
+    Ok(digest[..entry.len() - Crc::SIZE] == entry[Crc::SIZE..])
+}
+
+/// Look up `block`, whose CRC is `crc`, in the signature's index.
+fn find_block<R: RollingHash>(
+    signature: &IndexedSignature<'_, R>,
+    crc: R,
+    block: &[u8],
+) -> Result<Option<u32>, DiffError> {
+    let Some(blocks) = signature.blocks.get(&as_crc(crc)) else {
+        return Ok(None);
     };
+    let digest = crypto_hash(signature, block)?;
+    Ok(blocks.get(&digest[..signature.crypto_hash_size as usize]))
+}
+
+/// The strong hash of `block`, zero-padded to the longest supported digest.
+///
+/// This runs for every CRC hit, so it returns an array on the stack instead of allocating;
+/// callers truncate it to the signature's `crypto_hash_size`.
+#[inline]
+pub(crate) fn crypto_hash<R>(
+    signature: &IndexedSignature<'_, R>,
+    block: &[u8],
+) -> Result<Digest, DiffError> {
+    let mut digest = [0; DIGEST_SIZE];
+    let hash = signature.strong_hash();
+    hash.hash_block(block, &mut digest[..hash.size()]);
+    Ok(digest)
+}
+
+/// Write the delta that copies the first `prefix` bytes of `data` from the base data and the
+/// rest literally, as a v2 delta with repeats if `repeats` is set.
+pub(crate) fn append_delta(
+    prefix: usize,
+    data: &[u8],
+    repeats: bool,
+    mut out: impl Write,
+) -> io::Result<()> {
+    out.write_all(&delta_magic(repeats).to_be_bytes())?;
+    if prefix > 0 {
+        encode_copy(0, prefix as u64, &mut out)?;
+    }
+    if prefix < data.len() {
+        encode_literal(&data[prefix..], repeats, &mut out)?;
+    }
+    out.write_all(&[RS_OP_END])
+}
+
+/// The magic of v2 deltas if `repeats` is set, or else of v1 deltas.
+fn delta_magic(repeats: bool) -> u32 {
+    if repeats {
+        DELTA_V2_MAGIC
+    } else {
+        DELTA_MAGIC
+    }
+}
+
+/// Write `literal` to `out`, with the runs found by [find_repeat] as repeats if `repeats` is set.
+fn encode_literal(literal: &[u8], repeats: bool, out: &mut impl Write) -> io::Result<()> {
+    let mut start = 0;
     let mut here = 0;
+    while repeats && here + MIN_REPEAT_LEN <= literal.len() {
+        let Some((period, len)) = find_repeat(&literal[here..]) else {
+            here += 1;
+            continue;
+        };
+        if start < here {
+            encode_literal_header((here - start) as u64, out)?;
+            out.write_all(&literal[start..here])?;
+        }
+        let count = len / period;
+        encode_repeat(&literal[here..here + period], count as u64, out)?;
+        here += count * period;
+        start = here;
+    }
+    if start < literal.len() {
+        encode_literal_header((literal.len() - start) as u64, out)?;
+        out.write_all(&literal[start..])?;
+    }
+    Ok(())
+}
+
+/// The period and length of the run of a repeated pattern at the start of `data`, if it's at
+/// least [MIN_REPEAT_LEN] bytes long. The shortest period wins.
+fn find_repeat(data: &[u8]) -> Option<(usize, usize)> {
+    let window = data.get(..MIN_REPEAT_LEN)?;
+    (1..=MAX_REPEAT_PERIOD).find_map(|period| {
+        if window[period..] != window[..MIN_REPEAT_LEN - period] {
+            return None;
+        }
+        let len = period + data[period..].iter().zip(data).take_while(|(a, b)| a == b).count();
+        Some((period, len))
+    })
+}
+
+/// Calculate which parts of `data` differ from the base data represented by `signature`.
+///
+/// This runs the same matching as [diff()], but instead of a delta it returns the byte ranges of
+/// `data` that are not covered by copies from the base, in ascending order and with adjacent
+/// ranges merged. Tools that replicate in place (e.g. block devices) can use this to issue
+/// targeted writes of just those ranges.
+pub fn changed_ranges(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+) -> Result<Vec<Range<u64>>, DiffError> {
+    let mut delta = Vec::new();
+    diff(signature, data, &mut delta)?;
+
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    let mut position = 0;
+    let mut ops = OpReader::new(&delta).expect("diff produced an invalid delta");
+    while let Some(op) = ops.next_op().expect("diff produced an invalid delta") {
+        let len = op.output_len();
+        if !matches!(op, Op::Copy { .. }) {
+            let (start, end) = (position, position + len);
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+        }
+        position += len;
+    }
+    Ok(ranges)
+}
+
+/// How long the literals of a [DiffSession] grow before they are flushed.
+const SESSION_MAX_LITERAL: usize = 1 << 20;
+
+/// A delta calculation fed with the new data piece by piece, e.g. as it arrives from a socket.
+///
+/// Only the bytes that may still take part in a match are buffered: a few blocks of lookahead
+/// plus any pending literal, which is flushed once it grows past 1 MiB. The delta is written to
+/// `out` as it is found. It applies the same way as [diff()] over the concatenated input, though
+/// very long literals are split and [diff()] copies identical leading and trailing regions as a
+/// whole instead of searching them.
+///
+/// ```
+/// # use superfast_rsync::{apply, DiffSession, HashAlgorithm, Signature, SignatureOptions};
+/// # let options = SignatureOptions::new(16, 8, HashAlgorithm::Blake3);
+/// let base = b"the base data, which the receiver has".repeat(10);
+/// let signature = Signature::calculate(&base, options);
+/// let indexed = signature.index();
+/// let mut delta = vec![];
+/// let mut session = DiffSession::new(&indexed, &mut delta)?;
+/// for chunk in base.chunks(100) {
+///     session.push(chunk, &mut delta)?;
+/// }
+/// session.finish(&mut delta)?;
+///
+/// let mut out = vec![];
+/// apply(&base, &delta, &mut out)?;
+/// assert_eq!(out, base);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// # Security
+/// See [diff()].
+pub struct DiffSession<'a, 'b> {
+    matcher: Matcher<'a, 'b>,
+    window: Vec<u8>,
+    max_literal: usize,
+}
+
+impl<'a, 'b> DiffSession<'a, 'b> {
+    /// Start a delta against `signature`, writing the delta header to `out`.
+    pub fn new(
+        signature: &'a IndexedSignature<'b>,
+        out: &mut impl Write,
+    ) -> Result<Self, DiffError> {
+        Self::with_max_literal(signature, SESSION_MAX_LITERAL, out)
+    }
+
+    /// Like [DiffSession::new], flushing literals once they grow past `max_literal` bytes.
+    pub(crate) fn with_max_literal(
+        signature: &'a IndexedSignature<'b>,
+        max_literal: usize,
+        out: &mut impl Write,
+    ) -> Result<Self, DiffError> {
+        let matcher = Matcher::new(signature)?;
+        out.write_all(&DELTA_MAGIC.to_be_bytes())?;
+        Ok(DiffSession {
+            matcher,
+            window: Vec::new(),
+            max_literal: max_literal.max(1),
+        })
+    }
+
+    /// Feed the next piece of the new data, writing the part of the delta that is final to `out`.
+    pub fn push(&mut self, chunk: &[u8], out: &mut impl Write) -> Result<(), DiffError> {
+        self.window.extend_from_slice(chunk);
+        self.matcher.search(&self.window, out)?;
+        if self.matcher.here - self.matcher.state.emitted() >= self.max_literal {
+            self.matcher.flush(&self.window, out)?;
+        }
+        let consumed = self.matcher.consumed();
+        if consumed > 0 {
+            self.window.drain(..consumed);
+            self.matcher.rebase(consumed);
+        }
+        Ok(())
+    }
+
+    /// Write the rest of the delta to `out`.
+    pub fn finish(mut self, out: &mut impl Write) -> Result<(), DiffError> {
+        self.matcher.finish(&self.window, out)?;
+        Ok(())
+    }
+}
+
+/// How many blocks [diff_from_reader] reads at a time, and how long its literals grow before
+/// they are flushed.
+const READER_WINDOW_BLOCKS: usize = 4;
+
+/// Calculate a delta from `signature` to everything read from `reader`, writing it to `out`.
+///
+/// Only a sliding window of a few blocks of the new data is held in memory, so inputs can be far
+/// larger than RAM. The delta applies the same way as one produced by [diff()], though literals
+/// are split into ops of a few blocks each and identical leading and trailing regions are
+/// searched block by block instead of being copied as a whole. `reader` is read in pieces of a
+/// few blocks; wrap it in a [BufReader](std::io::BufReader) if small reads are expensive.
+///
+/// # Security
+/// See [diff()].
+pub fn diff_from_reader(
+    signature: &IndexedSignature<'_>,
+    mut reader: impl Read,
+    mut out: impl Write,
+) -> Result<(), DiffError> {
+    let window = READER_WINDOW_BLOCKS * signature.block_size as usize;
+    let mut session = DiffSession::with_max_literal(signature, window, &mut out)?;
+    let mut chunk = Vec::with_capacity(window);
+    loop {
+        chunk.clear();
+        (&mut reader).take(window as u64).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            return session.finish(&mut out);
+        }
+        session.push(&chunk, &mut out)?;
+    }
+}
+
+/// How many positions of the new data each task of [diff_parallel] searches.
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK_SIZE: usize = 1 << 20;
+
+/// A position of the new data whose CRC is in the signature, found by [find_hits].
+#[cfg(feature = "parallel")]
+#[derive(Clone, Copy, Debug)]
+struct Hit {
+    position: usize,
+    crc: Crc,
+    /// The block with the same strong hash, or `None` on a CRC collision, if it was looked up.
+    block: Option<Option<u32>>,
+}
+
+/// Find the positions in `positions` where a block of `data` has the CRC of a block of the base
+/// data, looking up their strong hashes.
+///
+/// Like [Matcher::search], this stops hashing the blocks of a CRC once it has collided
+/// `max_collisions` times, which bounds the hashing done on adversarial inputs. Those positions
+/// are still returned, and [Matcher::replay] looks them up itself if it gets there.
+#[cfg(feature = "parallel")]
+fn find_hits(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    positions: Range<usize>,
+    max_collisions: u32,
+) -> Result<Vec<Hit>, DiffError> {
+    let geometry = BlockGeometry::new(signature.block_size as usize);
+    let block_size = geometry.size;
+    let crypto_hash_size = signature.crypto_hash_size as usize;
+    let mut hits = Vec::new();
     let mut collisions: HashMap<Crc, u32, BuildCrcHasher> =
         HashMap::with_hasher(BuildCrcHasher::default());
-    while data.len() - here >= block_size as usize {
-        let mut crc = Crc::new().update(&data[here..here + block_size as usize]);
-        loop {
+    let Some(first) = data.get(positions.start..positions.start + block_size) else {
+        return Ok(hits);
+    };
+    let mut crc = Crc::new().update(first);
+    for position in positions.clone() {
+        if position > positions.start {
+            let (old, new) = (data[position - 1], data[position + block_size - 1]);
+            crc = geometry.rotate(crc, old, new);
+        }
+        let Some(blocks) = signature.blocks.get(&crc) else {
+            continue;
+        };
+        let count = collisions.entry(crc).or_insert(0);
+        let block = if *count < max_collisions {
+            let digest = crypto_hash(signature, &data[position..position + block_size])?;
+            let block = blocks.get(&digest[..crypto_hash_size]);
+            if block.is_none() {
+                *count += 1;
+            }
+            Some(block)
+        } else {
+            None
+        };
+        hits.push(Hit {
+            position,
+            crc,
+            block,
+        });
+    }
+    Ok(hits)
+}
+
+#[cfg(feature = "parallel")]
+impl Matcher<'_, '_> {
+    /// Match like [Matcher::search] with the default [DiffOptions], visiting only the positions
+    /// of `hits`, which must be in ascending order: every other position has a CRC that isn't in
+    /// the signature, so the search would roll past it.
+    ///
+    /// Matches, duplicate preferences and collision counts are decided here in order, exactly as
+    /// the search decides them, so the ops are the same.
+    fn replay(
+        &mut self,
+        data: &[u8],
+        hits: impl IntoIterator<Item = Hit>,
+        out: &mut impl Write,
+    ) -> Result<(), DiffError> {
+        let block_size = self.geometry.size;
+        for hit in hits {
+            let here = hit.position;
+            if here < self.here || data.len() - here < block_size {
+                continue;
+            }
             // if we detect too many CRC collisions, blacklist the CRC to avoid DoS
-            if collisions
-                .get(&crc)
-                .is_none_or(|&count| count < MAX_CRC_COLLISIONS)
+            if self
+                .collisions
+                .get(&hit.crc)
+                .is_some_and(|&count| count >= self.options.max_collisions)
             {
-                if let Some(blocks) = signature.blocks.get(&crc) {
-                    let digest = match signature.signature_type {
-                        SignatureType::Md4 => md4(&data[here..here + block_size as usize]).to_vec(),
-                        SignatureType::Blake3 => blake3(&data[here..here + block_size as usize]).to_vec(),
-                        SignatureType::Blake2 => return Err(DiffError::InvalidSignature), // Not implemented yet
+                continue;
+            }
+            let block = match hit.block {
+                Some(block) => block,
+                None => find_block(self.signature, hit.crc, &data[here..here + block_size])?,
+            };
+            match block {
+                Some(idx) => {
+                    let idx = if self.options.lookahead {
+                        self.prefer_run(data, here, idx)?
+                    } else {
+                        idx
                     };
-                    if let Some(&idx) = blocks.get(&&digest[..crypto_hash_size]) {
-                        // match found
-                        state.copy(
-                            idx as u64 * block_size as u64,
-                            block_size as usize,
-                            here,
-                            data,
-                            &mut out,
-                        )?;
-                        here += block_size as usize;
-                        break;
-                    }
-                    // CRC collision
-                    *collisions.entry(crc).or_insert(0) += 1;
+                    // The hits already hold the lookups prefer_run prefetches.
+                    self.prefetched.clear();
+                    self.matched(here, idx, data, out)?;
+                    self.run = Some((here + block_size, idx + 1));
+                    self.here = here + block_size;
                 }
+                None => *self.collisions.entry(hit.crc).or_insert(0) += 1,
             }
-            // no match, try to extend
-            here += 1;
-            if here + block_size as usize > data.len() {
-                break;
-            }
-            crc = crc.rotate(
-                block_size,
-                data[here - 1],
-                data[here + block_size as usize - 1],
-            );
         }
+        Ok(())
     }
-    state.emit(data.len(), data, &mut out)?;
-    out.write_all(&[RS_OP_END])?;
-    Ok(())
 }
 
 /// Calculate a delta using parallel processing and write it to `out`.
 /// This is a parallel version of the `diff` function that uses Rayon for
 /// multi-threaded block comparison.
 ///
+/// The new data is split into chunks that are searched at every byte offset on rayon's threads,
+/// each reading a block past its end so that blocks straddling two chunks are found. The matches
+/// are then chained from the start of the data by the same rules as [diff()], so the delta is
+/// byte-for-byte the one [diff()] writes for the same inputs, whatever the number of threads.
+///
 /// # Security
 /// Since `fast_rsync` uses the insecure MD4 hash algorithm, the resulting delta must not be
 /// trusted to correctly reconstruct `data`. The delta might fail to apply or produce the wrong
@@ -256,55 +1361,80 @@ pub fn diff_parallel(
     data: &[u8],
     mut out: impl Write,
 ) -> Result<(), DiffError> {
-    // MD4 is always sequential
-    if let SignatureType::Md4 = signature.signature_type {
+    // Only parallelize for Blake3, every other hash is sequential
+    if !matches!(signature.signature_type, SignatureType::Blake3) {
         return diff(signature, data, out);
     }
-    // Only parallelize for Blake3
-    let block_size = signature.block_size;
-    let crypto_hash_size = signature.crypto_hash_size as usize;
-    if let SignatureType::Blake3 = signature.signature_type {
-        if crypto_hash_size > BLAKE3_SIZE {
-            return Err(DiffError::InvalidSignature);
-        }
-    } else {
-        return Err(DiffError::InvalidSignature);
+    check_signature(signature)?;
+    let prefix = common_prefix(signature, data)?;
+    if prefix.append {
+        return Ok(append_delta(prefix.len, data, false, out)?);
     }
-    out.write_all(&DELTA_MAGIC.to_be_bytes())?;
-    let signature_arc = Arc::new(signature);
-    let block_size_usize = block_size as usize;
-    let blocks: Vec<_> = (0..data.len().saturating_sub(block_size_usize - 1))
-        .step_by(block_size_usize)
-        .collect();
-    let results: Vec<_> = blocks
+    let mut matcher = Matcher::new(signature)?;
+    let (suffix, suffix_offset) = common_suffix(signature, data, &prefix)?;
+    let end = data.len() - suffix;
+    let block_size = matcher.geometry.size;
+    // Positions from which a whole block fits before the common suffix.
+    let positions = prefix.len..(end + 1).saturating_sub(block_size).max(prefix.len);
+    let chunks: Vec<_> = positions.clone().step_by(PARALLEL_CHUNK_SIZE).collect();
+    let max_collisions = matcher.options.max_collisions;
+    let hits = chunks
         .par_iter()
         .map(|&start| {
-            let end = (start + block_size_usize).min(data.len());
-            let block_data = &data[start..end];
-            let crc = Crc::new().update(block_data);
-            if let Some(blocks) = signature_arc.blocks.get(&crc) {
-                let digest = blake3(block_data).to_vec();
-                if let Some(&idx) = blocks.get(&&digest[..crypto_hash_size]) {
-                    return Ok::<Option<(usize, u64, usize)>, DiffError>(Some((start, idx as u64 * block_size as u64, block_size_usize)));
-                }
-            }
-            Ok::<Option<(usize, u64, usize)>, DiffError>(None)
+            let chunk = start..(start + PARALLEL_CHUNK_SIZE).min(positions.end);
+            find_hits(signature, &data[..end], chunk, max_collisions)
         })
-        .collect();
-    let mut state = OutputState {
-        emitted: 0,
-        queued_copy: None,
-    };
-    for result in results {
-        match result? {
-            Some((start, offset, len)) => {
-                state.emit(start, data, &mut out)?;
-                state.copy(offset, len, start, data, &mut out)?;
+        .collect::<Result<Vec<_>, _>>()?;
+    out.write_all(&DELTA_MAGIC.to_be_bytes())?;
+    if prefix.len > 0 {
+        matcher.state.copy(0, prefix.len as u64, 0, data, &mut out)?;
+        matcher.here = prefix.len;
+    }
+    matcher.replay(&data[..end], hits.into_iter().flatten(), &mut out)?;
+    if suffix > 0 {
+        matcher.state.copy(suffix_offset, suffix as u64, end, data, &mut out)?;
+    }
+    matcher.finish(data, &mut out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockGeometry, OutputState};
+    use crate::consts::{RS_OP_COPY_N1_N1, RS_OP_LITERAL_1};
+
+    #[test]
+    fn output_state_tracks_offsets_beyond_4gib() {
+        // Chunked callers discard data as soon as it is encoded, so positions relative to the
+        // held data stay small while the absolute offsets grow past `u32::MAX`.
+        const BLOCK: u64 = 1 << 30;
+        let mut state = OutputState::with_observer(());
+        let mut out = vec![];
+        for i in 0..5 {
+            state.copy(i * BLOCK, BLOCK, 0, &[], &mut out).unwrap();
+            state.rebase(BLOCK as usize);
+        }
+        assert!(out.is_empty(), "adjacent copies should be merged");
+        state.emit(3, b"abc", &mut out).unwrap();
+        assert_eq!(state.emitted, 5 * BLOCK + 3);
+
+        let mut expected = vec![RS_OP_COPY_N1_N1 + 3, 0];
+        expected.extend_from_slice(&(5 * BLOCK).to_be_bytes());
+        expected.extend_from_slice(&[RS_OP_LITERAL_1 + 2, b'a', b'b', b'c']);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn block_geometry_matches_arithmetic() {
+        for size in [1, 3, 64, 100, 1 << 16, 1 << 20] {
+            let geometry = BlockGeometry::new(size);
+            assert_eq!(geometry.shift.is_some(), size.is_power_of_two());
+            for idx in [0, 1, 7, 1000] {
+                let offset = idx as u64 * size as u64;
+                assert_eq!(geometry.offset(idx), offset);
+                assert_eq!(geometry.index(offset), Some(idx as usize));
+                assert_eq!(geometry.index(offset + 1), (size == 1).then_some(idx as usize + 1));
             }
-            None => {}
         }
     }
-    state.emit(data.len(), data, &mut out)?;
-    out.write_all(&[RS_OP_END])?;
-    Ok(())
 }
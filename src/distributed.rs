@@ -0,0 +1,351 @@
+//! Diffing huge datasets across machines.
+//!
+//! The coordinator [plan]s the work, which splits the signature of the base data into shards
+//! and pairs every shard with the region of the new data at the same offsets. Each [Task] is
+//! serialized and shipped to a worker, which reads its region of the new data and calls
+//! [run_task]. The coordinator collects the [TaskResult]s and calls [merge], which checks that
+//! every task was answered with a delta of the right length before concatenating them into one
+//! delta.
+//!
+//! Workers only match blocks of their own shard, so data that moved further than a shard ends up
+//! as literals. Larger shards find more of it, at the cost of shipping more of the signature.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use crate::concat::{concat_deltas, ChunkDelta, ConcatError};
+use crate::diff::DiffError;
+use crate::patch::{ApplyError, Op, OpReader};
+use crate::shard::{split, SignatureShard};
+use crate::signature::Signature;
+use crate::strict::require;
+
+/// Indicates that distributed diffing failed.
+#[derive(Debug)]
+pub enum DistributedError {
+    /// A serialized task or result was truncated or invalid.
+    Invalid,
+    /// A task of the plan had no result.
+    MissingResult {
+        /// The id of the task.
+        task: u64,
+    },
+    /// A result didn't belong to any task of the plan, or a task had several results.
+    UnexpectedResult {
+        /// The id of the task the result claims to belong to.
+        task: u64,
+    },
+    /// A result contained an invalid delta.
+    InvalidDelta {
+        /// The id of the task.
+        task: u64,
+        /// Why the delta is invalid.
+        source: ApplyError,
+    },
+    /// A result reconstructed a different amount of data than its task's region.
+    LengthMismatch {
+        /// The id of the task.
+        task: u64,
+        /// The length of the region.
+        expected: u64,
+        /// The length of the output of the delta.
+        actual: u64,
+    },
+    /// A result copied data from outside the base data.
+    CopyOutOfBounds {
+        /// The id of the task.
+        task: u64,
+        /// The copy offset.
+        offset: u64,
+        /// The copy length.
+        len: u64,
+    },
+    /// The results couldn't be concatenated.
+    Concat(ConcatError),
+}
+
+impl fmt::Display for DistributedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DistributedError::Invalid => f.write_str("invalid serialized task or result"),
+            DistributedError::MissingResult { task } => {
+                write!(f, "task has no result (task={task})")
+            }
+            DistributedError::UnexpectedResult { task } => {
+                write!(f, "unexpected result (task={task})")
+            }
+            DistributedError::InvalidDelta { task, source } => {
+                write!(f, "result contains an invalid delta (task={task}, source={source})")
+            }
+            DistributedError::LengthMismatch {
+                task,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "result has the wrong length (task={task}, expected={expected}, actual={actual})",
+            ),
+            DistributedError::CopyOutOfBounds { task, offset, len } => write!(
+                f,
+                "result copies from outside the base data (task={task}, offset={offset}, len={len})",
+            ),
+            DistributedError::Concat(source) => {
+                write!(f, "couldn't concatenate results (source={source})")
+            }
+        }
+    }
+}
+
+impl Error for DistributedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DistributedError::InvalidDelta { source, .. } => Some(source),
+            DistributedError::Concat(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// A unit of work for a worker: diff a region of the new data against a shard of the signature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Task {
+    /// Identifies the task among the tasks of a plan.
+    pub id: u64,
+    /// The region of the new data to diff.
+    pub target_range: Range<u64>,
+    /// The shard of the signature to diff against.
+    pub shard: SignatureShard,
+}
+
+/// The delta a worker calculated for a [Task].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskResult {
+    /// The id of the task.
+    pub id: u64,
+    /// The delta, with copies referring to offsets in the whole base data.
+    pub delta: Vec<u8>,
+}
+
+/// Plan diffing `target_len` bytes of new data against `signature`, with shards of
+/// `blocks_per_shard` blocks.
+///
+/// Every shard is paired with the region of the new data at the same offsets as its blocks in the
+/// base data; the last region extends to the end of the new data. The regions cover the new data
+/// without gaps, though some may be empty if the new data is shorter than the base data.
+///
+/// # Panics
+/// Panics if `blocks_per_shard` is zero, unless the `strict` feature makes it 1.
+pub fn plan(signature: &Signature, target_len: u64, blocks_per_shard: usize) -> Vec<Task> {
+    let shards = split(signature, blocks_per_shard);
+    let starts: Vec<u64> = shards
+        .iter()
+        .map(|shard| shard.base_offset().min(target_len))
+        .collect();
+    shards
+        .into_iter()
+        .enumerate()
+        .map(|(i, shard)| Task {
+            id: i as u64,
+            target_range: starts[i]..starts.get(i + 1).copied().unwrap_or(target_len),
+            shard,
+        })
+        .collect()
+}
+
+/// Calculate the delta for `task` from `region`, the bytes of the new data in its target range.
+///
+/// # Panics
+/// Panics if `region` isn't as long as the target range, unless the `strict` feature turns that
+/// into [DiffError::InvalidArgument].
+///
+/// # Security
+/// See [diff()](crate::diff()).
+pub fn run_task(task: &Task, region: &[u8]) -> Result<TaskResult, DiffError> {
+    require!(
+        region.len() as u64 == task.target_range.end - task.target_range.start,
+        DiffError::InvalidArgument { name: "region" }
+    );
+    let mut delta = Vec::new();
+    task.shard.diff(region, &mut delta)?;
+    Ok(TaskResult { id: task.id, delta })
+}
+
+/// Check the `results` of the tasks of a plan and concatenate them into one delta.
+///
+/// Every task must have exactly one result, whose delta reconstructs as many bytes as the task's
+/// region and only copies from the base data described by the tasks' signature. This catches
+/// lost, duplicated and mixed-up results, but not workers that diffed the wrong data: validate the
+/// reconstructed data as described in [diff()](crate::diff()).
+pub fn merge(tasks: &[Task], results: &[TaskResult]) -> Result<Vec<u8>, DistributedError> {
+    let mut chunks: Vec<Option<ChunkDelta>> = vec![None; tasks.len()];
+    for result in results {
+        let task = tasks
+            .iter()
+            .position(|task| task.id == result.id)
+            .filter(|&i| chunks[i].is_none())
+            .ok_or(DistributedError::UnexpectedResult { task: result.id })?;
+        validate(&tasks[task], result)?;
+        chunks[task] = Some(ChunkDelta {
+            start: tasks[task].target_range.start,
+            delta: result.delta.clone(),
+        });
+    }
+    let chunks = chunks
+        .into_iter()
+        .zip(tasks)
+        .map(|(chunk, task)| chunk.ok_or(DistributedError::MissingResult { task: task.id }))
+        .collect::<Result<Vec<_>, _>>()?;
+    concat_deltas(&chunks).map_err(DistributedError::Concat)
+}
+
+/// Check that `result` reconstructs the region of `task` from the base data.
+fn validate(task: &Task, result: &TaskResult) -> Result<(), DistributedError> {
+    let invalid = |source| DistributedError::InvalidDelta {
+        task: task.id,
+        source,
+    };
+    let base_len = task.shard.total_blocks * task.shard.signature.block_size() as u64;
+    let mut ops = OpReader::new(&result.delta).map_err(invalid)?;
+    let mut len = 0u64;
+    while let Some(op) = ops.next_op().map_err(invalid)? {
+        if let Op::Copy { offset, len } = op {
+            if offset.checked_add(len).is_none_or(|end| end > base_len) {
+                return Err(DistributedError::CopyOutOfBounds {
+                    task: task.id,
+                    offset,
+                    len,
+                });
+            }
+        }
+        len = len.saturating_add(op.output_len());
+    }
+    let expected = task.target_range.end - task.target_range.start;
+    if len != expected {
+        return Err(DistributedError::LengthMismatch {
+            task: task.id,
+            expected,
+            actual: len,
+        });
+    }
+    Ok(())
+}
+
+/// Take `n` bytes from the front of `input`.
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], DistributedError> {
+    if input.len() < n {
+        return Err(DistributedError::Invalid);
+    }
+    let (taken, rest) = input.split_at(n);
+    *input = rest;
+    Ok(taken)
+}
+
+fn take_u64(input: &mut &[u8]) -> Result<u64, DistributedError> {
+    Ok(u64::from_be_bytes(take(input, 8)?.try_into().unwrap()))
+}
+
+impl Task {
+    /// Serialize the task for shipping to a worker.
+    ///
+    /// Layout: the id and the start and end of the target range as big-endian u64s, then the
+    /// serialized shard.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&self.target_range.start.to_be_bytes());
+        out.extend_from_slice(&self.target_range.end.to_be_bytes());
+        out.extend_from_slice(&self.shard.serialize());
+        out
+    }
+
+    /// Parse a task produced by [Task::serialize].
+    pub fn deserialize(task: &[u8]) -> Result<Self, DistributedError> {
+        let mut input = task;
+        let id = take_u64(&mut input)?;
+        let start = take_u64(&mut input)?;
+        let end = take_u64(&mut input)?;
+        if start > end {
+            return Err(DistributedError::Invalid);
+        }
+        let shard =
+            SignatureShard::deserialize(input.to_vec()).map_err(|_| DistributedError::Invalid)?;
+        Ok(Task {
+            id,
+            target_range: start..end,
+            shard,
+        })
+    }
+}
+
+impl TaskResult {
+    /// Serialize the result for sending back to the coordinator.
+    ///
+    /// Layout: the id as a big-endian u64, then the delta.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.delta.len());
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&self.delta);
+        out
+    }
+
+    /// Parse a result produced by [TaskResult::serialize].
+    pub fn deserialize(result: &[u8]) -> Result<Self, DistributedError> {
+        let mut input = result;
+        let id = take_u64(&mut input)?;
+        Ok(TaskResult {
+            id,
+            delta: input.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{merge, plan, run_task, DistributedError, Task, TaskResult};
+    use crate::{apply, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_distributed() {
+        let mut base = vec![0; 50_000];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut data = base.clone();
+        data[12_345..12_400].fill(1);
+        data.extend_from_slice(b"appended");
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(100, 8, HashAlgorithm::Blake3),
+        );
+        let tasks = plan(&signature, data.len() as u64, 128);
+        assert_eq!(tasks.len(), 4);
+        assert_eq!(tasks[3].target_range, 38_400..50_008);
+
+        let mut results: Vec<_> = tasks
+            .iter()
+            .rev()
+            .map(|task| {
+                let task = Task::deserialize(&task.serialize()).unwrap();
+                let range = task.target_range.start as usize..task.target_range.end as usize;
+                let result = run_task(&task, &data[range]).unwrap();
+                TaskResult::deserialize(&result.serialize()).unwrap()
+            })
+            .collect();
+        let delta = merge(&tasks, &results).unwrap();
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        results[0].id = 2;
+        assert!(matches!(
+            merge(&tasks, &results),
+            Err(DistributedError::LengthMismatch { task: 2, .. })
+        ));
+        results.remove(0);
+        assert!(matches!(
+            merge(&tasks, &results),
+            Err(DistributedError::MissingResult { task: 3 })
+        ));
+    }
+}
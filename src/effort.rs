@@ -0,0 +1,133 @@
+//! A single knob trading CPU time for delta size.
+
+use std::io::Write;
+
+use crate::diff::{diff_with_anchors, DiffError, DiffOptions};
+use crate::optimal::{diff_optimal, OPTIMAL_MAX_LEN};
+use crate::signature::IndexedSignature;
+
+/// How hard to try to make deltas small, from 1 (fastest) to 9 (smallest), like the levels of a
+/// compressor.
+///
+/// - 1: the first block found at every position.
+/// - 2-3: also look ahead among duplicate blocks, like [diff()](crate::diff()).
+/// - 4-7: also anchor relocated regions, more densely at higher levels.
+/// - 8-9: the smallest delta by [diff_optimal](crate::diff_optimal()) for inputs up to
+///   [OPTIMAL_MAX_LEN] bytes, and level 7 for larger ones.
+///
+/// [Effort::zstd_level] maps the level onto zstd for callers that compress signatures or
+/// deltas.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Effort(u8);
+
+impl Effort {
+    /// The fastest level.
+    pub const FASTEST: Effort = Effort(1);
+    /// The level of [diff()](crate::diff()).
+    pub const DEFAULT: Effort = Effort(3);
+    /// The level that makes the smallest deltas.
+    pub const SMALLEST: Effort = Effort(9);
+
+    /// The effort at `level`, clamped to 1-9.
+    pub fn new(level: u8) -> Self {
+        Effort(level.clamp(1, 9))
+    }
+
+    /// The level, from 1 to 9.
+    pub fn level(self) -> u8 {
+        self.0
+    }
+
+    /// Whether the matcher picks, among duplicate blocks, one that continues a copy run.
+    pub fn lookahead(self) -> bool {
+        self.0 >= 2
+    }
+
+    /// How many blocks apart relocated regions are anchored, if at all.
+    pub fn anchor_spacing_blocks(self) -> Option<usize> {
+        match self.0 {
+            1..=3 => None,
+            4 => Some(256),
+            5 => Some(128),
+            6 => Some(64),
+            _ => Some(16),
+        }
+    }
+
+    /// Whether inputs up to [OPTIMAL_MAX_LEN] get the smallest delta by dynamic programming.
+    pub fn optimize(self) -> bool {
+        self.0 >= 8
+    }
+
+    /// The zstd compression level of the same effort.
+    pub fn zstd_level(self) -> i32 {
+        [1, 2, 3, 5, 7, 9, 12, 15, 19][self.0 as usize - 1]
+    }
+}
+
+impl Default for Effort {
+    fn default() -> Self {
+        Effort::DEFAULT
+    }
+}
+
+/// Calculate a delta with the heuristics of `effort`, and write it to `out`.
+///
+/// # Security
+/// See [diff()](crate::diff()).
+pub fn diff_with_effort(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    effort: Effort,
+    out: impl Write,
+) -> Result<(), DiffError> {
+    if effort.optimize() && data.len() <= OPTIMAL_MAX_LEN {
+        return diff_optimal(signature, data, out);
+    }
+    let anchor_spacing = effort
+        .anchor_spacing_blocks()
+        .map(|blocks| blocks * signature.block_size as usize);
+    let options = DiffOptions {
+        lookahead: effort.lookahead(),
+        ..DiffOptions::default()
+    };
+    diff_with_anchors(signature, data, anchor_spacing, &options, false, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{diff_with_effort, Effort};
+    use crate::{apply, diff, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_effort() {
+        let mut base = vec![0; 32 * 1024];
+        for block in base.chunks_mut(128) {
+            rand::thread_rng().fill(&mut block[..64]);
+        }
+        let mut data = base[10_000..].to_vec();
+        data.extend_from_slice(&base[..10_000]);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(64, 8, HashAlgorithm::Blake3),
+        );
+        let indexed = signature.index();
+        let mut sizes = vec![];
+        for level in 0..=10 {
+            let mut delta = vec![];
+            diff_with_effort(&indexed, &data, Effort::new(level), &mut delta).unwrap();
+            let mut out = vec![];
+            apply(&base, &delta, &mut out).unwrap();
+            assert_eq!(out, data);
+            sizes.push(delta.len());
+        }
+        let mut plain = vec![];
+        diff(&indexed, &data, &mut plain).unwrap();
+        assert_eq!(sizes[Effort::DEFAULT.level() as usize], plain.len());
+        assert!(sizes[9] <= sizes[1], "{sizes:?}");
+        assert_eq!(Effort::new(0), Effort::FASTEST);
+        assert_eq!(Effort::new(10), Effort::SMALLEST);
+    }
+}
@@ -0,0 +1,120 @@
+//! Path-based helpers, and opening files for them with platform-specific access hints.
+//!
+//! The helpers read their inputs front to back exactly once, which is worth telling the OS about:
+//! on Windows the cache manager otherwise keeps its default, much smaller, read-ahead and lets
+//! pages of a multi-GB input evict everything else from the standby list.
+//!
+//! Long paths need no special handling here: the standard library already switches to verbatim
+//! (`\\?\`) paths on Windows when a path exceeds `MAX_PATH`.
+//!
+//! `FILE_FLAG_SEQUENTIAL_SCAN` is the only flag set. Unbuffered (`FILE_FLAG_NO_BUFFERING`) and
+//! overlapped (`FILE_FLAG_OVERLAPPED`) handles aren't offered: the first requires every read to be
+//! sector-aligned in offset, length and buffer address, and the second makes a handle unfit for
+//! the synchronous reads of [File], neither of which the helpers can honor. Callers that want them
+//! can open and read the file themselves and pass the data to the slice-based APIs.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::patch::{apply, ApplyError, Op, OpReader};
+
+#[cfg(windows)]
+mod flags {
+    /// `FILE_FLAG_SEQUENTIAL_SCAN`: enable aggressive read-ahead and early reuse of cached pages.
+    pub const FILE_FLAG_SEQUENTIAL_SCAN: u32 = 0x0800_0000;
+}
+
+/// Open `path` for a single sequential pass.
+pub(crate) fn open_sequential(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        options.custom_flags(flags::FILE_FLAG_SEQUENTIAL_SCAN);
+    }
+    options.open(path)
+}
+
+/// Apply `delta` to the file at `base_path`, writing the result to the file at `out_path`.
+///
+/// If the delta is a single copy of the entire basis (i.e. the file is unchanged), the output is
+/// produced with [fs::copy] instead of being streamed through memory. That clones the file on
+/// copy-on-write filesystems: `clonefile` on APFS, and `copy_file_range` (which reflinks on
+/// Btrfs/XFS and copies server-side on NFS) on Linux. If `base_path` and `out_path` are the same
+/// file, there is nothing to write at all.
+pub fn apply_file(
+    base_path: impl AsRef<Path>,
+    delta: &[u8],
+    out_path: impl AsRef<Path>,
+) -> Result<(), ApplyError> {
+    let base_path = base_path.as_ref();
+    let out_path = out_path.as_ref();
+    if let Some(len) = whole_file_copy(delta) {
+        if fs::metadata(base_path)?.len() == len {
+            // `fs::copy` of a file onto itself truncates it before reading it.
+            if !same_file(base_path, out_path)? {
+                fs::copy(base_path, out_path)?;
+            }
+            return Ok(());
+        }
+    }
+    let base = fs::read(base_path)?;
+    let mut out = BufWriter::new(File::create(out_path)?);
+    apply(&base, delta, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Whether `a` and `b` name the same existing file, through links or otherwise.
+pub(crate) fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    let (a_metadata, b_metadata) = match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(err), _) => return Err(err),
+        (_, Err(err)) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        (_, Err(err)) => return Err(err),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(a_metadata.dev() == b_metadata.dev() && a_metadata.ino() == b_metadata.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(a_metadata.len() == b_metadata.len() && fs::canonicalize(a)? == fs::canonicalize(b)?)
+    }
+}
+
+/// If `delta` consists of exactly one copy starting at offset zero, return its length.
+pub(crate) fn whole_file_copy(delta: &[u8]) -> Option<u64> {
+    let mut ops = OpReader::new(delta).ok()?;
+    match (ops.next_op().ok()?, ops.next_op().ok()?) {
+        (Some(Op::Copy { offset: 0, len }), None) => Some(len),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::whole_file_copy;
+    use crate::consts::{RS_OP_COPY_N1_N1, RS_OP_LITERAL_1};
+
+    #[test]
+    fn test_whole_file_copy() {
+        let magic = [0x72, 0x73, 0x02, 0x36];
+        let delta = |ops: &[u8]| [&magic[..], ops].concat();
+        // copy(0, 0x1234) with a 1-byte offset and a 2-byte length, then end
+        assert_eq!(
+            whole_file_copy(&delta(&[RS_OP_COPY_N1_N1 + 1, 0, 0x12, 0x34, 0])),
+            Some(0x1234)
+        );
+        assert_eq!(whole_file_copy(&delta(&[RS_OP_COPY_N1_N1, 1, 10, 0])), None);
+        assert_eq!(
+            whole_file_copy(&delta(&[RS_OP_COPY_N1_N1, 0, 10, RS_OP_LITERAL_1, b'x', 0])),
+            None
+        );
+        assert_eq!(whole_file_copy(&delta(&[0])), None);
+        assert_eq!(whole_file_copy(&[RS_OP_COPY_N1_N1, 0, 10, 0]), None);
+    }
+}
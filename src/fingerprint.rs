@@ -0,0 +1,229 @@
+//! Fingerprints of the base data, which catch deltas applied to the wrong base.
+//!
+//! A delta applied to different base data than its signature was calculated from usually applies
+//! without error and produces garbage. [diff_fingerprinted] prefixes the delta with
+//! [FINGERPRINTED_DELTA_MAGIC], the block size of the signature and a 64-bit fingerprint of the
+//! CRCs of its blocks. [apply()](crate::apply()) computes the CRCs of the base data it is given
+//! and fails with [ApplyError::WrongBasis] before writing anything if the fingerprints differ.
+//!
+//! CRCs are much cheaper to compute than the strong hashes of a signature, but they only catch
+//! mistakes, not base data crafted to match: validate the output as described in
+//! [diff()](crate::diff()).
+
+use std::io::{self, Read, Write};
+
+use crate::consts::{
+    FINGERPRINTED_DELTA_HEADER_SIZE, FINGERPRINTED_DELTA_MAGIC, MIN_FINGERPRINT_BLOCK_SIZE,
+};
+use crate::crc::Crc;
+use crate::diff::{diff, DiffError};
+use crate::patch::ApplyError;
+use crate::signature::IndexedSignature;
+
+/// The fingerprint of the base data `signature` was calculated from.
+pub fn signature_fingerprint(signature: &IndexedSignature<'_>) -> u64 {
+    let entry_size = Crc::SIZE + signature.crypto_hash_size as usize;
+    let crcs = signature
+        .entries
+        .chunks_exact(entry_size)
+        .map(|entry| &entry[..Crc::SIZE]);
+    fingerprint(signature.block_size, crcs)
+}
+
+/// The fingerprint of `base` with blocks of `block_size` bytes, which is that of its signature.
+pub fn basis_fingerprint(base: &[u8], block_size: u32) -> u64 {
+    // A block size of zero can't come from a signature; it fingerprints no blocks at all.
+    if block_size == 0 {
+        return fingerprint(0, std::iter::empty::<[u8; Crc::SIZE]>());
+    }
+    let crcs = base
+        .chunks(block_size as usize)
+        .map(|block| Crc::new().update(block).to_bytes());
+    fingerprint(block_size, crcs)
+}
+
+/// The fingerprint of the base data read from `base` with blocks of `block_size` bytes, reading
+/// it through a single 64 KiB buffer.
+pub(crate) fn read_fingerprint(mut base: impl Read, block_size: u32) -> io::Result<u64> {
+    let mut hasher = fingerprint_hasher(block_size);
+    // A block size of zero can't come from a signature; it fingerprints no blocks at all.
+    if block_size == 0 {
+        return Ok(finish(hasher));
+    }
+    let mut buf = vec![0; 64 * 1024];
+    let (mut crc, mut filled) = (Crc::new(), 0);
+    loop {
+        let mut chunk = match base.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => &buf[..n],
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        while !chunk.is_empty() {
+            let (head, tail) = chunk.split_at(chunk.len().min((block_size - filled) as usize));
+            crc = crc.update(head);
+            filled += head.len() as u32;
+            if filled == block_size {
+                hasher.update(&crc.to_bytes());
+                (crc, filled) = (Crc::new(), 0);
+            }
+            chunk = tail;
+        }
+    }
+    if filled > 0 {
+        hasher.update(&crc.to_bytes());
+    }
+    Ok(finish(hasher))
+}
+
+fn fingerprint(block_size: u32, crcs: impl Iterator<Item = impl AsRef<[u8]>>) -> u64 {
+    let mut hasher = fingerprint_hasher(block_size);
+    for crc in crcs {
+        hasher.update(crc.as_ref());
+    }
+    finish(hasher)
+}
+
+/// A hasher for the CRCs of blocks of `block_size` bytes.
+fn fingerprint_hasher(block_size: u32) -> ::blake3::Hasher {
+    let mut hasher = ::blake3::Hasher::new();
+    hasher.update(&block_size.to_be_bytes());
+    hasher
+}
+
+fn finish(hasher: ::blake3::Hasher) -> u64 {
+    u64::from_be_bytes(hasher.finalize().as_bytes()[..8].try_into().unwrap())
+}
+
+/// Calculate a delta like [diff()](crate::diff()), prefixed with the fingerprint of the base data
+/// of `signature`.
+///
+/// Signatures with blocks smaller than [MIN_FINGERPRINT_BLOCK_SIZE], which
+/// [apply()](crate::apply()) refuses to fingerprint, are rejected with
+/// [DiffError::InvalidSignature].
+///
+/// # Security
+/// See [diff()](crate::diff()).
+pub fn diff_fingerprinted(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    mut out: impl Write,
+) -> Result<(), DiffError> {
+    if signature.block_size < MIN_FINGERPRINT_BLOCK_SIZE {
+        return Err(DiffError::InvalidSignature);
+    }
+    out.write_all(&FINGERPRINTED_DELTA_MAGIC.to_be_bytes())?;
+    out.write_all(&signature.block_size.to_be_bytes())?;
+    out.write_all(&signature_fingerprint(signature).to_be_bytes())?;
+    diff(signature, data, out)
+}
+
+/// The delta inside `delta` if it is fingerprinted and the fingerprint matches `base`, or else
+/// `delta` itself. A block size below [MIN_FINGERPRINT_BLOCK_SIZE] fails with
+/// [ApplyError::InvalidFingerprint] before `base` is read.
+pub(crate) fn check_fingerprint<'a>(
+    delta: &'a [u8],
+    base: &[u8],
+) -> Result<&'a [u8], ApplyError> {
+    check_fingerprint_with(delta, |block_size| Ok(basis_fingerprint(base, block_size)))
+}
+
+/// Like [check_fingerprint], with the fingerprint of the base data for a block size computed by
+/// `base_fingerprint`, which is only called for fingerprinted deltas.
+pub(crate) fn check_fingerprint_with(
+    delta: &[u8],
+    base_fingerprint: impl FnOnce(u32) -> Result<u64, ApplyError>,
+) -> Result<&[u8], ApplyError> {
+    if !delta.starts_with(&FINGERPRINTED_DELTA_MAGIC.to_be_bytes()) {
+        return Ok(delta);
+    }
+    let Some(header) = delta.get(4..FINGERPRINTED_DELTA_HEADER_SIZE) else {
+        return Err(ApplyError::UnexpectedEof {
+            reading: "fingerprint",
+            expected: FINGERPRINTED_DELTA_HEADER_SIZE,
+            available: delta.len(),
+        });
+    };
+    let block_size = u32::from_be_bytes(header[..4].try_into().unwrap());
+    if block_size < MIN_FINGERPRINT_BLOCK_SIZE {
+        return Err(ApplyError::InvalidFingerprint { block_size });
+    }
+    let expected = u64::from_be_bytes(header[4..].try_into().unwrap());
+    let actual = base_fingerprint(block_size)?;
+    if actual != expected {
+        return Err(ApplyError::WrongBasis { expected, actual });
+    }
+    Ok(&delta[FINGERPRINTED_DELTA_HEADER_SIZE..])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{basis_fingerprint, diff_fingerprinted, read_fingerprint, signature_fingerprint};
+    use crate::{apply, ApplyError, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_fingerprint() {
+        let base = b"the base data the signature was calculated from".repeat(20);
+        let data = [&base[..500], b"changed", &base[500..]].concat();
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(64, 8, HashAlgorithm::Md4),
+        );
+        let indexed = signature.index();
+        assert_eq!(signature_fingerprint(&indexed), basis_fingerprint(&base, 64));
+        assert_ne!(basis_fingerprint(&base, 64), basis_fingerprint(&base, 32));
+
+        let mut delta = vec![];
+        diff_fingerprinted(&indexed, &data, &mut delta).unwrap();
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let mut out = vec![];
+        let wrong = &base[1..];
+        assert!(matches!(
+            apply(wrong, &delta, &mut out),
+            Err(ApplyError::WrongBasis { .. })
+        ));
+        assert!(out.is_empty());
+        assert!(matches!(
+            apply(&base, &delta[..10], &mut out),
+            Err(ApplyError::UnexpectedEof { .. })
+        ));
+
+        // Block sizes no signature should be fingerprinted with are rejected up front.
+        for block_size in [0u32, 1, 15] {
+            let mut tiny = delta.clone();
+            tiny[4..8].copy_from_slice(&block_size.to_be_bytes());
+            assert!(matches!(
+                apply(&base, &tiny, &mut out),
+                Err(ApplyError::InvalidFingerprint { block_size: b }) if b == block_size
+            ));
+        }
+        let small = Signature::calculate(&base, SignatureOptions::new(8, 8, HashAlgorithm::Md4));
+        assert!(diff_fingerprinted(&small.index(), &data, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_read_fingerprint() {
+        /// Reads at most 7 bytes at a time.
+        struct Trickle<'a>(&'a [u8]);
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(7);
+                self.0.read(&mut buf[..len])
+            }
+        }
+
+        let base: Vec<u8> = (0..200_001u32).map(|i| (i * 31 % 241) as u8).collect();
+        for block_size in [16, 1000, 65_536, 70_000, 300_000] {
+            let expected = basis_fingerprint(&base, block_size);
+            assert_eq!(read_fingerprint(&base[..], block_size).unwrap(), expected);
+            let trickled = read_fingerprint(Trickle(&base[..5000]), block_size).unwrap();
+            assert_eq!(trickled, basis_fingerprint(&base[..5000], block_size));
+        }
+    }
+}
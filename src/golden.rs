@@ -0,0 +1,159 @@
+//! Canonical signatures and deltas, for checking that other implementations of the formats, and
+//! future versions of this crate, are byte-for-byte compatible with this one.
+//!
+//! Every vector is calculated from [BASE] and [DATA] with [BLOCK_SIZE] and [CRYPTO_HASH_SIZE].
+//! [DATA] is [BASE] with a few bytes overwritten, a run of 40 dashes inserted and a tail appended,
+//! so the deltas have copies, literals and, in v2, a repeat. Deltas don't depend on the hash
+//! algorithm of the signature they were calculated against, so there is one per format.
+//!
+//! zstd output isn't stable across zstd versions, so compressed signatures and containers have no
+//! vectors; the compact signatures here store delta-encoded CRCs without zstd.
+//!
+//! [VECTORS] lists them all by name, e.g. for writing them to files:
+//!
+//! ```
+//! for (name, bytes) in superfast_rsync::golden::VECTORS {
+//!     assert!(!bytes.is_empty(), "{name}");
+//! }
+//! ```
+
+use crate::{HashAlgorithm, SignatureOptions};
+
+/// The base data.
+pub const BASE: &[u8] = b"\
+superfast_rsync golden vectors. This base data spans a few blocks of thirty-two bytes, and every \
+block is different, so that each of them can be matched on its own. A signature lists the CRC \
+and a truncated strong hash of every block!";
+
+/// The new data.
+pub const DATA: &[u8] = b"\
+superfast_rsync golden vectors. This base data spans a few blockEDIT thirty-two bytes, and every \
+block is different, so that each of them can be matched on its \
+----------------------------------------own. A signature lists the CRC and a truncated strong \
+hash of every block! And a tail.";
+
+/// The block size of all signatures.
+pub const BLOCK_SIZE: u32 = 32;
+
+/// The size of the strong hashes in all signatures.
+pub const CRYPTO_HASH_SIZE: u32 = 8;
+
+/// The options the signatures of `hash_algorithm` were calculated with.
+pub fn options(hash_algorithm: HashAlgorithm) -> SignatureOptions {
+    SignatureOptions::new(BLOCK_SIZE, CRYPTO_HASH_SIZE, hash_algorithm)
+}
+
+/// The MD4 signature of [BASE].
+pub const SIGNATURE_MD4: &[u8] = &[
+    0x72, 0x73, 0x01, 0x36, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x08, 0x17, 0xd9, 0x10, 0x58,
+    0x2c, 0x6b, 0x58, 0x84, 0x7e, 0x00, 0x00, 0x01, 0xfa, 0x9f, 0x0f, 0x40, 0x60, 0x40, 0x6b, 0x73,
+    0x95, 0x87, 0x24, 0x53, 0x00, 0x3d, 0x0f, 0xa4, 0x09, 0x9a, 0x56, 0xbd, 0x9c, 0x1f, 0x10, 0x7a,
+    0xf8, 0xcd, 0x0f, 0x26, 0x77, 0xab, 0x95, 0x62, 0x74, 0xfd, 0xb7, 0xc5, 0xf3, 0x13, 0x0e, 0xc7,
+    0xc4, 0x9e, 0x27, 0xbb, 0x29, 0xed, 0x8c, 0x06, 0xfa, 0x85, 0x0e, 0xde, 0xc1, 0x65, 0xe3, 0x84,
+    0xda, 0x37, 0x1b, 0xb1, 0x01, 0x5e, 0x0f, 0x8e, 0xf1, 0xd8, 0xde, 0xe5, 0x16, 0x08, 0x85, 0x23,
+    0x1b, 0x89, 0x04, 0xd2, 0x01, 0x9d, 0x6d, 0x17, 0xec, 0x53, 0x0f, 0x20,
+];
+
+/// [SIGNATURE_MD4] in the compact form, with delta-encoded CRCs.
+pub const SIGNATURE_MD4_COMPACT: &[u8] = &[
+    0x72, 0x73, 0x01, 0x39, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x6c, 0x72, 0x73, 0x01,
+    0x36, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x08, 0x17, 0xd9, 0x10, 0x58, 0xe2, 0xc5, 0xfe,
+    0xe8, 0x05, 0x9e, 0x00, 0x64, 0xf8, 0x8f, 0xff, 0x82, 0xfa, 0x45, 0xff, 0xa1, 0x07, 0x72, 0x00,
+    0x17, 0x06, 0xd9, 0x00, 0xb0, 0x1a, 0x2a, 0xf5, 0x44, 0x2c, 0x6b, 0x58, 0x84, 0x7e, 0x00, 0x00,
+    0x01, 0x60, 0x40, 0x6b, 0x73, 0x95, 0x87, 0x24, 0x53, 0x09, 0x9a, 0x56, 0xbd, 0x9c, 0x1f, 0x10,
+    0x7a, 0x77, 0xab, 0x95, 0x62, 0x74, 0xfd, 0xb7, 0xc5, 0xc4, 0x9e, 0x27, 0xbb, 0x29, 0xed, 0x8c,
+    0x06, 0xc1, 0x65, 0xe3, 0x84, 0xda, 0x37, 0x1b, 0xb1, 0xf1, 0xd8, 0xde, 0xe5, 0x16, 0x08, 0x85,
+    0x23, 0x01, 0x9d, 0x6d, 0x17, 0xec, 0x53, 0x0f, 0x20,
+];
+
+/// The BLAKE3 signature of [BASE].
+pub const SIGNATURE_BLAKE3: &[u8] = &[
+    0x72, 0x73, 0x01, 0x38, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x08, 0x17, 0xd9, 0x10, 0x58,
+    0xc2, 0x39, 0x7a, 0x6d, 0x80, 0x4d, 0x75, 0xc0, 0xfa, 0x9f, 0x0f, 0x40, 0x69, 0x0b, 0x93, 0x0c,
+    0xfc, 0x1a, 0x87, 0x48, 0x00, 0x3d, 0x0f, 0xa4, 0xe2, 0x42, 0x34, 0xd5, 0x0c, 0xd0, 0x18, 0x13,
+    0xf8, 0xcd, 0x0f, 0x26, 0x7c, 0xa2, 0x28, 0x46, 0xa0, 0xc4, 0xa1, 0x8e, 0xf3, 0x13, 0x0e, 0xc7,
+    0x6e, 0xea, 0x4d, 0x95, 0xdc, 0xd3, 0xa1, 0x7b, 0xfa, 0x85, 0x0e, 0xde, 0x6e, 0x38, 0xc1, 0x24,
+    0x54, 0x38, 0x57, 0xf7, 0x01, 0x5e, 0x0f, 0x8e, 0xae, 0xc8, 0x57, 0xab, 0xb7, 0x3e, 0x18, 0x1a,
+    0x1b, 0x89, 0x04, 0xd2, 0x5a, 0x5e, 0xe5, 0x8f, 0x77, 0x33, 0x50, 0x0d,
+];
+
+/// [SIGNATURE_BLAKE3] in the compact form, with delta-encoded CRCs.
+pub const SIGNATURE_BLAKE3_COMPACT: &[u8] = &[
+    0x72, 0x73, 0x01, 0x39, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x6c, 0x72, 0x73, 0x01,
+    0x38, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x08, 0x17, 0xd9, 0x10, 0x58, 0xe2, 0xc5, 0xfe,
+    0xe8, 0x05, 0x9e, 0x00, 0x64, 0xf8, 0x8f, 0xff, 0x82, 0xfa, 0x45, 0xff, 0xa1, 0x07, 0x72, 0x00,
+    0x17, 0x06, 0xd9, 0x00, 0xb0, 0x1a, 0x2a, 0xf5, 0x44, 0xc2, 0x39, 0x7a, 0x6d, 0x80, 0x4d, 0x75,
+    0xc0, 0x69, 0x0b, 0x93, 0x0c, 0xfc, 0x1a, 0x87, 0x48, 0xe2, 0x42, 0x34, 0xd5, 0x0c, 0xd0, 0x18,
+    0x13, 0x7c, 0xa2, 0x28, 0x46, 0xa0, 0xc4, 0xa1, 0x8e, 0x6e, 0xea, 0x4d, 0x95, 0xdc, 0xd3, 0xa1,
+    0x7b, 0x6e, 0x38, 0xc1, 0x24, 0x54, 0x38, 0x57, 0xf7, 0xae, 0xc8, 0x57, 0xab, 0xb7, 0x3e, 0x18,
+    0x1a, 0x5a, 0x5e, 0xe5, 0x8f, 0x77, 0x33, 0x50, 0x0d,
+];
+
+/// The delta from [BASE] to [DATA], as calculated by [diff()](crate::diff()).
+pub const DELTA: &[u8] = &[
+    0x72, 0x73, 0x02, 0x36, 0x45, 0x00, 0x40, 0x20, 0x45, 0x44, 0x49, 0x54, 0x20, 0x74, 0x68, 0x69,
+    0x72, 0x74, 0x79, 0x2d, 0x74, 0x77, 0x6f, 0x20, 0x62, 0x79, 0x74, 0x65, 0x73, 0x2c, 0x20, 0x61,
+    0x6e, 0x64, 0x20, 0x65, 0x76, 0x65, 0x72, 0x79, 0x45, 0x60, 0x40, 0x28, 0x2d, 0x2d, 0x2d, 0x2d,
+    0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d,
+    0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d, 0x2d,
+    0x2d, 0x2d, 0x2d, 0x2d, 0x45, 0xa0, 0x40, 0x16, 0x65, 0x72, 0x79, 0x20, 0x62, 0x6c, 0x6f, 0x63,
+    0x6b, 0x21, 0x20, 0x41, 0x6e, 0x64, 0x20, 0x61, 0x20, 0x74, 0x61, 0x69, 0x6c, 0x2e, 0x00,
+];
+
+/// The delta from [BASE] to [DATA], as calculated by [diff_v2](crate::diff_v2()).
+pub const DELTA_V2: &[u8] = &[
+    0x72, 0x73, 0x02, 0x38, 0x45, 0x00, 0x40, 0x20, 0x45, 0x44, 0x49, 0x54, 0x20, 0x74, 0x68, 0x69,
+    0x72, 0x74, 0x79, 0x2d, 0x74, 0x77, 0x6f, 0x20, 0x62, 0x79, 0x74, 0x65, 0x73, 0x2c, 0x20, 0x61,
+    0x6e, 0x64, 0x20, 0x65, 0x76, 0x65, 0x72, 0x79, 0x45, 0x60, 0x40, 0x55, 0x28, 0x01, 0x2d, 0x45,
+    0xa0, 0x40, 0x16, 0x65, 0x72, 0x79, 0x20, 0x62, 0x6c, 0x6f, 0x63, 0x6b, 0x21, 0x20, 0x41, 0x6e,
+    0x64, 0x20, 0x61, 0x20, 0x74, 0x61, 0x69, 0x6c, 0x2e, 0x00,
+];
+
+/// All vectors, by the name of their constant.
+pub const VECTORS: &[(&str, &[u8])] = &[
+    ("BASE", BASE),
+    ("DATA", DATA),
+    ("SIGNATURE_MD4", SIGNATURE_MD4),
+    ("SIGNATURE_MD4_COMPACT", SIGNATURE_MD4_COMPACT),
+    ("SIGNATURE_BLAKE3", SIGNATURE_BLAKE3),
+    ("SIGNATURE_BLAKE3_COMPACT", SIGNATURE_BLAKE3_COMPACT),
+    ("DELTA", DELTA),
+    ("DELTA_V2", DELTA_V2),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{apply, diff, diff_v2, CompactOptions, Signature};
+
+    #[test]
+    fn test_golden_vectors() {
+        let compact = CompactOptions {
+            delta_crcs: true,
+            ..Default::default()
+        };
+        let signatures = [
+            (HashAlgorithm::Md4, SIGNATURE_MD4, SIGNATURE_MD4_COMPACT),
+            (HashAlgorithm::Blake3, SIGNATURE_BLAKE3, SIGNATURE_BLAKE3_COMPACT),
+        ];
+        for (hash_algorithm, expected, expected_compact) in signatures {
+            let signature = Signature::calculate(BASE, options(hash_algorithm));
+            assert_eq!(signature.serialized(), expected, "{hash_algorithm:?}");
+            assert_eq!(signature.serialize_compact(compact), expected_compact);
+            let parsed = Signature::deserialize(expected_compact.to_vec()).unwrap();
+            assert_eq!(parsed.serialized(), expected);
+
+            let mut delta = vec![];
+            diff(&signature.index(), DATA, &mut delta).unwrap();
+            assert_eq!(delta, DELTA, "{hash_algorithm:?}");
+            let mut delta = vec![];
+            diff_v2(&signature.index(), DATA, &mut delta).unwrap();
+            assert_eq!(delta, DELTA_V2, "{hash_algorithm:?}");
+        }
+        for delta in [DELTA, DELTA_V2] {
+            let mut out = vec![];
+            apply(BASE, delta, &mut out).unwrap();
+            assert_eq!(out, DATA);
+        }
+    }
+}
@@ -17,6 +17,11 @@ use std::{collections::HashMap, hash::Hash, mem};
 /// With this the current use case of `SecondLayerMap<&[u8], u32>` takes up 24 bytes on 64-bit
 /// systems while `HashMap<&[u8], u32>` takes 48. Beyond that a [`SecondLayerMap`] consists of just
 /// a match and an if
+///
+/// A few entries are chained in an [`Arena`] shared by all maps of the index, since allocating a
+/// [`HashMap`] for every collision costs an allocation each and fragments the heap when indexing
+/// tens of millions of blocks. Only chains longer than [`MAX_CHAIN_LEN`] move to a [`HashMap`],
+/// which keeps lookups fast when crafted data makes many blocks share a weak hash.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SecondLayerMap<K, V>
 where
@@ -24,9 +29,53 @@ where
 {
     Empty,
     Single(K, V),
+    /// The first node and the length of a chain in the [`Arena`].
+    Chain(u32, u32),
     TwoOrMore(HashMap<K, V>),
 }
 
+/// The longest chain of entries kept in an [`Arena`] before moving them to a [`HashMap`].
+pub const MAX_CHAIN_LEN: u32 = 8;
+
+/// Marks the end of a chain.
+const END: u32 = u32::MAX;
+
+/// Bump-allocated storage for the chained entries of many [`SecondLayerMap`]s.
+///
+/// Entries are never freed individually: those of chains that move to a [`HashMap`] stay behind
+/// until the arena is dropped, which is bounded by [`MAX_CHAIN_LEN`] per map.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Arena<K, V> {
+    nodes: Vec<Node<K, V>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Node<K, V> {
+    key: K,
+    val: V,
+    next: u32,
+}
+
+impl<K, V> Default for Arena<K, V> {
+    fn default() -> Self {
+        Arena { nodes: Vec::new() }
+    }
+}
+
+impl<K, V> Arena<K, V> {
+    fn alloc(&mut self, key: K, val: V, next: u32) -> u32 {
+        let index = self.nodes.len() as u32;
+        assert!(index != END, "too many entries in arena");
+        self.nodes.push(Node { key, val, next });
+        index
+    }
+
+    /// Analogous to [`Vec::shrink_to_fit`]
+    pub fn shrink_to_fit(&mut self) {
+        self.nodes.shrink_to_fit();
+    }
+}
+
 impl<K, V> Default for SecondLayerMap<K, V>
 where
     K: Eq + Hash,
@@ -40,17 +89,47 @@ impl<K, V> SecondLayerMap<K, V>
 where
     K: Eq + Hash,
 {
-    /// Analogous to [`HashMap::insert`]
-    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
+    /// Analogous to [`HashMap::insert`], with chained entries allocated from `arena`
+    pub fn insert(&mut self, arena: &mut Arena<K, V>, key: K, val: V) -> Option<V>
+    where
+        K: Clone,
+        V: Clone,
+    {
         let old_state = mem::replace(self, Self::Empty);
 
         let (new_state, ret) = match old_state {
             Self::Empty => (Self::Single(key, val), None),
             Self::Single(old_key, old_val) => {
-                let mut map = HashMap::with_capacity(2);
-                map.insert(key, val);
-                let ret = map.insert(old_key, old_val);
-                (Self::TwoOrMore(map), ret)
+                if old_key == key {
+                    (Self::Single(key, val), Some(old_val))
+                } else {
+                    let tail = arena.alloc(old_key, old_val, END);
+                    (Self::Chain(arena.alloc(key, val, tail), 2), None)
+                }
+            }
+            Self::Chain(head, len) => {
+                let mut node = head;
+                while node != END {
+                    let entry = &mut arena.nodes[node as usize];
+                    if entry.key == key {
+                        *self = Self::Chain(head, len);
+                        return Some(mem::replace(&mut entry.val, val));
+                    }
+                    node = entry.next;
+                }
+                if len < MAX_CHAIN_LEN {
+                    (Self::Chain(arena.alloc(key, val, head), len + 1), None)
+                } else {
+                    let mut map = HashMap::with_capacity(len as usize + 1);
+                    let mut node = head;
+                    while node != END {
+                        let entry = &arena.nodes[node as usize];
+                        map.insert(entry.key.clone(), entry.val.clone());
+                        node = entry.next;
+                    }
+                    map.insert(key, val);
+                    (Self::TwoOrMore(map), None)
+                }
             }
             Self::TwoOrMore(mut map) => {
                 let ret = map.insert(key, val);
@@ -62,8 +141,8 @@ where
         ret
     }
 
-    /// Analogous to [`HashMap::get`]
-    pub fn get(&self, needle: &K) -> Option<&V> {
+    /// Analogous to [`HashMap::get`], with chained entries read from `arena`
+    pub fn get<'m>(&'m self, arena: &'m Arena<K, V>, needle: &K) -> Option<&'m V> {
         match self {
             Self::Single(key, val) => {
                 if needle == key {
@@ -72,8 +151,46 @@ where
                     None
                 }
             }
+            Self::Chain(head, _) => {
+                let mut node = *head;
+                while node != END {
+                    let entry = &arena.nodes[node as usize];
+                    if entry.key == *needle {
+                        return Some(&entry.val);
+                    }
+                    node = entry.next;
+                }
+                None
+            }
             Self::TwoOrMore(map) => map.get(needle),
             Self::Empty => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Arena, SecondLayerMap, MAX_CHAIN_LEN};
+
+    #[test]
+    fn test_second_layer_map() {
+        let mut arena = Arena::default();
+        let mut map = SecondLayerMap::default();
+        for i in 0..MAX_CHAIN_LEN {
+            assert_eq!(map.insert(&mut arena, i, i), None);
+            assert!(matches!(
+                map,
+                SecondLayerMap::Single(..) | SecondLayerMap::Chain(..)
+            ));
+        }
+        assert_eq!(map.insert(&mut arena, 3, 30), Some(3));
+        assert_eq!(map.insert(&mut arena, 100, 100), None);
+        assert!(matches!(map, SecondLayerMap::TwoOrMore(_)));
+        for i in 0..MAX_CHAIN_LEN {
+            let expected = if i == 3 { 30 } else { i };
+            assert_eq!(map.get(&arena, &i), Some(&expected));
+        }
+        assert_eq!(map.get(&arena, &100), Some(&100));
+        assert_eq!(map.get(&arena, &101), None);
+    }
+}
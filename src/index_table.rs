@@ -0,0 +1,173 @@
+//! The prebuilt block index stored in v2 signatures.
+//!
+//! The index is an open-addressing hash table of `1 << bits` slots, keyed by block CRC with
+//! linear probing. Each slot is a big-endian u32: 0 for an empty slot, otherwise one more than
+//! the index of a block. Serialized, it is `bits` as a big-endian u32 followed by the slots.
+//!
+//! Looking blocks up in the table directly means [Signature::index](crate::Signature::index)
+//! doesn't have to build a hash map, which matters for short-lived processes that compute a
+//! single delta against a large signature.
+
+use crate::crc::Crc;
+
+/// A block index table borrowed from a signature.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct IndexTable<'a> {
+    slots: &'a [u8],
+    mask: usize,
+    /// The block entries of the signature.
+    entries: &'a [u8],
+    entry_size: usize,
+}
+
+impl<'a> IndexTable<'a> {
+    /// Build the serialized table for `entries` (CRC followed by crypto hash, `entry_size` bytes
+    /// each).
+    pub(crate) fn build(entries: &[u8], entry_size: usize) -> Vec<u8> {
+        let mut out = vec![0; Self::serialized_len(entries.len() / entry_size)];
+        Self::build_into(entries, entry_size, &mut out);
+        out
+    }
+
+    /// The length of the serialized table for `count` blocks.
+    pub(crate) fn serialized_len(count: usize) -> usize {
+        4 + (4 << Self::bits(count))
+    }
+
+    fn bits(count: usize) -> u32 {
+        // at most half full, so probe sequences stay short
+        (count * 2).next_power_of_two().trailing_zeros().max(1)
+    }
+
+    /// Build the serialized table for `entries` into `out`, which must be
+    /// [Self::serialized_len] bytes long.
+    pub(crate) fn build_into(entries: &[u8], entry_size: usize, out: &mut [u8]) {
+        let count = entries.len() / entry_size;
+        let bits = Self::bits(count);
+        let mask = (1usize << bits) - 1;
+        let (header, slots) = out.split_at_mut(4);
+        header.copy_from_slice(&bits.to_be_bytes());
+        slots.fill(0);
+        // Insert in reverse, so that among identical blocks the last one wins, like with
+        // `IndexedSignature`'s hash map.
+        for idx in (0..count).rev() {
+            let entry = &entries[idx * entry_size..(idx + 1) * entry_size];
+            let mut slot = slot_hash(entry_crc(entry)) as usize & mask;
+            loop {
+                let slot_bytes = &mut slots[slot * 4..slot * 4 + 4];
+                match u32::from_be_bytes((*slot_bytes).try_into().unwrap()) {
+                    0 => {
+                        slot_bytes.copy_from_slice(&(idx as u32 + 1).to_be_bytes());
+                        break;
+                    }
+                    other => {
+                        let existing = (other - 1) as usize;
+                        if &entries[existing * entry_size..(existing + 1) * entry_size] == entry {
+                            break;
+                        }
+                    }
+                }
+                slot = (slot + 1) & mask;
+            }
+        }
+    }
+
+    /// Check a serialized table against the signature's block entries.
+    pub(crate) fn parse(section: &'a [u8], entries: &'a [u8], entry_size: usize) -> Option<Self> {
+        let bits = u32::from_be_bytes(section.get(..4)?.try_into().unwrap());
+        if bits == 0 || bits >= usize::BITS - 3 {
+            return None;
+        }
+        let slots = &section[4..];
+        if slots.len() != 4 << bits {
+            return None;
+        }
+        let count = (entries.len() / entry_size) as u64;
+        let mut empty = false;
+        for slot in slots.chunks_exact(4) {
+            let value = u32::from_be_bytes(slot.try_into().unwrap()) as u64;
+            if value > count {
+                return None;
+            }
+            empty |= value == 0;
+        }
+        // Lookups stop at an empty slot, so a table without one would never terminate.
+        if !empty {
+            return None;
+        }
+        Some(IndexTable {
+            slots,
+            mask: (1 << bits) - 1,
+            entries,
+            entry_size,
+        })
+    }
+
+    /// Wrap a serialized table that was built by [Self::build] or checked by [Self::parse].
+    pub(crate) fn trusted(section: &'a [u8], entries: &'a [u8], entry_size: usize) -> Self {
+        let bits = u32::from_be_bytes(section[..4].try_into().unwrap());
+        IndexTable {
+            slots: &section[4..],
+            mask: (1 << bits) - 1,
+            entries,
+            entry_size,
+        }
+    }
+
+    /// Find the first slot of a block with CRC `crc`.
+    #[inline]
+    pub(crate) fn find(&self, crc: Crc) -> Option<usize> {
+        let mut slot = slot_hash(crc) as usize & self.mask;
+        loop {
+            let entry = self.entry(slot)?;
+            if entry_crc(entry) == crc {
+                return Some(slot);
+            }
+            slot = (slot + 1) & self.mask;
+        }
+    }
+
+    /// Find the block with `crc` and `crypto_hash`, starting at `slot` from [Self::find].
+    #[inline]
+    pub(crate) fn get(&self, mut slot: usize, crc: Crc, crypto_hash: &[u8]) -> Option<u32> {
+        loop {
+            let entry = self.entry(slot)?;
+            if entry_crc(entry) == crc && &entry[Crc::SIZE..] == crypto_hash {
+                return Some(self.value(slot) - 1);
+            }
+            slot = (slot + 1) & self.mask;
+        }
+    }
+
+    #[inline]
+    fn value(&self, slot: usize) -> u32 {
+        u32::from_be_bytes(self.slots[slot * 4..slot * 4 + 4].try_into().unwrap())
+    }
+
+    /// The block entry in `slot`, or `None` if it's empty.
+    #[inline]
+    fn entry(&self, slot: usize) -> Option<&'a [u8]> {
+        let idx = self.value(slot).checked_sub(1)? as usize;
+        Some(&self.entries[idx * self.entry_size..(idx + 1) * self.entry_size])
+    }
+}
+
+fn entry_crc(entry: &[u8]) -> Crc {
+    Crc::from_bytes(entry[..Crc::SIZE].try_into().unwrap())
+}
+
+#[inline]
+fn slot_hash(crc: Crc) -> u64 {
+    mix(crc.0 as u64)
+}
+
+/// The avalanche function from xxhash, fixed to 64 bits so tables are portable.
+#[inline]
+pub(crate) fn mix(mut val: u64) -> u64 {
+    val ^= val >> 33;
+    val = val.wrapping_mul(0xC2B2AE3D27D4EB4F);
+    val ^= val >> 29;
+    val = val.wrapping_mul(0x165667B19E3779F9);
+    val ^= val >> 32;
+    val
+}
@@ -0,0 +1,102 @@
+//! JSON descriptions of deltas for debugging.
+
+use serde_json::{json, Value};
+
+use crate::patch::{Op, OpReader};
+
+/// Describe `delta` as JSON, for support tooling and log attachments.
+///
+/// The output lists every op with its position in the output. Parsing stops at the first error,
+/// which is included as `"error"`, so a delta that fails to apply can still be inspected up to
+/// the point where it goes wrong. The format is meant to be read by people and may change
+/// between releases.
+pub fn delta_to_json_debug(delta: &[u8]) -> String {
+    let mut ops = Vec::new();
+    let mut position = 0u64;
+    let error = match OpReader::new(delta) {
+        Ok(mut reader) => loop {
+            match reader.next_op() {
+                Ok(Some(Op::Literal(literal))) => {
+                    ops.push(json!({
+                        "op": "literal",
+                        "position": position,
+                        "len": literal.len(),
+                    }));
+                    position += literal.len() as u64;
+                }
+                Ok(Some(op @ Op::Repeat { pattern, count })) => {
+                    ops.push(json!({
+                        "op": "repeat",
+                        "position": position,
+                        "pattern_len": pattern.len(),
+                        "count": count,
+                        "len": op.output_len(),
+                    }));
+                    position = position.saturating_add(op.output_len());
+                }
+                Ok(Some(Op::Copy { offset, len })) => {
+                    ops.push(json!({
+                        "op": "copy",
+                        "position": position,
+                        "offset": offset,
+                        "len": len,
+                    }));
+                    position = position.saturating_add(len);
+                }
+                Ok(None) => break None,
+                Err(e) => break Some(e.to_string()),
+            }
+        },
+        Err(e) => Some(e.to_string()),
+    };
+    let mut value = json!({
+        "delta_len": delta.len(),
+        "output_len": position,
+        "ops": ops,
+    });
+    if let Some(error) = error {
+        value["error"] = Value::String(error);
+    }
+    value.to_string()
+}
+
+/// Lowercase hex encoding of `bytes`.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::delta_to_json_debug;
+    use crate::{diff, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_json_debug() {
+        let base: Vec<u8> = (0..128).collect();
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(16, 4, HashAlgorithm::Blake3),
+        );
+        let json: Value = serde_json::from_str(&signature.to_json_debug()).unwrap();
+        assert_eq!(json["type"], "Blake3");
+        assert_eq!(json["block_count"], 8);
+        assert_eq!(json["blocks"][1]["hash"].as_str().unwrap().len(), 8);
+
+        let data = [&base[16..64], b"new".as_slice()].concat();
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+        let json: Value = serde_json::from_str(&delta_to_json_debug(&delta)).unwrap();
+        assert_eq!(json["output_len"], 51);
+        assert_eq!(json["ops"][0]["op"], "copy");
+        assert_eq!(json["ops"][0]["offset"], 16);
+        assert_eq!(json["ops"][1]["op"], "literal");
+        assert_eq!(json["ops"][1]["position"], 48);
+        assert!(json.get("error").is_none());
+
+        let json: Value = serde_json::from_str(&delta_to_json_debug(&delta[..delta.len() - 1])).unwrap();
+        assert_eq!(json["ops"].as_array().unwrap().len(), 2);
+        assert!(json["error"].as_str().unwrap().contains("unexpected end"));
+    }
+}
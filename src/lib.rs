@@ -13,21 +13,109 @@
 #![allow(clippy::unreadable_literal)]
 #![deny(missing_docs)]
 
-mod consts;
+pub mod analysis;
+#[cfg(feature = "async")]
+pub mod async_io;
+mod auto;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod blake2;
+mod blake3;
+#[cfg(all(unix, feature = "blockdev"))]
+pub mod blockdev;
+mod builder;
+pub mod cache;
+pub mod cancel;
+#[cfg(feature = "codec")]
+pub mod codec;
+mod compact;
+pub mod compress;
+mod concat;
+pub mod consts;
 mod crc;
+mod crc32c;
 mod diff;
+#[cfg(feature = "distributed")]
+pub mod distributed;
+mod effort;
+mod file;
+pub mod fingerprint;
+#[cfg(feature = "golden")]
+pub mod golden;
 mod hasher;
 mod hashmap_variant;
+mod index_table;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "manifest")]
+pub mod manifest;
 mod md4;
-mod blake3;
+mod md5;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod observer;
+pub mod ops;
+mod optimal;
+pub mod ota;
+#[cfg(feature = "parallel")]
+mod parallel;
+pub mod params;
 mod patch;
+mod perfect_hash;
+mod pipeline;
+pub mod progress;
+pub mod recovery;
+pub mod remote;
+mod rolling_hash;
+pub mod seek;
+mod sha256;
+pub mod shard;
 mod signature;
+pub mod snapshot;
+pub mod streaming;
+pub mod strict;
+mod strong_hash;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+mod uring;
+#[cfg(feature = "watch")]
+pub mod watch;
 
 #[cfg(test)]
 mod tests;
 
-pub use diff::{diff, DiffError};
+pub use auto::{diff_auto, DiffStrategy};
+pub use builder::SignatureBuilder;
+pub use compact::CompactOptions;
+pub use concat::{concat_deltas, ChunkDelta, ConcatError};
+pub use crc::Crc;
+pub use crc32c::Crc32c;
+pub use diff::{
+    changed_ranges, diff, diff_anchored, diff_from_reader, diff_range, diff_v2, diff_with_options,
+    DiffError, DiffOptions, DiffSession,
+};
 #[cfg(feature = "parallel")]
 pub use diff::diff_parallel;
-pub use patch::{apply, apply_limited, ApplyError};
-pub use signature::{IndexedSignature, Signature, SignatureOptions, SignatureParseError, HashAlgorithm};
+pub use effort::{diff_with_effort, Effort};
+pub use file::apply_file;
+#[cfg(feature = "json")]
+pub use json::delta_to_json_debug;
+#[cfg(feature = "mmap")]
+pub use mmap::{apply_file_mmap, apply_mmap, diff_file};
+pub use observer::{diff_observed, DiffObserver};
+pub use optimal::{diff_optimal, OPTIMAL_MAX_LEN};
+#[cfg(feature = "parallel")]
+pub use parallel::Parallel;
+pub use patch::{
+    apply, apply_limited, apply_range, apply_with_options, apply_with_scratch, ApplyError,
+    ApplyOptions,
+};
+#[cfg(feature = "parallel")]
+pub use patch::{apply_parallel, apply_parallel_into};
+pub use pipeline::Pipeline;
+pub use rolling_hash::RollingHash;
+pub use signature::{
+    HashAlgorithm, IndexedSignature, IndexedSignatureOwned, InvalidArgumentError,
+    ParseHashAlgorithmError, Signature, SignatureExtensions, SignatureOptions,
+    SignatureParseError, SignatureRef,
+};
+pub use strong_hash::{register_strong_hash, RegisterError, StrongHash, MAX_STRONG_HASH_SIZE};
@@ -0,0 +1,220 @@
+//! Canonical binary encodings of signature metadata and tree manifests.
+//!
+//! Deployment systems often ship their own manifests describing what is being synced. The types
+//! here can be embedded in those with [serde], and have canonical [bincode] and CBOR encodings
+//! whose layout only changes together with [LAYOUT_VERSION]. Every encoding starts with the
+//! layout version, and decoding rejects versions it doesn't know.
+
+use std::error::Error;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::signature::{HashAlgorithm, Signature};
+
+/// The version of the layout written by this release.
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// Metadata describing a [Signature], without its block hashes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignatureMetadata {
+    /// The hash algorithm of the signature.
+    pub hash_algorithm: HashAlgorithm,
+    /// The block size of the signature.
+    pub block_size: u32,
+    /// The number of bytes of each block's crypto hash.
+    pub crypto_hash_size: u32,
+    /// The number of blocks, i.e. `ceil(basis length / block_size)`.
+    pub block_count: u64,
+    /// The BLAKE3 hash of [Signature::serialized], identifying the full signature.
+    pub digest: [u8; 32],
+}
+
+impl SignatureMetadata {
+    /// Describe `signature`, or return `None` if its hash algorithm isn't supported.
+    pub fn new(signature: &Signature) -> Option<Self> {
+        let options = signature.options()?;
+        Some(SignatureMetadata {
+            hash_algorithm: options.hash_algorithm,
+            block_size: options.block_size,
+            crypto_hash_size: options.crypto_hash_size,
+            block_count: signature.block_entries().len() as u64,
+            digest: *::blake3::hash(signature.serialized()).as_bytes(),
+        })
+    }
+
+    /// Check whether `signature` is the one described by this metadata.
+    pub fn matches(&self, signature: &Signature) -> bool {
+        Self::new(signature).as_ref() == Some(self)
+    }
+}
+
+/// One file of a [TreeManifest].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeEntry {
+    /// The path of the file relative to the root of the tree, with `/` as separator.
+    pub path: String,
+    /// The length of the file in bytes.
+    pub len: u64,
+    /// The signature of the file.
+    pub signature: SignatureMetadata,
+}
+
+/// A list of files and their signatures.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeManifest {
+    /// The files in the tree, sorted by path.
+    pub entries: Vec<TreeEntry>,
+}
+
+/// Indicates that an encoded manifest could not be decoded.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ManifestError {
+    /// The layout version is not supported by this release.
+    UnsupportedVersion {
+        /// The layout version of the encoding.
+        version: u32,
+    },
+    /// The encoding is malformed.
+    Malformed(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion { version } => {
+                write!(f, "unsupported manifest layout version {version}")
+            }
+            Self::Malformed(source) => write!(f, "malformed manifest (source={source})"),
+        }
+    }
+}
+
+impl Error for ManifestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Malformed(source) => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+/// The on-wire form: the layout version followed by the value.
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    version: u32,
+    value: T,
+}
+
+/// Just the version, to check it before decoding the rest.
+#[derive(Deserialize)]
+struct VersionOnly {
+    version: u32,
+}
+
+macro_rules! encodings {
+    ($ty:ty) => {
+        impl $ty {
+            /// Encode with [bincode]'s default (fixed-width, little-endian) layout.
+            pub fn to_bincode(&self) -> Vec<u8> {
+                bincode::serialize(&Versioned {
+                    version: LAYOUT_VERSION,
+                    value: self,
+                })
+                .expect("serializing to a Vec can't fail")
+            }
+
+            /// Decode an encoding produced by `to_bincode`.
+            pub fn from_bincode(bytes: &[u8]) -> Result<Self, ManifestError> {
+                // The version is the first field, so it can be read on its own.
+                let VersionOnly { version } =
+                    bincode::deserialize(bytes).map_err(|e| ManifestError::Malformed(e))?;
+                check_version(version)?;
+                let versioned: Versioned<Self> =
+                    bincode::deserialize(bytes).map_err(|e| ManifestError::Malformed(e))?;
+                Ok(versioned.value)
+            }
+
+            /// Encode as CBOR (RFC 8949).
+            pub fn to_cbor(&self) -> Vec<u8> {
+                let mut out = Vec::new();
+                ciborium::into_writer(
+                    &Versioned {
+                        version: LAYOUT_VERSION,
+                        value: self,
+                    },
+                    &mut out,
+                )
+                .expect("serializing to a Vec can't fail");
+                out
+            }
+
+            /// Decode an encoding produced by `to_cbor`.
+            pub fn from_cbor(bytes: &[u8]) -> Result<Self, ManifestError> {
+                let value: ciborium::Value =
+                    ciborium::from_reader(bytes).map_err(|e| ManifestError::Malformed(e.into()))?;
+                let VersionOnly { version } = value
+                    .deserialized()
+                    .map_err(|e| ManifestError::Malformed(e.into()))?;
+                check_version(version)?;
+                let versioned: Versioned<Self> = value
+                    .deserialized()
+                    .map_err(|e| ManifestError::Malformed(e.into()))?;
+                Ok(versioned.value)
+            }
+        }
+    };
+}
+
+encodings!(SignatureMetadata);
+encodings!(TreeManifest);
+
+fn check_version(version: u32) -> Result<(), ManifestError> {
+    if version != LAYOUT_VERSION {
+        return Err(ManifestError::UnsupportedVersion { version });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ManifestError, SignatureMetadata, TreeEntry, TreeManifest};
+    use crate::{HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_manifest_encodings() {
+        let signature = Signature::calculate(
+            &[1; 10_000],
+            SignatureOptions::new(1024, 16, HashAlgorithm::Blake3),
+        );
+        let metadata = SignatureMetadata::new(&signature).unwrap();
+        assert_eq!(metadata.block_count, 10);
+        assert!(metadata.matches(&signature));
+
+        let bincode = metadata.to_bincode();
+        // version, variant index, block size, hash size, block count, digest
+        assert_eq!(bincode.len(), 4 + 4 + 4 + 4 + 8 + 32);
+        assert_eq!(bincode[..4], 1u32.to_le_bytes());
+        assert_eq!(SignatureMetadata::from_bincode(&bincode).unwrap(), metadata);
+        assert_eq!(SignatureMetadata::from_cbor(&metadata.to_cbor()).unwrap(), metadata);
+
+        let manifest = TreeManifest {
+            entries: vec![TreeEntry {
+                path: "bin/app".into(),
+                len: 10_000,
+                signature: metadata,
+            }],
+        };
+        assert_eq!(TreeManifest::from_bincode(&manifest.to_bincode()).unwrap(), manifest);
+        assert_eq!(TreeManifest::from_cbor(&manifest.to_cbor()).unwrap(), manifest);
+
+        let mut future = manifest.to_bincode();
+        future[0] = 2;
+        assert!(matches!(
+            TreeManifest::from_bincode(&future),
+            Err(ManifestError::UnsupportedVersion { version: 2 })
+        ));
+        assert!(TreeManifest::from_cbor(b"garbage").is_err());
+    }
+}
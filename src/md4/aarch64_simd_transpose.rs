@@ -4,7 +4,7 @@
 
 use arrayref::array_ref;
 
-use std::arch::aarch64::{uint32x4_t, vtrnq_u32, vzipq_u32};
+use std::arch::aarch64::{uint32x4_t, uint32x4x2_t, vtrnq_u32, vzipq_u32};
 
 #[inline(always)]
 /// Loads four u32s (little-endian), potentially unaligned
@@ -45,6 +45,16 @@ pub unsafe fn load_16x4<'a, F: Fn(usize) -> &'a [u8; 64]>(data: F) -> [uint32x4_
     ])
 }
 
+/// Like `load_16x4`, with lanes 0..4 in the first register of each pair and lanes 4..8 in the
+/// second.
+#[inline]
+#[target_feature(enable = "neon")]
+pub unsafe fn load_16x8<'a, F: Fn(usize) -> &'a [u8; 64]>(data: F) -> [uint32x4x2_t; 16] {
+    let low = load_16x4(&data);
+    let high = load_16x4(|lane| data(lane + 4));
+    core::array::from_fn(|word| uint32x4x2_t(low[word], high[word]))
+}
+
 #[test]
 fn test_transpose() {
     let mut input = [[0; 64]; 4];
@@ -64,3 +74,23 @@ fn test_transpose() {
         }
     }
 }
+
+#[test]
+fn test_transpose_8() {
+    let mut input = [[0; 64]; 8];
+    for lane in 0..8 {
+        for i in 0..16 {
+            let value = (lane * 16 + i) as u32;
+            input[lane][i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    unsafe {
+        let output = load_16x8(|lane| &input[lane]);
+        let transmuted = core::mem::transmute::<_, [[u32; 8]; 16]>(output);
+        for lane in 0..8 {
+            for i in 0..16 {
+                assert_eq!(transmuted[i][lane], (lane * 16 + i) as u32);
+            }
+        }
+    }
+}
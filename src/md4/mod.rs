@@ -169,9 +169,9 @@ pub fn md4(data: &[u8]) -> [u8; 16] {
 
 mod simd {
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub const MAX_LANES: usize = 8;
+    pub const MAX_LANES: usize = 16;
     #[cfg(target_arch = "aarch64")]
-    pub const MAX_LANES: usize = 4;
+    pub const MAX_LANES: usize = 8;
     #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
     pub const MAX_LANES: usize = 0;
 
@@ -370,6 +370,32 @@ mod simd {
                 splat = splat,
             );
         }
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        mod lanes_16 {
+            #[inline(always)]
+            unsafe fn splat(x: u32) -> super::arch::__m512i {
+                super::arch::_mm512_set1_epi32(x as i32)
+            }
+            macro_rules! rotate_left {
+                ($x: expr, $shift: expr) => {{
+                    // `vprold`
+                    super::arch::_mm512_rol_epi32::<{ $shift as i32 }>($x)
+                }};
+            }
+            n_lanes!(
+                super::arch::__m512i,
+                "avx512f",
+                is_x86_feature_detected!("avx512f"),
+                load = crate::md4::x86_simd_transpose::load_16x16_avx512,
+                add = super::arch::_mm512_add_epi32,
+                and = super::arch::_mm512_and_si512,
+                or = super::arch::_mm512_or_si512,
+                andnot = super::arch::_mm512_andnot_si512,
+                xor = super::arch::_mm512_xor_si512,
+                rol = (rotate_left!),
+                splat = splat,
+            );
+        }
         #[cfg(target_arch = "aarch64")]
         mod lanes_4 {
             macro_rules! rotate_left {
@@ -405,29 +431,178 @@ mod simd {
             );
         }
 
+        #[cfg(target_arch = "aarch64")]
+        mod lanes_8 {
+            // Two independent u32x4s per word, so that the NEON pipelines have a second
+            // dependency chain to work on while the first one waits.
+            macro_rules! pairwise {
+                ($name: ident, $op: path) => {
+                    #[inline(always)]
+                    unsafe fn $name(
+                        a: super::arch::uint32x4x2_t,
+                        b: super::arch::uint32x4x2_t,
+                    ) -> super::arch::uint32x4x2_t {
+                        super::arch::uint32x4x2_t($op(a.0, b.0), $op(a.1, b.1))
+                    }
+                };
+            }
+            pairwise!(add, super::arch::vaddq_u32);
+            pairwise!(and, super::arch::vandq_u32);
+            pairwise!(or, super::arch::vorrq_u32);
+            pairwise!(xor, super::arch::veorq_u32);
+            #[inline(always)]
+            unsafe fn andnot(
+                a: super::arch::uint32x4x2_t,
+                b: super::arch::uint32x4x2_t,
+            ) -> super::arch::uint32x4x2_t {
+                // "bit clear", order of arguments is reversed compared to Intel
+                super::arch::uint32x4x2_t(
+                    super::arch::vbicq_u32(b.0, a.0),
+                    super::arch::vbicq_u32(b.1, a.1),
+                )
+            }
+            #[inline(always)]
+            unsafe fn splat(x: u32) -> super::arch::uint32x4x2_t {
+                super::arch::uint32x4x2_t(super::arch::vdupq_n_u32(x), super::arch::vdupq_n_u32(x))
+            }
+            macro_rules! rotate_left {
+                ($x: expr, $shift: expr) => {{
+                    let x = $x;
+                    // (x << shift) | (x >> (32 - shift))
+                    super::arch::uint32x4x2_t(
+                        super::arch::vorrq_u32(
+                            super::arch::vshlq_n_u32::<{ $shift as i32 }>(x.0),
+                            super::arch::vshrq_n_u32::<{ 32 - $shift as i32 }>(x.0),
+                        ),
+                        super::arch::vorrq_u32(
+                            super::arch::vshlq_n_u32::<{ $shift as i32 }>(x.1),
+                            super::arch::vshrq_n_u32::<{ 32 - $shift as i32 }>(x.1),
+                        ),
+                    )
+                }};
+            }
+            n_lanes!(
+                super::arch::uint32x4x2_t,
+                "neon",
+                std::arch::is_aarch64_feature_detected!("neon"),
+                load = crate::md4::aarch64_simd_transpose::load_16x8,
+                add = add,
+                and = and,
+                or = or,
+                andnot = andnot,
+                xor = xor,
+                rol = (rotate_left!),
+                splat = splat,
+            );
+        }
+
         use super::Md4xN;
 
         impl Md4xN {
-            /// Returns a SIMD implementation if one is available.
-            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            /// Returns the widest SIMD implementation available.
+            #[cfg(test)]
             pub fn select() -> Option<Md4xN> {
-                lanes_8::select().or_else(lanes_4::select)
+                Self::select_at_most(super::MAX_LANES)
+            }
+            /// Returns the widest SIMD implementation available with at most `max_lanes` lanes.
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            pub fn select_at_most(max_lanes: usize) -> Option<Md4xN> {
+                let impls: [fn() -> Option<Md4xN>; 3] =
+                    [lanes_16::select, lanes_8::select, lanes_4::select];
+                impls
+                    .into_iter()
+                    .filter_map(|select| select())
+                    .find(|simd_impl| simd_impl.lanes() <= max_lanes)
             }
             #[cfg(target_arch = "aarch64")]
-            pub fn select() -> Option<Md4xN> {
-                lanes_4::select()
+            pub fn select_at_most(max_lanes: usize) -> Option<Md4xN> {
+                let impls: [fn() -> Option<Md4xN>; 2] = [lanes_8::select, lanes_4::select];
+                impls
+                    .into_iter()
+                    .filter_map(|select| select())
+                    .find(|simd_impl| simd_impl.lanes() <= max_lanes)
             }
         }
     }
     #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
     mod no_simd {
+        #[cfg(target_arch = "aarch64")]
+        mod lanes_8 {
+            // Two independent u32x4s per word, so that the NEON pipelines have a second
+            // dependency chain to work on while the first one waits.
+            macro_rules! pairwise {
+                ($name: ident, $op: path) => {
+                    #[inline(always)]
+                    unsafe fn $name(
+                        a: super::arch::uint32x4x2_t,
+                        b: super::arch::uint32x4x2_t,
+                    ) -> super::arch::uint32x4x2_t {
+                        super::arch::uint32x4x2_t($op(a.0, b.0), $op(a.1, b.1))
+                    }
+                };
+            }
+            pairwise!(add, super::arch::vaddq_u32);
+            pairwise!(and, super::arch::vandq_u32);
+            pairwise!(or, super::arch::vorrq_u32);
+            pairwise!(xor, super::arch::veorq_u32);
+            #[inline(always)]
+            unsafe fn andnot(
+                a: super::arch::uint32x4x2_t,
+                b: super::arch::uint32x4x2_t,
+            ) -> super::arch::uint32x4x2_t {
+                // "bit clear", order of arguments is reversed compared to Intel
+                super::arch::uint32x4x2_t(
+                    super::arch::vbicq_u32(b.0, a.0),
+                    super::arch::vbicq_u32(b.1, a.1),
+                )
+            }
+            #[inline(always)]
+            unsafe fn splat(x: u32) -> super::arch::uint32x4x2_t {
+                super::arch::uint32x4x2_t(super::arch::vdupq_n_u32(x), super::arch::vdupq_n_u32(x))
+            }
+            macro_rules! rotate_left {
+                ($x: expr, $shift: expr) => {{
+                    let x = $x;
+                    // (x << shift) | (x >> (32 - shift))
+                    super::arch::uint32x4x2_t(
+                        super::arch::vorrq_u32(
+                            super::arch::vshlq_n_u32::<{ $shift as i32 }>(x.0),
+                            super::arch::vshrq_n_u32::<{ 32 - $shift as i32 }>(x.0),
+                        ),
+                        super::arch::vorrq_u32(
+                            super::arch::vshlq_n_u32::<{ $shift as i32 }>(x.1),
+                            super::arch::vshrq_n_u32::<{ 32 - $shift as i32 }>(x.1),
+                        ),
+                    )
+                }};
+            }
+            n_lanes!(
+                super::arch::uint32x4x2_t,
+                "neon",
+                std::arch::is_aarch64_feature_detected!("neon"),
+                load = crate::md4::aarch64_simd_transpose::load_16x8,
+                add = add,
+                and = and,
+                or = or,
+                andnot = andnot,
+                xor = xor,
+                rol = (rotate_left!),
+                splat = splat,
+            );
+        }
+
         use super::Md4xN;
 
         impl Md4xN {
             /// Returns a SIMD implementation if one is available.
+            #[cfg(test)]
             pub fn select() -> Option<Md4xN> {
                 None
             }
+            /// Returns a SIMD implementation with at most `max_lanes` lanes if one is available.
+            pub fn select_at_most(_max_lanes: usize) -> Option<Md4xN> {
+                None
+            }
         }
     }
 }
@@ -435,38 +610,39 @@ mod simd {
 pub fn md4_many<'a>(
     datas: impl ExactSizeIterator<Item = &'a [u8]>,
 ) -> impl ExactSizeIterator<Item = (&'a [u8], [u8; 16])> {
-    struct SimdImpl<'a> {
-        simd_impl: simd::Md4xN,
-        buf: [(&'a [u8], [u8; 16]); simd::MAX_LANES],
-        buf_len: usize,
-    }
+    // Each batch takes the widest implementation that fits in what is left, so a short tail
+    // still runs on narrower lanes rather than one block at a time.
     struct It<'a, I: Iterator<Item = &'a [u8]>> {
         len: usize,
         inner: I,
-        simd: Option<SimdImpl<'a>>,
+        buf: [(&'a [u8], [u8; 16]); simd::MAX_LANES],
+        buf_lanes: usize,
+        buf_len: usize,
     }
     impl<'a, I: Iterator<Item = &'a [u8]>> Iterator for It<'a, I> {
         type Item = (&'a [u8], [u8; 16]);
         #[allow(clippy::needless_range_loop)]
         fn next(&mut self) -> Option<Self::Item> {
-            if let Some(simd) = &mut self.simd {
-                if simd.buf_len == 0 && self.len >= simd.simd_impl.lanes() {
+            if self.buf_len == 0 {
+                if let Some(simd_impl) = simd::Md4xN::select_at_most(self.len) {
+                    let lanes = simd_impl.lanes();
                     let mut datas: [&[u8]; simd::MAX_LANES] = [&[]; simd::MAX_LANES];
-                    for ix in 0..simd.simd_impl.lanes() {
+                    for ix in 0..lanes {
                         datas[ix] = self.inner.next().unwrap();
                     }
-                    self.len -= simd.simd_impl.lanes();
-                    let digests = simd.simd_impl.md4(&datas);
-                    simd.buf_len = simd.simd_impl.lanes();
-                    for lane in 0..simd.simd_impl.lanes() {
-                        simd.buf[lane] = (datas[lane], digests[lane]);
+                    self.len -= lanes;
+                    let digests = simd_impl.md4(&datas);
+                    self.buf_lanes = lanes;
+                    self.buf_len = lanes;
+                    for lane in 0..lanes {
+                        self.buf[lane] = (datas[lane], digests[lane]);
                     }
                 }
-                if simd.buf_len > 0 {
-                    let digest = simd.buf[simd.simd_impl.lanes() - simd.buf_len];
-                    simd.buf_len -= 1;
-                    return Some(digest);
-                }
+            }
+            if self.buf_len > 0 {
+                let digest = self.buf[self.buf_lanes - self.buf_len];
+                self.buf_len -= 1;
+                return Some(digest);
             }
             self.inner.next().map(|data| {
                 self.len -= 1;
@@ -474,22 +650,21 @@ pub fn md4_many<'a>(
             })
         }
         fn size_hint(&self) -> (usize, Option<usize>) {
-            (self.len, Some(self.len))
+            let len = self.len + self.buf_len;
+            (len, Some(len))
         }
     }
     impl<'a, I: Iterator<Item = &'a [u8]>> ExactSizeIterator for It<'a, I> {
         fn len(&self) -> usize {
-            self.len
+            self.len + self.buf_len
         }
     }
     It {
         len: datas.len(),
         inner: datas,
-        simd: simd::Md4xN::select().map(|simd_impl| SimdImpl {
-            simd_impl,
-            buf: [(&[] as &[_], [0; 16]); simd::MAX_LANES],
-            buf_len: 0,
-        }),
+        buf: [(&[] as &[_], [0; 16]); simd::MAX_LANES],
+        buf_lanes: 0,
+        buf_len: 0,
     }
 }
 
@@ -547,3 +722,18 @@ fn tests() {
         }
     }
 }
+
+#[test]
+fn md4_many_matches_md4() {
+    let data: Vec<u8> = (0..40 * 129).map(|i| (i * 7 + i / 13) as u8).collect();
+    for block_len in [0, 1, 55, 56, 64, 129] {
+        for count in [1, 3, 4, 7, 8, 15, 16, 17, 31, 40] {
+            let blocks: Vec<&[u8]> = (0..count)
+                .map(|i| &data[i * block_len..(i + 1) * block_len])
+                .collect();
+            let digests: Vec<_> = md4_many(blocks.iter().copied()).collect();
+            let expected: Vec<_> = blocks.iter().map(|block| (*block, md4(block))).collect();
+            assert_eq!(digests, expected, "{} blocks of {} bytes", count, block_len);
+        }
+    }
+}
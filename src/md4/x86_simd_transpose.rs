@@ -3,9 +3,11 @@
 use arrayref::array_ref;
 
 use self::arch::{
-    __m128i, __m256i, _mm256_castsi128_si256, _mm256_inserti128_si256, _mm256_unpackhi_epi32,
-    _mm256_unpackhi_epi64, _mm256_unpacklo_epi32, _mm256_unpacklo_epi64, _mm_loadu_si128,
-    _mm_unpackhi_epi32, _mm_unpackhi_epi64, _mm_unpacklo_epi32, _mm_unpacklo_epi64,
+    __m128i, __m256i, __m512i, _mm256_castsi128_si256, _mm256_inserti128_si256,
+    _mm256_unpackhi_epi32, _mm256_unpackhi_epi64, _mm256_unpacklo_epi32, _mm256_unpacklo_epi64,
+    _mm512_castsi128_si512, _mm512_inserti32x4, _mm512_unpackhi_epi32, _mm512_unpackhi_epi64,
+    _mm512_unpacklo_epi32, _mm512_unpacklo_epi64, _mm_loadu_si128, _mm_unpackhi_epi32,
+    _mm_unpackhi_epi64, _mm_unpacklo_epi32, _mm_unpacklo_epi64,
 };
 #[cfg(target_arch = "x86")]
 use std::arch::x86 as arch;
@@ -97,6 +99,68 @@ pub unsafe fn load_16x8_avx2<'a, F: Fn(usize) -> &'a [u8; 64]>(data: F) -> [__m2
     ])
 }
 
+/// Load 16 bytes (1 u32x4) out of each lane of `data`, transposed.
+#[inline]
+#[target_feature(enable = "avx512f")]
+unsafe fn load_transpose16(data: [&[u8; 16]; 16]) -> [__m512i; 4] {
+    #[inline(always)]
+    /// Concatenate four u32x4s into a single u32x16
+    unsafe fn cat4x4(a: __m128i, b: __m128i, c: __m128i, d: __m128i) -> __m512i {
+        // `vinserti32x4`
+        let abcd = _mm512_inserti32x4::<1>(_mm512_castsi128_si512(a), b);
+        let abcd = _mm512_inserti32x4::<2>(abcd, c);
+        _mm512_inserti32x4::<3>(abcd, d)
+    }
+
+    // Each 128-bit quarter is transposed like `load_transpose4`, quarter q holding the lanes
+    // 4q..4q + 4.
+    let i0 = cat4x4(
+        load_u32x4(data[0]),
+        load_u32x4(data[4]),
+        load_u32x4(data[8]),
+        load_u32x4(data[12]),
+    );
+    let i1 = cat4x4(
+        load_u32x4(data[1]),
+        load_u32x4(data[5]),
+        load_u32x4(data[9]),
+        load_u32x4(data[13]),
+    );
+    let i2 = cat4x4(
+        load_u32x4(data[2]),
+        load_u32x4(data[6]),
+        load_u32x4(data[10]),
+        load_u32x4(data[14]),
+    );
+    let i3 = cat4x4(
+        load_u32x4(data[3]),
+        load_u32x4(data[7]),
+        load_u32x4(data[11]),
+        load_u32x4(data[15]),
+    );
+    let l01 = _mm512_unpacklo_epi32(i0, i1);
+    let h01 = _mm512_unpackhi_epi32(i0, i1);
+    let l23 = _mm512_unpacklo_epi32(i2, i3);
+    let h23 = _mm512_unpackhi_epi32(i2, i3);
+    [
+        _mm512_unpacklo_epi64(l01, l23),
+        _mm512_unpackhi_epi64(l01, l23),
+        _mm512_unpacklo_epi64(h01, h23),
+        _mm512_unpackhi_epi64(h01, h23),
+    ]
+}
+
+#[inline]
+#[target_feature(enable = "avx512f")]
+pub unsafe fn load_16x16_avx512<'a, F: Fn(usize) -> &'a [u8; 64]>(data: F) -> [__m512i; 16] {
+    core::mem::transmute::<[[__m512i; 4]; 4], [__m512i; 16]>([
+        load_transpose16(get_blocks!(data, (0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15), 0, 16)),
+        load_transpose16(get_blocks!(data, (0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15), 16, 16)),
+        load_transpose16(get_blocks!(data, (0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15), 32, 16)),
+        load_transpose16(get_blocks!(data, (0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15), 48, 16)),
+    ])
+}
+
 /// Load 16 bytes (1 u32x4) out of each lane of `data`, transposed.
 #[inline]
 #[target_feature(enable = "sse2")]
@@ -0,0 +1,33 @@
+//! MD5 block hashes, the block checksums of the rsync protocol since version 30.
+
+use ::md5::{Digest, Md5};
+
+pub const MD5_SIZE: usize = 16;
+
+/// Compute the MD5 hash of a single block of data
+pub fn md5(data: &[u8]) -> [u8; MD5_SIZE] {
+    Md5::digest(data).into()
+}
+
+/// Compute MD5 hashes of multiple blocks of data
+pub fn md5_many<'a>(
+    datas: impl ExactSizeIterator<Item = &'a [u8]>,
+) -> impl ExactSizeIterator<Item = (&'a [u8], [u8; MD5_SIZE])> {
+    datas.map(|data| (data, md5(data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_md5() {
+        assert_eq!(
+            md5(b"abc"),
+            *b"\x90\x01\x50\x98\x3c\xd2\x4f\xb0\xd6\x96\x3f\x7d\x28\xe1\x7f\x72"
+        );
+        let datas = [&b"block1"[..], b"block2"];
+        let results: Vec<_> = md5_many(datas.into_iter()).collect();
+        assert_eq!(results, [(datas[0], md5(datas[0])), (datas[1], md5(datas[1]))]);
+    }
+}
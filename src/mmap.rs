@@ -0,0 +1,178 @@
+//! Memory-mapped variants of the core APIs.
+//!
+//! Mapping a file lets the kernel page the parts of it that are actually referenced in and out
+//! on demand, instead of first copying the whole file into anonymous memory.
+//!
+//! # Safety
+//! A mapped file must not be modified (by this or any other process) while it is mapped: the
+//! mapping would observe the change, which is undefined behavior for a `&[u8]`. The functions in
+//! this module are therefore `unsafe`, and callers must guarantee exclusive access to the files
+//! they pass in, for example through advisory locking or by only mapping immutable artifacts.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::diff::{diff, DiffError};
+use crate::file::{apply_file, open_sequential, same_file, whole_file_copy};
+use crate::patch::{apply, ApplyError};
+use crate::signature::{IndexedSignature, Signature, SignatureOptions};
+
+impl Signature {
+    /// Compute the signature of the file at `path`, hashing it straight from a read-only mapping.
+    ///
+    /// # Safety
+    /// The file must not be modified, by this or any other process, until this function returns.
+    pub unsafe fn calculate_file(
+        path: impl AsRef<Path>,
+        options: SignatureOptions,
+    ) -> io::Result<Signature> {
+        let buf = map(&open_sequential(path.as_ref())?)?;
+        Ok(Signature::calculate(buf.as_deref().unwrap_or_default(), options))
+    }
+}
+
+/// Calculate a delta from `signature` to the file at `path`, writing it to `out`.
+///
+/// The new data is searched straight from a read-only mapping of the file.
+///
+/// # Safety
+/// The file at `path` must not be modified, by this or any other process, until this function
+/// returns.
+pub unsafe fn diff_file(
+    signature: &IndexedSignature<'_>,
+    path: impl AsRef<Path>,
+    out: impl Write,
+) -> Result<(), DiffError> {
+    let data = map(&open_sequential(path.as_ref())?)?;
+    diff(signature, data.as_deref().unwrap_or_default(), out)
+}
+
+/// Like [apply_file](crate::apply_file), with copy commands served straight from a read-only
+/// mapping of the basis instead of a copy of it in memory. Applying in place, with `out_path`
+/// the same file as `base_path`, falls back to [apply_file](crate::apply_file).
+///
+/// # Safety
+/// The file at `base_path` must not be modified, by this or any other process, until this
+/// function returns.
+pub unsafe fn apply_file_mmap(
+    base_path: impl AsRef<Path>,
+    delta: &[u8],
+    out_path: impl AsRef<Path>,
+) -> Result<(), ApplyError> {
+    let base_path = base_path.as_ref();
+    let out_path = out_path.as_ref();
+    if same_file(base_path, out_path)? {
+        // Creating the output would truncate the mapping, so read the basis into memory instead.
+        return apply_file(base_path, delta, out_path);
+    }
+    if let Some(len) = whole_file_copy(delta) {
+        if fs::metadata(base_path)?.len() == len {
+            fs::copy(base_path, out_path)?;
+            return Ok(());
+        }
+    }
+    let mut out = BufWriter::new(File::create(out_path)?);
+    apply_mmap(&File::open(base_path)?, delta, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Apply `delta` to the contents of `base_file`, writing the result to `out`.
+///
+/// The basis is mapped read-only and copy commands are served straight from the mapping.
+///
+/// # Safety
+/// `base_file` must not be modified, by this or any other process, until this function returns.
+pub unsafe fn apply_mmap(
+    base_file: &File,
+    delta: &[u8],
+    out: &mut impl Write,
+) -> Result<(), ApplyError> {
+    let base = map(base_file)?;
+    apply(base.as_deref().unwrap_or_default(), delta, out)
+}
+
+/// Map `file` read-only, or return `None` if it is empty (zero-length mappings are not portable).
+unsafe fn map(file: &File) -> std::io::Result<Option<Mmap>> {
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    Mmap::map(file).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use super::{apply_file_mmap, apply_mmap, diff_file};
+    use crate::{diff, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_apply_mmap() {
+        let base: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut data = base.clone();
+        data.splice(70_000..70_100, b"hello world".iter().copied());
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(256, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).expect("diff error");
+
+        let path = std::env::temp_dir().join(format!("superfast_rsync_mmap_{}", std::process::id()));
+        fs::write(&path, &base).unwrap();
+        let mut out = vec![];
+        let result = unsafe { apply_mmap(&File::open(&path).unwrap(), &delta, &mut out) };
+        fs::remove_file(&path).unwrap();
+        result.expect("apply error");
+        assert_eq!(data, out);
+    }
+
+    #[test]
+    fn test_file_helpers() {
+        let base: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut data = base.clone();
+        data.splice(20_000..20_050, b"hello world".iter().copied());
+        let options = SignatureOptions::new(256, 8, HashAlgorithm::Blake3);
+        let path = |what: &str| {
+            std::env::temp_dir().join(format!("superfast_rsync_mmap_{}_{}", what, std::process::id()))
+        };
+        let (base_path, data_path, empty_path, out_path) =
+            (path("base"), path("data"), path("empty"), path("out"));
+        fs::write(&base_path, &base).unwrap();
+        fs::write(&data_path, &data).unwrap();
+        fs::write(&empty_path, b"").unwrap();
+
+        unsafe {
+            let signature = Signature::calculate_file(&base_path, options).unwrap();
+            assert_eq!(signature, Signature::calculate(&base, options));
+            let empty = Signature::calculate_file(&empty_path, options).unwrap();
+            assert_eq!(empty, Signature::calculate(&[], options));
+
+            let mut delta = vec![];
+            diff_file(&signature.index(), &data_path, &mut delta).expect("diff error");
+            apply_file_mmap(&base_path, &delta, &out_path).expect("apply error");
+            assert_eq!(fs::read(&out_path).unwrap(), data);
+
+            // An unchanged file takes the whole-file copy path.
+            let mut delta = vec![];
+            diff_file(&signature.index(), &base_path, &mut delta).expect("diff error");
+            apply_file_mmap(&base_path, &delta, &out_path).expect("apply error");
+            assert_eq!(fs::read(&out_path).unwrap(), base);
+
+            // In place, both unchanged and changed.
+            apply_file_mmap(&base_path, &delta, &base_path).expect("apply error");
+            assert_eq!(fs::read(&base_path).unwrap(), base);
+            let mut delta = vec![];
+            diff_file(&signature.index(), &data_path, &mut delta).expect("diff error");
+            apply_file_mmap(&base_path, &delta, &base_path).expect("apply error");
+            assert_eq!(fs::read(&base_path).unwrap(), data);
+        }
+        for path in [base_path, data_path, empty_path, out_path] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
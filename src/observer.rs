@@ -0,0 +1,152 @@
+//! Hooks into the events of a delta calculation.
+
+use std::io::Write;
+
+use crate::diff::{diff_observed_with, DiffError, DiffOptions};
+use crate::signature::IndexedSignature;
+
+/// Receives the events of the block matcher during [diff_observed], e.g. to collect telemetry or
+/// to tune the options of the next delta.
+///
+/// Positions are offsets in the new data. Every byte of it is reported exactly once, by
+/// [DiffObserver::block_matched], [DiffObserver::literal_flushed] or
+/// [DiffObserver::region_skipped]. All methods do nothing by default, and calls are made inline
+/// from the search loop, so they should be cheap.
+pub trait DiffObserver {
+    /// A block of the base data at `offset` matched `len` bytes at `position`.
+    fn block_matched(&mut self, position: u64, offset: u64, len: u64) {
+        let _ = (position, offset, len);
+    }
+
+    /// The block at `position` has the CRC `crc` of a block of the base data, but not its strong
+    /// hash. `blacklisted` is set for the collision after which the CRC is no longer looked up.
+    fn collision(&mut self, position: u64, crc: u32, blacklisted: bool) {
+        let _ = (position, crc, blacklisted);
+    }
+
+    /// `len` bytes at `position` were written as a literal, or as repeats in v2 deltas.
+    fn literal_flushed(&mut self, position: u64, len: u64) {
+        let _ = (position, len);
+    }
+
+    /// `len` bytes at `position` are identical to the base data at the start or end and were
+    /// copied without searching them.
+    fn region_skipped(&mut self, position: u64, len: u64) {
+        let _ = (position, len);
+    }
+}
+
+/// The observer of the other diff functions, which ignores everything.
+impl DiffObserver for () {}
+
+impl<T: DiffObserver + ?Sized> DiffObserver for &mut T {
+    fn block_matched(&mut self, position: u64, offset: u64, len: u64) {
+        (**self).block_matched(position, offset, len)
+    }
+
+    fn collision(&mut self, position: u64, crc: u32, blacklisted: bool) {
+        (**self).collision(position, crc, blacklisted)
+    }
+
+    fn literal_flushed(&mut self, position: u64, len: u64) {
+        (**self).literal_flushed(position, len)
+    }
+
+    fn region_skipped(&mut self, position: u64, len: u64) {
+        (**self).region_skipped(position, len)
+    }
+}
+
+/// Calculate a delta like [diff()](crate::diff()), reporting the events of the matcher to
+/// `observer`.
+///
+/// # Security
+/// See [diff()](crate::diff()).
+pub fn diff_observed(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    observer: &mut impl DiffObserver,
+    out: impl Write,
+) -> Result<(), DiffError> {
+    let options = DiffOptions::default();
+    diff_observed_with(signature, data, None, &options, false, observer, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{diff_observed, DiffObserver};
+    use crate::{apply, diff, HashAlgorithm, Signature, SignatureOptions};
+
+    #[derive(Default)]
+    struct Tally {
+        covered: Vec<(u64, u64)>,
+        matched: u64,
+        collisions: u64,
+        literals: u64,
+        skipped: u64,
+    }
+
+    impl DiffObserver for Tally {
+        fn block_matched(&mut self, position: u64, _offset: u64, len: u64) {
+            self.covered.push((position, len));
+            self.matched += len;
+        }
+
+        fn collision(&mut self, _position: u64, _crc: u32, _blacklisted: bool) {
+            self.collisions += 1;
+        }
+
+        fn literal_flushed(&mut self, position: u64, len: u64) {
+            self.covered.push((position, len));
+            self.literals += len;
+        }
+
+        fn region_skipped(&mut self, position: u64, len: u64) {
+            self.covered.push((position, len));
+            self.skipped += len;
+        }
+    }
+
+    #[test]
+    fn test_diff_observed() {
+        let mut base = vec![0; 64 * 1024];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut data = base.clone();
+        data[20_000..20_100].fill(0);
+        data.splice(40_000..40_000, *b"inserted");
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(256, 8, HashAlgorithm::Blake3),
+        );
+        let indexed = signature.index();
+        let mut tally = Tally::default();
+        let mut delta = vec![];
+        diff_observed(&indexed, &data, &mut tally, &mut delta).unwrap();
+
+        let mut plain = vec![];
+        diff(&indexed, &data, &mut plain).unwrap();
+        assert_eq!(delta, plain);
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        tally.covered.sort_unstable();
+        let mut position = 0;
+        for &(start, len) in &tally.covered {
+            assert_eq!(start, position);
+            position += len;
+        }
+        assert_eq!(position, data.len() as u64);
+        assert!(tally.skipped >= 20_000 && tally.matched > 0 && tally.literals > 0);
+        assert!(tally.literals < 2 * 256 + 2 * 256 + 8);
+
+        // Appending skips the search altogether.
+        let mut tally = Tally::default();
+        let appended = [&base[..], b"tail"].concat();
+        diff_observed(&indexed, &appended, &mut tally, &mut vec![]).unwrap();
+        assert_eq!((tally.skipped, tally.literals), (base.len() as u64, 4));
+        assert_eq!((tally.matched, tally.collisions), (0, 0));
+    }
+}
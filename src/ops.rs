@@ -0,0 +1,225 @@
+//! Encoding and decoding single delta ops.
+//!
+//! [diff()](crate::diff()) and [apply()](crate::apply()) handle whole deltas. Protocols that
+//! interleave their own framing with rsync ops can use these instead, so they pick the same size
+//! classes as this crate. See [consts](crate::consts) for the format.
+
+use std::io::{self, Write};
+
+use crate::consts::{
+    RS_OP_COPY_N1_N1, RS_OP_COPY_N8_N8, RS_OP_END, RS_OP_LITERAL_1, RS_OP_LITERAL_64,
+    RS_OP_LITERAL_N1, RS_OP_LITERAL_N2, RS_OP_LITERAL_N4, RS_OP_LITERAL_N8, RS_OP_REPEAT_N1,
+    RS_OP_REPEAT_N8,
+};
+use crate::patch::ApplyError;
+use crate::strict::{invalid_input, require};
+
+/// A decoded op header. The data of a literal and the pattern of a repeat follow their header in
+/// the delta.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OpHeader {
+    /// The end of the delta.
+    End,
+    /// A literal of this many bytes.
+    Literal(u64),
+    /// Append `len` bytes of the base data, starting at `offset`.
+    Copy {
+        /// Where the copied bytes start in the base data.
+        offset: u64,
+        /// How many bytes to copy.
+        len: u64,
+    },
+    /// Append a pattern of `pattern_len` bytes `count` times. Only valid in v2 deltas.
+    Repeat {
+        /// How many times the pattern is appended.
+        count: u64,
+        /// The length of the pattern.
+        pattern_len: u8,
+    },
+}
+
+/// Write the header of a literal of `len` bytes to `out`, in the smallest size class.
+///
+/// The caller writes the literal bytes after it.
+///
+/// # Panics
+/// Panics if `len` is zero, which the format can't express, unless the `strict` feature turns
+/// that into an [io::ErrorKind::InvalidInput] error.
+pub fn encode_literal_header(len: u64, out: &mut impl Write) -> io::Result<()> {
+    require!(len != 0, invalid_input("len"));
+    if len <= 64 {
+        out.write_all(&[RS_OP_LITERAL_1 + (len - 1) as u8])?;
+    } else if len <= u8::MAX as u64 {
+        out.write_all(&[RS_OP_LITERAL_N1, len as u8])?;
+    } else if len <= u16::MAX as u64 {
+        let [v1, v2] = (len as u16).to_be_bytes();
+        out.write_all(&[RS_OP_LITERAL_N2, v1, v2])?;
+    } else if len <= u32::MAX as u64 {
+        let [v1, v2, v3, v4] = (len as u32).to_be_bytes();
+        out.write_all(&[RS_OP_LITERAL_N4, v1, v2, v3, v4])?;
+    } else {
+        let [v1, v2, v3, v4, v5, v6, v7, v8] = len.to_be_bytes();
+        out.write_all(&[RS_OP_LITERAL_N8, v1, v2, v3, v4, v5, v6, v7, v8])?;
+    }
+
+    Ok(())
+}
+
+/// Write a copy of `len` bytes of the base data starting at `offset` to `out`, in the smallest
+/// size classes.
+pub fn encode_copy(offset: u64, len: u64, out: &mut impl Write) -> io::Result<()> {
+    fn size_class_marker(offset: u64, len: u64) -> u8 {
+        let offset_len = u64_size_class(offset);
+        let len_len = u64_size_class(len);
+
+        RS_OP_COPY_N1_N1 + offset_len * 4 + len_len
+    }
+
+    let marker = size_class_marker(offset, len);
+    out.write_all(&[marker])?;
+    write_varint(offset, out)?;
+    write_varint(len, out)?;
+
+    Ok(())
+}
+
+/// Write a repeat of `pattern`, `count` times, to `out`. Only v2 deltas may contain repeats.
+///
+/// # Panics
+/// Panics if `pattern` is empty or longer than 255 bytes, or if `count` is zero, unless the
+/// `strict` feature turns that into an [io::ErrorKind::InvalidInput] error.
+pub fn encode_repeat(pattern: &[u8], count: u64, out: &mut impl Write) -> io::Result<()> {
+    require!(
+        !pattern.is_empty() && pattern.len() <= u8::MAX as usize && count != 0,
+        invalid_input("pattern")
+    );
+    out.write_all(&[RS_OP_REPEAT_N1 + u64_size_class(count)])?;
+    write_varint(count, out)?;
+    out.write_all(&[pattern.len() as u8])?;
+    out.write_all(pattern)
+}
+
+fn u64_size_class(val: u64) -> u8 {
+    if val <= u8::MAX as u64 {
+        0
+    } else if val <= u16::MAX as u64 {
+        1
+    } else if val <= u32::MAX as u64 {
+        2
+    } else {
+        3
+    }
+}
+
+fn write_varint(val: u64, out: &mut impl Write) -> io::Result<()> {
+    if val <= u8::MAX as u64 {
+        out.write_all(&[val as u8])?;
+    } else if val <= u16::MAX as u64 {
+        out.write_all(&(val as u16).to_be_bytes())?;
+    } else if val <= u32::MAX as u64 {
+        out.write_all(&(val as u32).to_be_bytes())?;
+    } else {
+        out.write_all(&val.to_be_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Decode the op header at the start of `input`, returning it and its length in bytes.
+///
+/// For a literal, the literal bytes follow the header and aren't consumed, and neither is the
+/// pattern of a repeat.
+pub fn decode_op_header(input: &[u8]) -> Result<(OpHeader, usize), ApplyError> {
+    let (&cmd, mut rest) = input.split_first().ok_or(ApplyError::UnexpectedEof {
+        reading: "cmd",
+        expected: 1,
+        available: 0,
+    })?;
+    let header = decode_header(cmd, |len, reading| {
+        if rest.len() < len {
+            return Err(ApplyError::UnexpectedEof {
+                reading,
+                expected: len,
+                available: rest.len(),
+            });
+        }
+        let mut b = [0; 8];
+        b[8 - len..].copy_from_slice(&rest[..len]);
+        rest = &rest[len..];
+        Ok(u64::from_be_bytes(b))
+    })?;
+    Ok((header, input.len() - rest.len()))
+}
+
+/// Decode command `cmd`, reading its parameters with `read_varint(len, reading)`.
+pub(crate) fn decode_header(
+    cmd: u8,
+    mut read_varint: impl FnMut(usize, &'static str) -> Result<u64, ApplyError>,
+) -> Result<OpHeader, ApplyError> {
+    match cmd {
+        RS_OP_END => Ok(OpHeader::End),
+        RS_OP_LITERAL_1..=RS_OP_LITERAL_N8 => {
+            if cmd <= RS_OP_LITERAL_64 {
+                // <=64, length is encoded in `cmd`
+                Ok(OpHeader::Literal((1 + cmd - RS_OP_LITERAL_1) as u64))
+            } else {
+                let n = read_varint(1 << (cmd - RS_OP_LITERAL_N1) as usize, "literal length")?;
+                Ok(OpHeader::Literal(n))
+            }
+        }
+        RS_OP_COPY_N1_N1..=RS_OP_COPY_N8_N8 => {
+            let mode = cmd - RS_OP_COPY_N1_N1;
+            let offset_len = 1 << (mode / 4) as usize;
+            let len_len = 1 << (mode % 4) as usize;
+            let offset = read_varint(offset_len, "copy offset")?;
+            let len = read_varint(len_len, "copy length")?;
+            Ok(OpHeader::Copy { offset, len })
+        }
+        RS_OP_REPEAT_N1..=RS_OP_REPEAT_N8 => {
+            let count = read_varint(1 << (cmd - RS_OP_REPEAT_N1) as usize, "repeat count")?;
+            let pattern_len = read_varint(1, "repeat pattern length")? as u8;
+            Ok(OpHeader::Repeat { count, pattern_len })
+        }
+        _ => Err(ApplyError::UnknownCommand { command: cmd }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_op_header, encode_copy, encode_literal_header, encode_repeat, OpHeader};
+
+    #[test]
+    fn test_op_round_trip() {
+        for len in [1, 64, 65, 255, 256, 65536, 1 << 32] {
+            let mut out = vec![];
+            encode_literal_header(len, &mut out).unwrap();
+            out.push(0xff);
+            assert_eq!(
+                decode_op_header(&out).unwrap(),
+                (OpHeader::Literal(len), out.len() - 1)
+            );
+        }
+        for (offset, len) in [(0, 1), (300, 2), (1 << 20, 70000), (1 << 40, 1 << 33)] {
+            let mut out = vec![];
+            encode_copy(offset, len, &mut out).unwrap();
+            assert_eq!(
+                decode_op_header(&out).unwrap(),
+                (OpHeader::Copy { offset, len }, out.len())
+            );
+            assert!(decode_op_header(&out[..out.len() - 1]).is_err());
+        }
+        for count in [1, 255, 256, 1 << 20, 1 << 40] {
+            let mut out = vec![];
+            encode_repeat(&[0xde, 0xad], count, &mut out).unwrap();
+            let header = OpHeader::Repeat {
+                count,
+                pattern_len: 2,
+            };
+            assert_eq!(decode_op_header(&out).unwrap(), (header, out.len() - 2));
+        }
+        assert_eq!(decode_op_header(&[0]).unwrap(), (OpHeader::End, 1));
+        assert!(decode_op_header(&[]).is_err());
+        assert!(decode_op_header(&[0xff]).is_err());
+    }
+}
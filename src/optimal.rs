@@ -0,0 +1,384 @@
+//! Calculating the smallest delta for small inputs.
+//!
+//! [diff()](crate::diff()) commits to the first match it finds at every position. [diff_optimal]
+//! instead looks up the blocks of the base data at every position of the new data first, and
+//! then picks the sequence of literals and copies with the fewest encoded bytes by dynamic
+//! programming, taking the header sizes of the ops into account. The cost of a literal or copy
+//! op only depends on the size class of its length, so for every position the cheapest op ending
+//! there is found with a sliding window minimum per size class.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+
+use crate::consts::{DELTA_MAGIC, RS_OP_END};
+use crate::crc::Crc;
+use crate::diff::{
+    append_delta, check_signature, common_prefix, common_suffix, crypto_hash, diff, DiffError,
+    MAX_CRC_COLLISIONS,
+};
+use crate::hasher::BuildCrcHasher;
+use crate::ops::{encode_copy, encode_literal_header};
+use crate::signature::IndexedSignature;
+
+/// Inputs larger than this are diffed with [diff()](crate::diff()) by [diff_optimal], since the
+/// search needs a few dozen bytes of memory per byte of input.
+pub const OPTIMAL_MAX_LEN: usize = 4 << 20;
+
+/// The size classes of literal lengths: the longest literal of each class and the size of its
+/// op header.
+const LITERAL_CLASSES: [(u64, u64); 5] = [
+    (64, 1),
+    (u8::MAX as u64, 2),
+    (u16::MAX as u64, 3),
+    (u32::MAX as u64, 5),
+    (u64::MAX, 9),
+];
+
+/// The size classes of copy offsets and lengths: the largest value of each class and its
+/// encoded size.
+const COPY_CLASSES: [(u64, u64); 4] = [
+    (u8::MAX as u64, 1),
+    (u16::MAX as u64, 2),
+    (u32::MAX as u64, 4),
+    (u64::MAX, 8),
+];
+
+/// Marks positions without a matching block and literal steps.
+const NONE: u32 = u32::MAX;
+
+/// Calculate the smallest delta from the base data represented by `signature` to `data` that
+/// copies whole blocks, and write it to `out`.
+///
+/// This is slower than [diff()](crate::diff()) and needs memory proportional to `data`, in
+/// exchange for every byte of delta size, which matters for updates shipped to many devices,
+/// like mobile OTA updates. Inputs longer than [OPTIMAL_MAX_LEN] are diffed with
+/// [diff()](crate::diff()) instead.
+///
+/// # Security
+/// See [diff()](crate::diff()).
+pub fn diff_optimal(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    mut out: impl Write,
+) -> Result<(), DiffError> {
+    if data.len() > OPTIMAL_MAX_LEN {
+        return diff(signature, data, out);
+    }
+    check_signature(signature)?;
+    let prefix = common_prefix(signature, data)?;
+    if prefix.append {
+        return Ok(append_delta(prefix.len, data, false, out)?);
+    }
+    // The last block of the base data may be shorter than a block, so it is only found here.
+    let (suffix, suffix_offset) = common_suffix(signature, data, &prefix)?;
+    let end = data.len() - suffix;
+
+    let block_size = signature.block_size as usize;
+    let matches = find_matches(signature, &data[..end])?;
+    let mut steps = cheapest_steps(signature, &matches, block_size);
+    if suffix > 0 {
+        steps.push((data.len(), Some(suffix_offset)));
+    }
+
+    out.write_all(&DELTA_MAGIC.to_be_bytes())?;
+    let mut pending_copy: Option<(u64, u64)> = None;
+    let mut position = 0;
+    for (step_end, copy) in steps {
+        let len = (step_end - position) as u64;
+        match copy {
+            Some(offset) => match &mut pending_copy {
+                Some((start, pending_len)) if *start + *pending_len == offset => {
+                    *pending_len += len
+                }
+                _ => {
+                    if let Some((offset, len)) = pending_copy.replace((offset, len)) {
+                        encode_copy(offset, len, &mut out)?;
+                    }
+                }
+            },
+            None => {
+                if let Some((offset, len)) = pending_copy.take() {
+                    encode_copy(offset, len, &mut out)?;
+                }
+                encode_literal_header(len, &mut out)?;
+                out.write_all(&data[position..step_end])?;
+            }
+        }
+        position = step_end;
+    }
+    if let Some((offset, len)) = pending_copy {
+        encode_copy(offset, len, &mut out)?;
+    }
+    out.write_all(&[RS_OP_END])?;
+    Ok(())
+}
+
+/// The block of the base data found at every position of `data`, or [NONE].
+fn find_matches(signature: &IndexedSignature<'_>, data: &[u8]) -> Result<Vec<u32>, DiffError> {
+    let block_size = signature.block_size as usize;
+    let crypto_hash_size = signature.crypto_hash_size as usize;
+    let mut matches = vec![NONE; data.len()];
+    if data.len() < block_size {
+        return Ok(matches);
+    }
+    let mut collisions: HashMap<Crc, u32, BuildCrcHasher> =
+        HashMap::with_hasher(BuildCrcHasher::default());
+    let mut crc = Crc::new().update(&data[..block_size]);
+    for position in 0..=data.len() - block_size {
+        if position > 0 {
+            crc = crc.rotate(
+                block_size as u32,
+                data[position - 1],
+                data[position + block_size - 1],
+            );
+        }
+        // if we detect too many CRC collisions, blacklist the CRC to avoid DoS
+        if collisions
+            .get(&crc)
+            .is_some_and(|&count| count >= MAX_CRC_COLLISIONS)
+        {
+            continue;
+        }
+        let Some(blocks) = signature.blocks.get(&crc) else {
+            continue;
+        };
+        let digest = crypto_hash(signature, &data[position..position + block_size])?;
+        match blocks.get(&digest[..crypto_hash_size]) {
+            Some(idx) => matches[position] = idx,
+            None => *collisions.entry(crc).or_insert(0) += 1,
+        }
+    }
+    Ok(matches)
+}
+
+/// The minimum of a key over a window of candidates that slides forward.
+#[derive(Default)]
+struct WindowMin {
+    deque: VecDeque<(usize, i64)>,
+}
+
+impl WindowMin {
+    fn push(&mut self, candidate: usize, key: i64) {
+        while self.deque.back().is_some_and(|&(_, back)| back >= key) {
+            self.deque.pop_back();
+        }
+        self.deque.push_back((candidate, key));
+    }
+
+    /// The candidate with the smallest key among those from `first` on.
+    fn min(&mut self, first: usize) -> Option<(usize, i64)> {
+        while self.deque.front().is_some_and(|&(candidate, _)| candidate < first) {
+            self.deque.pop_front();
+        }
+        self.deque.front().copied()
+    }
+}
+
+/// Consecutive positions of the new data, a block apart, that match consecutive blocks of the
+/// base data, and could therefore be copied by a single op.
+struct Chain {
+    /// The position and block of every step, and the cost of starting a copy there.
+    steps: Vec<(usize, u32, i64)>,
+    /// For every size class of copy lengths, the cheapest start among the steps pushed so far.
+    windows: [WindowMin; COPY_CLASSES.len()],
+    /// For every size class, how many steps were pushed to its window.
+    pushed: [usize; COPY_CLASSES.len()],
+}
+
+impl Chain {
+    /// The cost, start and block of the cheapest copy ending after the last step.
+    fn cheapest_copy(&mut self, block_size: usize) -> Option<(u64, usize, u32)> {
+        let blocks = self.steps.len();
+        let mut best: Option<(u64, usize, u32)> = None;
+        let mut fewest = 1;
+        for (class, (largest, len_size)) in COPY_CLASSES.iter().enumerate() {
+            // copies of `fewest..=most` blocks are in this class
+            let most = (*largest / block_size as u64).min(usize::MAX as u64) as usize;
+            if most >= fewest && blocks >= fewest {
+                while self.pushed[class] <= blocks - fewest {
+                    let (_, _, key) = self.steps[self.pushed[class]];
+                    self.windows[class].push(self.pushed[class], key);
+                    self.pushed[class] += 1;
+                }
+                if let Some((first, key)) = self.windows[class].min(blocks.saturating_sub(most)) {
+                    let total = key as u64 + len_size;
+                    if best.is_none_or(|(best, _, _)| total < best) {
+                        let (start, block, _) = self.steps[first];
+                        best = Some((total, start, block));
+                    }
+                }
+            }
+            fewest = most.saturating_add(1);
+        }
+        best
+    }
+}
+
+/// The cost and start of the cheapest literal ending at `j`.
+fn cheapest_literal(
+    j: usize,
+    cost: &[u64],
+    windows: &mut [WindowMin; LITERAL_CLASSES.len()],
+) -> (u64, usize) {
+    let mut best = (u64::MAX, 0);
+    let mut shortest = 1;
+    for ((longest, header), window) in LITERAL_CLASSES.iter().zip(windows) {
+        // literals of `shortest..=longest` bytes are in this class
+        if j >= shortest {
+            let start = j - shortest;
+            window.push(start, cost[start] as i64 - start as i64);
+        }
+        let first = (j as u64).saturating_sub(*longest) as usize;
+        if let Some((start, key)) = window.min(first) {
+            let total = (key + j as i64) as u64 + header;
+            if total < best.0 {
+                best = (total, start);
+            }
+        }
+        shortest = longest.saturating_add(1) as usize;
+    }
+    best
+}
+
+/// The ops of the cheapest encoding of the data in `matches`, as the end of every op and the
+/// offset of the copy, or `None` for a literal.
+fn cheapest_steps(
+    signature: &IndexedSignature<'_>,
+    matches: &[u32],
+    block_size: usize,
+) -> Vec<(usize, Option<u64>)> {
+    let n = matches.len();
+    let entry_size = Crc::SIZE + signature.crypto_hash_size as usize;
+    let entry = |idx: u32| {
+        signature
+            .entries
+            .get(idx as usize * entry_size..(idx as usize + 1) * entry_size)
+    };
+    let copy_size = |val: u64| COPY_CLASSES.iter().find(|(max, _)| val <= *max).unwrap().1;
+
+    // The cost of the cheapest encoding of the first `j` bytes, and its last op as its start and
+    // copied block.
+    let mut cost = vec![0u64; n + 1];
+    let mut last_op = vec![(0u32, NONE); n + 1];
+    let mut literals: [WindowMin; LITERAL_CLASSES.len()] = Default::default();
+    // The chain every position is part of, by position modulo the block size.
+    let mut chains: HashMap<usize, Chain> = HashMap::new();
+    for j in 0..=n {
+        if j > 0 {
+            let (mut total, mut start) = cheapest_literal(j, &cost, &mut literals);
+            let mut block = NONE;
+            let chain = j
+                .checked_sub(block_size)
+                .filter(|&p| matches[p] != NONE)
+                .and_then(|p| chains.get_mut(&(p % block_size)));
+            if let Some((copy_total, copy_start, copy_block)) =
+                chain.and_then(|chain| chain.cheapest_copy(block_size))
+            {
+                if copy_total < total {
+                    (total, start, block) = (copy_total, copy_start, copy_block);
+                }
+            }
+            cost[j] = total;
+            last_op[j] = (start as u32, block);
+        }
+
+        // Make the block at `j` a start for copies, continuing the chain of the block before it.
+        if j < n && matches[j] != NONE {
+            let residue = j % block_size;
+            let continued = chains.get(&residue).and_then(|chain| {
+                let &(position, block, _) = chain.steps.last()?;
+                let next = block.checked_add(1)?;
+                let continues = position + block_size == j
+                    && entry(next).is_some()
+                    && entry(next) == entry(matches[j]);
+                continues.then_some(next)
+            });
+            let block = continued.unwrap_or(matches[j]);
+            if continued.is_none() {
+                chains.insert(
+                    residue,
+                    Chain {
+                        steps: Vec::new(),
+                        windows: Default::default(),
+                        pushed: [0; COPY_CLASSES.len()],
+                    },
+                );
+            }
+            let offset = block as u64 * block_size as u64;
+            let key = (cost[j] + 1 + copy_size(offset)) as i64;
+            chains.get_mut(&residue).unwrap().steps.push((j, block, key));
+        }
+    }
+
+    let mut steps = Vec::new();
+    let mut j = n;
+    while j > 0 {
+        let (start, block) = last_op[j];
+        steps.push((j, (block != NONE).then(|| block as u64 * block_size as u64)));
+        j = start as usize;
+    }
+    steps.reverse();
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::diff_optimal;
+    use crate::{apply, diff, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_diff_optimal() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let mut base = vec![0; rng.gen_range(1000..20_000)];
+            rng.fill(&mut base[..]);
+            let mut data = base.clone();
+            for _ in 0..rng.gen_range(0..10) {
+                let at = rng.gen_range(0..data.len());
+                match rng.gen_range(0..3) {
+                    0 => data[at] = rng.gen(),
+                    1 => {
+                        data.remove(at);
+                    }
+                    _ => data.insert(at, rng.gen()),
+                }
+            }
+            let signature = Signature::calculate(
+                &base,
+                SignatureOptions::new(64, 8, HashAlgorithm::Blake3),
+            );
+            let indexed = signature.index();
+            let mut greedy = vec![];
+            diff(&indexed, &data, &mut greedy).unwrap();
+            let mut optimal = vec![];
+            diff_optimal(&indexed, &data, &mut optimal).unwrap();
+            let mut out = vec![];
+            apply(&base, &optimal, &mut out).unwrap();
+            assert_eq!(out, data);
+            assert!(optimal.len() <= greedy.len(), "{} vs {}", optimal.len(), greedy.len());
+        }
+
+        // The first block of the new data is block 10, which ends like block 2 starts, so taking
+        // it misaligns the run of blocks 2 to 7 that follows.
+        let mut base = vec![0; 20 * 64];
+        rng.fill(&mut base[..]);
+        base.copy_within(2 * 64..2 * 64 + 32, 10 * 64 + 32);
+        let mut data = base[10 * 64..10 * 64 + 32].to_vec();
+        data.extend_from_slice(&base[2 * 64..8 * 64]);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(64, 8, HashAlgorithm::Md4),
+        );
+        let indexed = signature.index();
+        let mut greedy = vec![];
+        diff(&indexed, &data, &mut greedy).unwrap();
+        let mut optimal = vec![];
+        diff_optimal(&indexed, &data, &mut optimal).unwrap();
+        let mut out = vec![];
+        apply(&base, &optimal, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(optimal.len() < greedy.len(), "{} vs {}", optimal.len(), greedy.len());
+    }
+}
@@ -0,0 +1,298 @@
+//! Constrained in-place apply for firmware over-the-air updates.
+//!
+//! Microcontrollers usually can't hold a second copy of their firmware, so the new image is
+//! written over the old one, in order, one flash erase block at a time. Once an erase block has
+//! been rewritten its old contents are gone, so a copy that reads from there can only be served
+//! from a small RAM "lookback window" that keeps the most recently overwritten basis bytes.
+//!
+//! Besides the delta itself, [apply] needs exactly one erase block plus the lookback window of
+//! RAM, no matter how large the image is. The whole delta must be in memory: it is read twice,
+//! once by [check] before anything is written, so a delta that would need more lookback than
+//! configured fails without touching the flash, and once more to apply it. Devices that can't
+//! hold the delta can store it in a spare flash region and map or copy it from there, or
+//! stream it through [apply_with_scratch](crate::apply_with_scratch) into a separate slot.
+
+use std::io;
+
+use crate::patch::{fill_repeated, ApplyError, Op, OpReader};
+use crate::strict::require;
+
+/// Flash memory holding the basis image, which is overwritten with the output.
+pub trait Flash {
+    /// Read `buf.len()` bytes starting at `offset`.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Erase the erase block starting at `offset` and program it with `data`.
+    ///
+    /// `offset` is a multiple of the erase block size, and `data` is a full erase block except
+    /// for the last block of the output.
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+}
+
+/// Options for [check] and [apply].
+#[derive(Copy, Clone, Debug)]
+pub struct OtaOptions {
+    /// The size of a flash erase block, which is also the unit output is written in.
+    pub erase_block_size: usize,
+    /// How many of the most recently overwritten basis bytes are kept in RAM.
+    pub lookback_window: usize,
+}
+
+/// Check that `delta` can be applied in place with `options` to a basis of `base_len` bytes.
+///
+/// Returns the length of the output.
+pub fn check(delta: &[u8], base_len: u64, options: &OtaOptions) -> Result<u64, ApplyError> {
+    require!(
+        options.erase_block_size > 0,
+        ApplyError::InvalidArgument { name: "erase_block_size" }
+    );
+    let mut ops = OpReader::new(delta)?;
+    let mut position = 0u64;
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Literal(_) | Op::Repeat { .. } => {
+                position = position.saturating_add(op.output_len())
+            }
+            Op::Copy { offset, len } => {
+                check_copy(offset, len, base_len)?;
+                for_each_segment(position, offset, len, options, |source, _, overwritten| {
+                    check_lookback(source, overwritten, options)
+                })?;
+                position += len;
+            }
+        }
+    }
+    Ok(position)
+}
+
+/// Apply `delta` in place to the basis image of `base_len` bytes stored in `flash`.
+///
+/// The delta is [check]ed first, so invalid deltas are rejected before the flash is modified.
+/// This reads `delta` twice, so all of it must be in memory; see [the module docs](self).
+/// Returns the length of the output. Errors reading the flash are returned as
+/// [ApplyError::ReadIo], errors writing it as [ApplyError::Io].
+pub fn apply(
+    flash: &mut impl Flash,
+    base_len: u64,
+    delta: &[u8],
+    options: &OtaOptions,
+) -> Result<u64, ApplyError> {
+    check(delta, base_len, options)?;
+    let mut state = InPlaceWriter {
+        flash,
+        base_len,
+        block: vec![0; options.erase_block_size],
+        fill: 0,
+        overwritten: 0,
+        window: vec![0; options.lookback_window],
+    };
+    let mut ops = OpReader::new(delta)?;
+    let mut position = 0;
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Literal(mut literal) => {
+                position += literal.len() as u64;
+                while !literal.is_empty() {
+                    let n = literal.len().min(state.block.len() - state.fill);
+                    state.block[state.fill..state.fill + n].copy_from_slice(&literal[..n]);
+                    literal = &literal[n..];
+                    state.advance(n)?;
+                }
+            }
+            Op::Repeat { pattern, .. } => {
+                let len = op.output_len();
+                let mut done = 0;
+                while done < len {
+                    let n = (len - done).min((state.block.len() - state.fill) as u64) as usize;
+                    fill_repeated(pattern, done, &mut state.block[state.fill..state.fill + n]);
+                    done += n as u64;
+                    state.advance(n)?;
+                }
+                position += len;
+            }
+            Op::Copy { offset, len } => {
+                for_each_segment(position, offset, len, options, |source, n, _| {
+                    state.copy(source, n)?;
+                    state.advance(n)
+                })?;
+                position += len;
+            }
+        }
+    }
+    state.flush()?;
+    Ok(state.overwritten)
+}
+
+fn check_copy(offset: u64, len: u64, base_len: u64) -> Result<(), ApplyError> {
+    if len == 0 {
+        return Err(ApplyError::CopyZero);
+    }
+    match offset.checked_add(len) {
+        Some(end) if end <= base_len => Ok(()),
+        _ => Err(ApplyError::CopyOutOfBounds {
+            offset,
+            len,
+            data_len: base_len as usize,
+        }),
+    }
+}
+
+fn check_lookback(source: u64, overwritten: u64, options: &OtaOptions) -> Result<(), ApplyError> {
+    let available_from = overwritten.saturating_sub(options.lookback_window as u64);
+    if source < available_from {
+        return Err(ApplyError::CopyOutsideWindow {
+            offset: source,
+            available_from,
+        });
+    }
+    Ok(())
+}
+
+/// Split the copy of `len` bytes from `offset` to output `position` at erase block boundaries,
+/// and call `f(source, len, overwritten)` for each piece, where `overwritten` is how
+/// much of the basis has been overwritten by the time that piece is written.
+fn for_each_segment(
+    mut position: u64,
+    mut source: u64,
+    mut len: u64,
+    options: &OtaOptions,
+    mut f: impl FnMut(u64, usize, u64) -> Result<(), ApplyError>,
+) -> Result<(), ApplyError> {
+    let erase_block_size = options.erase_block_size as u64;
+    while len > 0 {
+        let overwritten = position / erase_block_size * erase_block_size;
+        let n = len.min(overwritten + erase_block_size - position);
+        f(source, n as usize, overwritten)?;
+        position += n;
+        source += n;
+        len -= n;
+    }
+    Ok(())
+}
+
+struct InPlaceWriter<'a, F> {
+    flash: &'a mut F,
+    base_len: u64,
+    /// The output erase block being assembled.
+    block: Vec<u8>,
+    fill: usize,
+    /// Everything before this offset has been overwritten with output.
+    overwritten: u64,
+    /// The old contents of `overwritten - window.len()..overwritten`, stored at `offset % len`.
+    window: Vec<u8>,
+}
+
+impl<F: Flash> InPlaceWriter<'_, F> {
+    /// Read `n` basis bytes from `source` into the current block.
+    fn copy(&mut self, mut source: u64, n: usize) -> Result<(), ApplyError> {
+        let mut dest = &mut self.block[self.fill..self.fill + n];
+        // The part that was already overwritten is served from the window.
+        while source < self.overwritten && !dest.is_empty() {
+            let index = (source % self.window.len() as u64) as usize;
+            let m = dest
+                .len()
+                .min(self.window.len() - index)
+                .min((self.overwritten - source) as usize);
+            dest[..m].copy_from_slice(&self.window[index..index + m]);
+            dest = &mut dest[m..];
+            source += m as u64;
+        }
+        if !dest.is_empty() {
+            self.flash.read(source, dest).map_err(ApplyError::ReadIo)?;
+        }
+        Ok(())
+    }
+
+    /// Account for `n` bytes added to the current block, writing it out once full.
+    fn advance(&mut self, n: usize) -> Result<(), ApplyError> {
+        self.fill += n;
+        if self.fill == self.block.len() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write out the current block, saving the basis bytes it overwrites to the window first.
+    fn flush(&mut self) -> Result<(), ApplyError> {
+        if self.fill == 0 {
+            return Ok(());
+        }
+        let end = self.overwritten + self.fill as u64;
+        let window_len = self.window.len() as u64;
+        let mut start = end.saturating_sub(window_len).max(self.overwritten);
+        let saved_end = end.min(self.base_len);
+        while start < saved_end {
+            let index = (start % window_len) as usize;
+            let m = (saved_end - start).min(window_len - index as u64) as usize;
+            let window = &mut self.window[index..index + m];
+            self.flash.read(start, window).map_err(ApplyError::ReadIo)?;
+            start += m as u64;
+        }
+        self.flash.write_block(self.overwritten, &self.block[..self.fill])?;
+        self.overwritten = end;
+        self.fill = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{Flash, OtaOptions};
+    use crate::{diff, ApplyError, HashAlgorithm, Signature, SignatureOptions};
+
+    struct RamFlash(Vec<u8>);
+
+    impl Flash for RamFlash {
+        fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+            Ok(())
+        }
+        fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            let offset = offset as usize;
+            if self.0.len() < offset + data.len() {
+                self.0.resize(offset + data.len(), 0xff);
+            }
+            self.0[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn delta(base: &[u8], data: &[u8]) -> Vec<u8> {
+        let signature = Signature::calculate(
+            base,
+            SignatureOptions::new(32, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), data, &mut delta).unwrap();
+        delta
+    }
+
+    #[test]
+    fn test_ota_apply() {
+        use rand::Rng;
+        let mut base = vec![0; 4096];
+        rand::thread_rng().fill(&mut base[..]);
+        // Moving the tail forward needs no lookback; moving the head back needs 1024 bytes.
+        let data = [&base[2048..3072], &base[..1024], b"new firmware", &base[3072..]].concat();
+        let delta = delta(&base, &data);
+
+        let mut options = OtaOptions {
+            erase_block_size: 256,
+            lookback_window: 512,
+        };
+        let mut flash = RamFlash(base.clone());
+        assert!(matches!(
+            super::apply(&mut flash, base.len() as u64, &delta, &options),
+            Err(ApplyError::CopyOutsideWindow { .. })
+        ));
+        assert_eq!(flash.0, base, "a rejected delta must not modify the flash");
+
+        options.lookback_window = 1024;
+        let len = super::apply(&mut flash, base.len() as u64, &delta, &options).unwrap();
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(&flash.0[..data.len()], &data[..]);
+    }
+}
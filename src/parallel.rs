@@ -0,0 +1,115 @@
+//! Running the parallel entry points on a thread pool of the caller's choice.
+//!
+//! [diff_parallel](crate::diff_parallel), [apply_parallel](crate::apply_parallel()),
+//! [Signature::calculate_parallel] and [Signature::index_parallel] run on rayon's global pool,
+//! which has a thread per core. A server handling many requests at once can bound the CPU each
+//! of them uses by running them through a [Parallel] with a pool of its own, or with a maximum
+//! number of threads.
+
+use std::io::Write;
+
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+
+use crate::diff::{diff_parallel, DiffError};
+use crate::patch::{apply_parallel, apply_parallel_into, ApplyError};
+use crate::signature::{IndexedSignature, Signature, SignatureOptions};
+
+/// The parallel entry points, run on a chosen thread pool.
+///
+/// ```
+/// use superfast_rsync::{HashAlgorithm, Parallel, SignatureOptions};
+///
+/// let base = vec![7u8; 100_000];
+/// let options = SignatureOptions::new(1024, 8, HashAlgorithm::Blake3);
+/// let parallel = Parallel::with_max_threads(2).unwrap();
+/// let signature = parallel.signature(&base, options);
+///
+/// let mut delta = Vec::new();
+/// parallel.diff(&signature.index(), &base, &mut delta).unwrap();
+/// assert_eq!(parallel.apply(&base, &delta, usize::MAX).unwrap(), base);
+/// ```
+#[derive(Debug, Default)]
+pub struct Parallel<'a> {
+    pool: Pool<'a>,
+}
+
+#[derive(Debug, Default)]
+enum Pool<'a> {
+    #[default]
+    Global,
+    Borrowed(&'a ThreadPool),
+    Owned(ThreadPool),
+}
+
+impl Parallel<'static> {
+    /// Run on rayon's global pool, like the free functions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run on a pool of its own with at most `threads` threads, or one per core if `threads` is
+    /// zero.
+    pub fn with_max_threads(threads: usize) -> Result<Self, ThreadPoolBuildError> {
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+        Ok(Parallel {
+            pool: Pool::Owned(pool),
+        })
+    }
+}
+
+impl<'a> Parallel<'a> {
+    /// Run on `pool`, which can be shared with other work of the caller.
+    pub fn with_pool(pool: &'a ThreadPool) -> Self {
+        Parallel {
+            pool: Pool::Borrowed(pool),
+        }
+    }
+
+    /// The number of threads the work is spread across.
+    pub fn threads(&self) -> usize {
+        self.install(rayon::current_num_threads)
+    }
+
+    fn install<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
+        match &self.pool {
+            Pool::Global => f(),
+            Pool::Borrowed(pool) => pool.install(f),
+            Pool::Owned(pool) => pool.install(f),
+        }
+    }
+
+    /// [Signature::calculate_parallel] on this pool.
+    pub fn signature(&self, buf: &[u8], options: SignatureOptions) -> Signature {
+        self.install(|| Signature::calculate_parallel(buf, options))
+    }
+
+    /// [Signature::index_parallel] on this pool.
+    pub fn index<'s>(&self, signature: &'s Signature) -> IndexedSignature<'s> {
+        self.install(|| signature.index_parallel())
+    }
+
+    /// [diff_parallel](crate::diff_parallel) on this pool.
+    pub fn diff(
+        &self,
+        signature: &IndexedSignature<'_>,
+        data: &[u8],
+        out: impl Write + Send,
+    ) -> Result<(), DiffError> {
+        self.install(|| diff_parallel(signature, data, out))
+    }
+
+    /// [apply_parallel](crate::apply_parallel()) on this pool.
+    pub fn apply(&self, base: &[u8], delta: &[u8], limit: usize) -> Result<Vec<u8>, ApplyError> {
+        self.install(|| apply_parallel(base, delta, limit))
+    }
+
+    /// [apply_parallel_into](crate::apply_parallel_into()) on this pool.
+    pub fn apply_into(
+        &self,
+        base: &[u8],
+        delta: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, ApplyError> {
+        self.install(|| apply_parallel_into(base, delta, out))
+    }
+}
@@ -0,0 +1,124 @@
+//! Estimates of how signature options behave on a file of a given size.
+//!
+//! A delta is mis-applied when a block of the new data has the same CRC and crypto hash as a
+//! different block of the base data. [estimate] reports how often CRCs are expected to collide
+//! (each collision costs a crypto hash calculation while diffing) and how likely a mis-apply is,
+//! so tools can document their choice of options or warn about risky ones at runtime.
+
+use crate::signature::{HashAlgorithm, SignatureOptions};
+
+/// [Estimate::warnings] reports a mis-apply probability above this.
+pub const MAX_MISAPPLY_PROBABILITY: f64 = 1e-9;
+
+/// [Estimate::warnings] reports CRC collisions at more than this fraction of the positions.
+pub const MAX_CRC_COLLISION_RATE: f64 = 0.01;
+
+/// The expected behavior of some [SignatureOptions] on a file of a given size.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Estimate {
+    /// The number of blocks in the signature.
+    pub block_count: u64,
+    /// The expected number of positions in a new file of the same size whose CRC matches a
+    /// different block of the base data.
+    pub expected_crc_collisions: f64,
+    /// The probability that two different blocks share a crypto hash, by the birthday bound.
+    ///
+    /// This assumes the data isn't crafted to collide, which is only a safe assumption for
+    /// collision-resistant hash algorithms.
+    pub misapply_probability: f64,
+    /// Whether the hash algorithm resists deliberately crafted collisions.
+    pub collision_resistant: bool,
+}
+
+/// Estimate the behavior of `options` for a file of `file_size` bytes.
+///
+/// Panics if `options.block_size` is zero, unless the `strict` feature makes it 1.
+pub fn estimate(options: &SignatureOptions, file_size: u64) -> Estimate {
+    #[cfg(feature = "strict")]
+    let options = &options.clamped();
+    assert!(options.block_size > 0);
+    let block_count = file_size.div_ceil(options.block_size as u64);
+    // Every position of the new data is looked up among all blocks by its 32-bit CRC.
+    let positions = file_size.saturating_sub(options.block_size as u64 - 1);
+    Estimate {
+        block_count,
+        expected_crc_collisions: positions as f64 * block_count as f64 / 2f64.powi(32),
+        misapply_probability: misapply_probability(block_count, options.crypto_hash_size),
+        collision_resistant: !matches!(
+            options.hash_algorithm,
+            HashAlgorithm::Md4 | HashAlgorithm::Md5
+        ),
+    }
+}
+
+/// The birthday bound for `block_count` blocks with crypto hashes of `crypto_hash_size` bytes:
+/// `block_count² / 2^(bits + 1)`, capped at 1.
+///
+/// [SignatureOptions::hash_size_for] is the inverse of this.
+pub fn misapply_probability(block_count: u64, crypto_hash_size: u32) -> f64 {
+    let n = block_count as f64;
+    (n * n / 2f64.powi(8 * crypto_hash_size as i32 + 1)).min(1.0)
+}
+
+impl Estimate {
+    /// Describe what is risky about these options, if anything.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !self.collision_resistant {
+            warnings.push(
+                "the hash algorithm isn't collision resistant; crafted data can make deltas \
+                 mis-apply"
+                    .to_owned(),
+            );
+        }
+        if self.misapply_probability > MAX_MISAPPLY_PROBABILITY {
+            warnings.push(format!(
+                "mis-apply probability {:e} exceeds {MAX_MISAPPLY_PROBABILITY:e}; use a larger \
+                 crypto hash size",
+                self.misapply_probability
+            ));
+        }
+        // Each position collides with this probability.
+        let collision_rate = self.block_count as f64 / 2f64.powi(32);
+        if collision_rate > MAX_CRC_COLLISION_RATE {
+            warnings.push(format!(
+                "{:.0} CRC collisions expected; use a larger block size to speed up diffing",
+                self.expected_crc_collisions
+            ));
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate, misapply_probability};
+    use crate::{HashAlgorithm, SignatureOptions};
+
+    #[test]
+    fn test_estimate() {
+        let options = SignatureOptions::new(4096, 16, HashAlgorithm::Blake3);
+        let estimate = estimate(&options, 1 << 30);
+        assert_eq!(estimate.block_count, 1 << 18);
+        // 2^30 positions * 2^18 blocks / 2^32
+        assert!((estimate.expected_crc_collisions - 65536.0).abs() < 1.0);
+        assert_eq!(estimate.misapply_probability, 2f64.powi(36 - 129));
+        assert!(estimate.warnings().is_empty());
+
+        let risky = super::estimate(
+            &SignatureOptions::new(64, 4, HashAlgorithm::Md4),
+            1 << 30,
+        );
+        assert_eq!(risky.misapply_probability, 1.0);
+        assert_eq!(risky.warnings().len(), 2);
+        // 2^32 blocks: every position collides once on average
+        let huge = super::estimate(&options, 1 << 44);
+        assert_eq!(huge.warnings().len(), 1);
+
+        for block_count in [1, 1000, 1 << 30] {
+            let size = SignatureOptions::hash_size_for(block_count, 1e-12);
+            assert!(misapply_probability(block_count, size) <= 1e-12);
+            assert!(misapply_probability(block_count, size - 1) > 1e-12 || size == 1);
+        }
+    }
+}
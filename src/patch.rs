@@ -1,11 +1,17 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::io::{self, Write};
-use std::{fmt, mem};
+use std::fmt;
+use std::ops::Range;
 
-use crate::consts::{
-    DELTA_MAGIC, RS_OP_COPY_N1_N1, RS_OP_COPY_N8_N8, RS_OP_END, RS_OP_LITERAL_1, RS_OP_LITERAL_64,
-    RS_OP_LITERAL_N1, RS_OP_LITERAL_N8,
-};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::consts::{DELTA_MAGIC, DELTA_V2_MAGIC, RS_OP_REPEAT_N1, RS_OP_REPEAT_N8};
+use crate::compress::{delta_limit, unpack_delta};
+use crate::fingerprint::check_fingerprint;
+use crate::ops::{decode_header, OpHeader};
+use crate::strict::require;
 
 /// Indicates that a delta could not be applied because it was invalid.
 #[derive(Debug)]
@@ -24,7 +30,8 @@ pub enum ApplyError {
         /// The remaining length of the input.
         available: usize,
     },
-    /// The resulting data would have exceeded the output limit given to [apply_limited()].
+    /// The resulting data would have exceeded the output limit given to [apply_limited()] or
+    /// [ApplyOptions::max_output].
     OutputLimit {
         /// The item being written.
         what: &'static str,
@@ -44,6 +51,46 @@ pub enum ApplyError {
     },
     /// The delta contained a zero-length copy command.
     CopyZero,
+    /// The delta contained more ops than [ApplyOptions::max_ops].
+    TooManyOps {
+        /// The op limit.
+        limit: usize,
+    },
+    /// The delta contained a literal longer than [ApplyOptions::max_literal_len].
+    LiteralTooLong {
+        /// The length of the literal.
+        len: usize,
+        /// The literal length limit.
+        limit: usize,
+    },
+    /// The delta contained an empty literal, rejected by [ApplyOptions::reject_suspicious].
+    LiteralZero,
+    /// The delta contained a copy of base data that an earlier copy already read, rejected by
+    /// [ApplyOptions::reject_suspicious].
+    CopyOverlap {
+        /// The copy offset.
+        offset: u64,
+        /// The copy length.
+        len: u64,
+    },
+    /// The delta contained a repeat with an empty pattern or a zero count.
+    RepeatZero,
+    /// The delta contained a copy from a different position than the one it writes to, which
+    /// can't be applied in place.
+    CopyNotInPlace {
+        /// The copy offset.
+        offset: u64,
+        /// The position in the output.
+        position: u64,
+    },
+    /// The delta contained a copy from basis data that an in-place apply has already overwritten
+    /// and no longer retains.
+    CopyOutsideWindow {
+        /// The offset of the first byte that is no longer available.
+        offset: u64,
+        /// The lowest offset that is still available.
+        available_from: u64,
+    },
     /// The delta contained an unrecognized command.
     UnknownCommand {
         /// The command byte encountered.
@@ -54,6 +101,37 @@ pub enum ApplyError {
         /// The length of the trailing data.
         length: usize,
     },
+    /// The seek index passed along with the delta was built for a different delta.
+    SeekIndexMismatch {
+        /// The length of the delta the index was built for.
+        expected: u64,
+        /// The length of the delta.
+        delta_len: usize,
+    },
+    /// The delta was a compressed container that couldn't be decompressed.
+    Decompress(io::Error),
+    /// The delta was fingerprinted with different base data than it was applied to.
+    WrongBasis {
+        /// The fingerprint in the delta.
+        expected: u64,
+        /// The fingerprint of the base data.
+        actual: u64,
+    },
+    /// The delta was fingerprinted with a block size below
+    /// [MIN_FINGERPRINT_BLOCK_SIZE](crate::consts::MIN_FINGERPRINT_BLOCK_SIZE).
+    InvalidFingerprint {
+        /// The block size in the delta.
+        block_size: u32,
+    },
+    /// An argument was invalid, with the `strict` feature; see [the strict module](crate::strict).
+    InvalidArgument {
+        /// The name of the argument.
+        name: &'static str,
+    },
+    /// The apply was cancelled; see [the cancel module](crate::cancel).
+    Cancelled,
+    /// There was an IO error while reading the base data or the delta.
+    ReadIo(io::Error),
     /// There was an IO error while writing the output
     Io(io::Error),
 }
@@ -87,12 +165,55 @@ impl fmt::Display for ApplyError {
                 "requested copy is out of bounds (offset={offset}, len={len}, data_len={data_len})",
             ),
             ApplyError::CopyZero => f.write_str("copy length is empty"),
+            ApplyError::TooManyOps { limit } => {
+                write!(f, "exceeded op count limit (limit={limit})")
+            }
+            ApplyError::LiteralTooLong { len, limit } => {
+                write!(f, "literal exceeds length limit (len={len}, limit={limit})")
+            }
+            ApplyError::LiteralZero => f.write_str("literal is empty"),
+            ApplyError::CopyOverlap { offset, len } => write!(
+                f,
+                "copy reads base data that was already copied (offset={offset}, len={len})",
+            ),
+            ApplyError::RepeatZero => f.write_str("repeat is empty"),
+            ApplyError::CopyNotInPlace { offset, position } => write!(
+                f,
+                "copy can't be applied in place (offset={offset}, position={position})",
+            ),
+            ApplyError::CopyOutsideWindow {
+                offset,
+                available_from,
+            } => write!(
+                f,
+                "copy reads data that was already overwritten (offset={offset}, available_from={available_from})",
+            ),
             ApplyError::UnknownCommand { command } => {
                 write!(f, "unexpected command byte: 0x{command:02x}")
             }
             ApplyError::TrailingData { length } => {
                 write!(f, "unexpected data after end command (len={length})")
             }
+            ApplyError::SeekIndexMismatch {
+                expected,
+                delta_len,
+            } => write!(
+                f,
+                "seek index belongs to a different delta (expected={expected}, delta_len={delta_len})",
+            ),
+            Self::Decompress(source) => {
+                write!(f, "couldn't decompress the delta (source={source})")
+            }
+            Self::WrongBasis { expected, actual } => write!(
+                f,
+                "delta belongs to different base data (expected=0x{expected:016x}, actual=0x{actual:016x})",
+            ),
+            Self::InvalidFingerprint { block_size } => {
+                write!(f, "fingerprint has an invalid block size (block_size={block_size})")
+            }
+            Self::InvalidArgument { name } => write!(f, "invalid argument (name={name})"),
+            Self::Cancelled => f.write_str("apply was cancelled"),
+            Self::ReadIo(source) => write!(f, "io error while reading the input (source={source})"),
             Self::Io(source) => write!(f, "io error while writing the output (source={source})"),
         }
     }
@@ -106,100 +227,215 @@ impl From<io::Error> for ApplyError {
     }
 }
 
+/// A single command decoded from a delta.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Op<'a> {
+    /// Append these bytes to the output.
+    Literal(&'a [u8]),
+    /// Append `len` bytes of the base data, starting at `offset`.
+    Copy { offset: u64, len: u64 },
+    /// Append `pattern` `count` times.
+    Repeat { pattern: &'a [u8], count: u64 },
+}
+
+impl Op<'_> {
+    /// How many bytes the op appends to the output, saturating at `u64::MAX`.
+    pub(crate) fn output_len(&self) -> u64 {
+        match *self {
+            Op::Literal(literal) => literal.len() as u64,
+            Op::Copy { len, .. } => len,
+            Op::Repeat { pattern, count } => (pattern.len() as u64).saturating_mul(count),
+        }
+    }
+}
+
+/// Decodes the commands of a delta one at a time.
+pub(crate) struct OpReader<'a> {
+    delta: &'a [u8],
+    /// Whether this is a v2 delta, which may contain repeats.
+    v2: bool,
+}
+
+impl<'a> OpReader<'a> {
+    /// Start reading `delta`, checking its magic.
+    pub(crate) fn new(delta: &'a [u8]) -> Result<Self, ApplyError> {
+        let mut reader = OpReader { delta, v2: false };
+        let magic = u32::from_be_bytes(*reader.read_array("magic")?);
+        match magic {
+            DELTA_MAGIC => {}
+            DELTA_V2_MAGIC => reader.v2 = true,
+            _ => return Err(ApplyError::WrongMagic { magic }),
+        }
+        Ok(reader)
+    }
+
+    /// Resume reading `delta` at `offset`, which must be the start of an op, e.g. as recorded in a
+    /// [SeekIndex](crate::seek::SeekIndex).
+    pub(crate) fn resume(delta: &'a [u8], offset: usize) -> Self {
+        OpReader {
+            delta: &delta[offset..],
+            v2: delta.starts_with(&DELTA_V2_MAGIC.to_be_bytes()),
+        }
+    }
+
+    /// How many bytes of the delta haven't been read yet.
+    pub(crate) fn remaining(&self) -> usize {
+        self.delta.len()
+    }
+
+    fn read_n(&mut self, n: usize, reading: &'static str) -> Result<&'a [u8], ApplyError> {
+        if self.delta.len() < n {
+            return Err(ApplyError::UnexpectedEof {
+                reading,
+                expected: n,
+                available: self.delta.len(),
+            });
+        }
+        let (prefix, rest) = self.delta.split_at(n);
+        self.delta = rest;
+        Ok(prefix)
+    }
+
+    fn read_array<const N: usize>(&mut self, reading: &'static str) -> Result<&'a [u8; N], ApplyError> {
+        Ok(self.read_n(N, reading)?.try_into().unwrap())
+    }
+
+    fn read_varint(&mut self, len: usize, reading: &'static str) -> Result<u64, ApplyError> {
+        let mut b = [0; 8];
+        b[8 - len..8].copy_from_slice(self.read_n(len, reading)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    /// Decode the next command, or return `None` once the end command has been reached.
+    pub(crate) fn next_op(&mut self) -> Result<Option<Op<'a>>, ApplyError> {
+        let [cmd] = *self.read_array("cmd")?;
+        check_command(cmd, self.v2)?;
+        match decode_header(cmd, |len, reading| self.read_varint(len, reading))? {
+            OpHeader::End => {
+                if self.delta.is_empty() {
+                    Ok(None)
+                } else {
+                    // extra content after EOF
+                    Err(ApplyError::TrailingData {
+                        length: self.delta.len(),
+                    })
+                }
+            }
+            OpHeader::Literal(n) => {
+                // A literal longer than the address space can't be contained in `delta`.
+                let n = usize::try_from(n).unwrap_or(usize::MAX);
+                Ok(Some(Op::Literal(self.read_n(n, "literal")?)))
+            }
+            OpHeader::Copy { offset, len } => Ok(Some(Op::Copy { offset, len })),
+            OpHeader::Repeat { count, pattern_len } => {
+                if count == 0 || pattern_len == 0 {
+                    return Err(ApplyError::RepeatZero);
+                }
+                let pattern = self.read_n(pattern_len as usize, "repeat pattern")?;
+                Ok(Some(Op::Repeat { pattern, count }))
+            }
+        }
+    }
+}
+
+/// Limits on the resources a delta may use, for [apply_with_options].
+///
+/// The defaults impose no limits, like [apply()].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ApplyOptions {
+    /// How many bytes may be written to the output.
+    pub max_output: usize,
+    /// How many ops the delta may contain.
+    pub max_ops: usize,
+    /// How long a literal may be.
+    pub max_literal_len: usize,
+    /// Whether to reject empty literals and copies of base data that an earlier copy already
+    /// read. [diff()](crate::diff()) writes neither for most data, but new data that contains
+    /// the same block more than once legitimately copies it more than once.
+    pub reject_suspicious: bool,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        ApplyOptions {
+            max_output: usize::MAX,
+            max_ops: usize::MAX,
+            max_literal_len: usize::MAX,
+            reject_suspicious: false,
+        }
+    }
+}
+
 /// Apply `delta` to the base data `base`, writing the result to `out`.
-/// Errors if more than `limit` bytes would be written to `out`.
+/// Errors with [ApplyError::OutputLimit] if more than `limit` bytes would be written to `out`.
+///
+/// Each op is checked against the remaining limit before any of it is written, so `out` never
+/// receives more than `limit` bytes, however large the result of a malicious delta would be.
+///
+/// `delta` may also be a container from [pack_delta](crate::compress::pack_delta), which is
+/// decompressed first, and may be fingerprinted by
+/// [diff_fingerprinted](crate::fingerprint::diff_fingerprinted), in which case nothing is written
+/// unless `base` matches the fingerprint.
 pub fn apply_limited(
     base: &[u8],
-    mut delta: &[u8],
+    delta: &[u8],
     out: &mut impl Write,
-    mut limit: usize,
+    limit: usize,
+) -> Result<(), ApplyError> {
+    let options = ApplyOptions {
+        max_output: limit,
+        ..ApplyOptions::default()
+    };
+    apply_with_options(base, delta, out, &options)
+}
+
+/// Apply `delta` to the base data `base`, writing the result to `out`, within the limits of
+/// `options`, for deltas from untrusted peers.
+///
+/// Like [apply_limited()], each op is checked before any of it is written, and the ops before it
+/// have been written to `out` when a limit is exceeded.
+pub fn apply_with_options(
+    base: &[u8],
+    delta: &[u8],
+    out: &mut impl Write,
+    options: &ApplyOptions,
 ) -> Result<(), ApplyError> {
-    macro_rules! read_n {
-        ($n:expr, $what:expr) => {{
-            let n = $n;
-            if delta.len() < n {
-                return Err(ApplyError::UnexpectedEof {
-                    reading: $what,
-                    expected: n,
-                    available: delta.len(),
-                });
-            }
-            let (prefix, rest) = delta.split_at(n);
-            delta = rest;
-            prefix
-        }};
-    }
-    macro_rules! read_int {
-        ($ty:ty, $what:expr) => {{
-            let mut b = [0; mem::size_of::<$ty>()];
-            b.copy_from_slice(read_n!(mem::size_of::<$ty>(), $what));
-            <$ty>::from_be_bytes(b)
-        }};
-    }
-    macro_rules! read_varint {
-        ($len:expr, $what:expr) => {{
-            let len = $len;
-            let mut b = [0; 8];
-            b[8 - len..8].copy_from_slice(read_n!(len, $what));
-            u64::from_be_bytes(b)
-        }};
-    }
     macro_rules! safe_cast {
         ($val:expr, $ty:ty, $err:expr) => {{
             let val = $val;
-                          if val as u64 > <$ty>::MAX as u64 {
+            if val as u64 > <$ty>::MAX as u64 {
                 return Err($err);
             }
             val as $ty
         }};
     }
-    macro_rules! safe_extend {
-        ($slice:expr, $what:expr) => {{
-            let slice: &[u8] = $slice;
-            if slice.len() > limit {
-                return Err(ApplyError::OutputLimit {
-                    what: $what,
-                    wanted: slice.len(),
-                    available: limit,
-                });
+    let mut limit = options.max_output;
+    let delta = unpack_delta(delta, base, delta_limit(limit))?;
+    let mut ops = OpReader::new(check_fingerprint(&delta, base)?)?;
+    let mut op_count = 0;
+    // The ranges of the base data copied so far, by start, if overlaps are rejected.
+    let mut copied = BTreeMap::new();
+    while let Some(op) = ops.next_op()? {
+        op_count += 1;
+        if op_count > options.max_ops {
+            return Err(ApplyError::TooManyOps {
+                limit: options.max_ops,
+            });
+        }
+        let (slice, what) = match op {
+            Op::Literal(literal) => {
+                if literal.len() > options.max_literal_len {
+                    return Err(ApplyError::LiteralTooLong {
+                        len: literal.len(),
+                        limit: options.max_literal_len,
+                    });
+                }
+                if literal.is_empty() && options.reject_suspicious {
+                    return Err(ApplyError::LiteralZero);
+                }
+                (literal, "literal")
             }
-            limit -= slice.len();
-            out.write_all(slice)?;
-        }};
-    }
-    let magic = read_int!(u32, "magic");
-    if magic != DELTA_MAGIC {
-        return Err(ApplyError::WrongMagic { magic });
-    }
-    loop {
-        let cmd = read_int!(u8, "cmd");
-        match cmd {
-            RS_OP_END => {
-                break;
-            }
-            RS_OP_LITERAL_1..=RS_OP_LITERAL_N8 => {
-                let n = if cmd <= RS_OP_LITERAL_64 {
-                    // <=64, length is encoded in `cmd`
-                    (1 + cmd - RS_OP_LITERAL_1) as usize
-                } else {
-                    safe_cast!(
-                        read_varint!(1 << (cmd - RS_OP_LITERAL_N1) as usize, "literal length"),
-                        usize,
-                        ApplyError::OutputLimit {
-                            what: "literal",
-                            wanted: usize::MAX,
-                            available: limit,
-                        }
-                    )
-                };
-                safe_extend!(read_n!(n, "literal"), "literal");
-            }
-            RS_OP_COPY_N1_N1..=RS_OP_COPY_N8_N8 => {
-                let mode = cmd - RS_OP_COPY_N1_N1;
-                let offset_len = 1 << (mode / 4) as usize;
-                let len_len = 1 << (mode % 4) as usize;
-                let offset = read_varint!(offset_len, "copy offset");
-                let len = read_varint!(len_len, "copy length");
+            Op::Copy { offset, len } => {
                 let make_oob_error = || ApplyError::CopyOutOfBounds {
                     offset,
                     len,
@@ -211,24 +447,53 @@ pub fn apply_limited(
                     return Err(ApplyError::CopyZero);
                 }
                 let end = offset.checked_add(len).ok_or_else(make_oob_error)?;
-                let subslice = base.get(offset..end).ok_or_else(make_oob_error)?;
-                safe_extend!(subslice, "copy");
+                let slice = base.get(offset..end).ok_or_else(make_oob_error)?;
+                if options.reject_suspicious {
+                    // The copied ranges are disjoint, so only the last one starting before `end`
+                    // can overlap.
+                    if copied.range(..end).next_back().is_some_and(|(_, &e)| e > offset) {
+                        return Err(ApplyError::CopyOverlap {
+                            offset: offset as u64,
+                            len: len as u64,
+                        });
+                    }
+                    copied.insert(offset, end);
+                }
+                (slice, "copy")
+            }
+            Op::Repeat { pattern, .. } => {
+                let len = op.output_len();
+                if len > limit as u64 {
+                    return Err(ApplyError::OutputLimit {
+                        what: "repeat",
+                        wanted: usize::try_from(len).unwrap_or(usize::MAX),
+                        available: limit,
+                    });
+                }
+                limit -= len as usize;
+                write_repeated(pattern, 0..len, out)?;
+                continue;
             }
-            _ => return Err(ApplyError::UnknownCommand { command: cmd }),
+        };
+        if slice.len() > limit {
+            return Err(ApplyError::OutputLimit {
+                what,
+                wanted: slice.len(),
+                available: limit,
+            });
         }
+        limit -= slice.len();
+        out.write_all(slice)?;
     }
-    if delta.is_empty() {
-        Ok(())
-    } else {
-        // extra content after EOF
-        Err(ApplyError::TrailingData {
-            length: delta.len(),
-        })
-    }
+    Ok(())
 }
 
 /// Apply `delta` to the base data `base`, appending the result to `out`.
 ///
+/// `out` can be any writer, like a file, a socket or a hasher: the result is written op by op as
+/// it is reconstructed and never held in memory as a whole. Ops can be small, so wrap unbuffered
+/// writers in a [BufWriter](std::io::BufWriter).
+///
 /// # Security
 /// This function should not be used with untrusted input, as a delta may create an arbitrarily
 /// large output which can exhaust available memory. Use [apply_limited()] instead to set an upper
@@ -236,3 +501,364 @@ pub fn apply_limited(
 pub fn apply(base: &[u8], delta: &[u8], out: &mut impl Write) -> Result<(), ApplyError> {
     apply_limited(base, delta, out, usize::MAX)
 }
+
+/// Apply `delta` to the base data `base`, writing only `range` of the result to `out`.
+///
+/// Ops before the range are skipped without copying anything, and the delta isn't read past the
+/// end of the range, so this serves random access into the result (e.g. HTTP range requests)
+/// without reconstructing all of it. The part of `range` past the end of the result is ignored,
+/// and an empty or inverted range writes nothing. Returns the number of bytes written.
+///
+/// Compressed and fingerprinted deltas are accepted like [apply()] accepts them.
+pub fn apply_range(
+    base: &[u8],
+    delta: &[u8],
+    range: Range<u64>,
+    out: &mut impl Write,
+) -> Result<u64, ApplyError> {
+    let delta = unpack_delta(delta, base, delta_limit(usize::MAX))?;
+    let ops = OpReader::new(check_fingerprint(&delta, base)?)?;
+    apply_ops_range(base, ops, 0, range, out)
+}
+
+/// Write `range` of the result of the remaining `ops`, whose output starts at `position`.
+pub(crate) fn apply_ops_range(
+    base: &[u8],
+    mut ops: OpReader<'_>,
+    mut position: u64,
+    range: Range<u64>,
+    out: &mut impl Write,
+) -> Result<u64, ApplyError> {
+    if range.start >= range.end {
+        return Ok(0);
+    }
+    let mut written = 0;
+    while position < range.end {
+        let Some(op) = ops.next_op()? else {
+            break;
+        };
+        if let Op::Copy { offset, len } = op {
+            if len == 0 {
+                return Err(ApplyError::CopyZero);
+            }
+            if offset.checked_add(len).is_none_or(|end| end > base.len() as u64) {
+                return Err(ApplyError::CopyOutOfBounds {
+                    offset,
+                    len,
+                    data_len: base.len(),
+                });
+            }
+        }
+        let end = position.saturating_add(op.output_len());
+        if end > range.start {
+            let from = range.start.saturating_sub(position);
+            let to = range.end.min(end) - position;
+            match op {
+                Op::Literal(literal) => out.write_all(&literal[from as usize..to as usize])?,
+                Op::Copy { offset, .. } => {
+                    out.write_all(&base[(offset + from) as usize..(offset + to) as usize])?
+                }
+                Op::Repeat { pattern, .. } => write_repeated(pattern, from..to, out)?,
+            }
+            written += to - from;
+        }
+        position = end;
+    }
+    Ok(written)
+}
+
+/// How many bytes of the result each task of [apply_parallel()] reconstructs.
+#[cfg(feature = "parallel")]
+const PARALLEL_SEGMENT_SIZE: usize = 1 << 20;
+
+/// The ops of a delta with the position in the result where each starts.
+#[cfg(feature = "parallel")]
+struct OpTable<'a> {
+    ops: Vec<(u64, Op<'a>)>,
+    output_len: u64,
+}
+
+#[cfg(feature = "parallel")]
+impl<'a> OpTable<'a> {
+    /// Decode all ops of `delta`, checking that its copies are within `base`.
+    fn build(base: &[u8], delta: &'a [u8]) -> Result<Self, ApplyError> {
+        let mut reader = OpReader::new(delta)?;
+        let mut ops = Vec::new();
+        let mut position = 0u64;
+        while let Some(op) = reader.next_op()? {
+            if let Op::Copy { offset, len } = op {
+                if len == 0 {
+                    return Err(ApplyError::CopyZero);
+                }
+                if offset.checked_add(len).is_none_or(|end| end > base.len() as u64) {
+                    return Err(ApplyError::CopyOutOfBounds {
+                        offset,
+                        len,
+                        data_len: base.len(),
+                    });
+                }
+            }
+            ops.push((position, op));
+            position = position.saturating_add(op.output_len());
+        }
+        Ok(OpTable {
+            ops,
+            output_len: position,
+        })
+    }
+
+    /// The length of the result, if it is at most `limit`.
+    fn checked_len(&self, limit: usize) -> Result<usize, ApplyError> {
+        match usize::try_from(self.output_len) {
+            Ok(len) if len <= limit => Ok(len),
+            _ => Err(ApplyError::OutputLimit {
+                what: "output",
+                wanted: usize::try_from(self.output_len).unwrap_or(usize::MAX),
+                available: limit,
+            }),
+        }
+    }
+
+    /// Reconstruct the result into `out`, which is exactly as long, splitting it into segments
+    /// that are filled on rayon's threads.
+    fn write(&self, base: &[u8], out: &mut [u8]) {
+        out.par_chunks_mut(PARALLEL_SEGMENT_SIZE)
+            .enumerate()
+            .for_each(|(i, segment)| {
+                let start = (i * PARALLEL_SEGMENT_SIZE) as u64;
+                let end = start + segment.len() as u64;
+                // The last op starting at or before the segment, found by binary search.
+                let first = self.ops.partition_point(|&(position, _)| position <= start) - 1;
+                for &(position, op) in self.ops[first..].iter().take_while(|(p, _)| *p < end) {
+                    let from = start.saturating_sub(position);
+                    let to = end.min(position + op.output_len()) - position;
+                    let dest = &mut segment[(position + from - start) as usize..]
+                        [..(to - from) as usize];
+                    match op {
+                        Op::Literal(literal) => {
+                            dest.copy_from_slice(&literal[from as usize..to as usize])
+                        }
+                        Op::Copy { offset, .. } => dest.copy_from_slice(
+                            &base[(offset + from) as usize..(offset + to) as usize],
+                        ),
+                        Op::Repeat { pattern, .. } => fill_repeated(pattern, from, dest),
+                    }
+                }
+            });
+    }
+}
+
+/// Apply `delta` to the base data `base` on rayon's threads, returning the result.
+/// Errors with [ApplyError::OutputLimit] if the result would be longer than `limit` bytes.
+///
+/// The delta is scanned first to find where each op's output starts, then the result is
+/// allocated at once and split into segments of disjoint output ranges that are reconstructed in
+/// parallel. Nothing is allocated for the result if the delta is invalid. Like [apply_limited()],
+/// `delta` may be packed or fingerprinted.
+///
+/// # Features
+/// This function requires the `parallel` feature to be enabled.
+#[cfg(feature = "parallel")]
+pub fn apply_parallel(base: &[u8], delta: &[u8], limit: usize) -> Result<Vec<u8>, ApplyError> {
+    let delta = unpack_delta(delta, base, delta_limit(limit))?;
+    let table = OpTable::build(base, check_fingerprint(&delta, base)?)?;
+    let mut out = vec![0; table.checked_len(limit)?];
+    table.write(base, &mut out);
+    Ok(out)
+}
+
+/// Apply `delta` to the base data `base` on rayon's threads, writing the result to the start of
+/// `out` and returning its length.
+///
+/// This is [apply_parallel()] with a preallocated buffer, such as a writable memory map of the
+/// output file. Errors with [ApplyError::OutputLimit] before writing anything if the result
+/// doesn't fit in `out`.
+///
+/// # Features
+/// This function requires the `parallel` feature to be enabled.
+#[cfg(feature = "parallel")]
+pub fn apply_parallel_into(base: &[u8], delta: &[u8], out: &mut [u8]) -> Result<usize, ApplyError> {
+    let delta = unpack_delta(delta, base, delta_limit(out.len()))?;
+    let table = OpTable::build(base, check_fingerprint(&delta, base)?)?;
+    let len = table.checked_len(out.len())?;
+    table.write(base, &mut out[..len]);
+    Ok(len)
+}
+
+/// Apply a delta without allocating, using `scratch` as the only buffer.
+///
+/// The delta is read sequentially with `read_delta`, which behaves like [io::Read::read]. Copy
+/// commands read the base data of `base_len` bytes with `read_base(offset, buf)`, which must fill
+/// `buf` completely. The output is passed to `write` in pieces of at most `scratch.len()` bytes.
+/// Returns the length of the output. Errors of `read_base` and `read_delta` are returned as
+/// [ApplyError::ReadIo], those of `write` as [ApplyError::Io].
+///
+/// This is meant for embedded environments where neither the delta nor the base fits in RAM;
+/// a scratch buffer of a few hundred bytes is enough, larger buffers mean fewer callback calls.
+/// The patterns of repeats in v2 deltas, up to 255 bytes, are held on the stack.
+/// Panics if `scratch` is empty, unless the `strict` feature turns that into
+/// [ApplyError::InvalidArgument].
+pub fn apply_with_scratch(
+    base_len: u64,
+    mut read_base: impl FnMut(u64, &mut [u8]) -> io::Result<()>,
+    read_delta: impl FnMut(&mut [u8]) -> io::Result<usize>,
+    mut write: impl FnMut(&[u8]) -> io::Result<()>,
+    scratch: &mut [u8],
+) -> Result<u64, ApplyError> {
+    require!(!scratch.is_empty(), ApplyError::InvalidArgument { name: "scratch" });
+    let mut delta = DeltaStream(read_delta);
+    let mut magic = [0; 4];
+    delta.read_exact(&mut magic, "magic")?;
+    let v2 = match u32::from_be_bytes(magic) {
+        DELTA_MAGIC => false,
+        DELTA_V2_MAGIC => true,
+        magic => return Err(ApplyError::WrongMagic { magic }),
+    };
+    let mut written = 0u64;
+    loop {
+        let mut cmd = [0];
+        delta.read_exact(&mut cmd, "cmd")?;
+        check_command(cmd[0], v2)?;
+        let header = decode_header(cmd[0], |len, reading| {
+            let mut b = [0; 8];
+            delta.read_exact(&mut b[8 - len..], reading)?;
+            Ok(u64::from_be_bytes(b))
+        })?;
+        match header {
+            OpHeader::End => break,
+            OpHeader::Literal(len) => {
+                let mut remaining = len;
+                while remaining > 0 {
+                    let n = remaining.min(scratch.len() as u64) as usize;
+                    let available = delta.fill(&mut scratch[..n])?;
+                    if available < n {
+                        return Err(ApplyError::UnexpectedEof {
+                            reading: "literal",
+                            expected: usize::try_from(len).unwrap_or(usize::MAX),
+                            available: (len - remaining) as usize + available,
+                        });
+                    }
+                    write(&scratch[..n])?;
+                    remaining -= n as u64;
+                }
+                written += len;
+            }
+            OpHeader::Copy { offset, len } => {
+                if len == 0 {
+                    return Err(ApplyError::CopyZero);
+                }
+                if !matches!(offset.checked_add(len), Some(end) if end <= base_len) {
+                    return Err(ApplyError::CopyOutOfBounds {
+                        offset,
+                        len,
+                        data_len: usize::try_from(base_len).unwrap_or(usize::MAX),
+                    });
+                }
+                let mut done = 0;
+                while done < len {
+                    let n = (len - done).min(scratch.len() as u64) as usize;
+                    read_base(offset + done, &mut scratch[..n]).map_err(ApplyError::ReadIo)?;
+                    write(&scratch[..n])?;
+                    done += n as u64;
+                }
+                written += len;
+            }
+            OpHeader::Repeat { count, pattern_len } => {
+                if count == 0 || pattern_len == 0 {
+                    return Err(ApplyError::RepeatZero);
+                }
+                let mut pattern = [0; u8::MAX as usize];
+                let pattern = &mut pattern[..pattern_len as usize];
+                delta.read_exact(pattern, "repeat pattern")?;
+                let len = (pattern_len as u64).saturating_mul(count);
+                let mut done = 0;
+                while done < len {
+                    let n = (len - done).min(scratch.len() as u64) as usize;
+                    fill_repeated(pattern, done, &mut scratch[..n]);
+                    write(&scratch[..n])?;
+                    done += n as u64;
+                }
+                written = written.saturating_add(len);
+            }
+        }
+    }
+    // extra content after EOF
+    let length = delta.fill(scratch)?;
+    if length > 0 {
+        return Err(ApplyError::TrailingData { length });
+    }
+    Ok(written)
+}
+
+/// Reject the op codes that only v2 deltas may contain in v1 deltas.
+fn check_command(cmd: u8, v2: bool) -> Result<(), ApplyError> {
+    if !v2 && (RS_OP_REPEAT_N1..=RS_OP_REPEAT_N8).contains(&cmd) {
+        return Err(ApplyError::UnknownCommand { command: cmd });
+    }
+    Ok(())
+}
+
+/// Fill `buf` with the bytes of `pattern` repeated endlessly, starting at `offset` into the
+/// repetition.
+pub(crate) fn fill_repeated(pattern: &[u8], offset: u64, buf: &mut [u8]) {
+    let period = pattern.len();
+    let phase = (offset % period as u64) as usize;
+    let mut filled = period.min(buf.len());
+    for (i, byte) in buf[..filled].iter_mut().enumerate() {
+        *byte = pattern[(phase + i) % period];
+    }
+    // `filled` stays a multiple of the period, so the filled part can be copied as a whole.
+    while filled < buf.len() {
+        let n = filled.min(buf.len() - filled);
+        buf.copy_within(..n, filled);
+        filled += n;
+    }
+}
+
+/// Write `range` of the bytes of `pattern` repeated endlessly to `out`.
+pub(crate) fn write_repeated(
+    pattern: &[u8],
+    range: Range<u64>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    let mut position = range.start;
+    while position < range.end {
+        let n = (range.end - position).min(buf.len() as u64) as usize;
+        fill_repeated(pattern, position, &mut buf[..n]);
+        out.write_all(&buf[..n])?;
+        position += n as u64;
+    }
+    Ok(())
+}
+
+/// A delta read sequentially through a [io::Read::read]-like callback.
+struct DeltaStream<F>(F);
+
+impl<F: FnMut(&mut [u8]) -> io::Result<usize>> DeltaStream<F> {
+    /// Fill `buf`, returning how much was read if the delta ended first.
+    fn fill(&mut self, buf: &mut [u8]) -> Result<usize, ApplyError> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match (self.0)(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(ApplyError::ReadIo(e)),
+            }
+        }
+        Ok(filled)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8], reading: &'static str) -> Result<(), ApplyError> {
+        let available = self.fill(buf)?;
+        if available < buf.len() {
+            return Err(ApplyError::UnexpectedEof {
+                reading,
+                expected: buf.len(),
+                available,
+            });
+        }
+        Ok(())
+    }
+}
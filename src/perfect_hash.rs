@@ -0,0 +1,187 @@
+//! A minimal perfect hash index over the CRCs of a signature's blocks.
+//!
+//! The distinct CRCs are spread over buckets of about [KEYS_PER_BUCKET] keys, and every bucket
+//! gets a pilot: the first value that sends all of its keys to slots no other key took, like in
+//! PTHash. There are exactly as many slots as distinct CRCs, and a lookup hashes the CRC, reads
+//! the pilot of its bucket and probes a single slot, which stores the CRC to reject blocks that
+//! aren't in the signature.
+//!
+//! Building takes several times longer than a hash map, so this pays off for signatures that are
+//! kept around and diffed against many times.
+
+use std::cmp::Reverse;
+
+use crate::crc::Crc;
+use crate::index_table::mix;
+
+/// The average number of keys per bucket. Fewer means more pilots to store, more means longer
+/// searches for the pilots of the largest buckets.
+const KEYS_PER_BUCKET: usize = 2;
+
+/// Pilots are searched up to this value before starting over with another seed.
+const MAX_PILOT: u32 = 1 << 20;
+
+/// A perfect hash index borrowing the block entries of a signature.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct PerfectIndex<'a> {
+    seed: u64,
+    pilots: Vec<u32>,
+    slots: Vec<Slot>,
+    /// The indexes of the blocks, grouped by CRC.
+    blocks: Vec<u32>,
+    /// The block entries of the signature.
+    entries: &'a [u8],
+    entry_size: usize,
+}
+
+/// The blocks with one CRC, as a range of [PerfectIndex::blocks]. Unused slots have no blocks.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Slot {
+    crc: Crc,
+    start: u32,
+    len: u32,
+}
+
+impl<'a> PerfectIndex<'a> {
+    /// Build the index for `entries` (CRC followed by crypto hash, `entry_size` bytes each).
+    pub(crate) fn build(entries: &'a [u8], entry_size: usize) -> Self {
+        let count = entries.len() / entry_size;
+        let crc = |idx: u32| entry_crc(&entries[idx as usize * entry_size..]);
+        // Among identical blocks the last one comes first and wins, like with
+        // `IndexedSignature`'s hash map.
+        let mut blocks: Vec<u32> = (0..count as u32).collect();
+        blocks.sort_unstable_by_key(|&idx| (crc(idx), Reverse(idx)));
+        let mut groups: Vec<Slot> = Vec::new();
+        for (i, &idx) in blocks.iter().enumerate() {
+            match groups.last_mut() {
+                Some(group) if group.crc == crc(idx) => group.len += 1,
+                _ => groups.push(Slot {
+                    crc: crc(idx),
+                    start: i as u32,
+                    len: 1,
+                }),
+            }
+        }
+        let (seed, pilots, slots) = (0..)
+            .find_map(|seed| place(&groups, seed).map(|(pilots, slots)| (seed, pilots, slots)))
+            .expect("no seed left to try");
+        PerfectIndex {
+            seed,
+            pilots,
+            slots,
+            blocks,
+            entries,
+            entry_size,
+        }
+    }
+
+    /// Find the slot of the blocks with CRC `crc`.
+    #[inline]
+    pub(crate) fn find(&self, crc: Crc) -> Option<usize> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let hash = key_hash(crc, self.seed);
+        let pilot = self.pilots[reduce(hash, self.pilots.len())];
+        let slot = position(hash, pilot, self.slots.len());
+        (self.slots[slot].crc == crc).then_some(slot)
+    }
+
+    /// Find the block with `crypto_hash` in `slot` from [Self::find].
+    #[inline]
+    pub(crate) fn get(&self, slot: usize, crypto_hash: &[u8]) -> Option<u32> {
+        let Slot { start, len, .. } = self.slots[slot];
+        self.blocks[start as usize..(start + len) as usize]
+            .iter()
+            .copied()
+            .find(|&idx| {
+                let entry = &self.entries[idx as usize * self.entry_size..][..self.entry_size];
+                &entry[Crc::SIZE..] == crypto_hash
+            })
+    }
+}
+
+/// Find a pilot for every bucket of `groups` with `seed`, returning the pilots and the groups in
+/// their slots, or `None` if a bucket has no pilot below [MAX_PILOT].
+fn place(groups: &[Slot], seed: u64) -> Option<(Vec<u32>, Vec<Slot>)> {
+    let n = groups.len();
+    let bucket_count = n.div_ceil(KEYS_PER_BUCKET).max(1);
+    let mut keys: Vec<(usize, u64, usize)> = groups
+        .iter()
+        .enumerate()
+        .map(|(group, slot)| {
+            let hash = key_hash(slot.crc, seed);
+            (reduce(hash, bucket_count), hash, group)
+        })
+        .collect();
+    keys.sort_unstable();
+    let mut buckets: Vec<&[(usize, u64, usize)]> = keys.chunk_by(|a, b| a.0 == b.0).collect();
+    // The largest buckets are the hardest to place, so place them while most slots are free.
+    buckets.sort_by_key(|bucket| Reverse(bucket.len()));
+
+    let mut pilots = vec![0; bucket_count];
+    let mut slots = vec![Slot::default(); n];
+    let mut positions = Vec::new();
+    for bucket in buckets {
+        let pilot = (0..MAX_PILOT).find(|&pilot| {
+            positions.clear();
+            for &(_, hash, _) in bucket {
+                let slot = position(hash, pilot, n);
+                if slots[slot].len != 0 || positions.contains(&slot) {
+                    return false;
+                }
+                positions.push(slot);
+            }
+            true
+        })?;
+        pilots[bucket[0].0] = pilot;
+        for (&(_, _, group), &slot) in bucket.iter().zip(&positions) {
+            slots[slot] = groups[group];
+        }
+    }
+    Some((pilots, slots))
+}
+
+#[inline]
+fn key_hash(crc: Crc, seed: u64) -> u64 {
+    mix(crc.0 as u64 ^ seed.wrapping_mul(0x9E3779B97F4A7C15))
+}
+
+#[inline]
+fn position(hash: u64, pilot: u32, n: usize) -> usize {
+    reduce(mix(hash ^ mix(pilot as u64)), n)
+}
+
+/// Map `hash` onto `0..n` without a division.
+#[inline]
+fn reduce(hash: u64, n: usize) -> usize {
+    ((hash as u128 * n as u128) >> 64) as usize
+}
+
+fn entry_crc(entry: &[u8]) -> Crc {
+    Crc::from_bytes(entry[..Crc::SIZE].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PerfectIndex;
+    use crate::crc::Crc;
+
+    #[test]
+    fn test_perfect_index() {
+        // 4-byte CRC and 1-byte crypto hash, with some CRCs shared by several blocks
+        let mut entries = Vec::new();
+        for i in 0..10_000u32 {
+            entries.extend_from_slice(&(i % 7000 * 7919).to_be_bytes());
+            entries.push(i as u8);
+        }
+        let index = PerfectIndex::build(&entries, 5);
+        assert_eq!(index.slots.len(), 7000);
+        for i in 0..10_000u32 {
+            let slot = index.find(Crc(i % 7000 * 7919)).unwrap();
+            assert_eq!(index.get(slot, &[i as u8]), Some(i));
+        }
+        assert_eq!(index.find(Crc(1)), None);
+        assert_eq!(PerfectIndex::build(&[], 5).find(Crc(0)), None);
+    }
+}
@@ -0,0 +1,253 @@
+//! A staged read → compute → write pipeline for large inputs.
+//!
+//! [Pipeline] runs the reading of the input and the writing of the output on their own threads,
+//! connected to the hashing/matching stage by bounded channels. While one chunk is being hashed
+//! or matched, the next one is already being read and the previous output is being written, so
+//! neither the disk nor the CPU sits idle waiting on the other.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+use crate::diff::{DiffError, DiffSession};
+use crate::file::open_sequential;
+use crate::signature::{IndexedSignature, Signature, SignatureOptions};
+use crate::strict::{invalid_input, require};
+
+/// A builder for pipelined signature and delta calculation.
+///
+/// ```
+/// use superfast_rsync::{HashAlgorithm, Pipeline, SignatureOptions};
+///
+/// let base = vec![7u8; 100_000];
+/// let options = SignatureOptions::new(1024, 8, HashAlgorithm::Blake3);
+/// let pipeline = Pipeline::new().chunk_size(16 * 1024).queue_depth(2);
+/// let signature = pipeline.signature(&base[..], options).unwrap();
+///
+/// let mut delta = Vec::new();
+/// pipeline.diff(&signature.index(), &base[..], &mut delta).unwrap();
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct Pipeline {
+    chunk_size: usize,
+    queue_depth: usize,
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    io_uring: bool,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            chunk_size: 1 << 20,
+            queue_depth: 4,
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            io_uring: false,
+        }
+    }
+}
+
+impl Pipeline {
+    /// Create a pipeline with the default chunk size (1 MiB) and queue depth (4).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how many bytes each stage processes at a time.
+    ///
+    /// For signatures this is rounded up to a multiple of the block size.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Set how many chunks may be queued between two stages before the faster stage blocks.
+    pub fn queue_depth(mut self, queue_depth: usize) -> Self {
+        self.queue_depth = queue_depth.max(1);
+        self
+    }
+
+    /// Read the input of [signature_file](Self::signature_file) and [diff_file](Self::diff_file)
+    /// through io_uring, keeping `queue_depth` chunk reads in flight at once.
+    ///
+    /// Falls back to synchronous reads where io_uring is unavailable.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub fn io_uring(mut self, enabled: bool) -> Self {
+        self.io_uring = enabled;
+        self
+    }
+
+    /// Compute the signature of everything read from `input`.
+    ///
+    /// The result is identical to [Signature::calculate] over the same bytes.
+    /// Panics if the provided options are invalid, unless the `strict` feature turns that into an
+    /// [io::ErrorKind::InvalidInput] error.
+    pub fn signature(
+        &self,
+        input: impl Read + Send,
+        options: SignatureOptions,
+    ) -> io::Result<Signature> {
+        self.signature_from(|chunk_size, output| read_stage(input, chunk_size, output), options)
+    }
+
+    fn signature_from(
+        &self,
+        read: impl FnOnce(usize, SyncSender<Vec<u8>>) -> io::Result<()> + Send,
+        options: SignatureOptions,
+    ) -> io::Result<Signature> {
+        require!(options.is_valid(), invalid_input("options"));
+
+        // Every chunk but the last must be a whole number of blocks.
+        let block_size = options.block_size as usize;
+        let chunk_size = self.chunk_size.div_ceil(block_size) * block_size;
+
+        let mut signature = Vec::new();
+        Signature::write_header(&options, &mut signature);
+        thread::scope(|scope| {
+            let (tx, rx) = sync_channel(self.queue_depth);
+            let reader = scope.spawn(move || read(chunk_size, tx));
+            for chunk in rx {
+                Signature::hash_blocks(&chunk, &options, &mut signature);
+            }
+            join(reader)
+        })?;
+        Ok(Signature::from_serialized_parts(&options, signature))
+    }
+
+    /// Calculate a delta from `signature` to everything read from `input`, writing it to `out`.
+    ///
+    /// Only a few chunks of `input` are held in memory at once. The delta applies the same way
+    /// as one produced by [diff()][crate::diff()], though long literals are split into several
+    /// ops.
+    pub fn diff(
+        &self,
+        signature: &IndexedSignature<'_>,
+        input: impl Read + Send,
+        out: impl Write + Send,
+    ) -> Result<(), DiffError> {
+        self.diff_from(
+            signature,
+            |chunk_size, output| read_stage(input, chunk_size, output),
+            out,
+        )
+    }
+
+    fn diff_from(
+        &self,
+        signature: &IndexedSignature<'_>,
+        read: impl FnOnce(usize, SyncSender<Vec<u8>>) -> io::Result<()> + Send,
+        out: impl Write + Send,
+    ) -> Result<(), DiffError> {
+        thread::scope(|scope| {
+            let (in_tx, in_rx) = sync_channel(self.queue_depth);
+            let (out_tx, out_rx) = sync_channel(self.queue_depth);
+            let reader = scope.spawn(move || read(self.chunk_size, in_tx));
+            let writer = scope.spawn(move || write_stage(out, out_rx));
+
+            let result = self.diff_stage(signature, in_rx, out_tx);
+            // A failed read or write stops the compute stage early, so report those first.
+            join(reader)?;
+            join(writer)?;
+            result
+        })
+    }
+
+    /// Compute the signature of the file at `path`.
+    ///
+    /// The file is opened with the platform's sequential-access hints.
+    pub fn signature_file(
+        &self,
+        path: impl AsRef<Path>,
+        options: SignatureOptions,
+    ) -> io::Result<Signature> {
+        let file = open_sequential(path.as_ref())?;
+        self.signature_from(|chunk_size, output| self.read_file(&file, chunk_size, output), options)
+    }
+
+    /// Calculate a delta from `signature` to the file at `path`, writing it to the file at
+    /// `out_path`.
+    ///
+    /// The input is opened with the platform's sequential-access hints.
+    pub fn diff_file(
+        &self,
+        signature: &IndexedSignature<'_>,
+        path: impl AsRef<Path>,
+        out_path: impl AsRef<Path>,
+    ) -> Result<(), DiffError> {
+        let file = open_sequential(path.as_ref())?;
+        let out = File::create(out_path)?;
+        self.diff_from(
+            signature,
+            |chunk_size, output| self.read_file(&file, chunk_size, output),
+            out,
+        )
+    }
+
+    fn read_file(
+        &self,
+        file: &File,
+        chunk_size: usize,
+        output: SyncSender<Vec<u8>>,
+    ) -> io::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        if self.io_uring {
+            if let Some(result) =
+                crate::uring::read_stage(file, chunk_size, self.queue_depth, &output)
+            {
+                return result;
+            }
+        }
+        read_stage(file, chunk_size, output)
+    }
+
+    fn diff_stage(
+        &self,
+        signature: &IndexedSignature<'_>,
+        input: Receiver<Vec<u8>>,
+        output: SyncSender<Vec<u8>>,
+    ) -> Result<(), DiffError> {
+        let mut buf = Vec::with_capacity(self.chunk_size);
+        let mut session = DiffSession::with_max_literal(signature, self.chunk_size, &mut buf)?;
+        for chunk in input {
+            session.push(&chunk, &mut buf)?;
+            if buf.len() >= self.chunk_size && !send(&output, &mut buf, self.chunk_size) {
+                return Ok(());
+            }
+        }
+        session.finish(&mut buf)?;
+        send(&output, &mut buf, 0);
+        Ok(())
+    }
+}
+
+/// Hand `buf` to the next stage, leaving a fresh buffer in its place.
+/// Returns false if the next stage has gone away.
+fn send(output: &SyncSender<Vec<u8>>, buf: &mut Vec<u8>, capacity: usize) -> bool {
+    let full = std::mem::replace(buf, Vec::with_capacity(capacity));
+    output.send(full).is_ok()
+}
+
+fn read_stage(mut input: impl Read, chunk_size: usize, output: SyncSender<Vec<u8>>) -> io::Result<()> {
+    loop {
+        let mut chunk = Vec::with_capacity(chunk_size);
+        (&mut input).take(chunk_size as u64).read_to_end(&mut chunk)?;
+        if chunk.is_empty() || output.send(chunk).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn write_stage(mut out: impl Write, input: Receiver<Vec<u8>>) -> io::Result<()> {
+    for buf in input {
+        out.write_all(&buf)?;
+    }
+    out.flush()
+}
+
+fn join<T>(handle: thread::ScopedJoinHandle<'_, io::Result<T>>) -> io::Result<T> {
+    match handle.join() {
+        Ok(result) => result,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
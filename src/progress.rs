@@ -0,0 +1,279 @@
+//! Progress reporting for signature calculation, diff and apply, e.g. to drive progress bars.
+//!
+//! The functions of this module call a `FnMut(ProgressEvent)` inline as they go, so it should
+//! be cheap: update a counter or an atomic, and redraw elsewhere.
+
+use std::cell::Cell;
+use std::io::{self, Write};
+
+use crate::diff::{diff_observed_with, DiffError, DiffOptions};
+use crate::observer::DiffObserver;
+use crate::patch::{apply_limited, ApplyError};
+use crate::signature::{IndexedSignature, Signature, SignatureOptions};
+
+/// How much data is processed or written between reports.
+const REPORT_INTERVAL: usize = 64 * 1024;
+
+/// What a progress callback is told.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ProgressEvent {
+    /// The first `processed` of `total` bytes of the input are done.
+    Processed {
+        /// How many bytes of the input are done.
+        processed: u64,
+        /// The length of the input.
+        total: u64,
+    },
+    /// A block of the base data at `offset` matched `len` bytes at `position` in the new data.
+    BlockMatched {
+        /// The position of the match in the new data.
+        position: u64,
+        /// The offset of the block in the base data.
+        offset: u64,
+        /// The length of the match.
+        len: u64,
+    },
+    /// `len` more bytes were written to the output: the delta of a diff or the result of an
+    /// apply.
+    Emitted {
+        /// How many bytes were written since the last report.
+        len: u64,
+    },
+}
+
+/// Compute a signature like [Signature::calculate], reporting the bytes hashed to `progress`.
+///
+/// Panics if the provided options are invalid, unless the `strict` feature replaces them with
+/// the nearest valid ones.
+pub fn signature(
+    buf: &[u8],
+    options: SignatureOptions,
+    mut progress: impl FnMut(ProgressEvent),
+) -> Signature {
+    #[cfg(feature = "strict")]
+    let options = options.clamped();
+    assert!(options.is_valid());
+
+    // Every chunk but the last must be a whole number of blocks.
+    let block_size = options.block_size as usize;
+    let chunk_size = REPORT_INTERVAL.div_ceil(block_size) * block_size;
+    let mut signature = Vec::new();
+    Signature::write_header(&options, &mut signature);
+    let total = buf.len() as u64;
+    let mut processed = 0;
+    for chunk in buf.chunks(chunk_size) {
+        Signature::hash_blocks(chunk, &options, &mut signature);
+        processed += chunk.len() as u64;
+        progress(ProgressEvent::Processed { processed, total });
+    }
+    Signature::from_serialized_parts(&options, signature)
+}
+
+/// Calculate a delta like [diff()](crate::diff()), reporting the bytes of `data` searched, the
+/// matched blocks and the bytes of delta written to `progress`.
+///
+/// # Security
+/// See [diff()](crate::diff()).
+pub fn diff(
+    signature: &IndexedSignature<'_>,
+    data: &[u8],
+    progress: impl FnMut(ProgressEvent),
+    out: impl Write,
+) -> Result<(), DiffError> {
+    let written = Cell::new(0);
+    let mut reporter = Reporter {
+        progress,
+        total: data.len() as u64,
+        processed: 0,
+        reported: 0,
+        written: &written,
+        emitted: 0,
+    };
+    let out = Counting {
+        out,
+        written: &written,
+    };
+    let options = DiffOptions::default();
+    diff_observed_with(signature, data, None, &options, false, &mut reporter, out)?;
+    reporter.report(true);
+    Ok(())
+}
+
+/// Apply a delta like [apply()](crate::apply()), reporting the bytes of the result written to
+/// `progress`.
+///
+/// # Security
+/// See [apply()](crate::apply()).
+pub fn apply(
+    base: &[u8],
+    delta: &[u8],
+    out: &mut impl Write,
+    progress: impl FnMut(ProgressEvent),
+) -> Result<(), ApplyError> {
+    let mut out = Emitting {
+        out,
+        progress,
+        pending: 0,
+    };
+    apply_limited(base, delta, &mut out, usize::MAX)?;
+    if out.pending > 0 {
+        (out.progress)(ProgressEvent::Emitted { len: out.pending });
+    }
+    Ok(())
+}
+
+/// Counts the bytes written through it in a cell shared with a [Reporter].
+struct Counting<'a, W> {
+    out: W,
+    written: &'a Cell<u64>,
+}
+
+impl<W: Write> Write for Counting<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.written.set(self.written.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reports the bytes written through it in batches.
+struct Emitting<W, F> {
+    out: W,
+    progress: F,
+    /// The bytes written since the last report.
+    pending: u64,
+}
+
+impl<W: Write, F: FnMut(ProgressEvent)> Write for Emitting<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.pending += n as u64;
+        if self.pending >= REPORT_INTERVAL as u64 {
+            (self.progress)(ProgressEvent::Emitted { len: self.pending });
+            self.pending = 0;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Turns the events of the matcher into progress reports.
+struct Reporter<'a, F> {
+    progress: F,
+    total: u64,
+    /// The end of the furthest region of the new data that has been reported by the matcher.
+    processed: u64,
+    /// The value of `processed` in the last [ProgressEvent::Processed].
+    reported: u64,
+    /// The bytes of delta written so far, counted by [Counting].
+    written: &'a Cell<u64>,
+    /// The bytes of delta reported so far.
+    emitted: u64,
+}
+
+impl<F: FnMut(ProgressEvent)> Reporter<'_, F> {
+    fn advance(&mut self, position: u64, len: u64) {
+        self.processed = self.processed.max(position + len);
+        self.report(false);
+    }
+
+    /// Report the progress since the last report if there's enough of it, or any at all if
+    /// `force` is set.
+    fn report(&mut self, force: bool) {
+        let threshold = if force { 1 } else { REPORT_INTERVAL as u64 };
+        if self.processed - self.reported >= threshold {
+            self.reported = self.processed;
+            (self.progress)(ProgressEvent::Processed {
+                processed: self.processed,
+                total: self.total,
+            });
+        }
+        let len = self.written.get() - self.emitted;
+        if len >= threshold {
+            self.emitted = self.written.get();
+            (self.progress)(ProgressEvent::Emitted { len });
+        }
+    }
+}
+
+impl<F: FnMut(ProgressEvent)> DiffObserver for Reporter<'_, F> {
+    fn block_matched(&mut self, position: u64, offset: u64, len: u64) {
+        (self.progress)(ProgressEvent::BlockMatched {
+            position,
+            offset,
+            len,
+        });
+        self.advance(position, len);
+    }
+
+    fn literal_flushed(&mut self, position: u64, len: u64) {
+        self.advance(position, len);
+    }
+
+    fn region_skipped(&mut self, position: u64, len: u64) {
+        self.advance(position, len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{apply, diff, signature, ProgressEvent};
+    use crate::{HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_progress() {
+        let mut base = vec![0; 1 << 20];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut data = base.clone();
+        rand::thread_rng().fill(&mut data[100_000..200_000]);
+        rand::thread_rng().fill(&mut data[600_000..700_000]);
+        let options = SignatureOptions::new(1024, 8, HashAlgorithm::Blake3);
+
+        let mut events = vec![];
+        let sig = signature(&base, options, |event| events.push(event));
+        assert_eq!(sig, Signature::calculate(&base, options));
+        assert_eq!(events.len(), 16);
+        let total = base.len() as u64;
+        assert_eq!(events.last(), Some(&ProgressEvent::Processed { processed: total, total }));
+
+        let mut events = vec![];
+        let mut delta = vec![];
+        diff(&sig.index(), &data, |event| events.push(event), &mut delta).unwrap();
+        let mut processed = 0;
+        let mut emitted = 0;
+        let mut matched = 0;
+        for event in events {
+            match event {
+                ProgressEvent::Processed { processed: p, .. } => {
+                    assert!(p > processed);
+                    processed = p;
+                }
+                ProgressEvent::Emitted { len } => emitted += len,
+                ProgressEvent::BlockMatched { len, .. } => matched += len,
+            }
+        }
+        assert_eq!(processed, data.len() as u64);
+        assert_eq!(emitted, delta.len() as u64);
+        assert!(matched > 0);
+
+        let mut emitted = 0;
+        let mut out = vec![];
+        apply(&base, &delta, &mut out, |event| match event {
+            ProgressEvent::Emitted { len } => emitted += len,
+            _ => unreachable!(),
+        })
+        .unwrap();
+        assert_eq!(out, data);
+        assert_eq!(emitted, data.len() as u64);
+    }
+}
@@ -0,0 +1,352 @@
+//! Recovering from a corrupted or truncated basis.
+//!
+//! A delta assumes the receiver still has the base data its signature was calculated from. When
+//! that data has been damaged since, the receiver can ask for just the damaged parts instead of
+//! the whole file:
+//!
+//! 1. The receiver calls [missing_ranges] with its base data, the signature it sent and the
+//!    delta, and sends the resulting ranges to the sender.
+//! 2. The sender calls [supplement] with the delta, the data it was calculated from and those
+//!    ranges, and sends back the [Supplement]. Every byte the delta copies from the base also
+//!    appears in the new data, so the sender can rebuild it without having the base data.
+//! 3. The receiver calls [apply_with_supplement], which reads the supplied ranges from the
+//!    supplement instead of the base data.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use crate::patch::{apply_with_scratch, ApplyError, Op, OpReader};
+use crate::signature::Signature;
+
+/// Indicates that the base data needed by a delta couldn't be determined or supplied.
+#[derive(Debug)]
+pub enum RecoveryError {
+    /// The delta couldn't be decoded.
+    Delta(ApplyError),
+    /// The signature uses a hash algorithm this crate can't calculate.
+    UnsupportedSignature,
+    /// A serialized supplement ended unexpectedly.
+    TruncatedSupplement {
+        /// The remaining length of the input.
+        available: usize,
+    },
+    /// A delta copies more data than the data it was supposedly calculated for contains.
+    DataMismatch {
+        /// The position in the data the copy writes to.
+        position: u64,
+        /// The copy length.
+        len: u64,
+        /// The length of the data.
+        data_len: usize,
+    },
+}
+
+impl fmt::Display for RecoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryError::Delta(source) => write!(f, "invalid delta (source={source})"),
+            RecoveryError::UnsupportedSignature => f.write_str("unsupported signature"),
+            RecoveryError::TruncatedSupplement { available } => {
+                write!(f, "unexpected end of supplement (available={available})")
+            }
+            RecoveryError::DataMismatch {
+                position,
+                len,
+                data_len,
+            } => write!(
+                f,
+                "delta doesn't describe the data (position={position}, len={len}, data_len={data_len})",
+            ),
+        }
+    }
+}
+
+impl Error for RecoveryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RecoveryError::Delta(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<ApplyError> for RecoveryError {
+    fn from(source: ApplyError) -> Self {
+        Self::Delta(source)
+    }
+}
+
+/// Find the ranges of `base` that `delta` copies but that can't be trusted, in ascending order
+/// and with adjacent ranges merged.
+///
+/// A range can't be trusted if it lies past the end of `base`, or if the block of `base` it lies
+/// in no longer matches `signature`, which must be the signature the delta was calculated
+/// against. Only the copied parts of damaged blocks are returned, since nothing else is needed
+/// to apply the delta.
+pub fn missing_ranges(
+    base: &[u8],
+    signature: &Signature,
+    delta: &[u8],
+) -> Result<Vec<Range<u64>>, RecoveryError> {
+    let options = signature.options().ok_or(RecoveryError::UnsupportedSignature)?;
+    let block_size = options.block_size as u64;
+    let entries: Vec<_> = signature.block_entries().collect();
+    // Whether each block was checked yet, and if so whether it is intact.
+    let mut intact: Vec<Option<bool>> = vec![None; entries.len()];
+    let mut entry = Vec::new();
+    let mut is_intact = |block: u64| -> bool {
+        let i = block as usize;
+        if i >= entries.len() {
+            return false;
+        }
+        *intact[i].get_or_insert_with(|| {
+            let start = block * block_size;
+            let end = (start + block_size).min(base.len() as u64);
+            if start >= end {
+                return false;
+            }
+            entry.clear();
+            Signature::hash_blocks(&base[start as usize..end as usize], &options, &mut entry);
+            entries[i] == entry
+        })
+    };
+
+    let mut missing = Vec::new();
+    let mut ops = OpReader::new(delta)?;
+    while let Some(op) = ops.next_op()? {
+        let Op::Copy { offset, len } = op else {
+            continue;
+        };
+        let end = offset.saturating_add(len);
+        let mut block = offset / block_size;
+        while block * block_size < end {
+            let range = offset.max(block * block_size)..end.min((block + 1) * block_size);
+            if range.end > base.len() as u64 || !is_intact(block) {
+                missing.push(range);
+            }
+            block += 1;
+        }
+    }
+    Ok(merge(missing))
+}
+
+/// Sort `ranges` and merge overlapping and adjacent ones.
+fn merge(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Base data supplied by the sender for the ranges a receiver is missing.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Supplement {
+    /// Pieces of base data and their offsets, in ascending order and not overlapping.
+    pub pieces: Vec<(u64, Vec<u8>)>,
+}
+
+impl Supplement {
+    /// Serialize the supplement for sending to the receiver.
+    ///
+    /// Layout: the number of pieces, then for every piece its offset, its length and its data.
+    /// All integers are big-endian u64s.
+    pub fn serialize(&self) -> Vec<u8> {
+        let len = self.pieces.iter().map(|(_, data)| 16 + data.len()).sum::<usize>();
+        let mut out = Vec::with_capacity(8 + len);
+        out.extend_from_slice(&(self.pieces.len() as u64).to_be_bytes());
+        for (offset, data) in &self.pieces {
+            out.extend_from_slice(&offset.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    /// Parse a supplement produced by [Supplement::serialize].
+    pub fn deserialize(mut input: &[u8]) -> Result<Self, RecoveryError> {
+        fn take<'a>(input: &mut &'a [u8], n: u64) -> Result<&'a [u8], RecoveryError> {
+            let n = usize::try_from(n).unwrap_or(usize::MAX);
+            if input.len() < n {
+                return Err(RecoveryError::TruncatedSupplement {
+                    available: input.len(),
+                });
+            }
+            let (taken, rest) = input.split_at(n);
+            *input = rest;
+            Ok(taken)
+        }
+        fn take_u64(input: &mut &[u8]) -> Result<u64, RecoveryError> {
+            Ok(u64::from_be_bytes(take(input, 8)?.try_into().unwrap()))
+        }
+
+        let count = take_u64(&mut input)?;
+        let mut pieces = Vec::new();
+        for _ in 0..count {
+            let offset = take_u64(&mut input)?;
+            let len = take_u64(&mut input)?;
+            pieces.push((offset, take(&mut input, len)?.to_vec()));
+        }
+        Ok(Supplement { pieces })
+    }
+
+    /// The length of base data needed to hold every piece.
+    fn end(&self) -> u64 {
+        self.pieces
+            .iter()
+            .map(|(offset, data)| offset + data.len() as u64)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Rebuild the `needed` ranges of the base data from `data`, the new data `delta` was calculated
+/// for.
+///
+/// Only bytes that `delta` copies can be rebuilt; any other parts of `needed` are left out.
+pub fn supplement(
+    delta: &[u8],
+    data: &[u8],
+    needed: &[Range<u64>],
+) -> Result<Supplement, RecoveryError> {
+    let needed = merge(needed.to_vec());
+    let mut pieces = Vec::new();
+    let mut ops = OpReader::new(delta)?;
+    let mut position = 0u64;
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Literal(_) | Op::Repeat { .. } => {
+                position = position.saturating_add(op.output_len())
+            }
+            Op::Copy { offset, len } => {
+                if position.saturating_add(len) > data.len() as u64 {
+                    return Err(RecoveryError::DataMismatch {
+                        position,
+                        len,
+                        data_len: data.len(),
+                    });
+                }
+                let end = offset + len;
+                let first = needed.partition_point(|range| range.end <= offset);
+                for range in needed[first..].iter().take_while(|range| range.start < end) {
+                    let start = range.start.max(offset);
+                    let stop = range.end.min(end);
+                    let from = (position + start - offset) as usize;
+                    pieces.push((start, &data[from..from + (stop - start) as usize]));
+                }
+                position += len;
+            }
+        }
+    }
+
+    // Several copies may cover the same base data; keep each byte once.
+    pieces.sort_by_key(|&(offset, _)| offset);
+    let mut supplement = Supplement::default();
+    let mut covered = 0;
+    for (offset, bytes) in pieces {
+        let end = offset + bytes.len() as u64;
+        if end <= covered {
+            continue;
+        }
+        let skip = covered.saturating_sub(offset) as usize;
+        match supplement.pieces.last_mut() {
+            Some((last, data)) if *last + data.len() as u64 == offset + skip as u64 => {
+                data.extend_from_slice(&bytes[skip..]);
+            }
+            _ => supplement.pieces.push((offset + skip as u64, bytes[skip..].to_vec())),
+        }
+        covered = end;
+    }
+    Ok(supplement)
+}
+
+/// Apply `delta` to `base`, reading the ranges in `supplement` from it instead of from `base`,
+/// and write the result to `out`.
+///
+/// # Security
+/// See [apply()](crate::apply()).
+pub fn apply_with_supplement(
+    base: &[u8],
+    supplement: &Supplement,
+    delta: &[u8],
+    out: &mut impl Write,
+) -> Result<(), ApplyError> {
+    let base_len = (base.len() as u64).max(supplement.end());
+    let read_base = |offset: u64, buf: &mut [u8]| {
+        let end = offset + buf.len() as u64;
+        buf.fill(0);
+        if offset < base.len() as u64 {
+            let available = &base[offset as usize..(end as usize).min(base.len())];
+            buf[..available.len()].copy_from_slice(available);
+        }
+        let first = supplement
+            .pieces
+            .partition_point(|(start, data)| start + data.len() as u64 <= offset);
+        for (start, data) in supplement.pieces[first..].iter().take_while(|(start, _)| *start < end) {
+            let from = offset.max(*start);
+            let to = end.min(start + data.len() as u64);
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+        Ok(())
+    };
+    let mut delta = delta;
+    let mut scratch = vec![0; 64 << 10];
+    apply_with_scratch(
+        base_len,
+        read_base,
+        |buf| delta.read(buf),
+        |buf| out.write_all(buf),
+        &mut scratch,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{apply_with_supplement, missing_ranges, supplement, Supplement};
+    use crate::{apply, diff, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_recovery() {
+        let mut base = vec![0; 10_000];
+        rand::thread_rng().fill(&mut base[..]);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(100, 8, HashAlgorithm::Blake3),
+        );
+        let mut data = base[5000..].to_vec();
+        data.extend_from_slice(b"new data");
+        data.extend_from_slice(&base[..5000]);
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+
+        // Nothing is missing from an intact base.
+        assert_eq!(missing_ranges(&base, &signature, &delta).unwrap(), vec![]);
+
+        let mut damaged = base[..9950].to_vec();
+        damaged[1234] ^= 1;
+        let needed = missing_ranges(&damaged, &signature, &delta).unwrap();
+        assert_eq!(needed, vec![1200..1300, 9900..10_000]);
+        let mut wrong = vec![];
+        apply(&damaged, &delta, &mut wrong).unwrap_err();
+
+        let supplied = supplement(&delta, &data, &needed).unwrap();
+        assert_eq!(supplied.pieces.len(), 2);
+        assert_eq!(supplied.pieces[0], (1200, base[1200..1300].to_vec()));
+        let supplied = Supplement::deserialize(&supplied.serialize()).unwrap();
+        let mut out = vec![];
+        apply_with_supplement(&damaged, &supplied, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        assert!(Supplement::deserialize(&supplied.serialize()[..20]).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Applying deltas against a basis that lives somewhere else, such as an object store.
+//!
+//! [apply] reads the copy commands of a delta ahead of time and asks the [BlockSource] for all
+//! of their ranges at once, so a source backed by a high-latency store can coalesce neighbouring
+//! ranges and fetch them concurrently instead of issuing one request per copy.
+//!
+//! [apply_provided] instead asks a [BlockProvider] for one copy at a time, which suits sources
+//! that serve blocks from a cache or that fetch them lazily, like zsync clients.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::patch::{write_repeated, ApplyError, Op, OpReader};
+
+#[cfg(feature = "object_store")]
+mod object_store;
+
+#[cfg(feature = "object_store")]
+pub use self::object_store::ObjectStoreSource;
+
+/// How many bytes of basis data are requested from the source at a time.
+const BATCH_BYTES: u64 = 16 << 20;
+
+/// A basis that can be read by byte range.
+pub trait BlockSource {
+    /// Read the given ranges of the basis into `buf`, one after the other.
+    ///
+    /// `buf.len()` is the total length of `ranges`. Ranges may overlap and are not sorted.
+    /// A range that extends past the end of the basis is an error.
+    fn fetch(&mut self, ranges: &[Range<u64>], buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// A basis that hands out its blocks one at a time, borrowed from a cache or fetched on demand.
+pub trait BlockProvider {
+    /// Read `len` bytes of the basis starting at `offset`.
+    ///
+    /// The result must be exactly `len` bytes long. A range that extends past the end of the
+    /// basis is an error.
+    fn read_block(&mut self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>>;
+}
+
+/// A basis in memory, whose blocks are borrowed.
+impl BlockProvider for &[u8] {
+    fn read_block(&mut self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.get(offset..offset.checked_add(len)?))
+            .map(Cow::Borrowed)
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+impl<T: BlockProvider + ?Sized> BlockProvider for &mut T {
+    fn read_block(&mut self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        (**self).read_block(offset, len)
+    }
+}
+
+/// Apply `delta` to the basis provided by `provider`, writing the result to `out`.
+///
+/// Each copy is read from `provider` as it is reached, in pieces of up to 16 MiB, so only the
+/// block being copied needs to be held in memory.
+pub fn apply_provided(
+    mut provider: impl BlockProvider,
+    delta: &[u8],
+    out: &mut impl Write,
+) -> Result<(), ApplyError> {
+    let mut ops = OpReader::new(delta)?;
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Literal(literal) => out.write_all(literal)?,
+            Op::Repeat { pattern, .. } => write_repeated(pattern, 0..op.output_len(), out)?,
+            Op::Copy { mut offset, len } => {
+                if len == 0 {
+                    return Err(ApplyError::CopyZero);
+                }
+                let end = offset.checked_add(len).ok_or(ApplyError::CopyOutOfBounds {
+                    offset,
+                    len,
+                    data_len: usize::MAX,
+                })?;
+                while offset < end {
+                    let n = (end - offset).min(BATCH_BYTES) as usize;
+                    let block = provider.read_block(offset, n)?;
+                    if block.len() != n {
+                        return Err(ApplyError::UnexpectedEof {
+                            reading: "block",
+                            expected: n,
+                            available: block.len(),
+                        });
+                    }
+                    out.write_all(&block)?;
+                    offset += n as u64;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply `delta` to the basis provided by `source`, writing the result to `out`.
+///
+/// Copies are requested from `source` in batches of up to 16 MiB.
+pub fn apply(
+    source: &mut impl BlockSource,
+    delta: &[u8],
+    out: &mut impl Write,
+) -> Result<(), ApplyError> {
+    let mut ops = OpReader::new(delta)?;
+    let mut batch = Vec::new();
+    let mut ranges = Vec::new();
+    let mut batch_bytes = 0;
+    let mut buf = Vec::new();
+    loop {
+        let op = ops.next_op()?;
+        match op {
+            Some(Op::Copy { mut offset, len }) => {
+                if len == 0 {
+                    return Err(ApplyError::CopyZero);
+                }
+                let end = offset.checked_add(len).ok_or(ApplyError::CopyOutOfBounds {
+                    offset,
+                    len,
+                    data_len: usize::MAX,
+                })?;
+                // Split huge copies so a batch never needs more than about `BATCH_BYTES`.
+                while offset < end {
+                    let n = (end - offset).min(BATCH_BYTES);
+                    ranges.push(offset..offset + n);
+                    batch.push(Op::Copy { offset, len: n });
+                    batch_bytes += n;
+                    offset += n;
+                    if batch_bytes >= BATCH_BYTES {
+                        flush(source, &mut batch, &mut ranges, &mut buf, out)?;
+                        batch_bytes = 0;
+                    }
+                }
+            }
+            Some(op) => batch.push(op),
+            None => break,
+        }
+    }
+    flush(source, &mut batch, &mut ranges, &mut buf, out)?;
+    Ok(())
+}
+
+/// Fetch the copies of `batch` and write all of its ops to `out`.
+fn flush(
+    source: &mut impl BlockSource,
+    batch: &mut Vec<Op<'_>>,
+    ranges: &mut Vec<Range<u64>>,
+    buf: &mut Vec<u8>,
+    out: &mut impl Write,
+) -> Result<(), ApplyError> {
+    let total: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+    buf.clear();
+    buf.resize(total as usize, 0);
+    if !ranges.is_empty() {
+        source.fetch(ranges, buf)?;
+    }
+    let mut copied = &buf[..];
+    for op in batch.drain(..) {
+        match op {
+            Op::Literal(literal) => out.write_all(literal)?,
+            Op::Repeat { pattern, .. } => write_repeated(pattern, 0..op.output_len(), out)?,
+            Op::Copy { len, .. } => {
+                let (data, rest) = copied.split_at(len as usize);
+                out.write_all(data)?;
+                copied = rest;
+            }
+        }
+    }
+    ranges.clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::io;
+    use std::ops::Range;
+
+    use super::{apply_provided, BlockProvider, BlockSource};
+    use crate::{diff, HashAlgorithm, Signature, SignatureOptions};
+
+    struct CountingSource<'a> {
+        base: &'a [u8],
+        fetches: usize,
+    }
+
+    impl BlockSource for CountingSource<'_> {
+        fn fetch(&mut self, ranges: &[Range<u64>], mut buf: &mut [u8]) -> io::Result<()> {
+            self.fetches += 1;
+            for range in ranges {
+                let data = self
+                    .base
+                    .get(range.start as usize..range.end as usize)
+                    .ok_or(io::ErrorKind::UnexpectedEof)?;
+                let (dest, rest) = buf.split_at_mut(data.len());
+                dest.copy_from_slice(data);
+                buf = rest;
+            }
+            Ok(())
+        }
+    }
+
+    /// Fetches whole blocks of the base and caches them, like a zsync client.
+    struct CachingProvider<'a> {
+        base: &'a [u8],
+        cache: HashMap<u64, Vec<u8>>,
+        fetches: usize,
+    }
+
+    impl BlockProvider for CachingProvider<'_> {
+        fn read_block(&mut self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+            let base = self.base;
+            let block = self.cache.entry(offset).or_insert_with(|| {
+                self.fetches += 1;
+                base.get(offset as usize..).unwrap_or_default().to_vec()
+            });
+            block.get(..len).map(Cow::Borrowed).ok_or(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    #[test]
+    fn test_apply_provided() {
+        let block: Vec<u8> = (0..=255).collect();
+        let base = block.repeat(100);
+        let data = [&base[1000..5000], b"inserted", &base[1000..5000]].concat();
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(500, 8, HashAlgorithm::Md4),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+
+        let mut out = vec![];
+        apply_provided(&base[..], &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let mut provider = CachingProvider {
+            base: &base,
+            cache: HashMap::new(),
+            fetches: 0,
+        };
+        let mut out = vec![];
+        apply_provided(&mut provider, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(provider.fetches < 3, "{}", provider.fetches);
+
+        assert!(apply_provided(&base[..1000], &delta, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_remote_apply() {
+        let base: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let data = [&base[50_000..], b"inserted", &base[..30_000]].concat();
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(256, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+
+        let mut source = CountingSource {
+            base: &base,
+            fetches: 0,
+        };
+        let mut out = vec![];
+        super::apply(&mut source, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(source.fetches, 1);
+
+        // out-of-bounds copies surface the source's error
+        let mut source = CountingSource {
+            base: &base[..1000],
+            fetches: 0,
+        };
+        assert!(super::apply(&mut source, &delta, &mut vec![]).is_err());
+    }
+}
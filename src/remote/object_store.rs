@@ -0,0 +1,144 @@
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+use futures::stream::{self, StreamExt, TryStreamExt};
+use object_store::path::Path;
+use object_store::ObjectStore;
+use tokio::runtime::Handle;
+
+use super::BlockSource;
+
+/// A [BlockSource] reading the basis from an object in an [ObjectStore] (S3, GCS, Azure, ...).
+///
+/// Ranges that are close together are merged into one request, and at most
+/// [concurrency](Self::concurrency) requests are in flight at a time. Requests run on the given
+/// tokio runtime, so [fetch](BlockSource::fetch) must not be called from within an async task;
+/// use `spawn_blocking` there.
+#[derive(Debug)]
+pub struct ObjectStoreSource {
+    store: Arc<dyn ObjectStore>,
+    location: Path,
+    runtime: Handle,
+    coalesce: u64,
+    concurrency: usize,
+}
+
+impl ObjectStoreSource {
+    /// Read the basis from `location` in `store`, running requests on `runtime`.
+    ///
+    /// By default ranges less than 1 MiB apart are merged and 8 requests run concurrently.
+    pub fn new(store: Arc<dyn ObjectStore>, location: Path, runtime: Handle) -> Self {
+        ObjectStoreSource {
+            store,
+            location,
+            runtime,
+            coalesce: 1 << 20,
+            concurrency: 8,
+        }
+    }
+
+    /// Merge ranges that are at most `gap` bytes apart into a single request.
+    pub fn coalesce(mut self, gap: u64) -> Self {
+        self.coalesce = gap;
+        self
+    }
+
+    /// Set how many requests may be in flight at once.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+}
+
+impl BlockSource for ObjectStoreSource {
+    fn fetch(&mut self, ranges: &[Range<u64>], buf: &mut [u8]) -> io::Result<()> {
+        let requests = coalesce(ranges, self.coalesce);
+        let store = &self.store;
+        let location = &self.location;
+        let bodies: Vec<_> = self
+            .runtime
+            .block_on(
+                stream::iter(requests.iter().cloned())
+                    .map(|range| store.get_range(location, range))
+                    .buffered(self.concurrency)
+                    .try_collect(),
+            )
+            .map_err(io::Error::other)?;
+
+        let mut buf = buf;
+        for range in ranges {
+            // The request containing this range is the last one starting at or before it.
+            let i = requests.partition_point(|request| request.start <= range.start) - 1;
+            let start = (range.start - requests[i].start) as usize;
+            let end = (range.end - requests[i].start) as usize;
+            let data = bodies[i].get(start..end).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "range past the end of the object")
+            })?;
+            let (dest, rest) = buf.split_at_mut(data.len());
+            dest.copy_from_slice(data);
+            buf = rest;
+        }
+        Ok(())
+    }
+}
+
+/// Sort `ranges` and merge those at most `gap` bytes apart.
+fn coalesce(ranges: &[Range<u64>], gap: u64) -> Vec<Range<u64>> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_unstable_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(gap) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use object_store::memory::InMemory;
+    use object_store::path::Path;
+    use object_store::{ObjectStore, PutPayload};
+
+    use super::{coalesce, ObjectStoreSource};
+    use crate::remote::BlockSource;
+
+    #[test]
+    fn test_coalesce() {
+        assert_eq!(coalesce(&[20..30, 0..10, 12..15, 100..110], 2), vec![0..15, 20..30, 100..110]);
+        assert_eq!(coalesce(&[0..10, 5..8, 40..50], 100), vec![0..50]);
+    }
+
+    #[test]
+    fn test_object_store_source() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let store = Arc::new(InMemory::new());
+        let location = Path::from("basis");
+        let base: Vec<u8> = (0..10_000u32).map(|i| (i % 256) as u8).collect();
+        runtime
+            .block_on(store.put(&location, PutPayload::from(base.clone())))
+            .unwrap();
+
+        let mut source = ObjectStoreSource::new(store, location, runtime.handle().clone())
+            .coalesce(16)
+            .concurrency(2);
+        let ranges = [5000..5100, 0..10, 12..20, 9990..10_000, 5050..5060];
+        let mut buf = vec![0; 138];
+        source.fetch(&ranges, &mut buf).unwrap();
+        let expected: Vec<u8> = ranges
+            .iter()
+            .flat_map(|r| base[r.start as usize..r.end as usize].iter().copied())
+            .collect();
+        assert_eq!(buf, expected);
+
+        let past_end = 9990..10_010;
+        assert!(source.fetch(std::slice::from_ref(&past_end), &mut [0; 20]).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+
+use crate::crc::Crc;
+
+/// The weak hash of the blocks of a signature, which is rolled over the new data byte by byte to
+/// find the candidates for the strong hash to confirm.
+///
+/// [Crc], the rollsum of librsync, is the default. Signatures don't record which rolling hash
+/// they were calculated with unless it sets [RollingHash::MAGIC_FLAG], so both sides must agree
+/// on it, and signatures of other hashes are calculated and read with the `_with_rolling_hash`
+/// functions of [Signature](crate::Signature).
+pub trait RollingHash: Copy + Default + fmt::Debug + Eq + Send + Sync + 'static {
+    /// Set in the magic of the signatures calculated with this hash, so that they aren't read as
+    /// those of another one. [Crc] sets none, since its signatures are librsync's.
+    const MAGIC_FLAG: u32 = 0;
+
+    /// Extend the hash with `buf`. The default value is the hash of no bytes.
+    fn update(self, buf: &[u8]) -> Self;
+
+    /// The hash of the window one byte further than the `size` bytes hashed by `self`, which
+    /// drops `old_byte` at the start and takes in `new_byte` at the end.
+    fn rotate(self, size: u32, old_byte: u8, new_byte: u8) -> Self;
+
+    /// Like [RollingHash::rotate] for a window of `1 << shift` bytes.
+    ///
+    /// Block sizes are usually powers of two, so implementations can override this to replace a
+    /// multiplication with a shift.
+    #[inline]
+    fn rotate_shift(self, shift: u32, old_byte: u8, new_byte: u8) -> Self {
+        self.rotate(1 << shift, old_byte, new_byte)
+    }
+
+    /// Rotate the hash over several bytes: `out[i]` is the hash after rotating out `old[..=i]`
+    /// and in `new[..=i]` one byte at a time. `old` and `new` must be as long as `out`.
+    ///
+    /// This is the hot loop of the search through data without matches, so implementations can
+    /// override it to compute several windows at once with SIMD.
+    #[inline]
+    fn rotate_many(self, size: u32, old: &[u8], new: &[u8], out: &mut [Self]) {
+        assert!(old.len() == out.len() && new.len() == out.len());
+        let mut hash = self;
+        for ((out, &old), &new) in out.iter_mut().zip(old).zip(new) {
+            hash = hash.rotate(size, old, new);
+            *out = hash;
+        }
+    }
+
+    /// The hash of the `size` bytes hashed by `self` without `old_byte` at the start.
+    fn rollout(self, size: u32, old_byte: u8) -> Self;
+
+    /// The serialized hash, as stored for each block of a signature.
+    fn to_bytes(self) -> [u8; Crc::SIZE];
+}
+
+impl RollingHash for Crc {
+    #[inline]
+    fn update(self, buf: &[u8]) -> Self {
+        Crc::update(self, buf)
+    }
+
+    #[inline]
+    fn rotate(self, size: u32, old_byte: u8, new_byte: u8) -> Self {
+        Crc::rotate(self, size, old_byte, new_byte)
+    }
+
+    #[inline]
+    fn rotate_shift(self, shift: u32, old_byte: u8, new_byte: u8) -> Self {
+        Crc::rotate_shift(self, shift, old_byte, new_byte)
+    }
+
+    #[inline]
+    fn rotate_many(self, size: u32, old: &[u8], new: &[u8], out: &mut [Self]) {
+        Crc::rotate_many(self, size, old, new, out)
+    }
+
+    #[inline]
+    fn rollout(self, size: u32, old_byte: u8) -> Self {
+        Crc::rollout(self, size, old_byte)
+    }
+
+    #[inline]
+    fn to_bytes(self) -> [u8; Crc::SIZE] {
+        Crc::to_bytes(self)
+    }
+}
+
+/// `hash` as the key of the block indexes, which are built from the serialized hashes.
+#[inline]
+pub(crate) fn as_crc<R: RollingHash>(hash: R) -> Crc {
+    Crc::from_bytes(hash.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck_macros::quickcheck;
+
+    use super::RollingHash;
+    use crate::{apply, diff, HashAlgorithm, Signature, SignatureOptions};
+
+    /// A polynomial hash of base 257, the textbook Rabin-Karp hash.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct RabinKarp(u32);
+
+    const BASE: u32 = 257;
+
+    impl RollingHash for RabinKarp {
+        fn update(self, buf: &[u8]) -> Self {
+            RabinKarp(buf.iter().fold(self.0, |hash, &byte| {
+                hash.wrapping_mul(BASE).wrapping_add(byte as u32)
+            }))
+        }
+
+        fn rotate(self, size: u32, old_byte: u8, new_byte: u8) -> Self {
+            let hash = self.rollout(size, old_byte).0;
+            RabinKarp(hash.wrapping_mul(BASE).wrapping_add(new_byte as u32))
+        }
+
+        fn rollout(self, size: u32, old_byte: u8) -> Self {
+            let weight = BASE.wrapping_pow(size - 1);
+            RabinKarp(self.0.wrapping_sub((old_byte as u32).wrapping_mul(weight)))
+        }
+
+        fn to_bytes(self) -> [u8; 4] {
+            self.0.to_be_bytes()
+        }
+    }
+
+    #[quickcheck]
+    fn rotate_one(mut buf: Vec<u8>, byte: u8) -> bool {
+        if buf.is_empty() {
+            return true;
+        }
+        let sum1 = RabinKarp::default().update(&buf).rotate(buf.len() as u32, buf[0], byte);
+        buf.push(byte);
+        sum1 == RabinKarp::default().update(&buf[1..])
+    }
+
+    #[test]
+    fn test_custom_rolling_hash() {
+        let base: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let data = [&base[50_000..], b"new", &base[..49_999]].concat();
+        let options = SignatureOptions::new(1000, 8, HashAlgorithm::Blake3);
+        let signature = Signature::<RabinKarp>::calculate_with_rolling_hash(&base, options);
+        assert_ne!(signature.serialized(), Signature::calculate(&base, options).serialized());
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+        assert!(delta.len() < 2000, "{}", delta.len());
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let serialized = signature.serialized().to_vec();
+        let deserialized =
+            Signature::<RabinKarp>::deserialize_with_rolling_hash(serialized).unwrap();
+        assert_eq!(deserialized, signature);
+    }
+}
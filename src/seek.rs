@@ -0,0 +1,216 @@
+//! Seek indexes for random access into the result of a delta.
+//!
+//! [apply_range](crate::apply_range()) has to decode every op before the requested range. A
+//! [SeekIndex] records where every Nth op starts in the delta and in the result, so a range can
+//! be found by binary search instead. The index is small and is stored next to the delta, like
+//! the seek table of a seekable zstd file. It also serves as a list of checkpoints from which an
+//! interrupted apply can be resumed.
+
+use std::io::Write;
+use std::ops::Range;
+
+use crate::consts::{DELTA_HEADER_SIZE, SEEK_INDEX_MAGIC};
+use crate::patch::{apply_ops_range, ApplyError, OpReader};
+use crate::strict::require;
+
+/// A table of positions in a delta and in its result, see the [module docs](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeekIndex {
+    delta_len: u64,
+    output_len: u64,
+    /// The position in the result and the offset in the delta of every indexed op, in order.
+    /// The first entry is always the first op.
+    entries: Vec<(u64, u64)>,
+}
+
+impl SeekIndex {
+    /// Index every `interval`th op of `delta`.
+    ///
+    /// Smaller intervals make lookups faster and the index larger: every entry takes 16 bytes.
+    ///
+    /// # Panics
+    /// Panics if `interval` is zero, unless the `strict` feature turns that into
+    /// [ApplyError::InvalidArgument].
+    pub fn build(delta: &[u8], interval: usize) -> Result<Self, ApplyError> {
+        require!(interval > 0, ApplyError::InvalidArgument { name: "interval" });
+        let mut ops = OpReader::new(delta)?;
+        let mut entries = Vec::new();
+        let mut position = 0u64;
+        for i in 0.. {
+            let offset = (delta.len() - ops.remaining()) as u64;
+            let Some(op) = ops.next_op()? else {
+                break;
+            };
+            if i % interval == 0 {
+                entries.push((position, offset));
+            }
+            position = position.saturating_add(op.output_len());
+        }
+        Ok(SeekIndex {
+            delta_len: delta.len() as u64,
+            output_len: position,
+            entries,
+        })
+    }
+
+    /// The length of the result of the delta.
+    pub fn output_len(&self) -> u64 {
+        self.output_len
+    }
+
+    /// The last indexed op that starts at or before `position` in the result, as its position in
+    /// the result and its offset in the delta.
+    ///
+    /// An apply that was interrupted after writing `position` bytes can resume from here.
+    pub fn checkpoint(&self, position: u64) -> (u64, u64) {
+        let i = self.entries.partition_point(|&(start, _)| start <= position);
+        self.entries
+            .get(i.wrapping_sub(1))
+            .copied()
+            .unwrap_or((0, DELTA_HEADER_SIZE as u64))
+    }
+
+    /// Serialize the index for storing next to the delta.
+    ///
+    /// Layout: [SEEK_INDEX_MAGIC], the length of the delta, the length of the result and the
+    /// number of entries, then the position in the result and offset in the delta of every entry.
+    /// All integers but the magic are big-endian u64s.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + 3 * 8 + self.entries.len() * 16);
+        out.extend_from_slice(&SEEK_INDEX_MAGIC.to_be_bytes());
+        out.extend_from_slice(&self.delta_len.to_be_bytes());
+        out.extend_from_slice(&self.output_len.to_be_bytes());
+        out.extend_from_slice(&(self.entries.len() as u64).to_be_bytes());
+        for (position, offset) in &self.entries {
+            out.extend_from_slice(&position.to_be_bytes());
+            out.extend_from_slice(&offset.to_be_bytes());
+        }
+        out
+    }
+
+    /// Parse an index produced by [SeekIndex::serialize].
+    ///
+    /// The entries are checked to be in order, but not against the delta: pass the index only
+    /// along with the delta it was built for.
+    pub fn deserialize(index: &[u8]) -> Result<Self, ApplyError> {
+        if index.len() < 4 {
+            return Err(ApplyError::UnexpectedEof {
+                reading: "magic",
+                expected: 4,
+                available: index.len(),
+            });
+        }
+        let magic = u32::from_be_bytes(index[..4].try_into().unwrap());
+        if magic != SEEK_INDEX_MAGIC {
+            return Err(ApplyError::WrongMagic { magic });
+        }
+        let mut input = &index[4..];
+        let mut read = |reading: &'static str| -> Result<u64, ApplyError> {
+            if input.len() < 8 {
+                return Err(ApplyError::UnexpectedEof {
+                    reading,
+                    expected: 8,
+                    available: input.len(),
+                });
+            }
+            let (value, rest) = input.split_at(8);
+            input = rest;
+            Ok(u64::from_be_bytes(value.try_into().unwrap()))
+        };
+        let delta_len = read("delta length")?;
+        let output_len = read("output length")?;
+        let count = read("entry count")?;
+        let mut entries = Vec::with_capacity(usize::try_from(count).unwrap_or(0).min(1 << 16));
+        for _ in 0..count {
+            let position = read("entry position")?;
+            let offset = read("entry offset")?;
+            entries.push((position, offset));
+        }
+        if !input.is_empty() {
+            return Err(ApplyError::TrailingData {
+                length: input.len(),
+            });
+        }
+        let in_order = entries.windows(2).all(|w| w[0].0 <= w[1].0 && w[0].1 < w[1].1);
+        let in_bounds = entries
+            .iter()
+            .all(|&(position, offset)| position <= output_len && offset < delta_len);
+        if !in_order || !in_bounds {
+            return Err(ApplyError::SeekIndexMismatch {
+                expected: delta_len,
+                delta_len: usize::try_from(delta_len).unwrap_or(usize::MAX),
+            });
+        }
+        Ok(SeekIndex {
+            delta_len,
+            output_len,
+            entries,
+        })
+    }
+}
+
+/// Apply `delta` to `base`, writing only `range` of the result to `out`, like
+/// [apply_range](crate::apply_range()) but starting at the nearest op recorded in `index`.
+///
+/// Returns the number of bytes written. Resume an interrupted apply that already wrote
+/// `position` bytes with a range of `position..index.output_len()`.
+pub fn apply_range(
+    base: &[u8],
+    delta: &[u8],
+    index: &SeekIndex,
+    range: Range<u64>,
+    out: &mut impl Write,
+) -> Result<u64, ApplyError> {
+    if index.delta_len != delta.len() as u64 {
+        return Err(ApplyError::SeekIndexMismatch {
+            expected: index.delta_len,
+            delta_len: delta.len(),
+        });
+    }
+    // Check the magic, even though the ops are read from the checkpoint on.
+    OpReader::new(delta)?;
+    let (position, offset) = index.checkpoint(range.start);
+    apply_ops_range(base, OpReader::resume(delta, offset as usize), position, range, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{apply_range, SeekIndex};
+    use crate::{diff, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_seek_index() {
+        let mut base = vec![0; 20_000];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut data = Vec::new();
+        for i in 0..100 {
+            data.extend_from_slice(&base[i * 150..i * 150 + 100]);
+            data.extend_from_slice(&[i as u8; 10]);
+        }
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(50, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+
+        let index = SeekIndex::build(&delta, 8).unwrap();
+        assert_eq!(index.output_len(), data.len() as u64);
+        assert_eq!(index.checkpoint(0), (0, 4));
+        let index = SeekIndex::deserialize(&index.serialize()).unwrap();
+        for range in [0..11_000, 5000..5001, 1234..5678, 10_990..11_000, 10_000..20_000] {
+            let mut out = vec![];
+            let written = apply_range(&base, &delta, &index, range.clone(), &mut out).unwrap();
+            let expected = &data[range.start as usize..(range.end as usize).min(data.len())];
+            assert_eq!(out, expected);
+            assert_eq!(written, expected.len() as u64);
+        }
+
+        assert!(apply_range(&base, &delta[..delta.len() - 1], &index, 0..1, &mut vec![]).is_err());
+        let serialized = index.serialize();
+        assert!(SeekIndex::deserialize(&serialized[..serialized.len() - 1]).is_err());
+        assert!(SeekIndex::deserialize(&delta).is_err());
+    }
+}
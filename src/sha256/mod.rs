@@ -0,0 +1,34 @@
+//! SHA-256 block hashes, for pipelines that require a FIPS-approved hash.
+
+use sha2::{Digest, Sha256};
+
+pub const SHA256_SIZE: usize = 32;
+
+/// Compute the SHA-256 hash of a single block of data
+pub fn sha256(data: &[u8]) -> [u8; SHA256_SIZE] {
+    Sha256::digest(data).into()
+}
+
+/// Compute SHA-256 hashes of multiple blocks of data
+pub fn sha256_many<'a>(
+    datas: impl ExactSizeIterator<Item = &'a [u8]>,
+) -> impl ExactSizeIterator<Item = (&'a [u8], [u8; SHA256_SIZE])> {
+    datas.map(|data| (data, sha256(data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256(b"abc"),
+            *b"\xba\x78\x16\xbf\x8f\x01\xcf\xea\x41\x41\x40\xde\x5d\xae\x22\x23\
+               \xb0\x03\x61\xa3\x96\x17\x7a\x9c\xb4\x10\xff\x61\xf2\x00\x15\xad"
+        );
+        let datas = [&b"block1"[..], b"block2"];
+        let results: Vec<_> = sha256_many(datas.into_iter()).collect();
+        assert_eq!(results, [(datas[0], sha256(datas[0])), (datas[1], sha256(datas[1]))]);
+    }
+}
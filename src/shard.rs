@@ -0,0 +1,285 @@
+//! Splitting signatures into shards, so that several machines can diff against a huge base.
+//!
+//! [split] cuts a signature into shards of consecutive blocks. Each shard is a signature of its
+//! own, plus where its blocks lie in the whole signature, and can be shipped to a worker that
+//! diffs a region of the new data against it with [SignatureShard::diff]. The copies in the
+//! resulting deltas refer to offsets in the whole base data, so the deltas of consecutive regions
+//! can be merged with [concat_deltas](crate::concat_deltas()). [join] reassembles the shards.
+
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+use crate::consts::{DELTA_MAGIC, RS_OP_END};
+use crate::diff::{diff, DiffError};
+use crate::ops::{encode_copy, encode_literal_header};
+use crate::patch::{Op, OpReader};
+use crate::signature::Signature;
+
+/// Indicates that signature shards could not be parsed or reassembled.
+#[derive(Debug)]
+pub enum ShardError {
+    /// A serialized shard was truncated or contained an invalid signature.
+    Invalid,
+    /// There were no shards to join.
+    NoShards,
+    /// A shard was calculated with different options than the first one.
+    OptionsMismatch {
+        /// The index of the shard.
+        shard: usize,
+    },
+    /// A shard didn't start where the previous one ended.
+    NotContiguous {
+        /// The index of the shard.
+        shard: usize,
+        /// The first block of the shard.
+        first_block: u64,
+        /// The block after the previous shard.
+        expected: u64,
+    },
+    /// The shards didn't add up to the whole signature.
+    Incomplete {
+        /// The number of blocks in all shards.
+        blocks: u64,
+        /// The number of blocks in the whole signature.
+        expected: u64,
+    },
+}
+
+impl fmt::Display for ShardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardError::Invalid => f.write_str("invalid signature shard"),
+            ShardError::NoShards => f.write_str("no signature shards to join"),
+            ShardError::OptionsMismatch { shard } => {
+                write!(f, "shard has different signature options (shard={shard})")
+            }
+            ShardError::NotContiguous {
+                shard,
+                first_block,
+                expected,
+            } => write!(
+                f,
+                "shards aren't contiguous (shard={shard}, first_block={first_block}, expected={expected})",
+            ),
+            ShardError::Incomplete { blocks, expected } => write!(
+                f,
+                "shards don't cover the whole signature (blocks={blocks}, expected={expected})",
+            ),
+        }
+    }
+}
+
+impl Error for ShardError {}
+
+/// A part of a signature, as produced by [split].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignatureShard {
+    /// The index of the first block of the shard in the whole signature.
+    pub first_block: u64,
+    /// The number of blocks in the whole signature.
+    pub total_blocks: u64,
+    /// The blocks of the shard, as a signature of its own.
+    pub signature: Signature,
+}
+
+/// Split `signature` into shards of `blocks_per_shard` consecutive blocks. The last shard may be
+/// smaller.
+///
+/// An empty signature yields one empty shard, so that every signature can be joined again.
+///
+/// # Panics
+/// Panics if `blocks_per_shard` is zero, unless the `strict` feature makes it 1.
+pub fn split(signature: &Signature, blocks_per_shard: usize) -> Vec<SignatureShard> {
+    #[cfg(feature = "strict")]
+    let blocks_per_shard = blocks_per_shard.max(1);
+    assert!(blocks_per_shard > 0);
+    let entries: Vec<_> = signature.block_entries().collect();
+    let total_blocks = entries.len() as u64;
+    let shard = |first_block: usize, entries: &[&[u8]]| {
+        let mut serialized = signature.header().to_vec();
+        for entry in entries {
+            serialized.extend_from_slice(entry);
+        }
+        SignatureShard {
+            first_block: first_block as u64,
+            total_blocks,
+            signature: Signature::deserialize(serialized).expect("shard of a valid signature"),
+        }
+    };
+    if entries.is_empty() {
+        return vec![shard(0, &[])];
+    }
+    entries
+        .chunks(blocks_per_shard)
+        .enumerate()
+        .map(|(i, entries)| shard(i * blocks_per_shard, entries))
+        .collect()
+}
+
+/// Reassemble the signature that `shards`, in any order, were split from.
+pub fn join(shards: &[SignatureShard]) -> Result<Signature, ShardError> {
+    let mut order: Vec<usize> = (0..shards.len()).collect();
+    order.sort_by_key(|&i| shards[i].first_block);
+    let first = &shards[*order.first().ok_or(ShardError::NoShards)?];
+    let mut serialized = first.signature.header().to_vec();
+    let mut blocks = 0;
+    for shard in order {
+        let SignatureShard {
+            first_block,
+            total_blocks,
+            signature,
+        } = &shards[shard];
+        if signature.header() != first.signature.header() || *total_blocks != first.total_blocks {
+            return Err(ShardError::OptionsMismatch { shard });
+        }
+        if *first_block != blocks {
+            return Err(ShardError::NotContiguous {
+                shard,
+                first_block: *first_block,
+                expected: blocks,
+            });
+        }
+        for entry in signature.block_entries() {
+            serialized.extend_from_slice(entry);
+            blocks += 1;
+        }
+    }
+    if blocks != first.total_blocks {
+        return Err(ShardError::Incomplete {
+            blocks,
+            expected: first.total_blocks,
+        });
+    }
+    Signature::deserialize(serialized).map_err(|_| ShardError::Invalid)
+}
+
+impl SignatureShard {
+    /// Where the data of the shard starts in the whole base data.
+    pub fn base_offset(&self) -> u64 {
+        self.first_block * self.signature.block_size() as u64
+    }
+
+    /// Calculate a delta from the blocks of this shard to `data`, and write it to `out`.
+    ///
+    /// Copies refer to offsets in the whole base data. Only the blocks of this shard are matched,
+    /// so data that moved in from the region of another shard becomes literals.
+    ///
+    /// # Security
+    /// See [diff()](crate::diff()).
+    pub fn diff(&self, data: &[u8], mut out: impl Write) -> Result<(), DiffError> {
+        let mut delta = Vec::new();
+        diff(&self.signature.index(), data, &mut delta)?;
+        let base_offset = self.base_offset();
+        out.write_all(&DELTA_MAGIC.to_be_bytes())?;
+        let mut ops = OpReader::new(&delta).expect("diff produced an invalid delta");
+        while let Some(op) = ops.next_op().expect("diff produced an invalid delta") {
+            match op {
+                Op::Literal(literal) => {
+                    encode_literal_header(literal.len() as u64, &mut out)?;
+                    out.write_all(literal)?;
+                }
+                Op::Copy { offset, len } => encode_copy(base_offset + offset, len, &mut out)?,
+                Op::Repeat { .. } => unreachable!("diff writes v1 deltas"),
+            }
+        }
+        out.write_all(&[RS_OP_END])?;
+        Ok(())
+    }
+
+    /// Serialize the shard for shipping to a worker.
+    ///
+    /// Layout: the first block and the total number of blocks as big-endian u64s, then the
+    /// serialized signature of the shard.
+    pub fn serialize(&self) -> Vec<u8> {
+        let signature = self.signature.serialized();
+        let mut out = Vec::with_capacity(16 + signature.len());
+        out.extend_from_slice(&self.first_block.to_be_bytes());
+        out.extend_from_slice(&self.total_blocks.to_be_bytes());
+        out.extend_from_slice(signature);
+        out
+    }
+
+    /// Parse a shard produced by [SignatureShard::serialize].
+    pub fn deserialize(mut shard: Vec<u8>) -> Result<Self, ShardError> {
+        if shard.len() < 16 {
+            return Err(ShardError::Invalid);
+        }
+        let first_block = u64::from_be_bytes(shard[..8].try_into().unwrap());
+        let total_blocks = u64::from_be_bytes(shard[8..16].try_into().unwrap());
+        shard.drain(..16);
+        let signature = Signature::deserialize(shard).map_err(|_| ShardError::Invalid)?;
+        let blocks = signature.block_entries().len() as u64;
+        if first_block.checked_add(blocks).is_none_or(|end| end > total_blocks) {
+            return Err(ShardError::Invalid);
+        }
+        Ok(SignatureShard {
+            first_block,
+            total_blocks,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{join, split, ShardError, SignatureShard};
+    use crate::{apply, concat_deltas, ChunkDelta, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_shards() {
+        let mut base = vec![0; 10_050];
+        rand::thread_rng().fill(&mut base[..]);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(100, 8, HashAlgorithm::Blake3),
+        );
+        let mut shards = split(&signature, 30);
+        assert_eq!(shards.len(), 4);
+        assert_eq!(shards[3].first_block, 90);
+        assert_eq!(shards[3].base_offset(), 9000);
+        shards.reverse();
+        let shards: Vec<_> = shards
+            .iter()
+            .map(|shard| SignatureShard::deserialize(shard.serialize()).unwrap())
+            .collect();
+        assert_eq!(join(&shards).unwrap(), signature);
+
+        // Each worker diffs the region of the new data that corresponds to its shard.
+        let mut data = base.clone();
+        data[4321] ^= 1;
+        let chunks: Vec<_> = shards
+            .iter()
+            .map(|shard| {
+                let start = shard.base_offset() as usize;
+                let end = (start + 3000).min(data.len());
+                let mut delta = vec![];
+                shard.diff(&data[start..end], &mut delta).unwrap();
+                ChunkDelta {
+                    start: start as u64,
+                    delta,
+                }
+            })
+            .collect();
+        let delta = concat_deltas(&chunks).unwrap();
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        assert!(matches!(
+            join(&shards[1..]),
+            Err(ShardError::Incomplete {
+                blocks: 90,
+                expected: 101,
+            })
+        ));
+        assert!(matches!(join(&[]), Err(ShardError::NoShards)));
+        let empty = Signature::calculate(
+            &[],
+            SignatureOptions::new(100, 8, HashAlgorithm::Blake3),
+        );
+        assert_eq!(join(&split(&empty, 1)).unwrap(), empty);
+    }
+}
@@ -1,56 +1,365 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+#[cfg(feature = "parallel")]
+use std::hash::BuildHasher;
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use arrayref::array_ref;
 
-use crate::consts::{BLAKE2_MAGIC, BLAKE3_MAGIC, MD4_MAGIC};
+use crate::blake3::BLAKE3_SIZE;
+use crate::compact::{self, CompactOptions};
+use crate::consts::{
+    BLAKE2_MAGIC, BLAKE3_MAGIC, COMPACT_SIGNATURE_MAGIC, MD4_MAGIC, MD5_MAGIC, SEEDED_MAGIC_FLAG,
+    SEEDED_SIGNATURE_HEADER_SIZE, SHA256_MAGIC, SIGNATURE_EXT_HEADER_SIZE, SIGNATURE_EXT_MAGIC,
+    SIGNATURE_EXT_SOURCE_BLAKE3, SIGNATURE_EXT_SOURCE_LEN, SIGNATURE_HEADER_SIZE,
+    SIGNATURE_V2_HEADER_SIZE, SIGNATURE_V2_MAGIC,
+};
 use crate::crc::Crc;
 use crate::hasher::BuildCrcHasher;
-use crate::hashmap_variant::SecondLayerMap;
-use crate::md4::{md4, md4_many, MD4_SIZE};
-use crate::blake3::{blake3, blake3_many, BLAKE3_SIZE};
+use crate::hashmap_variant::{Arena, SecondLayerMap};
+use crate::index_table::IndexTable;
+use crate::perfect_hash::PerfectIndex;
+use crate::rolling_hash::RollingHash;
+use crate::strong_hash::{self, SeededHash, StrongHash, MAX_STRONG_HASH_SIZE};
+use crate::strict::{invalid_input, require};
+
+/// How many bytes [Signature::calculate_from_reader] reads at a time, rounded up to whole blocks.
+const READER_CHUNK_SIZE: usize = 1 << 20;
+
+/// How many bytes each task of [Signature::calculate_parallel] hashes, rounded up to whole blocks.
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK_SIZE: usize = 1 << 20;
+
+/// How many blocks are passed to [StrongHash::hash_many] at a time.
+const HASH_BATCH: usize = 256;
+
+/// How many maps [Signature::index_parallel] splits the blocks into by CRC.
+#[cfg(feature = "parallel")]
+const INDEX_SHARDS: usize = 64;
+
+/// How many blocks each task of [Signature::index_parallel] sorts into shards.
+#[cfg(feature = "parallel")]
+const INDEX_CHUNK_BLOCKS: usize = 1 << 16;
 
 /// An rsync signature.
 ///
 /// A signature contains hashed information about a block of data. It is used to compute a delta
 /// against that data.
+///
+/// The blocks are found with the rolling hash `R`, which is [Crc] unless the signature is
+/// calculated with [Signature::calculate_with_rolling_hash].
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Signature {
+pub struct Signature<R = Crc> {
     signature_type: SignatureType,
     block_size: u32,
     crypto_hash_size: u32,
+    checksum_seed: u32,
     // This contains a valid serialized signature which must contain the correct magic for `signature_type`
-    // and a matching `block_size` and `crypto_hash_size`.
+    // and a matching `block_size`, `crypto_hash_size` and `checksum_seed`.
+    // For v2 signatures it is wrapped in a v2 header and followed by the prebuilt index, and for
+    // extended signatures all of that follows the extension area.
     signature: Vec<u8>,
+    /// Where the plain signature starts within `signature` (after the extension area and the v2
+    /// header, if any).
+    start: usize,
+    /// Where the plain signature ends; the prebuilt index follows for v2 signatures.
+    end: usize,
+    rolling_hash: PhantomData<R>,
+}
+
+/// Information about the data a signature was calculated from, carried in the extension area of
+/// an extended signature, see [Signature::with_extensions].
+///
+/// With it, a receiver can tell that its file is unchanged without diffing, and check the output
+/// of a sync without a side channel.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignatureExtensions {
+    /// The length of the data.
+    pub source_len: Option<u64>,
+    /// The BLAKE3 hash of the data.
+    pub source_blake3: Option<[u8; 32]>,
+}
+
+impl SignatureExtensions {
+    /// The length and BLAKE3 hash of `data`.
+    pub fn of(data: &[u8]) -> Self {
+        SignatureExtensions {
+            source_len: Some(data.len() as u64),
+            source_blake3: Some(*::blake3::hash(data).as_bytes()),
+        }
+    }
+
+    /// Whether `data` is the data described, or `None` if neither its length nor its hash is
+    /// known.
+    ///
+    /// The length is compared first, so data of another length isn't hashed.
+    pub fn matches(&self, data: &[u8]) -> Option<bool> {
+        if self.source_len.is_none() && self.source_blake3.is_none() {
+            return None;
+        }
+        if self.source_len.is_some_and(|len| len != data.len() as u64) {
+            return Some(false);
+        }
+        Some(
+            self.source_blake3
+                .is_none_or(|hash| hash == *::blake3::hash(data).as_bytes()),
+        )
+    }
+
+    /// The records of the extension area.
+    fn encode(&self) -> Vec<u8> {
+        let mut area = Vec::new();
+        let mut record = |kind: u8, value: &[u8]| {
+            area.push(kind);
+            area.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            area.extend_from_slice(value);
+        };
+        if let Some(len) = self.source_len {
+            record(SIGNATURE_EXT_SOURCE_LEN, &len.to_be_bytes());
+        }
+        if let Some(hash) = self.source_blake3 {
+            record(SIGNATURE_EXT_SOURCE_BLAKE3, &hash);
+        }
+        area
+    }
+
+    /// Parse the records of an extension area, skipping those of unknown types.
+    fn parse(mut area: &[u8]) -> Option<Self> {
+        let mut extensions = SignatureExtensions::default();
+        while !area.is_empty() {
+            let kind = *area.first()?;
+            let len = u32::from_be_bytes(area.get(1..5)?.try_into().unwrap()) as usize;
+            let value = area.get(5..5usize.checked_add(len)?)?;
+            match kind {
+                SIGNATURE_EXT_SOURCE_LEN => {
+                    extensions.source_len = Some(u64::from_be_bytes(value.try_into().ok()?))
+                }
+                SIGNATURE_EXT_SOURCE_BLAKE3 => extensions.source_blake3 = Some(value.try_into().ok()?),
+                _ => {}
+            }
+            area = &area[5 + len..];
+        }
+        Some(extensions)
+    }
+}
+
+/// The length of the extension header and area at the start of the valid serialized signature
+/// `signature`, or 0 if it isn't an extended signature.
+fn ext_len(signature: &[u8]) -> usize {
+    if u32::from_be_bytes(*array_ref![signature, 0, 4]) != SIGNATURE_EXT_MAGIC {
+        return 0;
+    }
+    SIGNATURE_EXT_HEADER_SIZE + u32::from_be_bytes(*array_ref![signature, 4, 4]) as usize
+}
+
+/// The extensions of the valid serialized signature `signature`.
+fn extensions(signature: &[u8]) -> SignatureExtensions {
+    let area = &signature[SIGNATURE_EXT_HEADER_SIZE.min(ext_len(signature))..ext_len(signature)];
+    SignatureExtensions::parse(area).unwrap_or_default()
+}
+
+/// A serialized signature borrowed from a byte slice, such as a memory map or a network buffer.
+///
+/// This reads a signature like [Signature::deserialize] without copying it, and indexes it for
+/// diffing with [SignatureRef::index].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SignatureRef<'a, R = Crc> {
+    signature_type: SignatureType,
+    block_size: u32,
+    crypto_hash_size: u32,
+    checksum_seed: u32,
+    /// A valid serialized signature, like [Signature]'s.
+    signature: &'a [u8],
+    start: usize,
+    end: usize,
+    rolling_hash: PhantomData<R>,
 }
 
 /// A signature with a block index, suitable for calculating deltas.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct IndexedSignature<'a> {
+pub struct IndexedSignature<'a, R = Crc> {
     pub(crate) signature_type: SignatureType,
     pub(crate) block_size: u32,
     pub(crate) crypto_hash_size: u32,
-    /// crc -> crypto hash -> block index
-    pub(crate) blocks: HashMap<Crc, SecondLayerMap<&'a [u8], u32>, BuildCrcHasher>,
+    pub(crate) checksum_seed: u32,
+    pub(crate) blocks: BlockIndex<'a>,
+    /// The serialized entry (CRC followed by crypto hash) of each block, in order.
+    pub(crate) entries: &'a [u8],
+    pub(crate) rolling_hash: PhantomData<R>,
+}
+
+/// Signatures are serialized as the bytes of [Signature::serialized].
+#[cfg(feature = "serde")]
+impl<R> serde::Serialize for Signature<R> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.signature)
+    }
+}
+
+/// Signatures are deserialized like [Signature::deserialize], from bytes or (e.g. in JSON) a
+/// sequence of numbers.
+#[cfg(feature = "serde")]
+impl<'de, R: RollingHash> serde::Deserialize<'de> for Signature<R> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a serialized signature")
+            }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(bytes.to_vec())
+            }
+
+            fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(bytes)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1 << 16));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(bytes)
+            }
+        }
+
+        let bytes = deserializer.deserialize_byte_buf(BytesVisitor)?;
+        Self::deserialize_with_rolling_hash(bytes).map_err(serde::de::Error::custom)
+    }
+}
+
+/// An indexed signature that owns its data, for keeping in long-lived maps and sharing across
+/// threads.
+///
+/// The signature is converted to a v2 signature (see [Signature::with_index]) whose block index is
+/// stored as offsets next to the blocks, so [IndexedSignatureOwned::index] is free and clones
+/// only bump a reference count.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexedSignatureOwned<R = Crc> {
+    signature: Arc<Signature<R>>,
+}
+
+/// One shard of a [BlockIndex::Sharded] index, like the contents of [BlockIndex::Map].
+#[cfg(feature = "parallel")]
+type BlockMap<'a> = (
+    HashMap<Crc, SecondLayerMap<&'a [u8], u32>, BuildCrcHasher>,
+    Arena<&'a [u8], u32>,
+);
+
+/// The block lookup structure of an [IndexedSignature].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum BlockIndex<'a> {
+    /// crc -> crypto hash -> block index, with the entries of colliding CRCs in the arena
+    Map(
+        HashMap<Crc, SecondLayerMap<&'a [u8], u32>, BuildCrcHasher>,
+        Arena<&'a [u8], u32>,
+    ),
+    /// Maps built on separate threads, each holding the CRCs of its [shard], see
+    /// [Signature::index_parallel].
+    #[cfg(feature = "parallel")]
+    Sharded(Box<[BlockMap<'a>]>),
+    /// The prebuilt index of a v2 signature.
+    Table(IndexTable<'a>),
+    /// A perfect hash index, see [Signature::index_perfect].
+    Perfect(PerfectIndex<'a>),
+}
+
+/// The shard of a [BlockIndex::Sharded] index that holds `crc`.
+#[cfg(feature = "parallel")]
+#[inline]
+fn shard(crc: Crc) -> usize {
+    // The maps pick buckets by the low bits of the hash and tag them with the top 7, so shard by
+    // bits in between to keep both well distributed within a shard.
+    (BuildCrcHasher::default().hash_one(crc) >> 40) as usize % INDEX_SHARDS
+}
+
+/// The blocks that have a given CRC, as found by [BlockIndex::get].
+pub(crate) enum Candidates<'s, 'a> {
+    Map(&'s SecondLayerMap<&'a [u8], u32>, &'s Arena<&'a [u8], u32>),
+    Table(&'s IndexTable<'a>, Crc, usize),
+    Perfect(&'s PerfectIndex<'a>, usize),
+}
+
+impl<'a> BlockIndex<'a> {
+    /// Look up the blocks with CRC `crc`, if there are any.
+    #[inline]
+    pub(crate) fn get(&self, crc: &Crc) -> Option<Candidates<'_, 'a>> {
+        match self {
+            BlockIndex::Map(map, arena) => map.get(crc).map(|map| Candidates::Map(map, arena)),
+            #[cfg(feature = "parallel")]
+            BlockIndex::Sharded(shards) => {
+                let (map, arena) = &shards[shard(*crc)];
+                map.get(crc).map(|map| Candidates::Map(map, arena))
+            }
+            BlockIndex::Table(table) => table
+                .find(*crc)
+                .map(|slot| Candidates::Table(table, *crc, slot)),
+            BlockIndex::Perfect(index) => index
+                .find(*crc)
+                .map(|slot| Candidates::Perfect(index, slot)),
+        }
+    }
+}
+
+impl Candidates<'_, '_> {
+    /// The index of the block with crypto hash `crypto_hash` among the candidates.
+    #[inline]
+    pub(crate) fn get(&self, crypto_hash: &[u8]) -> Option<u32> {
+        match self {
+            Candidates::Map(map, arena) => map.get(arena, &crypto_hash).copied(),
+            Candidates::Table(table, crc, slot) => table.get(*slot, *crc, crypto_hash),
+            Candidates::Perfect(index, slot) => index.get(*slot, crypto_hash),
+        }
+    }
 }
 
 /// The hash type used with within the signature.
 /// Note that this library generally only supports MD4 signatures.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug)]
 pub enum SignatureType {
     Md4,
     Blake2,
     Blake3,
+    Sha256,
+    Md5,
+    /// A hash of [Signature::calculate_with_hash] or
+    /// [register_strong_hash](crate::register_strong_hash).
+    Custom(&'static dyn StrongHash),
 }
 
+/// Types are the same if their magics are.
+impl PartialEq for SignatureType {
+    fn eq(&self, other: &Self) -> bool {
+        self.strong_hash().magic() == other.strong_hash().magic()
+    }
+}
+
+impl Eq for SignatureType {}
+
 /// Public hash algorithm enum for user selection
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum HashAlgorithm {
     /// MD4 hash algorithm (legacy, insecure)
     Md4,
     /// BLAKE3 hash algorithm (modern, secure, fast)
     Blake3,
+    /// BLAKE2b hash algorithm, as used by librsync (secure, interoperable)
+    Blake2,
+    /// SHA-256 hash algorithm (secure, FIPS-approved)
+    Sha256,
+    /// MD5 hash algorithm, as used by the rsync protocol since version 30 (insecure)
+    Md5,
 }
 
 impl SignatureType {
@@ -60,16 +369,25 @@ impl SignatureType {
             BLAKE2_MAGIC => Some(SignatureType::Blake2),
             MD4_MAGIC => Some(SignatureType::Md4),
             BLAKE3_MAGIC => Some(SignatureType::Blake3),
-            _ => None,
+            SHA256_MAGIC => Some(SignatureType::Sha256),
+            MD5_MAGIC => Some(SignatureType::Md5),
+            magic => strong_hash::registered(magic).map(SignatureType::Custom),
         }
     }
     fn to_magic(self) -> [u8; Self::SIZE] {
+        self.strong_hash().magic().to_be_bytes()
+    }
+
+    /// The hash of the blocks of signatures of this type.
+    pub(crate) fn strong_hash(self) -> &'static dyn StrongHash {
         match self {
-            SignatureType::Md4 => MD4_MAGIC,
-            SignatureType::Blake2 => BLAKE2_MAGIC,
-            SignatureType::Blake3 => BLAKE3_MAGIC,
+            SignatureType::Md4 => &strong_hash::Md4,
+            SignatureType::Blake2 => &strong_hash::Blake2,
+            SignatureType::Blake3 => &strong_hash::Blake3,
+            SignatureType::Sha256 => &strong_hash::Sha256,
+            SignatureType::Md5 => &strong_hash::Md5,
+            SignatureType::Custom(hash) => hash,
         }
-        .to_be_bytes()
     }
 }
 
@@ -79,21 +397,88 @@ impl HashAlgorithm {
         match self {
             HashAlgorithm::Md4 => SignatureType::Md4,
             HashAlgorithm::Blake3 => SignatureType::Blake3,
+            HashAlgorithm::Blake2 => SignatureType::Blake2,
+            HashAlgorithm::Sha256 => SignatureType::Sha256,
+            HashAlgorithm::Md5 => SignatureType::Md5,
         }
     }
     
     /// Get the maximum hash size for this algorithm
+    ///
+    /// This is the size of its regular digest. BLAKE3 crypto hashes may be longer, see
+    /// [HashAlgorithm::max_xof_size].
     pub fn max_hash_size(self) -> usize {
         match self {
-            HashAlgorithm::Md4 => MD4_SIZE,
             HashAlgorithm::Blake3 => BLAKE3_SIZE,
+            _ => self.max_xof_size(),
+        }
+    }
+
+    /// The largest crypto hash size signatures of this algorithm can use.
+    ///
+    /// This is [HashAlgorithm::max_hash_size] except for BLAKE3, whose output is extended with
+    /// its XOF for larger crypto hash sizes.
+    pub fn max_xof_size(self) -> usize {
+        self.to_signature_type().strong_hash().size()
+    }
+}
+
+impl HashAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md4 => "md4",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Blake2 => "blake2",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Md5 => "md5",
         }
     }
 }
 
+/// Prints the lowercase name of the algorithm, which [FromStr] parses back.
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parses the name of an algorithm (`md4`, `blake2`, `blake3`, `sha256` or `md5`), ignoring case.
+impl FromStr for HashAlgorithm {
+    type Err = ParseHashAlgorithmError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        [
+            HashAlgorithm::Md4,
+            HashAlgorithm::Blake2,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Md5,
+        ]
+            .into_iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| ParseHashAlgorithmError(name.to_owned()))
+    }
+}
+
+/// Indicates that the name of a hash algorithm was not recognized.
+#[derive(Debug)]
+pub struct ParseHashAlgorithmError(String);
+
+impl fmt::Display for ParseHashAlgorithmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown hash algorithm, expected md4, blake2, blake3, sha256 or md5 (name={})",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseHashAlgorithmError {}
+
 /// Indicates that a signature was not valid.
 #[derive(Debug)]
-pub struct SignatureParseError(());
+pub struct SignatureParseError(pub(crate) ());
 
 impl fmt::Display for SignatureParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -103,148 +488,1160 @@ impl fmt::Display for SignatureParseError {
 
 impl Error for SignatureParseError {}
 
+/// Indicates that an argument of a `try_` function of [Signature] was invalid, which the
+/// infallible counterpart panics on.
+#[derive(Debug)]
+pub struct InvalidArgumentError {
+    /// The name of the argument, or of the field of [SignatureOptions].
+    pub name: &'static str,
+}
+
+impl fmt::Display for InvalidArgumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid argument (name={})", self.name)
+    }
+}
+
+impl Error for InvalidArgumentError {}
+
 /// Options for [Signature::calculate].
-#[derive(Copy, Clone, Debug)]
+///
+/// New fields may be added; [SignatureOptions::new] builds options that keep compiling when they
+/// are.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct SignatureOptions {
     /// The granularity of the signature.
     /// Smaller block sizes yield larger, but more precise, signatures.
     pub block_size: u32,
-    /// The number of bytes to use from the hash. Must be at most the hash size.
+    /// The number of bytes to use from the hash. Must be at most
+    /// [HashAlgorithm::max_xof_size].
     /// The larger this is, the less likely that a delta will be mis-applied.
     pub crypto_hash_size: u32,
     /// The hash algorithm to use for the signature.
     pub hash_algorithm: HashAlgorithm,
+    /// rsync's `checksum_seed`, which is mixed into the strong hash of every block: hashed as 4
+    /// little-endian bytes after the block, or before it for MD5, so that blocks crafted to
+    /// collide under one seed most likely don't under another. The rolling checksum isn't seeded.
+    ///
+    /// 0 is no seed, which librsync expects; other seeds are stored in the signature, see
+    /// [consts](crate::consts), which librsync can't read.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub checksum_seed: u32,
 }
 
-impl Signature {
-    const HEADER_SIZE: usize = SignatureType::SIZE + 2 * 4; // magic, block_size, then crypto_hash_size
+impl SignatureOptions {
+    /// Options with the given block size, crypto hash size and hash algorithm, and no checksum
+    /// seed.
+    pub const fn new(
+        block_size: u32,
+        crypto_hash_size: u32,
+        hash_algorithm: HashAlgorithm,
+    ) -> Self {
+        SignatureOptions {
+            block_size,
+            crypto_hash_size,
+            hash_algorithm,
+            checksum_seed: 0,
+        }
+    }
+
+    /// These options with the checksum seed `checksum_seed`, see
+    /// [SignatureOptions::checksum_seed].
+    pub fn with_checksum_seed(self, checksum_seed: u32) -> Self {
+        SignatureOptions {
+            checksum_seed,
+            ..self
+        }
+    }
+
+    /// The smallest `crypto_hash_size` that keeps the probability of two different blocks among
+    /// `block_count` sharing a hash below `target_probability`.
+    ///
+    /// By the birthday bound, that probability is about `block_count² / 2^(bits + 1)` for a hash
+    /// of `bits` bits (see [params::misapply_probability](crate::params::misapply_probability)).
+    /// The result is at least 1, and may exceed the hash size of the algorithm for very large
+    /// block counts and very small targets.
+    ///
+    /// Panics if `target_probability` isn't positive, unless the `strict` feature makes it the
+    /// smallest positive value.
+    pub fn hash_size_for(block_count: u64, target_probability: f64) -> u32 {
+        #[cfg(feature = "strict")]
+        let target_probability = if target_probability > 0.0 {
+            target_probability
+        } else {
+            f64::MIN_POSITIVE
+        };
+        assert!(target_probability > 0.0);
+        let bits = 2.0 * (block_count.max(1) as f64).log2() - 1.0 - target_probability.log2();
+        // negative values saturate to 0
+        ((bits / 8.0).ceil() as u32).max(1)
+    }
 
+    /// The size of the serialized header of signatures calculated with these options.
+    pub(crate) fn header_size(&self) -> usize {
+        if self.checksum_seed == 0 {
+            SIGNATURE_HEADER_SIZE
+        } else {
+            SEEDED_SIGNATURE_HEADER_SIZE
+        }
+    }
+
+    /// Whether the block size is positive and the hash size at most that of the algorithm.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.check().is_ok()
+    }
+
+    /// [SignatureOptions::is_valid], naming the first invalid field.
+    fn check(&self) -> Result<(), InvalidArgumentError> {
+        if self.block_size == 0 {
+            return Err(InvalidArgumentError { name: "block_size" });
+        }
+        if self.crypto_hash_size > self.hash_algorithm.max_xof_size() as u32 {
+            return Err(InvalidArgumentError { name: "crypto_hash_size" });
+        }
+        Ok(())
+    }
+
+    /// The nearest valid options, for the `strict` feature.
+    #[cfg(feature = "strict")]
+    pub(crate) fn clamped(&self) -> Self {
+        SignatureOptions {
+            block_size: self.block_size.max(1),
+            crypto_hash_size: self
+                .crypto_hash_size
+                .min(self.hash_algorithm.max_xof_size() as u32),
+            ..*self
+        }
+    }
+}
+
+impl Signature {
     /// Compute a signature for the given data using the specified hash algorithm.
     ///
     /// `options.block_size` must be greater than zero. `options.crypto_hash_size` must be at most the hash size.
-    /// Panics if the provided options are invalid.
+    /// Panics if the provided options are invalid, unless the `strict` feature replaces them with
+    /// the nearest valid ones. [Signature::try_calculate] returns an error instead.
     pub fn calculate(buf: &[u8], options: SignatureOptions) -> Signature {
-        assert!(options.block_size > 0);
-        assert!(options.crypto_hash_size <= options.hash_algorithm.max_hash_size() as u32);
-        
-        let signature_type = options.hash_algorithm.to_signature_type();
+        Self::calculate_with_rolling_hash(buf, options)
+    }
+
+    /// Compute a signature like [Signature::calculate], or fail if the options are invalid.
+    pub fn try_calculate(
+        buf: &[u8],
+        options: SignatureOptions,
+    ) -> Result<Signature, InvalidArgumentError> {
+        options.check()?;
+        Ok(Self::calculate_with_rolling_hash(buf, options))
+    }
+
+    /// Compute a signature like [Signature::calculate], hashing chunks of `buf` on the threads
+    /// of the rayon pool.
+    ///
+    /// The result is identical to [Signature::calculate].
+    ///
+    /// # Features
+    /// This function requires the `parallel` feature to be enabled.
+    #[cfg(feature = "parallel")]
+    pub fn calculate_parallel(buf: &[u8], options: SignatureOptions) -> Signature {
+        use rayon::prelude::*;
+
+        #[cfg(feature = "strict")]
+        let options = options.clamped();
+        assert!(options.is_valid());
+
+        // Every chunk but the last must be a whole number of blocks.
+        let block_size = options.block_size as usize;
+        let chunk_size = PARALLEL_CHUNK_SIZE.div_ceil(block_size) * block_size;
+        let chunks: Vec<Vec<u8>> = buf
+            .par_chunks(chunk_size)
+            .map(|chunk| {
+                let mut entries = Vec::new();
+                Self::hash_blocks_seeded(chunk, &options, &mut entries);
+                entries
+            })
+            .collect();
+        let len = chunks.iter().map(Vec::len).sum::<usize>();
+        let mut signature = Vec::with_capacity(options.header_size() + len);
+        Self::write_header(&options, &mut signature);
+        for entries in chunks {
+            signature.extend_from_slice(&entries);
+        }
+        Self::from_serialized_parts(&options, signature)
+    }
+
+    /// Compute a signature of everything read from `reader`, holding only a chunk of it in memory
+    /// at a time, so inputs can be far larger than RAM.
+    ///
+    /// The result is identical to [Signature::calculate] over the same bytes.
+    /// [Pipeline::signature](crate::Pipeline::signature) does the same, with the reading on a
+    /// thread of its own.
+    /// Panics if the provided options are invalid, unless the `strict` feature turns that into an
+    /// [io::ErrorKind::InvalidInput] error.
+    pub fn calculate_from_reader(
+        mut reader: impl Read,
+        options: SignatureOptions,
+    ) -> io::Result<Signature> {
+        require!(options.is_valid(), invalid_input("options"));
+
+        // Every chunk but the last must be a whole number of blocks.
+        let block_size = options.block_size as usize;
+        let chunk_size = READER_CHUNK_SIZE.div_ceil(block_size) * block_size;
+        let mut signature = Vec::new();
+        Self::write_header(&options, &mut signature);
+        let mut chunk = Vec::with_capacity(chunk_size);
+        loop {
+            chunk.clear();
+            (&mut reader).take(chunk_size as u64).read_to_end(&mut chunk)?;
+            Self::hash_blocks(&chunk, &options, &mut signature);
+            if chunk.len() < chunk_size {
+                return Ok(Self::from_serialized_parts(&options, signature));
+            }
+        }
+    }
+
+    /// Compute a signature for the given data using a custom [StrongHash].
+    ///
+    /// Diffing against the signature works like for the built-in algorithms. To deserialize it,
+    /// register `hash` with [register_strong_hash](crate::register_strong_hash) first.
+    ///
+    /// Panics if `block_size` is zero, `crypto_hash_size` is larger than the hash or the size of
+    /// the hash is out of range, unless the `strict` feature replaces the first two with the
+    /// nearest valid values. [Signature::try_calculate_with_hash] returns an error instead.
+    pub fn calculate_with_hash(
+        buf: &[u8],
+        block_size: u32,
+        crypto_hash_size: u32,
+        hash: &'static dyn StrongHash,
+    ) -> Signature {
+        assert!((1..=MAX_STRONG_HASH_SIZE).contains(&hash.size()));
+        let max_hash_size = hash.size() as u32;
+        #[cfg(feature = "strict")]
+        let (block_size, crypto_hash_size) =
+            (block_size.max(1), crypto_hash_size.min(max_hash_size));
+        assert!(block_size > 0 && crypto_hash_size <= max_hash_size);
+        Self::calculate_with_hash_unchecked(buf, block_size, crypto_hash_size, hash)
+    }
+
+    /// Compute a signature like [Signature::calculate_with_hash], or fail if an argument is
+    /// invalid.
+    pub fn try_calculate_with_hash(
+        buf: &[u8],
+        block_size: u32,
+        crypto_hash_size: u32,
+        hash: &'static dyn StrongHash,
+    ) -> Result<Signature, InvalidArgumentError> {
+        if !(1..=MAX_STRONG_HASH_SIZE).contains(&hash.size()) {
+            return Err(InvalidArgumentError { name: "hash" });
+        }
+        if block_size == 0 {
+            return Err(InvalidArgumentError { name: "block_size" });
+        }
+        if crypto_hash_size > hash.size() as u32 {
+            return Err(InvalidArgumentError { name: "crypto_hash_size" });
+        }
+        Ok(Self::calculate_with_hash_unchecked(buf, block_size, crypto_hash_size, hash))
+    }
+
+    /// [Signature::calculate_with_hash] with valid arguments.
+    fn calculate_with_hash_unchecked(
+        buf: &[u8],
+        block_size: u32,
+        crypto_hash_size: u32,
+        hash: &'static dyn StrongHash,
+    ) -> Signature {
+        let signature_type = SignatureType::Custom(hash);
+        let mut signature = Vec::new();
+        signature.extend_from_slice(&signature_type.to_magic());
+        signature.extend_from_slice(&block_size.to_be_bytes());
+        signature.extend_from_slice(&crypto_hash_size.to_be_bytes());
+        let (block_len, hash_len) = (block_size as usize, crypto_hash_size as usize);
+        Self::hash_blocks_with(buf, block_len, hash_len, hash, &mut signature);
+        Signature {
+            signature_type,
+            block_size,
+            crypto_hash_size,
+            checksum_seed: 0,
+            start: 0,
+            end: signature.len(),
+            signature,
+            rolling_hash: PhantomData,
+        }
+    }
+
+    /// Write the signature header for `options` to `out`.
+    pub(crate) fn write_header(options: &SignatureOptions, out: &mut Vec<u8>) {
+        Self::write_header_with(options, out);
+    }
+
+    /// Hash every block of `buf` (with the CRC as well as the selected hash) and append the
+    /// serialized block entries to `out`.
+    ///
+    /// Only the last block of `buf` may be shorter than `options.block_size`, so callers hashing a
+    /// stream piecewise must split it at multiples of the block size.
+    pub(crate) fn hash_blocks(buf: &[u8], options: &SignatureOptions, out: &mut Vec<u8>) {
+        Self::hash_blocks_seeded(buf, options, out);
+    }
+
+    /// Compute a v2 signature, which carries a prebuilt block index.
+    ///
+    /// See [Signature::with_index].
+    pub fn calculate_with_index(buf: &[u8], options: SignatureOptions) -> Signature {
+        Self::calculate(buf, options).with_index()
+    }
+
+    /// Read a binary signature.
+    ///
+    /// Signatures written by [Signature::serialize_compact] are decoded transparently, as are
+    /// v2 signatures (see [Signature::with_index]).
+    pub fn deserialize(signature: Vec<u8>) -> Result<Signature, SignatureParseError> {
+        Self::deserialize_with_rolling_hash(signature)
+    }
+}
+
+/// The size of the header of the plain serialized signature starting with `magic`.
+pub(crate) fn header_size(magic: [u8; 4]) -> usize {
+    if u32::from_be_bytes(magic) & SEEDED_MAGIC_FLAG == 0 {
+        SIGNATURE_HEADER_SIZE
+    } else {
+        SEEDED_SIGNATURE_HEADER_SIZE
+    }
+}
+
+/// Wrap the plain serialized signature `plain`, whose header is `header_size` bytes long, in a
+/// v2 signature with a prebuilt index of its entries.
+fn wrap_v2(plain: &[u8], header_size: usize, entry_size: usize) -> Vec<u8> {
+    let table = IndexTable::build(&plain[header_size..], entry_size);
+    let mut signature = Vec::with_capacity(SIGNATURE_V2_HEADER_SIZE + plain.len() + table.len());
+    signature.extend_from_slice(&SIGNATURE_V2_MAGIC.to_be_bytes());
+    signature.extend_from_slice(&(plain.len() as u64).to_be_bytes());
+    signature.extend_from_slice(plain);
+    signature.extend_from_slice(&table);
+    signature
+}
+
+impl<R: RollingHash> Signature<R> {
+    const HEADER_SIZE: usize = SIGNATURE_HEADER_SIZE;
+    const V2_HEADER_SIZE: usize = SIGNATURE_V2_HEADER_SIZE;
+
+    /// Compute a signature like [Signature::calculate], with the rolling hash `R`.
+    ///
+    /// Panics if the provided options are invalid, unless the `strict` feature replaces them with
+    /// the nearest valid ones, see [Signature::try_calculate].
+    pub fn calculate_with_rolling_hash(buf: &[u8], options: SignatureOptions) -> Self {
+        #[cfg(feature = "strict")]
+        let options = options.clamped();
+        assert!(options.is_valid());
+
         let num_blocks = buf.chunks(options.block_size as usize).len();
 
         let mut signature = Vec::with_capacity(
-            Self::HEADER_SIZE + num_blocks * (Crc::SIZE + options.crypto_hash_size as usize),
+            options.header_size() + num_blocks * (Crc::SIZE + options.crypto_hash_size as usize),
         );
+        Self::write_header_with(&options, &mut signature);
+        Self::hash_blocks_seeded(buf, &options, &mut signature);
 
-        signature.extend_from_slice(&signature_type.to_magic());
-        signature.extend_from_slice(&options.block_size.to_be_bytes());
-        signature.extend_from_slice(&options.crypto_hash_size.to_be_bytes());
-
-        // Hash all the blocks (with the CRC as well as the selected hash)
-        match options.hash_algorithm {
-            HashAlgorithm::Md4 => {
-                let chunks = buf.chunks_exact(options.block_size as usize);
-                let remainder = chunks.remainder();
-                for (block, md4_hash) in md4_many(chunks).chain(if remainder.is_empty() {
-                    None
-                } else {
-                    // Manually tack on the last block if necessary, since `md4_many`
-                    // requires every block to be identical in size
-                    Some((remainder, md4(remainder)))
-                }) {
-                    let crc = Crc::new().update(block);
-                    let crypto_hash = &md4_hash[..options.crypto_hash_size as usize];
-                    signature.extend_from_slice(&crc.to_bytes());
-                    signature.extend_from_slice(crypto_hash);
-                }
-            }
-            HashAlgorithm::Blake3 => {
-                let chunks = buf.chunks_exact(options.block_size as usize);
-                let remainder = chunks.remainder();
-                for (block, blake3_hash) in blake3_many(chunks).chain(if remainder.is_empty() {
-                    None
-                } else {
-                    // Manually tack on the last block if necessary
-                    Some((remainder, blake3(remainder)))
-                }) {
-                    let crc = Crc::new().update(block);
-                    let crypto_hash = &blake3_hash[..options.crypto_hash_size as usize];
-                    signature.extend_from_slice(&crc.to_bytes());
-                    signature.extend_from_slice(crypto_hash);
-                }
+        Self::from_serialized_parts(&options, signature)
+    }
+
+    /// [Signature::write_header] with the magic for the rolling hash `R`.
+    fn write_header_with(options: &SignatureOptions, out: &mut Vec<u8>) {
+        let seeded = options.checksum_seed != 0;
+        out.extend_from_slice(&Self::magic(options.hash_algorithm.to_signature_type(), seeded));
+        out.extend_from_slice(&options.block_size.to_be_bytes());
+        out.extend_from_slice(&options.crypto_hash_size.to_be_bytes());
+        if seeded {
+            out.extend_from_slice(&options.checksum_seed.to_be_bytes());
+        }
+    }
+
+    /// The magic of the signatures of `signature_type` calculated with `R`, with or without a
+    /// checksum seed.
+    fn magic(signature_type: SignatureType, seeded: bool) -> [u8; 4] {
+        let seeded = if seeded { SEEDED_MAGIC_FLAG } else { 0 };
+        (u32::from_be_bytes(signature_type.to_magic()) | R::MAGIC_FLAG | seeded).to_be_bytes()
+    }
+
+    /// The type of the signatures of `R` that start with `magic`, if any do, and whether they
+    /// have a checksum seed.
+    fn signature_type(magic: [u8; 4]) -> Option<(SignatureType, bool)> {
+        let magic = u32::from_be_bytes(magic);
+        if magic & R::MAGIC_FLAG != R::MAGIC_FLAG {
+            return None;
+        }
+        let seeded = magic & SEEDED_MAGIC_FLAG != 0;
+        let magic = magic & !R::MAGIC_FLAG & !SEEDED_MAGIC_FLAG;
+        Some((SignatureType::from_magic(magic.to_be_bytes())?, seeded))
+    }
+
+    /// [Signature::hash_blocks] with the rolling hash `R` and the checksum seed of `options`.
+    fn hash_blocks_seeded(buf: &[u8], options: &SignatureOptions, out: &mut Vec<u8>) {
+        let hash = options.hash_algorithm.to_signature_type().strong_hash();
+        let hash = SeededHash::new(hash, options.checksum_seed);
+        let (block_size, crypto_hash_size) =
+            (options.block_size as usize, options.crypto_hash_size as usize);
+        Self::hash_blocks_with(buf, block_size, crypto_hash_size, &hash, out);
+    }
+
+    /// [Signature::hash_blocks] with any [StrongHash], and the rolling hash `R`.
+    fn hash_blocks_with(
+        buf: &[u8],
+        block_size: usize,
+        crypto_hash_size: usize,
+        hash: &dyn StrongHash,
+        out: &mut Vec<u8>,
+    ) {
+        let size = hash.size();
+        let mut push = |block: &[u8], digest: &[u8]| {
+            out.extend_from_slice(&R::default().update(block).to_bytes());
+            out.extend_from_slice(&digest[..crypto_hash_size]);
+        };
+        let mut blocks = Vec::with_capacity(HASH_BATCH);
+        let mut digests = vec![0; HASH_BATCH * size];
+        let (whole, remainder) = buf.split_at(buf.len() - buf.len() % block_size);
+        for batch in whole.chunks(HASH_BATCH * block_size) {
+            blocks.clear();
+            blocks.extend(batch.chunks_exact(block_size));
+            let digests = &mut digests[..blocks.len() * size];
+            hash.hash_many(&blocks, digests);
+            for (block, digest) in blocks.iter().zip(digests.chunks_exact(size)) {
+                push(block, digest);
             }
         }
-        
+        // Hash the last block on its own, since `hash_many` requires every block to be
+        // identical in size
+        if !remainder.is_empty() {
+            hash.hash_block(remainder, &mut digests[..size]);
+            push(remainder, &digests[..size]);
+        }
+    }
+
+    /// Wrap a serialized signature that was produced by [Signature::write_header] and
+    /// [Signature::hash_blocks] with the same `options`.
+    pub(crate) fn from_serialized_parts(options: &SignatureOptions, signature: Vec<u8>) -> Self {
         Signature {
-            signature_type,
+            signature_type: options.hash_algorithm.to_signature_type(),
             block_size: options.block_size,
             crypto_hash_size: options.crypto_hash_size,
+            checksum_seed: options.checksum_seed,
+            start: 0,
+            end: signature.len(),
             signature,
+            rolling_hash: PhantomData,
         }
     }
 
-    /// Read a binary signature.
-    pub fn deserialize(signature: Vec<u8>) -> Result<Signature, SignatureParseError> {
+    /// Convert this signature to a v2 signature, which carries a prebuilt block index.
+    ///
+    /// [Signature::index] on a v2 signature (also after serializing and deserializing it) uses
+    /// the prebuilt index instead of building a hash map of all blocks, so diffing can start
+    /// right away. The index makes the serialized signature up to about twice as large, and
+    /// v2 signatures can't be read by librsync or by older versions of this crate.
+    pub fn with_index(self) -> Self {
+        if self.has_index() {
+            return self;
+        }
+        let entry_size = Crc::SIZE + self.crypto_hash_size as usize;
+        let mut signature = self.signature[..self.start].to_vec();
+        signature.extend_from_slice(&wrap_v2(self.plain(), self.header_size(), entry_size));
+        Signature {
+            start: self.start + Self::V2_HEADER_SIZE,
+            end: self.end + Self::V2_HEADER_SIZE,
+            signature,
+            ..self
+        }
+    }
+
+    /// Drop the prebuilt index of a v2 signature, leaving a plain (librsync-compatible) one.
+    pub fn without_index(self) -> Self {
+        if !self.has_index() {
+            return self;
+        }
+        let ext_len = ext_len(&self.signature);
+        let mut signature = self.signature;
+        signature.truncate(self.end);
+        signature.drain(ext_len..self.start);
+        Signature {
+            start: ext_len,
+            end: signature.len(),
+            signature,
+            ..self
+        }
+    }
+
+    /// Whether this is a v2 signature, which carries a prebuilt block index.
+    fn has_index(&self) -> bool {
+        self.end != self.signature.len()
+    }
+
+    /// Convert this signature to an extended signature carrying `extensions`, replacing any it
+    /// already carries, or to a regular one if `extensions` is empty.
+    ///
+    /// ```
+    /// use superfast_rsync::{HashAlgorithm, Signature, SignatureExtensions, SignatureOptions};
+    ///
+    /// let data = b"hello world";
+    /// let options = SignatureOptions::new(4, 8, HashAlgorithm::Blake3);
+    /// let signature =
+    ///     Signature::calculate(data, options).with_extensions(SignatureExtensions::of(data));
+    ///
+    /// let received = Signature::deserialize(signature.into_serialized()).unwrap();
+    /// assert_eq!(received.extensions().matches(data), Some(true));
+    /// ```
+    ///
+    /// Extended signatures can't be read by librsync or by older versions of this crate.
+    pub fn with_extensions(self, extensions: SignatureExtensions) -> Self {
+        let old_len = ext_len(&self.signature);
+        let area = extensions.encode();
+        let mut signature = Vec::new();
+        if !area.is_empty() {
+            signature.reserve(SIGNATURE_EXT_HEADER_SIZE + area.len() + self.signature.len() - old_len);
+            signature.extend_from_slice(&SIGNATURE_EXT_MAGIC.to_be_bytes());
+            signature.extend_from_slice(&(area.len() as u32).to_be_bytes());
+            signature.extend_from_slice(&area);
+        }
+        let new_len = signature.len();
+        signature.extend_from_slice(&self.signature[old_len..]);
+        Signature {
+            start: self.start - old_len + new_len,
+            end: self.end - old_len + new_len,
+            signature,
+            ..self
+        }
+    }
+
+    /// The extensions this signature carries, which are empty unless it is an extended signature.
+    pub fn extensions(&self) -> SignatureExtensions {
+        extensions(&self.signature)
+    }
+
+    /// The offset in the data of the last block, where the input to [Signature::extend] starts.
+    pub fn tail_offset(&self) -> u64 {
+        self.block_count().saturating_sub(1) as u64 * self.block_size as u64
+    }
+
+    /// Update this signature for data that has grown, such as an append-only log.
+    ///
+    /// `new_tail` is the data from [Signature::tail_offset] on: the last block, which may have
+    /// been incomplete, followed by everything appended since. Only those blocks are hashed, and
+    /// the result is identical to calculating the signature of the whole data again.
+    ///
+    /// ```
+    /// use superfast_rsync::{HashAlgorithm, Signature, SignatureOptions};
+    ///
+    /// let options = SignatureOptions::new(16, 8, HashAlgorithm::Blake3);
+    /// let mut log = b"first entry\n".repeat(10);
+    /// let mut signature = Signature::calculate(&log, options);
+    ///
+    /// log.extend_from_slice(b"second entry\n");
+    /// signature.extend(&log[signature.tail_offset() as usize..]);
+    /// assert_eq!(signature, Signature::calculate(&log, options));
+    /// ```
+    ///
+    /// A prebuilt index is rebuilt. Extensions are dropped, since they describe the old data.
+    pub fn extend(&mut self, new_tail: &[u8]) {
+        let indexed = self.has_index();
+        let signature = Signature {
+            signature: std::mem::take(&mut self.signature),
+            ..*self
+        };
+        let mut signature = signature
+            .with_extensions(SignatureExtensions::default())
+            .without_index();
+        let entry_size = Crc::SIZE + signature.crypto_hash_size as usize;
+        let keep = signature.header_size() + signature.block_count().saturating_sub(1) * entry_size;
+        signature.signature.truncate(keep);
+        Self::hash_blocks_with(
+            new_tail,
+            signature.block_size as usize,
+            signature.crypto_hash_size as usize,
+            &SeededHash::new(signature.signature_type.strong_hash(), signature.checksum_seed),
+            &mut signature.signature,
+        );
+        signature.end = signature.signature.len();
+        *self = if indexed { signature.with_index() } else { signature };
+    }
+
+    /// The plain signature, without the v2 header and index.
+    fn plain(&self) -> &[u8] {
+        &self.signature[self.start..self.end]
+    }
+
+    /// Read a binary signature like [Signature::deserialize], which was calculated with the
+    /// rolling hash `R`.
+    pub fn deserialize_with_rolling_hash(signature: Vec<u8>) -> Result<Self, SignatureParseError> {
         if signature.len() < Self::HEADER_SIZE {
             return Err(SignatureParseError(()));
         }
-        let signature_type = SignatureType::from_magic(*array_ref![signature, 0, 4])
-            .ok_or(SignatureParseError(()))?;
-        let block_size = u32::from_be_bytes(*array_ref![signature, 4, 4]);
-        let crypto_hash_size = u32::from_be_bytes(*array_ref![signature, 8, 4]);
-        let block_signature_size = Crc::SIZE + crypto_hash_size as usize;
-        if (signature.len() - Self::HEADER_SIZE) % block_signature_size != 0 {
-            return Err(SignatureParseError(()));
+        if u32::from_be_bytes(*array_ref![signature, 0, 4]) == COMPACT_SIGNATURE_MAGIC {
+            let signature = compact::decode(&signature).ok_or(SignatureParseError(()))?;
+            if u32::from_be_bytes(*array_ref![signature, 0, 4]) == COMPACT_SIGNATURE_MAGIC {
+                return Err(SignatureParseError(()));
+            }
+            return Self::deserialize_with_rolling_hash(signature);
         }
+        let SignatureRef {
+            signature_type,
+            block_size,
+            crypto_hash_size,
+            checksum_seed,
+            start,
+            end,
+            ..
+        } = SignatureRef::<R>::deserialize_with_rolling_hash(&signature)?;
         Ok(Signature {
             signature_type,
             block_size,
             crypto_hash_size,
+            checksum_seed,
             signature,
+            start,
+            end,
+            rolling_hash: PhantomData,
         })
     }
 
+    /// Borrow this signature as a [SignatureRef].
+    pub fn as_signature_ref(&self) -> SignatureRef<'_, R> {
+        SignatureRef {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            checksum_seed: self.checksum_seed,
+            signature: &self.signature,
+            start: self.start,
+            end: self.end,
+            rolling_hash: PhantomData,
+        }
+    }
+
     /// Get the serialized form of this signature.
     pub fn serialized(&self) -> &[u8] {
         &self.signature
     }
 
+    /// Serialize this signature in a compact form, which [Signature::deserialize] reads back.
+    ///
+    /// Compression only pays off for large signatures; the hashes themselves don't compress. The
+    /// compact form has neither a prebuilt index nor extensions.
+    pub fn serialize_compact(&self, options: CompactOptions) -> Vec<u8> {
+        compact::encode(self.plain(), self.header_size(), self.crypto_hash_size as usize, options)
+    }
+
     /// Get ownership of the serialized form of this signature.
     pub fn into_serialized(self) -> Vec<u8> {
         self.signature
     }
 
-    fn blocks(&self) -> impl ExactSizeIterator<Item = (Crc, &[u8])> {
-        self.signature[Self::HEADER_SIZE..]
-            .chunks(Crc::SIZE + self.crypto_hash_size as usize)
-            .map(|b| {
-                (
-                    Crc::from_bytes(*array_ref!(b, 0, Crc::SIZE)),
-                    &b[Crc::SIZE..],
-                )
+    /// The options this signature was calculated with, if its hash is supported for calculation.
+    pub(crate) fn options(&self) -> Option<SignatureOptions> {
+        let hash_algorithm = self.hash_algorithm()?;
+        if self.block_size == 0 || self.crypto_hash_size as usize > hash_algorithm.max_xof_size() {
+            return None;
+        }
+        Some(SignatureOptions {
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            hash_algorithm,
+            checksum_seed: self.checksum_seed,
+        })
+    }
+
+    /// The serialized header: magic, block size, crypto hash size and the checksum seed, if any.
+    pub(crate) fn header(&self) -> &[u8] {
+        &self.plain()[..self.header_size()]
+    }
+
+    /// The size of [Signature::header].
+    fn header_size(&self) -> usize {
+        header_size(*array_ref![self.signature, self.start, 4])
+    }
+
+    /// The checksum seed mixed into the strong hashes of this signature, or 0 if there is none.
+    pub fn checksum_seed(&self) -> u32 {
+        self.checksum_seed
+    }
+
+    /// The size of the blocks this signature was calculated with.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// The number of bytes of each block's crypto hash that the signature stores.
+    pub fn crypto_hash_size(&self) -> u32 {
+        self.crypto_hash_size
+    }
+
+    /// The crypto hash of the blocks, or `None` for a custom [StrongHash].
+    pub fn hash_algorithm(&self) -> Option<HashAlgorithm> {
+        match self.signature_type {
+            SignatureType::Md4 => Some(HashAlgorithm::Md4),
+            SignatureType::Blake3 => Some(HashAlgorithm::Blake3),
+            SignatureType::Blake2 => Some(HashAlgorithm::Blake2),
+            SignatureType::Sha256 => Some(HashAlgorithm::Sha256),
+            SignatureType::Md5 => Some(HashAlgorithm::Md5),
+            SignatureType::Custom(_) => None,
+        }
+    }
+
+    /// The number of blocks, i.e. `ceil(basis length / block_size)`.
+    pub fn block_count(&self) -> usize {
+        self.block_entries().len()
+    }
+
+    /// The serialized entry (CRC followed by crypto hash) of each block, in order.
+    pub(crate) fn block_entries(&self) -> std::slice::Chunks<'_, u8> {
+        self.plain()[self.header_size()..].chunks(Crc::SIZE + self.crypto_hash_size as usize)
+    }
+
+    /// Describe this signature as JSON, for support tooling and log attachments.
+    ///
+    /// The output contains the header fields and, for every block, its CRC and crypto hash in
+    /// hex. The format is meant to be read by people and may change between releases.
+    #[cfg(feature = "json")]
+    pub fn to_json_debug(&self) -> String {
+        let blocks: Vec<_> = self
+            .blocks()
+            .map(|(index, crc, hash)| {
+                serde_json::json!({
+                    "index": index,
+                    "crc": crate::json::hex(&crc.to_bytes()),
+                    "hash": crate::json::hex(hash),
+                })
             })
+            .collect();
+        serde_json::json!({
+            "type": format!("{:?}", self.signature_type),
+            "block_size": self.block_size,
+            "crypto_hash_size": self.crypto_hash_size,
+            "checksum_seed": self.checksum_seed,
+            "block_count": blocks.len(),
+            "blocks": blocks,
+        })
+        .to_string()
+    }
+
+    /// The index, weak hash and (truncated) crypto hash of each block, in order.
+    pub fn blocks(&self) -> impl ExactSizeIterator<Item = (u32, Crc, &[u8])> {
+        self.block_entries().enumerate().map(|(index, b)| {
+            (
+                index as u32,
+                Crc::from_bytes(*array_ref!(b, 0, Crc::SIZE)),
+                &b[Crc::SIZE..],
+            )
+        })
+    }
+
+    /// Like [Signature::index], but with a perfect hash index, which finds the blocks of a CRC
+    /// with a single probe.
+    ///
+    /// Building the index takes longer than [Signature::index], but lookups while diffing are
+    /// faster, so use this for signatures that are kept around and diffed against many times.
+    pub fn index_perfect(&self) -> IndexedSignature<'_, R> {
+        let entry_size = Crc::SIZE + self.crypto_hash_size as usize;
+        let entries = &self.plain()[self.header_size()..];
+        IndexedSignature {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            checksum_seed: self.checksum_seed,
+            blocks: BlockIndex::Perfect(PerfectIndex::build(entries, entry_size)),
+            entries,
+            rolling_hash: PhantomData,
+        }
+    }
+
+    /// The length of the buffer [Signature::index_into] needs.
+    pub fn index_len(&self) -> usize {
+        IndexTable::serialized_len(self.blocks().len())
+    }
+
+    /// Like [Signature::index], but builds the block index in `buf` instead of allocating it.
+    ///
+    /// The index of a large signature is the largest allocation of diffing, so this lets
+    /// embedders place it in memory of their choosing, such as a pool or a memory-limited region.
+    /// The delta itself is written to any [Write](std::io::Write), so it can go to a
+    /// custom-allocated buffer as well. On v2 signatures, which carry a prebuilt index, `buf` is
+    /// left untouched.
+    ///
+    /// # Panics
+    /// Panics if `buf` is shorter than [Signature::index_len], unless the `strict` feature makes
+    /// this allocate the index like [Signature::index]. [Signature::try_index_into] returns an
+    /// error instead.
+    pub fn index_into<'a>(&'a self, buf: &'a mut [u8]) -> IndexedSignature<'a, R> {
+        if self.has_index() || (cfg!(feature = "strict") && buf.len() < self.index_len()) {
+            return self.index();
+        }
+        self.index_into_unchecked(buf)
+    }
+
+    /// Like [Signature::index_into], or fail if `buf` is shorter than [Signature::index_len] and
+    /// the signature has no prebuilt index.
+    pub fn try_index_into<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> Result<IndexedSignature<'a, R>, InvalidArgumentError> {
+        if self.has_index() {
+            return Ok(self.index());
+        }
+        if buf.len() < self.index_len() {
+            return Err(InvalidArgumentError { name: "buf" });
+        }
+        Ok(self.index_into_unchecked(buf))
+    }
+
+    /// [Signature::index_into] of a signature without a prebuilt index into a large enough `buf`.
+    fn index_into_unchecked<'a>(&'a self, buf: &'a mut [u8]) -> IndexedSignature<'a, R> {
+        let entry_size = Crc::SIZE + self.crypto_hash_size as usize;
+        let entries = &self.plain()[self.header_size()..];
+        let table = &mut buf[..self.index_len()];
+        IndexTable::build_into(entries, entry_size, table);
+        IndexedSignature {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            checksum_seed: self.checksum_seed,
+            blocks: BlockIndex::Table(IndexTable::trusted(table, entries, entry_size)),
+            entries,
+            rolling_hash: PhantomData,
+        }
     }
 
     /// Convert a signature to a form suitable for computing deltas.
-    pub fn index(&self) -> IndexedSignature<'_> {
-        let blocks = self.blocks();
-        let mut block_index: HashMap<Crc, SecondLayerMap<&[u8], u32>, BuildCrcHasher> =
-            HashMap::with_capacity_and_hasher(blocks.len(), BuildCrcHasher::default());
-        for (idx, (crc, crypto_hash)) in blocks.enumerate() {
-            block_index
-                .entry(crc)
-                .or_default()
-                .insert(crypto_hash, idx as u32);
-        }
-
-        // Multiple blocks having the same `Crc` value means that the hashmap will reserve more
-        // capacity than needed. This is particularly noticable when `self.blocks` contains a very
-        // large number of values
-        block_index.shrink_to_fit();
+    pub fn index(&self) -> IndexedSignature<'_, R> {
+        self.as_signature_ref().index()
+    }
+
+    /// Like [Signature::index], building the index on the threads of the rayon pool.
+    ///
+    /// The blocks are split into shards by CRC, whose maps are built independently, so this
+    /// scales with the number of cores on signatures with millions of blocks. Deltas are the same
+    /// as with [Signature::index].
+    ///
+    /// # Features
+    /// This function requires the `parallel` feature to be enabled.
+    #[cfg(feature = "parallel")]
+    pub fn index_parallel(&self) -> IndexedSignature<'_, R> {
+        use rayon::prelude::*;
+
+        if self.has_index() {
+            return self.index();
+        }
+        let entry_size = Crc::SIZE + self.crypto_hash_size as usize;
+        let entries = &self.plain()[self.header_size()..];
+        let block = |idx: usize| {
+            let entry = &entries[idx * entry_size..((idx + 1) * entry_size).min(entries.len())];
+            (Crc::from_bytes(*array_ref!(entry, 0, Crc::SIZE)), &entry[Crc::SIZE..])
+        };
+        let block_count = entries.len().div_ceil(entry_size);
+        // Sort the blocks of each chunk into shards, so that every shard can then insert its
+        // blocks in order: of blocks with the same hashes, the last one is indexed.
+        let chunks: Vec<Vec<Vec<u32>>> = (0..block_count.div_ceil(INDEX_CHUNK_BLOCKS))
+            .into_par_iter()
+            .map(|chunk| {
+                let mut shards = vec![Vec::new(); INDEX_SHARDS];
+                let start = chunk * INDEX_CHUNK_BLOCKS;
+                for idx in start..(start + INDEX_CHUNK_BLOCKS).min(block_count) {
+                    shards[shard(block(idx).0)].push(idx as u32);
+                }
+                shards
+            })
+            .collect();
+        let shards: Vec<_> = (0..INDEX_SHARDS)
+            .into_par_iter()
+            .map(|shard| {
+                let len = chunks.iter().map(|shards| shards[shard].len()).sum();
+                let (mut map, mut arena): BlockMap<'_> = (
+                    HashMap::with_capacity_and_hasher(len, BuildCrcHasher::default()),
+                    Arena::default(),
+                );
+                for &idx in chunks.iter().flat_map(|shards| &shards[shard]) {
+                    let (crc, crypto_hash) = block(idx as usize);
+                    map.entry(crc)
+                        .or_default()
+                        .insert(&mut arena, crypto_hash, idx);
+                }
+                map.shrink_to_fit();
+                arena.shrink_to_fit();
+                (map, arena)
+            })
+            .collect();
+
+        IndexedSignature {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            checksum_seed: self.checksum_seed,
+            blocks: BlockIndex::Sharded(shards.into_boxed_slice()),
+            entries,
+            rolling_hash: PhantomData,
+        }
+    }
+}
+
+impl<'a> SignatureRef<'a> {
+    /// Read a binary signature like [Signature::deserialize], borrowing it instead of taking
+    /// ownership.
+    ///
+    /// Signatures written by [Signature::serialize_compact] have to be decoded into a buffer of
+    /// their own, so they are only read by [Signature::deserialize].
+    pub fn deserialize(signature: &'a [u8]) -> Result<Self, SignatureParseError> {
+        Self::deserialize_with_rolling_hash(signature)
+    }
+}
+
+impl<'a, R: RollingHash> SignatureRef<'a, R> {
+    /// Read a binary signature like [SignatureRef::deserialize], which was calculated with the
+    /// rolling hash `R`.
+    pub fn deserialize_with_rolling_hash(signature: &'a [u8]) -> Result<Self, SignatureParseError> {
+        if signature.len() < SIGNATURE_HEADER_SIZE {
+            return Err(SignatureParseError(()));
+        }
+        if u32::from_be_bytes(*array_ref![signature, 0, 4]) == SIGNATURE_EXT_MAGIC {
+            return Self::deserialize_ext(signature);
+        }
+        if u32::from_be_bytes(*array_ref![signature, 0, 4]) == SIGNATURE_V2_MAGIC {
+            return Self::deserialize_v2(signature);
+        }
+        let (signature_type, seeded) = Signature::<R>::signature_type(*array_ref![signature, 0, 4])
+            .ok_or(SignatureParseError(()))?;
+        let block_size = u32::from_be_bytes(*array_ref![signature, 4, 4]);
+        let crypto_hash_size = u32::from_be_bytes(*array_ref![signature, 8, 4]);
+        let header_size = header_size(*array_ref![signature, 0, 4]);
+        if signature.len() < header_size {
+            return Err(SignatureParseError(()));
+        }
+        // A seeded header never holds seed 0, so every signature has a single serialization.
+        let checksum_seed = if seeded {
+            match u32::from_be_bytes(*array_ref![signature, SIGNATURE_HEADER_SIZE, 4]) {
+                0 => return Err(SignatureParseError(())),
+                seed => seed,
+            }
+        } else {
+            0
+        };
+        let block_signature_size = Crc::SIZE + crypto_hash_size as usize;
+        if !(signature.len() - header_size).is_multiple_of(block_signature_size) {
+            return Err(SignatureParseError(()));
+        }
+        Ok(SignatureRef {
+            signature_type,
+            block_size,
+            crypto_hash_size,
+            checksum_seed,
+            signature,
+            start: 0,
+            end: signature.len(),
+            rolling_hash: PhantomData,
+        })
+    }
+
+    /// Parse the extended signature `signature`.
+    fn deserialize_ext(signature: &'a [u8]) -> Result<Self, SignatureParseError> {
+        let len = u32::from_be_bytes(*array_ref![signature, 4, 4]);
+        let ext_len = SIGNATURE_EXT_HEADER_SIZE
+            .checked_add(len as usize)
+            .filter(|&ext_len| ext_len <= signature.len())
+            .ok_or(SignatureParseError(()))?;
+        SignatureExtensions::parse(&signature[SIGNATURE_EXT_HEADER_SIZE..ext_len])
+            .ok_or(SignatureParseError(()))?;
+        // The wrapped signature must be a regular or v2 one, not another extended one.
+        let wrapped = &signature[ext_len..];
+        if wrapped.len() < 4 || u32::from_be_bytes(*array_ref![wrapped, 0, 4]) == SIGNATURE_EXT_MAGIC {
+            return Err(SignatureParseError(()));
+        }
+        let wrapped = Self::deserialize_with_rolling_hash(wrapped)?;
+        Ok(SignatureRef {
+            signature,
+            start: ext_len + wrapped.start,
+            end: ext_len + wrapped.end,
+            ..wrapped
+        })
+    }
+
+    /// Parse the v2 signature `signature`, checking its prebuilt index.
+    fn deserialize_v2(signature: &'a [u8]) -> Result<Self, SignatureParseError> {
+        let start = SIGNATURE_V2_HEADER_SIZE;
+        let len = signature
+            .get(4..start)
+            .map(|len| u64::from_be_bytes(len.try_into().unwrap()))
+            .ok_or(SignatureParseError(()))?;
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| start.checked_add(len))
+            .filter(|&end| end <= signature.len())
+            .ok_or(SignatureParseError(()))?;
+        // The wrapped signature must be a plain one, not another container.
+        if end - start < SIGNATURE_HEADER_SIZE
+            || Signature::<R>::signature_type(*array_ref![signature, start, 4]).is_none()
+        {
+            return Err(SignatureParseError(()));
+        }
+        let plain = Self::deserialize_with_rolling_hash(&signature[start..end])?;
+        let entry_size = Crc::SIZE + plain.crypto_hash_size as usize;
+        IndexTable::parse(&signature[end..], plain.entries(), entry_size)
+            .ok_or(SignatureParseError(()))?;
+        Ok(SignatureRef {
+            signature,
+            start,
+            end,
+            ..plain
+        })
+    }
+
+    /// The plain signature, without the v2 header and index.
+    fn plain(&self) -> &'a [u8] {
+        &self.signature[self.start..self.end]
+    }
+
+    /// The serialized entry (CRC followed by crypto hash) of each block, in order.
+    fn entries(&self) -> &'a [u8] {
+        let plain = self.plain();
+        &plain[header_size(*array_ref![plain, 0, 4])..]
+    }
+
+    /// Get the serialized form of this signature.
+    pub fn serialized(&self) -> &'a [u8] {
+        self.signature
+    }
+
+    /// The checksum seed mixed into the strong hashes of this signature, or 0 if there is none.
+    pub fn checksum_seed(&self) -> u32 {
+        self.checksum_seed
+    }
+
+    /// The extensions this signature carries, see [Signature::extensions].
+    pub fn extensions(&self) -> SignatureExtensions {
+        extensions(self.signature)
+    }
+
+    /// Copy this signature into a [Signature] of its own.
+    pub fn to_signature(&self) -> Signature<R> {
+        Signature {
+            signature_type: self.signature_type,
+            block_size: self.block_size,
+            crypto_hash_size: self.crypto_hash_size,
+            checksum_seed: self.checksum_seed,
+            signature: self.signature.to_vec(),
+            start: self.start,
+            end: self.end,
+            rolling_hash: PhantomData,
+        }
+    }
+
+    /// Convert a signature to a form suitable for computing deltas, like [Signature::index].
+    ///
+    /// The index borrows the underlying bytes rather than this `SignatureRef`.
+    pub fn index(&self) -> IndexedSignature<'a, R> {
+        let entry_size = Crc::SIZE + self.crypto_hash_size as usize;
+        let entries = self.entries();
+        let blocks = if self.end != self.signature.len() {
+            // The index was checked when this signature was parsed, or built with it.
+            BlockIndex::Table(IndexTable::trusted(
+                &self.signature[self.end..],
+                entries,
+                entry_size,
+            ))
+        } else {
+            let blocks = entries.chunks(entry_size);
+            let mut block_index: HashMap<Crc, SecondLayerMap<&[u8], u32>, BuildCrcHasher> =
+                HashMap::with_capacity_and_hasher(blocks.len(), BuildCrcHasher::default());
+            let mut arena = Arena::default();
+            for (idx, block) in blocks.enumerate() {
+                let crc = Crc::from_bytes(*array_ref!(block, 0, Crc::SIZE));
+                block_index
+                    .entry(crc)
+                    .or_default()
+                    .insert(&mut arena, &block[Crc::SIZE..], idx as u32);
+            }
+
+            // Multiple blocks having the same `Crc` value means that the hashmap will reserve
+            // more capacity than needed. This is particularly noticable when the signature
+            // contains a very large number of blocks
+            block_index.shrink_to_fit();
+            arena.shrink_to_fit();
+            BlockIndex::Map(block_index, arena)
+        };
 
         IndexedSignature {
             signature_type: self.signature_type,
             block_size: self.block_size,
             crypto_hash_size: self.crypto_hash_size,
-            blocks: block_index,
+            checksum_seed: self.checksum_seed,
+            blocks,
+            entries,
+            rolling_hash: PhantomData,
+        }
+    }
+}
+
+impl<R: RollingHash> IndexedSignatureOwned<R> {
+    /// Build the block index of `signature`, unless it already carries one.
+    pub fn new(signature: Signature<R>) -> Self {
+        IndexedSignatureOwned {
+            signature: Arc::new(signature.with_index()),
+        }
+    }
+
+    /// The indexed signature, for calculating deltas.
+    pub fn index(&self) -> IndexedSignature<'_, R> {
+        self.signature.index()
+    }
+
+    /// The underlying v2 signature.
+    pub fn signature(&self) -> &Signature<R> {
+        &self.signature
+    }
+}
+
+impl<R: RollingHash> From<Signature<R>> for IndexedSignatureOwned<R> {
+    fn from(signature: Signature<R>) -> Self {
+        Self::new(signature)
+    }
+}
+
+impl<R> IndexedSignature<'_, R> {
+    /// The strong hash of the blocks, with the checksum seed mixed in.
+    pub(crate) fn strong_hash(&self) -> SeededHash<'static> {
+        SeededHash::new(self.signature_type.strong_hash(), self.checksum_seed)
+    }
+}
+
+impl<'a> IndexedSignature<'a> {
+    /// Read an indexed signature written by [IndexedSignature::serialize], borrowing `signature`.
+    ///
+    /// The stored index is checked, but not rebuilt, so a large signature is ready for diffing
+    /// right after it is loaded (e.g. from a memory map). Signatures without an index are indexed
+    /// like [Signature::index].
+    pub fn deserialize(signature: &'a [u8]) -> Result<Self, SignatureParseError> {
+        Self::deserialize_with_rolling_hash(signature)
+    }
+}
+
+impl<'a, R: RollingHash> IndexedSignature<'a, R> {
+    /// Read an indexed signature like [IndexedSignature::deserialize], which was calculated with
+    /// the rolling hash `R`.
+    pub fn deserialize_with_rolling_hash(signature: &'a [u8]) -> Result<Self, SignatureParseError> {
+        SignatureRef::deserialize_with_rolling_hash(signature).map(|signature| signature.index())
+    }
+
+    /// Serialize this signature together with a block index, so that a server can index a
+    /// signature once and reload it with [IndexedSignature::deserialize] on later runs.
+    ///
+    /// The result is a v2 signature (see [Signature::with_index]), which
+    /// [Signature::deserialize] reads as well.
+    pub fn serialize(&self) -> Vec<u8> {
+        let seeded = self.checksum_seed != 0;
+        let mut plain = Vec::with_capacity(SEEDED_SIGNATURE_HEADER_SIZE + self.entries.len());
+        plain.extend_from_slice(&Signature::<R>::magic(self.signature_type, seeded));
+        plain.extend_from_slice(&self.block_size.to_be_bytes());
+        plain.extend_from_slice(&self.crypto_hash_size.to_be_bytes());
+        if seeded {
+            plain.extend_from_slice(&self.checksum_seed.to_be_bytes());
         }
+        let header_size = plain.len();
+        plain.extend_from_slice(self.entries);
+        wrap_v2(&plain, header_size, Crc::SIZE + self.crypto_hash_size as usize)
     }
 }
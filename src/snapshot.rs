@@ -0,0 +1,258 @@
+//! Diffing memory snapshots and core dumps.
+//!
+//! Like [blockdev](crate::blockdev), this compares snapshots page for page at the same position
+//! instead of searching for shifted matches: live migration and checkpointing track memory by
+//! page frame, and pages don't move between two snapshots of the same guest or process.
+//!
+//! Snapshots are mostly zero pages, which this module handles specially. [signature] hashes the
+//! zero page once and reuses its entry, [diff] writes changed pages that became zero as repeats
+//! instead of literals, and [apply_sparse] leaves runs of zeros as holes in the output file
+//! instead of writing them.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::consts::{DELTA_V2_MAGIC, RS_OP_END};
+use crate::crc::Crc;
+use crate::diff::DiffError;
+use crate::ops::{encode_copy, encode_literal_header, encode_repeat};
+use crate::patch::{write_repeated, ApplyError, Op, OpReader};
+use crate::signature::{HashAlgorithm, Signature, SignatureOptions};
+
+/// The page size used by [signature_options].
+pub const PAGE_SIZE: u32 = 4096;
+
+/// Signature options for snapshots with pages of `page_size` bytes, e.g. [PAGE_SIZE] or 2 MiB
+/// for huge pages, and the full hash.
+pub fn signature_options(hash_algorithm: HashAlgorithm, page_size: u32) -> SignatureOptions {
+    SignatureOptions::new(page_size, hash_algorithm.max_hash_size() as u32, hash_algorithm)
+}
+
+/// Compute the signature of `snapshot`, hashing the zero page only once.
+///
+/// The result is identical to [Signature::calculate].
+/// Panics if the provided options are invalid, unless the `strict` feature replaces them with
+/// the nearest valid ones.
+pub fn signature(snapshot: &[u8], options: SignatureOptions) -> Signature {
+    #[cfg(feature = "strict")]
+    let options = options.clamped();
+    assert!(options.is_valid());
+
+    let mut signature = Vec::new();
+    Signature::write_header(&options, &mut signature);
+    hash_pages(snapshot, &options, &mut signature);
+    Signature::from_serialized_parts(&options, signature)
+}
+
+/// Calculate a delta from `signature` to `snapshot`, comparing page for page.
+///
+/// Unchanged pages are copies of the page at the same position, changed zero pages are repeats
+/// and other changed pages are literals, with runs of each merged into one op. The delta is in
+/// the v2 format, which [apply()](crate::apply()) and [apply_sparse] can apply, but librsync
+/// can't.
+pub fn diff(signature: &Signature, snapshot: &[u8], mut out: impl Write) -> Result<(), DiffError> {
+    let options = signature.options().ok_or(DiffError::InvalidSignature)?;
+    let page_size = options.block_size as usize;
+    let entry_size = Crc::SIZE + options.crypto_hash_size as usize;
+    let mut new_entries = Vec::new();
+    hash_pages(snapshot, &options, &mut new_entries);
+    let mut old_entries = signature.block_entries();
+
+    out.write_all(&DELTA_V2_MAGIC.to_be_bytes())?;
+    // The kind and start of the pages seen so far that have not been written yet.
+    let mut run: Option<(Page, usize)> = None;
+    let pages = snapshot.chunks(page_size).zip(new_entries.chunks(entry_size));
+    for (i, (page, new_entry)) in pages.enumerate() {
+        let kind = if old_entries.next() == Some(new_entry) {
+            Page::Unchanged
+        } else if is_zero(page) {
+            Page::Zero
+        } else {
+            Page::Changed
+        };
+        let start = i * page_size;
+        match run {
+            Some((pending, _)) if pending == kind => {}
+            Some((pending, from)) => {
+                write_run(pending, from, &snapshot[from..start], &mut out)?;
+                run = Some((kind, start));
+            }
+            None => run = Some((kind, start)),
+        }
+    }
+    if let Some((pending, from)) = run {
+        write_run(pending, from, &snapshot[from..], &mut out)?;
+    }
+    out.write_all(&[RS_OP_END])?;
+    Ok(())
+}
+
+/// Apply `delta` to the snapshot `base`, writing the result to `out`, which must be empty, e.g. a
+/// newly created file.
+///
+/// Zero pages, whether written as repeats, literals or copies, are skipped over instead of
+/// written, so they become holes in file systems that support sparse files. Returns the length
+/// of the output.
+pub fn apply_sparse(
+    base: &[u8],
+    delta: &[u8],
+    mut out: impl Write + Seek,
+) -> Result<u64, ApplyError> {
+    let mut ops = OpReader::new(delta)?;
+    let mut position = 0u64;
+    // The zeros at the end of the output that have been skipped over so far.
+    let mut hole = 0u64;
+    while let Some(op) = ops.next_op()? {
+        match op {
+            Op::Literal(literal) => write_sparse(literal, &mut hole, &mut out)?,
+            Op::Copy { offset, len } => {
+                if len == 0 {
+                    return Err(ApplyError::CopyZero);
+                }
+                let data = usize::try_from(offset)
+                    .ok()
+                    .zip(usize::try_from(len).ok())
+                    .and_then(|(offset, len)| base.get(offset..offset.checked_add(len)?))
+                    .ok_or(ApplyError::CopyOutOfBounds {
+                        offset,
+                        len,
+                        data_len: base.len(),
+                    })?;
+                write_sparse(data, &mut hole, &mut out)?;
+            }
+            Op::Repeat { pattern, .. } if is_zero(pattern) => {
+                hole = hole.saturating_add(op.output_len())
+            }
+            Op::Repeat { pattern, .. } => {
+                skip(hole, &mut out)?;
+                hole = 0;
+                write_repeated(pattern, 0..op.output_len(), &mut out)?;
+            }
+        }
+        position = position.saturating_add(op.output_len());
+    }
+    if hole > 0 {
+        // Writing the last byte extends the output over the trailing hole.
+        skip(hole - 1, &mut out)?;
+        out.write_all(&[0])?;
+    }
+    Ok(position)
+}
+
+/// What happened to a page between the two snapshots.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Page {
+    Unchanged,
+    Zero,
+    Changed,
+}
+
+/// Append the signature entries of the pages of `data` to `out`, reusing the entry of the zero
+/// page for all zero pages.
+fn hash_pages(data: &[u8], options: &SignatureOptions, out: &mut Vec<u8>) {
+    let page_size = options.block_size as usize;
+    let mut zero_entry = Vec::new();
+    Signature::hash_blocks(&vec![0; page_size], options, &mut zero_entry);
+    // The start of the run of pages that aren't zero, which are hashed together.
+    let mut run_start = 0;
+    for (i, page) in data.chunks_exact(page_size).enumerate() {
+        if is_zero(page) {
+            let start = i * page_size;
+            Signature::hash_blocks(&data[run_start..start], options, out);
+            out.extend_from_slice(&zero_entry);
+            run_start = start + page_size;
+        }
+    }
+    Signature::hash_blocks(&data[run_start..], options, out);
+}
+
+/// Write the op for the pages of `data` starting at `start`, which are all of the kind `page`.
+fn write_run(page: Page, start: usize, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+    match page {
+        Page::Unchanged => encode_copy(start as u64, data.len() as u64, out),
+        Page::Zero => encode_repeat(&[0], data.len() as u64, out),
+        Page::Changed => {
+            encode_literal_header(data.len() as u64, out)?;
+            out.write_all(data)
+        }
+    }
+}
+
+/// Write `data` to `out` after the zeros of `hole`, skipping over the zero pages of `data`.
+fn write_sparse(data: &[u8], hole: &mut u64, out: &mut (impl Write + Seek)) -> io::Result<()> {
+    for page in data.chunks(PAGE_SIZE as usize) {
+        if is_zero(page) {
+            *hole += page.len() as u64;
+        } else {
+            skip(*hole, out)?;
+            *hole = 0;
+            out.write_all(page)?;
+        }
+    }
+    Ok(())
+}
+
+/// Move the position of `out` forward by `n` bytes.
+fn skip(mut n: u64, out: &mut impl Seek) -> io::Result<()> {
+    while n > 0 {
+        let step = n.min(i64::MAX as u64);
+        out.seek(SeekFrom::Current(step as i64))?;
+        n -= step;
+    }
+    Ok(())
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|&byte| byte == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rand::Rng;
+
+    use super::{apply_sparse, diff, signature, signature_options, PAGE_SIZE};
+    use crate::{apply, HashAlgorithm, Signature};
+
+    #[test]
+    fn test_snapshot() {
+        let page = PAGE_SIZE as usize;
+        let mut base = vec![0; 64 * page + 100];
+        for i in [1, 2, 3, 10, 11, 40, 63] {
+            rand::thread_rng().fill(&mut base[i * page..(i + 1) * page]);
+        }
+        let mut data = base.clone();
+        data[2 * page..4 * page].fill(0);
+        rand::thread_rng().fill(&mut data[20 * page..21 * page]);
+        data[40 * page + 7] ^= 1;
+        data.truncate(63 * page + 10);
+
+        for hash_algorithm in [HashAlgorithm::Md4, HashAlgorithm::Blake3] {
+            let options = signature_options(hash_algorithm, PAGE_SIZE);
+            let signature = signature(&base, options);
+            assert_eq!(signature, Signature::calculate(&base, options));
+
+            let mut delta = vec![];
+            diff(&signature, &data, &mut delta).unwrap();
+            // The two changed pages and the tail are the only literals.
+            assert!(delta.len() < 2 * page + 100, "{}", delta.len());
+            let mut out = vec![];
+            apply(&base, &delta, &mut out).unwrap();
+            assert_eq!(out, data);
+
+            let mut sparse = Cursor::new(vec![]);
+            let len = apply_sparse(&base, &delta, &mut sparse).unwrap();
+            assert_eq!(len, data.len() as u64);
+            assert_eq!(sparse.into_inner(), data);
+        }
+
+        // A trailing hole still makes the output as long as it should be.
+        let options = signature_options(HashAlgorithm::Blake3, PAGE_SIZE);
+        let zeros = vec![0; 3 * page];
+        let mut delta = vec![];
+        diff(&signature(&base, options), &zeros, &mut delta).unwrap();
+        let mut sparse = Cursor::new(vec![]);
+        apply_sparse(&base, &delta, &mut sparse).unwrap();
+        assert_eq!(sparse.into_inner(), zeros);
+    }
+}
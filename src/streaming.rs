@@ -0,0 +1,141 @@
+//! Applying deltas without holding the base data, the delta or the output in memory.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::fingerprint::{check_fingerprint_with, read_fingerprint};
+use crate::patch::{apply_with_scratch, ApplyError};
+
+/// The size of the buffer that [apply_streaming] moves data through.
+const STREAMING_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Apply the delta read from `delta` to the base data read from `base`, writing the result to
+/// `out`. Returns the length of the output.
+///
+/// Copy ops seek in `base`, and everything else passes through a single buffer of 64 KiB, so
+/// memory use doesn't depend on the size of any of the three. Unlike [apply()](crate::apply()),
+/// this doesn't take compressed containers or fingerprinted deltas, and `out` may have received
+/// part of the output when an error is returned. See [apply_seekable] for deltas in memory.
+///
+/// `base` and `delta` are read in small pieces; wrap them in a [BufReader](std::io::BufReader) if
+/// small reads are expensive.
+pub fn apply_streaming(
+    mut base: impl Read + Seek,
+    mut delta: impl Read,
+    mut out: impl Write,
+) -> Result<u64, ApplyError> {
+    let base_len = base.seek(SeekFrom::End(0))?;
+    // Copies often follow each other in the base data, so only seek when they don't.
+    let mut position = base_len;
+    let read_base = |offset, buf: &mut [u8]| {
+        if offset != position {
+            base.seek(SeekFrom::Start(offset))?;
+        }
+        base.read_exact(buf)?;
+        position = offset + buf.len() as u64;
+        Ok(())
+    };
+    let mut scratch = vec![0; STREAMING_BUFFER_SIZE];
+    let written = apply_with_scratch(
+        base_len,
+        read_base,
+        |buf| delta.read(buf),
+        |buf| out.write_all(buf),
+        &mut scratch,
+    )?;
+    out.flush()?;
+    Ok(written)
+}
+
+/// Apply `delta` to the base data read from `base`, writing the result to `out`. Returns the
+/// length of the output.
+///
+/// Like [apply_streaming], copy ops are served by seeking in `base`, so patching a large image
+/// only needs the delta and a 64 KiB buffer in memory. `delta` may be fingerprinted by
+/// [diff_fingerprinted](crate::fingerprint::diff_fingerprinted), in which case `base` is read
+/// once to compute its fingerprint and nothing is written unless it matches. Compressed
+/// containers need the base data in memory and aren't supported.
+pub fn apply_seekable(
+    mut base: impl Read + Seek,
+    delta: &[u8],
+    out: impl Write,
+) -> Result<u64, ApplyError> {
+    let delta = check_fingerprint_with(delta, |block_size| {
+        base.seek(SeekFrom::Start(0)).map_err(ApplyError::ReadIo)?;
+        read_fingerprint(&mut base, block_size).map_err(ApplyError::ReadIo)
+    })?;
+    apply_streaming(base, delta, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rand::Rng;
+
+    use super::{apply_seekable, apply_streaming};
+    use crate::fingerprint::diff_fingerprinted;
+    use crate::{apply, diff_v2, ApplyError, HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_apply_streaming() {
+        let mut base = vec![0; 300_000];
+        rand::thread_rng().fill(&mut base[..]);
+        let mut data = base.clone();
+        data[1000..2000].fill(0);
+        data.splice(100_000..100_000, vec![9; 100_000]);
+        data.drain(250_000..260_000);
+        data.extend_from_slice(&base[..70_000]);
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(512, 8, HashAlgorithm::Blake3),
+        );
+        let mut delta = vec![];
+        diff_v2(&signature.index(), &data, &mut delta).unwrap();
+
+        let mut out = vec![];
+        let len = apply_streaming(Cursor::new(&base), &delta[..], &mut out).unwrap();
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(out, data);
+        let mut expected = vec![];
+        apply(&base, &delta, &mut expected).unwrap();
+        assert_eq!(out, expected);
+
+        let truncated = &delta[..delta.len() - 1];
+        assert!(matches!(
+            apply_streaming(Cursor::new(&base), truncated, vec![]),
+            Err(ApplyError::UnexpectedEof { .. })
+        ));
+        assert!(matches!(
+            apply_streaming(Cursor::new(&base[..1000]), &delta[..], vec![]),
+            Err(ApplyError::CopyOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_apply_seekable() {
+        let base: Vec<u8> = (0..200_000u32).map(|i| (i * 13 % 253) as u8).collect();
+        let data = [&base[150_000..], b"inserted", &base[..150_000]].concat();
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(1000, 8, HashAlgorithm::Md4),
+        );
+        let mut delta = vec![];
+        diff_fingerprinted(&signature.index(), &data, &mut delta).unwrap();
+
+        let mut base_file = Cursor::new(&base);
+        base_file.set_position(1234);
+        let mut out = vec![];
+        let len = apply_seekable(base_file, &delta, &mut out).unwrap();
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(out, data);
+
+        let mut out = vec![];
+        let mut wrong = base.clone();
+        wrong[100] ^= 1;
+        assert!(matches!(
+            apply_seekable(Cursor::new(&wrong), &delta, &mut out),
+            Err(ApplyError::WrongBasis { .. })
+        ));
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+//! Preconditions of public entry points.
+//!
+//! By default, entry points panic on arguments that are a bug in the caller, like a zero block
+//! size. With the `strict` feature they return an error instead, or, where they can't fail, use
+//! the nearest valid argument, so that the crate never panics in abort-on-panic and FFI contexts.
+//! Malformed deltas and signatures are errors either way.
+
+/// Check `cond` like `assert!`, or with the `strict` feature, return `Err(err.into())` from the
+/// enclosing function if it doesn't hold.
+macro_rules! require {
+    ($cond:expr, $err:expr) => {
+        if !$cond {
+            if cfg!(feature = "strict") {
+                return Err($err.into());
+            }
+            panic!(concat!("assertion failed: ", stringify!($cond)));
+        }
+    };
+}
+
+pub(crate) use require;
+
+/// The error of entry points returning [io::Result](std::io::Result) for an invalid argument.
+pub(crate) fn invalid_input(name: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid argument (name={name})"),
+    )
+}
+
+#[cfg(all(test, feature = "strict"))]
+mod tests {
+    use std::ops::Range;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use quickcheck_macros::quickcheck;
+
+    use crate::analysis::heatmap;
+    use crate::consts::{
+        BLAKE2_MAGIC, BLAKE3_MAGIC, DELTA_MAGIC, DELTA_V2_MAGIC, MD4_MAGIC, MD5_MAGIC, SHA256_MAGIC,
+    };
+    use crate::ops::{encode_literal_header, encode_repeat};
+    use crate::seek::SeekIndex;
+    use crate::shard::split;
+    use crate::{
+        apply_limited, apply_with_scratch, diff, diff_range, params, ApplyError, DiffError,
+        HashAlgorithm, InvalidArgumentError, Signature, SignatureOptions, StrongHash,
+    };
+
+    /// A strong hash of `N` bytes, all zero.
+    #[derive(Debug)]
+    struct Zeros<const N: usize>;
+
+    impl<const N: usize> StrongHash for Zeros<N> {
+        fn magic(&self) -> u32 {
+            0x7273_01f0 + N as u32
+        }
+
+        fn size(&self) -> usize {
+            N
+        }
+
+        fn hash_block(&self, _: &[u8], out: &mut [u8]) {
+            out.fill(0);
+        }
+    }
+
+    const EMPTY: Zeros<0> = Zeros;
+    const SHORT: Zeros<8> = Zeros;
+
+    /// The name of the invalid argument `result` failed on.
+    fn invalid_name<T>(result: Result<T, InvalidArgumentError>) -> &'static str {
+        result.map(|_| ()).unwrap_err().name
+    }
+
+    /// Run `f`, failing the test if it panics.
+    fn no_panic<T>(what: &str, f: impl FnOnce() -> T) -> T {
+        catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| panic!("{what} panicked"))
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let invalid = SignatureOptions::new(0, 100, HashAlgorithm::Blake3);
+        let base = b"some base data, long enough for a few blocks".repeat(10);
+        let signature = no_panic("Signature::calculate", || Signature::calculate(&base, invalid));
+        assert_eq!(signature.block_size(), 1);
+        no_panic("params::estimate", || params::estimate(&invalid, 100));
+        no_panic("hash_size_for", || SignatureOptions::hash_size_for(10, 0.0));
+        assert_eq!(no_panic("split", || split(&signature, 0)).len(), base.len());
+        no_panic("index_into", || diff(&signature.index_into(&mut []), &base, vec![])).unwrap();
+
+        // The try_ variants report what the infallible ones replace.
+        let try_calculate = || invalid_name(Signature::try_calculate(&base, invalid));
+        assert_eq!(no_panic("try_calculate", try_calculate), "block_size");
+        let too_long = SignatureOptions::new(16, 100, HashAlgorithm::Blake3);
+        let try_calculate = || invalid_name(Signature::try_calculate(&base, too_long));
+        assert_eq!(no_panic("try_calculate", try_calculate), "crypto_hash_size");
+        let valid = SignatureOptions::new(16, 8, HashAlgorithm::Blake3);
+        let checked = Signature::try_calculate(&base, valid).unwrap();
+        assert_eq!(checked, Signature::calculate(&base, valid));
+        let try_index_into = || invalid_name(checked.try_index_into(&mut []));
+        assert_eq!(no_panic("try_index_into", try_index_into), "buf");
+        let mut buf = vec![0; checked.index_len()];
+        let indexed = checked.try_index_into(&mut buf).unwrap();
+        diff(&indexed, &base, vec![]).unwrap();
+        let prebuilt = checked.clone().with_index();
+        assert!(prebuilt.try_index_into(&mut []).is_ok());
+        for (block_size, crypto_hash_size, hash, name) in [
+            (0, 8, &EMPTY as &'static dyn StrongHash, "hash"),
+            (0, 8, &SHORT, "block_size"),
+            (16, 9, &SHORT, "crypto_hash_size"),
+        ] {
+            let result = || Signature::try_calculate_with_hash(&base, block_size, crypto_hash_size, hash);
+            assert_eq!(invalid_name(no_panic("try_calculate_with_hash", result)), name);
+        }
+        assert!(Signature::try_calculate_with_hash(&base, 16, 8, &SHORT).is_ok());
+
+        let mut delta = vec![];
+        diff(&signature.index(), &base, &mut delta).unwrap();
+        assert!(matches!(
+            no_panic("diff_range", || diff_range(&signature.index(), &base, 0..1000, vec![])),
+            Err(DiffError::InvalidArgument { .. })
+        ));
+        assert!(matches!(
+            no_panic("SeekIndex::build", || SeekIndex::build(&delta, 0)),
+            Err(ApplyError::InvalidArgument { .. })
+        ));
+        assert!(matches!(
+            no_panic("heatmap", || heatmap(&delta, 0)),
+            Err(ApplyError::InvalidArgument { .. })
+        ));
+        let scratch = || {
+            let read_base = |_, _: &mut [u8]| Ok(());
+            apply_with_scratch(0, read_base, |_| Ok(0), |_| Ok(()), &mut [])
+        };
+        assert!(matches!(
+            no_panic("apply_with_scratch", scratch),
+            Err(ApplyError::InvalidArgument { .. })
+        ));
+        let inverted = Range { start: 10, end: 5 };
+        let inverted_range = || crate::apply_range(&base, &delta, inverted.clone(), &mut vec![]);
+        assert_eq!(no_panic("apply_range", inverted_range).unwrap(), 0);
+        let index = SeekIndex::build(&delta, 1).unwrap();
+        let inverted_range = || crate::seek::apply_range(&base, &delta, &index, inverted, &mut vec![]);
+        assert_eq!(no_panic("seek::apply_range", inverted_range).unwrap(), 0);
+        let literal = no_panic("encode_literal_header", || encode_literal_header(0, &mut vec![]));
+        assert!(literal.is_err());
+        assert!(no_panic("encode_repeat", || encode_repeat(&[], 1, &mut vec![])).is_err());
+    }
+
+    #[quickcheck]
+    fn malformed_input_never_panics(base: Vec<u8>, input: Vec<u8>) -> bool {
+        // Most random inputs would be rejected for their magic alone.
+        for magic in [DELTA_MAGIC, DELTA_V2_MAGIC] {
+            let delta = [&magic.to_be_bytes()[..], &input].concat();
+            // Unlike apply(), this can't run out of memory on a repeat of an exabyte.
+            let limited = || apply_limited(&base, &delta, &mut vec![], 1 << 20).is_ok();
+            no_panic("apply_limited", limited);
+            let range = || crate::apply_range(&base, &delta, 1000..5000, &mut vec![]).is_ok();
+            no_panic("apply_range", range);
+            no_panic("SeekIndex::build", || SeekIndex::build(&delta, 1).is_ok());
+            no_panic("heatmap", || heatmap(&delta, 4).is_ok());
+        }
+        for magic in [MD4_MAGIC, BLAKE2_MAGIC, BLAKE3_MAGIC, SHA256_MAGIC, MD5_MAGIC] {
+            let signature = [&magic.to_be_bytes()[..], &input].concat();
+            no_panic("Signature::deserialize", || {
+                if let Ok(signature) = Signature::deserialize(signature) {
+                    let _ = diff(&signature.index(), &base, &mut vec![]);
+                }
+            });
+        }
+        true
+    }
+}
@@ -0,0 +1,365 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::sync::{PoisonError, RwLock};
+
+use crate::blake2::{blake2, blake2_many, BLAKE2_SIZE};
+use crate::blake3::{blake3_xof, BLAKE3_MAX_SIZE};
+use crate::consts::{
+    BLAKE2_MAGIC, BLAKE3_MAGIC, COMPACT_SIGNATURE_MAGIC, CRC32C_MAGIC_FLAG, MD4_MAGIC,
+    MD5_MAGIC, SEEDED_MAGIC_FLAG, SHA256_MAGIC, SIGNATURE_EXT_MAGIC, SIGNATURE_V2_MAGIC,
+};
+use crate::md4::{md4, md4_many, MD4_SIZE};
+use crate::md5::{md5, md5_many, MD5_SIZE};
+use crate::sha256::{sha256, sha256_many, SHA256_SIZE};
+
+/// The longest hash a [StrongHash] may produce.
+///
+/// This was 32 until BLAKE3 crypto hashes could be extended to 64 bytes, see
+/// [HashAlgorithm::max_xof_size](crate::HashAlgorithm::max_xof_size).
+pub const MAX_STRONG_HASH_SIZE: usize = 64;
+
+/// The strong hash of the blocks of a signature, which confirms the matches of the rolling CRC.
+///
+/// The built-in algorithms implement this too. Custom ones are used with
+/// [Signature::calculate_with_hash](crate::Signature::calculate_with_hash), and need to be
+/// registered with [register_strong_hash] to deserialize their signatures.
+pub trait StrongHash: fmt::Debug + Sync {
+    /// The magic number that starts the signatures using this hash, which identifies it.
+    fn magic(&self) -> u32;
+
+    /// The length of the hash in bytes, from 1 to [MAX_STRONG_HASH_SIZE].
+    fn size(&self) -> usize;
+
+    /// Hash `block`, writing [StrongHash::size] bytes to `out`.
+    fn hash_block(&self, block: &[u8], out: &mut [u8]);
+
+    /// Hash each of `blocks`, which all have the same length, writing their hashes one after
+    /// another to `out`.
+    ///
+    /// The default hashes them one at a time; implementations can override it to hash several
+    /// at once, e.g. with SIMD.
+    fn hash_many(&self, blocks: &[&[u8]], out: &mut [u8]) {
+        for (block, out) in blocks.iter().zip(out.chunks_exact_mut(self.size())) {
+            self.hash_block(block, out);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Md4;
+
+impl StrongHash for Md4 {
+    fn magic(&self) -> u32 {
+        MD4_MAGIC
+    }
+
+    fn size(&self) -> usize {
+        MD4_SIZE
+    }
+
+    fn hash_block(&self, block: &[u8], out: &mut [u8]) {
+        out.copy_from_slice(&md4(block));
+    }
+
+    fn hash_many(&self, blocks: &[&[u8]], out: &mut [u8]) {
+        // `md4_many` hashes as many blocks at once as there are SIMD lanes.
+        let hashes = md4_many(blocks.iter().copied());
+        for ((_, hash), out) in hashes.zip(out.chunks_exact_mut(MD4_SIZE)) {
+            out.copy_from_slice(&hash);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Blake2;
+
+impl StrongHash for Blake2 {
+    fn magic(&self) -> u32 {
+        BLAKE2_MAGIC
+    }
+
+    fn size(&self) -> usize {
+        BLAKE2_SIZE
+    }
+
+    fn hash_block(&self, block: &[u8], out: &mut [u8]) {
+        out.copy_from_slice(&blake2(block));
+    }
+
+    fn hash_many(&self, blocks: &[&[u8]], out: &mut [u8]) {
+        let hashes = blake2_many(blocks.iter().copied());
+        for ((_, hash), out) in hashes.zip(out.chunks_exact_mut(BLAKE2_SIZE)) {
+            out.copy_from_slice(&hash);
+        }
+    }
+}
+
+/// BLAKE3, extended with its XOF to [BLAKE3_MAX_SIZE] bytes. The regular hash is a prefix of
+/// the extended one, so signatures with crypto hashes of up to 32 bytes don't change.
+#[derive(Debug)]
+pub(crate) struct Blake3;
+
+impl StrongHash for Blake3 {
+    fn magic(&self) -> u32 {
+        BLAKE3_MAGIC
+    }
+
+    fn size(&self) -> usize {
+        BLAKE3_MAX_SIZE
+    }
+
+    fn hash_block(&self, block: &[u8], out: &mut [u8]) {
+        blake3_xof(block, out);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Sha256;
+
+impl StrongHash for Sha256 {
+    fn magic(&self) -> u32 {
+        SHA256_MAGIC
+    }
+
+    fn size(&self) -> usize {
+        SHA256_SIZE
+    }
+
+    fn hash_block(&self, block: &[u8], out: &mut [u8]) {
+        out.copy_from_slice(&sha256(block));
+    }
+
+    fn hash_many(&self, blocks: &[&[u8]], out: &mut [u8]) {
+        let hashes = sha256_many(blocks.iter().copied());
+        for ((_, hash), out) in hashes.zip(out.chunks_exact_mut(SHA256_SIZE)) {
+            out.copy_from_slice(&hash);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Md5;
+
+impl StrongHash for Md5 {
+    fn magic(&self) -> u32 {
+        MD5_MAGIC
+    }
+
+    fn size(&self) -> usize {
+        MD5_SIZE
+    }
+
+    fn hash_block(&self, block: &[u8], out: &mut [u8]) {
+        out.copy_from_slice(&md5(block));
+    }
+
+    fn hash_many(&self, blocks: &[&[u8]], out: &mut [u8]) {
+        let hashes = md5_many(blocks.iter().copied());
+        for ((_, hash), out) in hashes.zip(out.chunks_exact_mut(MD5_SIZE)) {
+            out.copy_from_slice(&hash);
+        }
+    }
+}
+
+/// `hash` with rsync's `checksum_seed` mixed in: the seed, as 4 little-endian bytes, is hashed
+/// after each block, or before it for MD5 like rsync does since protocol 30. Seed 0 is no seed.
+#[derive(Debug)]
+pub(crate) struct SeededHash<'a> {
+    hash: &'a dyn StrongHash,
+    seed: u32,
+}
+
+thread_local! {
+    /// The seeded blocks being hashed by [SeededHash].
+    static SEEDED_BLOCKS: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+impl<'a> SeededHash<'a> {
+    pub(crate) fn new(hash: &'a dyn StrongHash, seed: u32) -> Self {
+        SeededHash { hash, seed }
+    }
+
+    /// Call `f` with each of `blocks` joined with the seed.
+    fn with_seeded<T>(&self, blocks: &[&[u8]], f: impl FnOnce(&[u8]) -> T) -> T {
+        let seed = self.seed.to_le_bytes();
+        let prepend = self.hash.magic() == MD5_MAGIC;
+        SEEDED_BLOCKS.with(|seeded| {
+            let mut seeded = seeded.borrow_mut();
+            seeded.clear();
+            for block in blocks {
+                if prepend {
+                    seeded.extend_from_slice(&seed);
+                }
+                seeded.extend_from_slice(block);
+                if !prepend {
+                    seeded.extend_from_slice(&seed);
+                }
+            }
+            f(&seeded)
+        })
+    }
+}
+
+impl StrongHash for SeededHash<'_> {
+    fn magic(&self) -> u32 {
+        self.hash.magic()
+    }
+
+    fn size(&self) -> usize {
+        self.hash.size()
+    }
+
+    fn hash_block(&self, block: &[u8], out: &mut [u8]) {
+        if self.seed == 0 {
+            return self.hash.hash_block(block, out);
+        }
+        self.with_seeded(&[block], |seeded| self.hash.hash_block(seeded, out));
+    }
+
+    fn hash_many(&self, blocks: &[&[u8]], out: &mut [u8]) {
+        if self.seed == 0 {
+            return self.hash.hash_many(blocks, out);
+        }
+        self.with_seeded(blocks, |mut rest| {
+            // The last block of a signature may be shorter than the others.
+            let seeded: Vec<&[u8]> = blocks
+                .iter()
+                .map(|block| {
+                    let (seeded, tail) = rest.split_at(block.len() + 4);
+                    rest = tail;
+                    seeded
+                })
+                .collect();
+            self.hash.hash_many(&seeded, out);
+        });
+    }
+}
+
+/// The magics that start signatures or signature containers of this crate.
+const RESERVED_MAGICS: [u32; 8] = [
+    MD4_MAGIC,
+    BLAKE2_MAGIC,
+    BLAKE3_MAGIC,
+    SHA256_MAGIC,
+    MD5_MAGIC,
+    COMPACT_SIGNATURE_MAGIC,
+    SIGNATURE_V2_MAGIC,
+    SIGNATURE_EXT_MAGIC,
+];
+
+/// The hashes added by [register_strong_hash].
+static REGISTERED: RwLock<Vec<&'static dyn StrongHash>> = RwLock::new(Vec::new());
+
+/// Indicates that a [StrongHash] could not be registered.
+#[derive(Debug, Eq, PartialEq)]
+pub enum RegisterError {
+    /// The magic of the hash is already used by another hash or a signature container, or has
+    /// [CRC32C_MAGIC_FLAG] or [SEEDED_MAGIC_FLAG] set.
+    MagicInUse {
+        /// The magic of the hash.
+        magic: u32,
+    },
+    /// The hash is empty or longer than [MAX_STRONG_HASH_SIZE].
+    InvalidSize {
+        /// The length of the hash.
+        size: usize,
+    },
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::MagicInUse { magic } => {
+                write!(f, "magic is already in use (magic=0x{magic:08x})")
+            }
+            RegisterError::InvalidSize { size } => write!(
+                f,
+                "hash size is out of range (size={size}, max_size={MAX_STRONG_HASH_SIZE})"
+            ),
+        }
+    }
+}
+
+impl Error for RegisterError {}
+
+/// Make [Signature::deserialize](crate::Signature::deserialize) recognize the signatures of
+/// `hash` by its magic, for the rest of the process.
+pub fn register_strong_hash(hash: &'static dyn StrongHash) -> Result<(), RegisterError> {
+    if !(1..=MAX_STRONG_HASH_SIZE).contains(&hash.size()) {
+        return Err(RegisterError::InvalidSize { size: hash.size() });
+    }
+    let magic = hash.magic();
+    let mut registered = REGISTERED.write().unwrap_or_else(PoisonError::into_inner);
+    if RESERVED_MAGICS.contains(&magic)
+        || magic & (CRC32C_MAGIC_FLAG | SEEDED_MAGIC_FLAG) != 0
+        || registered.iter().any(|hash| hash.magic() == magic)
+    {
+        return Err(RegisterError::MagicInUse { magic });
+    }
+    registered.push(hash);
+    Ok(())
+}
+
+/// The registered hash whose signatures start with `magic`.
+pub(crate) fn registered(magic: u32) -> Option<&'static dyn StrongHash> {
+    let registered = REGISTERED.read().unwrap_or_else(PoisonError::into_inner);
+    registered.iter().copied().find(|hash| hash.magic() == magic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{register_strong_hash, RegisterError, StrongHash};
+    use crate::consts::MD4_MAGIC;
+    use crate::{apply, diff, Signature};
+
+    /// FNV-1a, which is fast but not collision resistant.
+    #[derive(Debug)]
+    struct Fnv(u32);
+
+    impl StrongHash for Fnv {
+        fn magic(&self) -> u32 {
+            self.0
+        }
+
+        fn size(&self) -> usize {
+            8
+        }
+
+        fn hash_block(&self, block: &[u8], out: &mut [u8]) {
+            let hash = block.iter().fold(0xcbf29ce484222325u64, |hash, &byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+            out.copy_from_slice(&hash.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn test_custom_strong_hash() {
+        static FNV: Fnv = Fnv(0x72731001);
+        let base: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let data = [&base[50_000..], b"new", &base[..50_000]].concat();
+        let signature = Signature::calculate_with_hash(&base, 1000, 8, &FNV);
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).unwrap();
+        assert!(delta.len() < 100, "{}", delta.len());
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let serialized = signature.serialized().to_vec();
+        assert!(Signature::deserialize(serialized.clone()).is_err());
+        register_strong_hash(&FNV).unwrap();
+        assert_eq!(Signature::deserialize(serialized).unwrap(), signature);
+
+        assert_eq!(
+            register_strong_hash(&FNV),
+            Err(RegisterError::MagicInUse { magic: 0x72731001 })
+        );
+        static MD4: Fnv = Fnv(MD4_MAGIC);
+        assert_eq!(
+            register_strong_hash(&MD4),
+            Err(RegisterError::MagicInUse { magic: MD4_MAGIC })
+        );
+    }
+}
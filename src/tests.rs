@@ -9,27 +9,71 @@ use crate::diff_parallel;
 fn test_signature_creation(data: Vec<u8>, block_size: u32, crypto_hash_size: u32) {
     let signature = Signature::calculate(
         &data,
-        SignatureOptions {
-            block_size: block_size.saturating_add(1),
-            crypto_hash_size: crypto_hash_size % 16,
-            hash_algorithm: crate::HashAlgorithm::Blake3,
-        },
+        SignatureOptions::new(
+            block_size.saturating_add(1),
+            crypto_hash_size % 16,
+            crate::HashAlgorithm::Blake3,
+        ),
     );
     let serialized = signature.serialized().to_vec();
     let deserialized = Signature::deserialize(serialized).expect("deserialization error");
     assert_eq!(signature, deserialized);
 }
 
+#[quickcheck]
+fn test_compact_signature(data: Vec<u8>, block_size: u8, crypto_hash_size: u32, delta_crcs: bool) {
+    let signature = Signature::calculate(
+        &data,
+        SignatureOptions::new(
+            block_size as u32 + 1,
+            crypto_hash_size % 17,
+            crate::HashAlgorithm::Md4,
+        ),
+    );
+    let options = crate::CompactOptions {
+        #[cfg(feature = "zstd")]
+        zstd_level: None,
+        delta_crcs,
+    };
+    let compact = signature.serialize_compact(options);
+    assert_eq!(Signature::deserialize(compact).expect("deserialization error"), signature);
+    #[cfg(feature = "zstd")]
+    {
+        let options = crate::CompactOptions {
+            zstd_level: Some(3),
+            delta_crcs,
+        };
+        let compact = signature.serialize_compact(options);
+        assert_eq!(Signature::deserialize(compact).expect("deserialization error"), signature);
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_compact_signature_size() {
+    // zero-filled regions give runs of identical CRCs and hashes
+    let mut data = vec![0; 1 << 20];
+    data[..1000].fill(1);
+    let signature = Signature::calculate(
+        &data,
+        SignatureOptions::new(512, 8, crate::HashAlgorithm::Blake3),
+    );
+    let compact = signature.serialize_compact(crate::CompactOptions {
+        zstd_level: Some(3),
+        delta_crcs: true,
+    });
+    assert!(compact.len() * 20 < signature.serialized().len());
+    let mut corrupt = compact.clone();
+    corrupt[5..13].copy_from_slice(&u64::MAX.to_be_bytes());
+    assert!(Signature::deserialize(corrupt).is_err());
+}
+
 #[test]
 fn test_trivial() {
     let data = vec![0; 100000];
     let signature = Signature::calculate(
         &data,
-        SignatureOptions {
-            block_size: 64,
-            crypto_hash_size: 5,
-            hash_algorithm: crate::HashAlgorithm::Blake3,
-        },
+        SignatureOptions::new(64, 5, crate::HashAlgorithm::Blake3),
     );
     let indexed = signature.index();
     let mut patch = vec![];
@@ -49,11 +93,7 @@ fn test_delta_size() {
 
     let signature = Signature::calculate(
         &data1,
-        SignatureOptions {
-            block_size: 4096,
-            crypto_hash_size: 8,
-            hash_algorithm: crate::HashAlgorithm::Blake3,
-        },
+        SignatureOptions::new(4096, 8, crate::HashAlgorithm::Blake3),
     );
     let mut patch = vec![];
     diff(&signature.index(), &data2, &mut patch).expect("diff error");
@@ -72,11 +112,7 @@ fn test_random() {
     rand::thread_rng().fill(&mut data[..]);
     let signature = Signature::calculate(
         &base,
-        SignatureOptions {
-            block_size: 4,
-            crypto_hash_size: 8,
-            hash_algorithm: crate::HashAlgorithm::Md4,
-        },
+        SignatureOptions::new(4, 8, crate::HashAlgorithm::Md4),
     );
     let indexed = signature.index();
     let mut patch = vec![];
@@ -108,34 +144,36 @@ fn test_random() {
 fn test_signature_interoperability() {
     // interoperability: we generate identical signatures to librsync
     use rand::Rng;
-    for &block_len in &[10, 1024] {
-        for &strong_len in &[1, 8, 16] {
-            for &len in &[0, 1, 2, 10, 128, 500, 1111, 2000, 2048] {
-                let mut data = vec![0; len];
-                rand::thread_rng().fill(&mut data[..]);
-                let mut librsync_out = vec![];
-                librsync::whole::signature_with_options(
-                    &mut &data[..],
-                    &mut librsync_out,
-                    block_len,
-                    strong_len,
-                    librsync::SignatureType::MD4,
-                )
-                .unwrap();
-                let signature = Signature::calculate(
-                    &data,
-                    SignatureOptions {
-                        block_size: block_len as u32,
-                        crypto_hash_size: strong_len as u32,
-                        hash_algorithm: crate::HashAlgorithm::Md4,
-                    },
-                );
-                let serialized = signature.into_serialized();
-                assert_eq!(
-                    librsync_out, serialized,
-                    "block_len={}, strong_len={}, len={}",
-                    block_len, strong_len, len
-                );
+    for hash_algorithm in [crate::HashAlgorithm::Md4, crate::HashAlgorithm::Blake2] {
+        for &block_len in &[10, 1024] {
+            for &strong_len in &[1, 8, 16] {
+                for &len in &[0, 1, 2, 10, 128, 500, 1111, 2000, 2048] {
+                    let mut data = vec![0; len];
+                    rand::thread_rng().fill(&mut data[..]);
+                    let librsync_type = match hash_algorithm {
+                        crate::HashAlgorithm::Md4 => librsync::SignatureType::MD4,
+                        _ => librsync::SignatureType::Blake2,
+                    };
+                    let mut librsync_out = vec![];
+                    librsync::whole::signature_with_options(
+                        &mut &data[..],
+                        &mut librsync_out,
+                        block_len,
+                        strong_len,
+                        librsync_type,
+                    )
+                    .unwrap();
+                    let signature = Signature::calculate(
+                        &data,
+                        SignatureOptions::new(block_len as u32, strong_len as u32, hash_algorithm),
+                    );
+                    let serialized = signature.into_serialized();
+                    assert_eq!(
+                        librsync_out, serialized,
+                        "hash_algorithm={}, block_len={}, strong_len={}, len={}",
+                        hash_algorithm, block_len, strong_len, len
+                    );
+                }
             }
         }
     }
@@ -226,11 +264,7 @@ fn test_parallel_trivial() {
     let data = vec![0; 100000];
     let signature = Signature::calculate(
         &data,
-        SignatureOptions {
-            block_size: 64,
-            crypto_hash_size: 5,
-            hash_algorithm: crate::HashAlgorithm::Blake3,
-        },
+        SignatureOptions::new(64, 5, crate::HashAlgorithm::Blake3),
     );
     let indexed = signature.index();
     let mut patch = vec![];
@@ -241,17 +275,116 @@ fn test_parallel_trivial() {
     assert_eq!(data, out);
 }
 
+#[cfg(feature = "parallel")]
+#[test]
+fn test_calculate_parallel() {
+    use rand::Rng;
+    let mut data = vec![0; 3 << 20];
+    rand::thread_rng().fill(&mut data[..]);
+    let algorithms = [crate::HashAlgorithm::Md4, crate::HashAlgorithm::Blake3];
+    for (hash_algorithm, checksum_seed) in algorithms.into_iter().zip([0, 7]) {
+        for block_size in [1000, 4096] {
+            for len in [0, 999, 1 << 20, data.len()] {
+                let options = SignatureOptions::new(block_size, 8, hash_algorithm)
+                    .with_checksum_seed(checksum_seed);
+                assert_eq!(
+                    Signature::calculate_parallel(&data[..len], options),
+                    Signature::calculate(&data[..len], options)
+                );
+            }
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_parallel_shifted_data() {
+    use rand::Rng;
+    let mut base = vec![0; 3 << 20];
+    rand::thread_rng().fill(&mut base[..]);
+    // Insertions shift everything after them off the block grid, including across the boundaries
+    // of the chunks searched in parallel.
+    let mut data = base.clone();
+    for position in [(3 << 20) - 10, (2 << 20) + 1, (1 << 20) - 2, 12345] {
+        data.insert(position, 42);
+    }
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(4096, 8, crate::HashAlgorithm::Blake3),
+    );
+    let indexed = signature.index();
+    let mut sequential = vec![];
+    diff(&indexed, &data, &mut sequential).expect("sequential diff error");
+    let mut parallel = vec![];
+    diff_parallel(&indexed, &data, &mut parallel).expect("parallel diff error");
+    assert!(parallel.len() < 5 * 4096, "{}", parallel.len());
+    assert!(parallel.len() <= sequential.len() + 64);
+    let mut out = vec![];
+    apply(&base, &parallel, &mut out).expect("apply error");
+    assert_eq!(out, data);
+}
+
+#[cfg(feature = "parallel")]
+#[quickcheck]
+fn test_parallel_parity(base: Vec<u8>, inserted: Vec<(u16, u8)>, block_size: u8, seed: u32) {
+    // Two-letter data has many duplicate blocks and CRC collisions.
+    let base: Vec<u8> = base.iter().map(|byte| byte % 2).collect();
+    let mut data = base.clone();
+    for (position, byte) in inserted {
+        data.insert(position as usize % (data.len() + 1), byte % 3);
+    }
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(block_size as u32 % 8 + 1, 8, crate::HashAlgorithm::Blake3)
+            .with_checksum_seed(seed),
+    );
+    let indexed = signature.index();
+    let mut sequential = vec![];
+    diff(&indexed, &data, &mut sequential).expect("sequential diff error");
+    let mut parallel = vec![];
+    diff_parallel(&indexed, &data, &mut parallel).expect("parallel diff error");
+    assert_eq!(parallel, sequential);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_parallel_parity_across_chunks() {
+    use rand::Rng;
+    // Few distinct blocks, so duplicates and collisions span the chunks searched in parallel.
+    let mut rng = rand::thread_rng();
+    let mut blocks = vec![[0; 64]; 4];
+    for block in &mut blocks {
+        rng.fill(&mut block[..]);
+    }
+    let base: Vec<u8> = (0..(5 << 20) / 64)
+        .flat_map(|_| blocks[rng.gen_range(0..blocks.len())])
+        .collect();
+    let mut data = base.clone();
+    for _ in 0..200 {
+        let position = rng.gen_range(0..data.len());
+        data.insert(position, rng.gen());
+    }
+    for hash_algorithm in [crate::HashAlgorithm::Md4, crate::HashAlgorithm::Blake3] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(64, 8, hash_algorithm),
+        );
+        let indexed = signature.index();
+        let mut sequential = vec![];
+        diff(&indexed, &data, &mut sequential).expect("sequential diff error");
+        let mut parallel = vec![];
+        diff_parallel(&indexed, &data, &mut parallel).expect("parallel diff error");
+        assert!(parallel == sequential, "{} != {}", parallel.len(), sequential.len());
+    }
+}
+
 #[cfg(feature = "parallel")]
 #[test]
 fn test_parallel_vs_sequential() {
     let data = vec![0; 100000];
     let signature = Signature::calculate(
         &data,
-        SignatureOptions {
-            block_size: 4096,
-            crypto_hash_size: 8,
-            hash_algorithm: crate::HashAlgorithm::Blake3,
-        },
+        SignatureOptions::new(4096, 8, crate::HashAlgorithm::Blake3),
     );
     let indexed = signature.index();
     
@@ -276,3 +409,1285 @@ fn test_parallel_vs_sequential() {
     assert_eq!(data, sequential_out);
     assert_eq!(data, parallel_out);
 }
+
+#[test]
+fn test_signature_from_reader() {
+    use rand::Rng;
+    use std::io::Read;
+
+    /// Returns at most 1000 bytes per read, like a pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let mut data = vec![0; 3 << 20];
+    rand::thread_rng().fill(&mut data[..]);
+    for hash_algorithm in [crate::HashAlgorithm::Md4, crate::HashAlgorithm::Blake3] {
+        for (len, block_size) in [(0, 64), (100, 64), (1 << 20, 64), ((3 << 20) - 17, 1000)] {
+            let options = SignatureOptions::new(block_size, 8, hash_algorithm);
+            let data = &data[..len];
+            let signature = Signature::calculate_from_reader(Trickle(data), options).unwrap();
+            assert_eq!(signature, Signature::calculate(data, options), "{len}");
+        }
+    }
+}
+
+#[test]
+fn test_diff_from_reader() {
+    use crate::diff_from_reader;
+    use rand::Rng;
+
+    let mut base = vec![0; 200_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[1000..1500].fill(7);
+    data.splice(50_000..50_000, vec![1; 100_000]);
+    data.drain(190_000..210_000);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(256, 8, crate::HashAlgorithm::Blake3),
+    );
+    let indexed = signature.index();
+    for data in [&data[..], &base[..], &[]] {
+        let mut delta = vec![];
+        diff_from_reader(&indexed, data, &mut delta).unwrap();
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data);
+        let mut whole = vec![];
+        diff(&indexed, data, &mut whole).unwrap();
+        assert!(delta.len() <= whole.len() + data.len() / 256 * 2 + 64);
+    }
+}
+
+#[test]
+fn test_apply_into_writer() {
+    use std::io::Write;
+
+    /// Hashes what is written, keeping track of the largest single write.
+    #[derive(Default)]
+    struct Hashing {
+        hasher: ::blake3::Hasher,
+        largest_write: usize,
+    }
+
+    impl Write for Hashing {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.hasher.update(buf);
+            self.largest_write = self.largest_write.max(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let base: Vec<u8> = (0..100_000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let data = [&base[..40_000], b"changed", &base[40_000..]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(1024, 8, crate::HashAlgorithm::Blake3),
+    );
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).unwrap();
+    let mut out = Hashing::default();
+    apply(&base, &delta, &mut out).unwrap();
+    assert_eq!(out.hasher.finalize(), ::blake3::hash(&data));
+    assert!(out.largest_write < data.len());
+}
+
+#[test]
+fn test_apply_limited() {
+    use crate::consts::{DELTA_MAGIC, DELTA_V2_MAGIC, RS_OP_END};
+    use crate::ops::{encode_copy, encode_repeat};
+    use crate::{apply_limited, ApplyError};
+
+    // A tiny delta that copies the whole base a million times.
+    let base = b"a small base".to_vec();
+    let mut bomb = DELTA_MAGIC.to_be_bytes().to_vec();
+    for _ in 0..1_000_000 {
+        encode_copy(0, base.len() as u64, &mut bomb).unwrap();
+    }
+    bomb.push(RS_OP_END);
+    let mut out = vec![];
+    assert!(matches!(
+        apply_limited(&base, &bomb, &mut out, 1000),
+        Err(ApplyError::OutputLimit { what: "copy", .. })
+    ));
+    assert!(out.len() <= 1000);
+
+    // One repeat op for an exabyte of zeros.
+    let mut bomb = DELTA_V2_MAGIC.to_be_bytes().to_vec();
+    encode_repeat(&[0], 1 << 60, &mut bomb).unwrap();
+    bomb.push(RS_OP_END);
+    let mut out = vec![];
+    assert!(matches!(
+        apply_limited(&base, &bomb, &mut out, 1 << 20),
+        Err(ApplyError::OutputLimit { what: "repeat", .. })
+    ));
+    assert!(out.is_empty());
+
+    // A limit of exactly the output length is enough.
+    let data = [&base[..], b"literal", &base[..]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(4, 8, crate::HashAlgorithm::Md4),
+    );
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).unwrap();
+    let mut out = vec![];
+    apply_limited(&base, &delta, &mut out, data.len()).unwrap();
+    assert_eq!(out, data);
+    assert!(matches!(
+        apply_limited(&base, &delta, &mut vec![], data.len() - 1),
+        Err(ApplyError::OutputLimit { .. })
+    ));
+}
+
+#[test]
+fn test_apply_with_options() {
+    use crate::consts::{DELTA_MAGIC, RS_OP_END, RS_OP_LITERAL_N1};
+    use crate::ops::encode_copy;
+    use crate::{apply_with_options, ApplyError, ApplyOptions};
+    use rand::Rng;
+
+    let mut base = vec![0; 10_000];
+    rand::thread_rng().fill(&mut base[..]);
+    let data = [&base[..4_000], &[7; 1_000], &base[6_000..]].concat();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(100, 8, crate::HashAlgorithm::Blake3),
+    );
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).unwrap();
+    // A copy, a literal and a copy.
+    let options = ApplyOptions {
+        max_output: data.len(),
+        max_ops: 3,
+        max_literal_len: 1_000,
+        reject_suspicious: true,
+    };
+    let mut out = vec![];
+    apply_with_options(&base, &delta, &mut out, &options).unwrap();
+    assert_eq!(out, data);
+    let fewer_ops = ApplyOptions {
+        max_ops: 2,
+        ..options
+    };
+    assert!(matches!(
+        apply_with_options(&base, &delta, &mut vec![], &fewer_ops),
+        Err(ApplyError::TooManyOps { limit: 2 })
+    ));
+    let shorter_literals = ApplyOptions {
+        max_literal_len: 999,
+        ..options
+    };
+    assert!(matches!(
+        apply_with_options(&base, &delta, &mut vec![], &shorter_literals),
+        Err(ApplyError::LiteralTooLong {
+            len: 1_000,
+            limit: 999
+        })
+    ));
+
+    // The same block copied twice, and an empty literal.
+    let mut twice = DELTA_MAGIC.to_be_bytes().to_vec();
+    encode_copy(0, 200, &mut twice).unwrap();
+    encode_copy(100, 100, &mut twice).unwrap();
+    twice.push(RS_OP_END);
+    let empty = [&DELTA_MAGIC.to_be_bytes()[..], &[RS_OP_LITERAL_N1, 0, RS_OP_END]].concat();
+    let suspicious = ApplyOptions {
+        reject_suspicious: true,
+        ..ApplyOptions::default()
+    };
+    apply_with_options(&base, &twice, &mut vec![], &ApplyOptions::default()).unwrap();
+    assert!(matches!(
+        apply_with_options(&base, &twice, &mut vec![], &suspicious),
+        Err(ApplyError::CopyOverlap {
+            offset: 100,
+            len: 100
+        })
+    ));
+    apply_with_options(&base, &empty, &mut vec![], &ApplyOptions::default()).unwrap();
+    assert!(matches!(
+        apply_with_options(&base, &empty, &mut vec![], &suspicious),
+        Err(ApplyError::LiteralZero)
+    ));
+}
+
+#[test]
+fn test_diff_session() {
+    use crate::DiffSession;
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 100_000];
+    rng.fill(&mut base[..]);
+    let mut data = base.clone();
+    data.splice(30_000..30_000, vec![1; 5_000]);
+    data.drain(60_000..61_000);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(128, 8, crate::HashAlgorithm::Blake3),
+    );
+    let indexed = signature.index();
+    let mut delta = vec![];
+    let mut session = DiffSession::new(&indexed, &mut delta).unwrap();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        // Chunks both smaller and larger than a block.
+        let (chunk, tail) = rest.split_at(rng.gen_range(1..1000).min(rest.len()));
+        session.push(chunk, &mut delta).unwrap();
+        rest = tail;
+    }
+    session.finish(&mut delta).unwrap();
+    let mut out = vec![];
+    apply(&base, &delta, &mut out).unwrap();
+    assert_eq!(out, data);
+    assert!(delta.len() < 5_000 + 1_000, "{}", delta.len());
+}
+
+#[test]
+fn test_diff_with_options() {
+    use crate::{diff_with_options, DiffOptions};
+    use rand::Rng;
+
+    let mut base = vec![0; 100_000];
+    rand::thread_rng().fill(&mut base[..]);
+    // An unaligned insertion, and a region without matches.
+    let mut data = base.clone();
+    data.splice(10_000..10_000, b"abc".iter().copied());
+    rand::thread_rng().fill(&mut data[50_000..70_000]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(256, 8, crate::HashAlgorithm::Blake3),
+    );
+    let indexed = signature.index();
+    let delta_with = |options| {
+        let mut delta = vec![];
+        diff_with_options(&indexed, &data, &options, &mut delta).unwrap();
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, data, "{options:?}");
+        delta
+    };
+
+    let mut expected = vec![];
+    diff(&indexed, &data, &mut expected).unwrap();
+    let default = delta_with(DiffOptions::default());
+    assert_eq!(default, expected);
+    // Without the rolling search, the blocks between the insertion and the identical tail are
+    // out of alignment.
+    let blocks = delta_with(DiffOptions {
+        rolling_search: false,
+        ..DiffOptions::default()
+    });
+    assert!(blocks.len() > 50_000, "{}", blocks.len());
+    // Searching byte by byte for 1000 bytes realigns after the insertion.
+    let bounded = delta_with(DiffOptions {
+        max_search_distance: Some(1000),
+        ..DiffOptions::default()
+    });
+    assert!(bounded.len() < blocks.len(), "{}", bounded.len());
+    assert!(bounded.len() >= default.len());
+    delta_with(DiffOptions {
+        max_collisions: 0,
+        lookahead: false,
+        ..DiffOptions::default()
+    });
+}
+
+#[test]
+fn test_pipeline() {
+    use crate::Pipeline;
+    use rand::Rng;
+    let mut base = vec![0; 100000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[1234] ^= 1;
+    data.splice(50000..50000, [1, 2, 3]);
+    data.truncate(90000);
+    for hash_algorithm in [crate::HashAlgorithm::Md4, crate::HashAlgorithm::Blake3] {
+        let options = SignatureOptions::new(64, 8, hash_algorithm);
+        let signature = Signature::calculate(&base, options);
+        let indexed = signature.index();
+        let mut expected = vec![];
+        diff(&indexed, &data, &mut expected).expect("diff error");
+        for chunk_size in [1, 100, 4096, 1 << 20] {
+            let pipeline = Pipeline::new().chunk_size(chunk_size).queue_depth(2);
+            assert_eq!(
+                pipeline.signature(&base[..], options).expect("signature error"),
+                signature,
+            );
+            let mut patch = vec![];
+            pipeline
+                .diff(&indexed, &data[..], &mut patch)
+                .expect("pipeline diff error");
+            if chunk_size > data.len() {
+                assert_eq!(patch, expected);
+            }
+            let mut out = vec![];
+            apply(&base, &patch, &mut out).expect("apply error");
+            assert_eq!(data, out);
+        }
+    }
+}
+
+#[test]
+fn test_pipeline_files() {
+    use crate::Pipeline;
+    let base: Vec<u8> = (0..50000u32).map(|i| (i * 7 % 256) as u8).collect();
+    let mut data = base.clone();
+    data.splice(100..200, b"changed".iter().copied());
+    let options = SignatureOptions::new(128, 8, crate::HashAlgorithm::Blake3);
+    let dir = std::env::temp_dir();
+    let pid = std::process::id();
+    let base_path = dir.join(format!("superfast_rsync_base_{pid}"));
+    let data_path = dir.join(format!("superfast_rsync_data_{pid}"));
+    let patch_path = dir.join(format!("superfast_rsync_patch_{pid}"));
+    std::fs::write(&base_path, &base).unwrap();
+    std::fs::write(&data_path, &data).unwrap();
+
+    let pipeline = Pipeline::new().chunk_size(4096);
+    let signature = pipeline
+        .signature_file(&base_path, options)
+        .expect("signature error");
+    assert_eq!(signature, Signature::calculate(&base, options));
+    pipeline
+        .diff_file(&signature.index(), &data_path, &patch_path)
+        .expect("pipeline diff error");
+    let patch = std::fs::read(&patch_path).unwrap();
+    for path in [base_path, data_path, patch_path] {
+        std::fs::remove_file(path).unwrap();
+    }
+    let mut out = vec![];
+    apply(&base, &patch, &mut out).expect("apply error");
+    assert_eq!(data, out);
+}
+
+#[test]
+fn test_apply_file() {
+    let base: Vec<u8> = (0..20480u32).map(|i| (i * 13 % 256) as u8).collect();
+    let mut changed = base.clone();
+    changed[5000] ^= 0xff;
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(256, 8, crate::HashAlgorithm::Blake3),
+    );
+    let dir = std::env::temp_dir();
+    let pid = std::process::id();
+    let base_path = dir.join(format!("superfast_rsync_apply_base_{pid}"));
+    let out_path = dir.join(format!("superfast_rsync_apply_out_{pid}"));
+    std::fs::write(&base_path, &base).unwrap();
+    // `base` itself hits the whole-file copy fast path, `changed` the regular one
+    for data in [&base, &changed] {
+        let mut patch = vec![];
+        diff(&signature.index(), data, &mut patch).expect("diff error");
+        crate::apply_file(&base_path, &patch, &out_path).expect("apply error");
+        assert_eq!(&std::fs::read(&out_path).unwrap(), data);
+    }
+    // in place, where the fast path must not copy the file onto itself
+    for data in [&base, &changed] {
+        std::fs::write(&base_path, &base).unwrap();
+        let mut patch = vec![];
+        diff(&signature.index(), data, &mut patch).expect("diff error");
+        crate::apply_file(&base_path, &patch, &base_path).expect("apply error");
+        assert_eq!(&std::fs::read(&base_path).unwrap(), data);
+    }
+    std::fs::remove_file(base_path).unwrap();
+    std::fs::remove_file(out_path).unwrap();
+}
+
+#[test]
+fn test_changed_ranges() {
+    let base: Vec<u8> = (0..64 * 100u32).map(|i| (i * 31 % 256) as u8).collect();
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(64, 8, crate::HashAlgorithm::Blake3),
+    );
+    let indexed = signature.index();
+    assert_eq!(crate::changed_ranges(&indexed, &base).unwrap(), vec![]);
+
+    let mut data = base.clone();
+    data[64 * 10 + 5] ^= 0xff;
+    data[64 * 50] ^= 0xff;
+    data.extend_from_slice(b"appended");
+    assert_eq!(
+        crate::changed_ranges(&indexed, &data).unwrap(),
+        vec![640..704, 3200..3264, 6400..6408],
+    );
+}
+
+#[test]
+fn test_apply_with_scratch() {
+    use rand::Rng;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[2000..].to_vec();
+    data.extend_from_slice(&[42; 300]);
+    data.extend_from_slice(&base[..500]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(64, 8, crate::HashAlgorithm::Blake3),
+    );
+    let mut patch = vec![];
+    diff(&signature.index(), &data, &mut patch).expect("diff error");
+
+    for scratch_len in [1, 7, 64, 4096] {
+        let mut scratch = vec![0; scratch_len];
+        let mut delta = &patch[..];
+        let mut out = vec![];
+        let len = crate::apply_with_scratch(
+            base.len() as u64,
+            |offset, buf| {
+                buf.copy_from_slice(&base[offset as usize..offset as usize + buf.len()]);
+                Ok(())
+            },
+            |buf| std::io::Read::read(&mut delta, buf),
+            |chunk| {
+                assert!(chunk.len() <= scratch_len);
+                out.extend_from_slice(chunk);
+                Ok(())
+            },
+            &mut scratch,
+        )
+        .expect("apply error");
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(out, data);
+    }
+
+    // errors match the in-memory apply
+    for bad in [&patch[..patch.len() - 1], &[114, 115, 2, 54, 0, 1][..], &[1, 2, 3, 4][..]] {
+        let mut delta = bad;
+        let err = crate::apply_with_scratch(
+            base.len() as u64,
+            |offset, buf| {
+                buf.copy_from_slice(&base[offset as usize..offset as usize + buf.len()]);
+                Ok(())
+            },
+            |buf| std::io::Read::read(&mut delta, buf),
+            |_| Ok(()),
+            &mut [0; 16],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            apply(&base, bad, &mut Vec::new()).unwrap_err().to_string()
+        );
+    }
+
+    // read errors are told apart from write errors
+    let failing = |_: &mut [u8]| Err(std::io::Error::other("unreadable"));
+    let mut delta = &patch[..];
+    let err = crate::apply_with_scratch(
+        base.len() as u64,
+        |_, buf| failing(buf).map(|_| ()),
+        |buf| std::io::Read::read(&mut delta, buf),
+        |_| Ok(()),
+        &mut [0; 16],
+    )
+    .unwrap_err();
+    assert!(matches!(err, crate::ApplyError::ReadIo(_)), "{err}");
+    let err = crate::apply_with_scratch(base.len() as u64, |_, _| Ok(()), failing, |_| Ok(()), &mut [0; 16])
+        .unwrap_err();
+    assert!(matches!(err, crate::ApplyError::ReadIo(_)), "{err}");
+    let mut delta = &patch[..];
+    let err = crate::apply_with_scratch(
+        base.len() as u64,
+        |_, _| Ok(()),
+        |buf| std::io::Read::read(&mut delta, buf),
+        |_| Err(std::io::Error::other("full")),
+        &mut [0; 16],
+    )
+    .unwrap_err();
+    assert!(matches!(err, crate::ApplyError::Io(_)), "{err}");
+}
+
+#[test]
+fn test_signature_ref() {
+    use crate::SignatureRef;
+    let base: Vec<u8> = (0..30_000u32).map(|i| (i * 7 + i / 1000) as u8).collect();
+    let mut data = base.clone();
+    data.splice(12_345..12_400, b"changed".iter().copied());
+    for checksum_seed in [0, 17] {
+        let options = SignatureOptions::new(256, 8, crate::HashAlgorithm::Blake3)
+            .with_checksum_seed(checksum_seed);
+        let plain = Signature::calculate(&base, options);
+        let indexed = Signature::calculate_with_index(&base, options);
+        let mut expected = vec![];
+        diff(&plain.index(), &data, &mut expected).expect("diff error");
+        for signature in [&plain, &indexed] {
+            let bytes = signature.serialized().to_vec();
+            let borrowed = SignatureRef::deserialize(&bytes).expect("deserialization error");
+            assert_eq!(borrowed, signature.as_signature_ref());
+            assert_eq!(borrowed.serialized(), &bytes[..]);
+            assert_eq!(borrowed.checksum_seed(), checksum_seed);
+            assert_eq!(&borrowed.to_signature(), signature);
+            // The index only borrows the bytes, not the temporary `SignatureRef`.
+            let index = SignatureRef::deserialize(&bytes).expect("deserialization error").index();
+            let mut delta = vec![];
+            diff(&index, &data, &mut delta).expect("diff error");
+            assert_eq!(delta, expected);
+        }
+        let compact = plain.serialize_compact(crate::CompactOptions::default());
+        assert!(SignatureRef::deserialize(&compact).is_err());
+        let truncated = &plain.serialized()[..plain.serialized().len() - 1];
+        assert!(SignatureRef::deserialize(truncated).is_err());
+    }
+}
+
+#[test]
+fn test_indexed_signature_owned() {
+    use crate::IndexedSignatureOwned;
+    use std::collections::HashMap;
+    fn assert_shareable<T: Send + Sync + 'static>(_: &T) {}
+
+    let base: Vec<u8> = (0..30_000u32).map(|i| (i * 7 + i / 1000) as u8).collect();
+    let mut data = base.clone();
+    data.splice(5_000..5_000, b"inserted".iter().copied());
+    let options = SignatureOptions::new(128, 8, crate::HashAlgorithm::Md4);
+    let signature = Signature::calculate(&base, options);
+    let mut expected = vec![];
+    diff(&signature.index(), &data, &mut expected).expect("diff error");
+
+    let mut cache = HashMap::new();
+    cache.insert("base", IndexedSignatureOwned::from(signature.clone()));
+    let owned = cache["base"].clone();
+    assert_shareable(&owned);
+    assert_eq!(owned.signature().clone().without_index(), signature);
+    let delta = std::thread::spawn(move || {
+        let mut delta = vec![];
+        diff(&owned.index(), &data, &mut delta).expect("diff error");
+        delta
+    })
+    .join()
+    .unwrap();
+    assert_eq!(delta, expected);
+}
+
+#[test]
+fn test_indexed_signature_serialize() {
+    use crate::IndexedSignature;
+    let base: Vec<u8> = (0..30_000u32).map(|i| (i * 13 + i / 777) as u8).collect();
+    let mut data = base.clone();
+    data.splice(20_000..20_100, b"changed".iter().copied());
+    for checksum_seed in [0, 99] {
+        let options = SignatureOptions::new(128, 8, crate::HashAlgorithm::Blake3)
+            .with_checksum_seed(checksum_seed);
+        let signature = Signature::calculate(&base, options);
+        let mut expected = vec![];
+        diff(&signature.index(), &data, &mut expected).expect("diff error");
+
+        let serialized = signature.index().serialize();
+        assert_eq!(serialized, Signature::calculate_with_index(&base, options).serialized());
+        assert_eq!(signature.index_perfect().serialize(), serialized);
+        let index = IndexedSignature::deserialize(&serialized).expect("deserialization error");
+        assert!(matches!(index.blocks, crate::signature::BlockIndex::Table(_)));
+        assert_eq!(index.serialize(), serialized);
+        let mut delta = vec![];
+        diff(&index, &data, &mut delta).expect("diff error");
+        assert_eq!(delta, expected);
+
+        // plain signatures are indexed on load
+        let index = IndexedSignature::deserialize(signature.serialized()).expect("deserialization error");
+        assert_eq!(index, signature.index());
+        assert!(IndexedSignature::deserialize(&serialized[..serialized.len() - 1]).is_err());
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    let options: SignatureOptions = serde_json::from_str(
+        r#"{"block_size": 1024, "crypto_hash_size": 16, "hash_algorithm": "Blake3"}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        options,
+        SignatureOptions::new(1024, 16, crate::HashAlgorithm::Blake3)
+    );
+
+    let signature = Signature::calculate(&[1; 5000], options.with_checksum_seed(5));
+    let json = serde_json::to_string(&signature).unwrap();
+    assert_eq!(serde_json::from_str::<Signature>(&json).unwrap(), signature);
+    #[cfg(feature = "manifest")]
+    {
+        let mut cbor = vec![];
+        ciborium::into_writer(&signature, &mut cbor).unwrap();
+        // a byte string, not an array of numbers
+        assert!(cbor.len() < signature.serialized().len() + 8);
+        assert_eq!(ciborium::from_reader::<Signature, _>(&cbor[..]).unwrap(), signature);
+    }
+    assert!(serde_json::from_str::<Signature>("[1, 2, 3]").is_err());
+}
+
+#[test]
+fn test_signature_accessors() {
+    use crate::Crc;
+    let data: Vec<u8> = (0..2500u32).map(|i| (i * 7) as u8).collect();
+    let options = SignatureOptions::new(1000, 6, crate::HashAlgorithm::Blake3);
+    let signature = Signature::calculate_with_index(&data, options);
+    let signature = Signature::deserialize(signature.into_serialized()).expect("deserialization error");
+    assert_eq!(signature.block_size(), 1000);
+    assert_eq!(signature.crypto_hash_size(), 6);
+    assert_eq!(signature.hash_algorithm(), Some(crate::HashAlgorithm::Blake3));
+    assert_eq!(signature.block_count(), 3);
+    let blocks: Vec<_> = signature.blocks().collect();
+    for (i, (index, crc, hash)) in blocks.into_iter().enumerate() {
+        let block = &data[i * 1000..data.len().min((i + 1) * 1000)];
+        assert_eq!(index, i as u32);
+        assert_eq!(crc, Crc::new().update(block));
+        assert_eq!(hash, &::blake3::hash(block).as_bytes()[..6]);
+    }
+}
+
+#[test]
+fn test_signature_extend() {
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 17 % 251) as u8).collect();
+    for checksum_seed in [0, 7] {
+        let options = SignatureOptions::new(100, 8, crate::HashAlgorithm::Md4)
+            .with_checksum_seed(checksum_seed);
+        // Grow from nothing, ending both within and at the end of a block.
+        let mut signature = Signature::calculate(&[], options);
+        let mut indexed = signature.clone().with_index();
+        for len in [0, 1, 99, 100, 250, 250, 1_000, 5_555, 10_000] {
+            signature.extend(&data[signature.tail_offset() as usize..len]);
+            indexed.extend(&data[indexed.tail_offset() as usize..len]);
+            let expected = Signature::calculate(&data[..len], options);
+            assert_eq!(signature, expected, "{len}");
+            assert_eq!(indexed, expected.with_index(), "{len}");
+        }
+    }
+
+    let options = SignatureOptions::new(64, 16, crate::HashAlgorithm::Blake3);
+    let mut signature = Signature::calculate(&data[..3_000], options)
+        .with_extensions(crate::SignatureExtensions::of(&data[..3_000]));
+    assert_eq!(signature.tail_offset(), 2_944);
+    signature.extend(&data[2_944..]);
+    assert_eq!(signature, Signature::calculate(&data, options));
+}
+
+#[test]
+fn test_signature_extensions() {
+    use crate::{SignatureExtensions, SignatureRef};
+    let base: Vec<u8> = (0..5000u32).map(|i| (i * 13 % 251) as u8).collect();
+    let mut data = base.clone();
+    data[2500] ^= 1;
+    let options = SignatureOptions::new(64, 8, crate::HashAlgorithm::Blake3);
+    let extensions = SignatureExtensions::of(&base);
+    assert_eq!(extensions.matches(&base), Some(true));
+    assert_eq!(extensions.matches(&data), Some(false));
+    assert_eq!(extensions.matches(&base[1..]), Some(false));
+    assert_eq!(SignatureExtensions::default().matches(&base), None);
+
+    let plain = Signature::calculate(&base, options);
+    let mut expected = vec![];
+    diff(&plain.index(), &data, &mut expected).expect("diff error");
+    for signature in [plain.clone(), plain.clone().with_index()] {
+        let extended = signature.clone().with_extensions(extensions);
+        assert_eq!(extended.extensions(), extensions);
+        let extended = Signature::deserialize(extended.into_serialized()).expect("deserialization error");
+        assert_eq!(extended.extensions(), extensions);
+        // The index survives, and the extensions survive adding or dropping it.
+        assert_eq!(extended.clone().without_index().with_index(), extended.clone().with_index());
+        assert_eq!(extended.clone().without_index().extensions(), extensions);
+        assert_eq!(extended.clone().with_extensions(SignatureExtensions::default()), signature);
+        let borrowed = SignatureRef::deserialize(extended.serialized()).expect("deserialization error");
+        assert_eq!(borrowed.extensions(), extensions);
+        assert_eq!(borrowed.to_signature(), extended);
+        let mut delta = vec![];
+        diff(&extended.index(), &data, &mut delta).expect("diff error");
+        assert_eq!(delta, expected);
+    }
+
+    // Unknown records are skipped.
+    let extended = plain.clone().with_extensions(extensions).into_serialized();
+    let area_len = u32::from_be_bytes(extended[4..8].try_into().unwrap());
+    let mut unknown = extended[..8].to_vec();
+    unknown[4..8].copy_from_slice(&(area_len + 7).to_be_bytes());
+    unknown.extend_from_slice(&[0xff, 0, 0, 0, 2, 1, 2]);
+    unknown.extend_from_slice(&extended[8..]);
+    let signature = Signature::deserialize(unknown).expect("deserialization error");
+    assert_eq!(signature.extensions(), extensions);
+
+    // Truncated areas and nested extended signatures are rejected.
+    let mut truncated = extended.clone();
+    truncated[4..8].copy_from_slice(&(area_len - 1).to_be_bytes());
+    assert!(Signature::deserialize(truncated).is_err());
+    let nested = signature.with_extensions(extensions).into_serialized();
+    let mut twice = nested[..8 + area_len as usize].to_vec();
+    twice.extend_from_slice(&nested);
+    assert!(Signature::deserialize(twice).is_err());
+}
+
+#[test]
+fn test_signature_with_index() {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut base = vec![0; 50_000];
+    rng.fill(&mut base[..20_000]);
+    // repeated blocks must resolve to the same block as with the hash map index
+    base.copy_within(..10_000, 30_000);
+    let mut data = base.clone();
+    for _ in 0..20 {
+        let i = rng.gen_range(0..data.len());
+        data[i] = rng.gen();
+    }
+    data.splice(1000..1000, [7; 333]);
+
+    let algorithms = [
+        crate::HashAlgorithm::Md4,
+        crate::HashAlgorithm::Blake3,
+        crate::HashAlgorithm::Sha256,
+        crate::HashAlgorithm::Md5,
+    ];
+    for hash_algorithm in algorithms {
+        let options = SignatureOptions::new(128, 8, hash_algorithm);
+        let plain = Signature::calculate(&base, options);
+        let indexed = Signature::calculate_with_index(&base, options);
+        let deserialized = Signature::deserialize(indexed.serialized().to_vec()).expect("deserialization error");
+        assert_eq!(deserialized, indexed);
+        assert_eq!(indexed.clone().without_index(), plain);
+        let compact = plain.serialize_compact(crate::CompactOptions::default());
+        assert_eq!(Signature::deserialize(compact).expect("deserialization error"), plain);
+
+        let mut expected = vec![];
+        diff(&plain.index(), &data, &mut expected).expect("diff error");
+        let mut delta = vec![];
+        diff(&deserialized.index(), &data, &mut delta).expect("diff error");
+        assert_eq!(delta, expected);
+        let mut buf = vec![0xff; plain.index_len()];
+        delta.clear();
+        diff(&plain.index_into(&mut buf), &data, &mut delta).expect("diff error");
+        assert_eq!(delta, expected);
+        delta.clear();
+        diff(&plain.index_perfect(), &data, &mut delta).expect("diff error");
+        assert_eq!(delta, expected);
+
+        // a table without empty slots would make lookups loop forever
+        let mut corrupt = indexed.into_serialized();
+        let table_start = 12 + plain.serialized().len() + 4;
+        let count = (plain.serialized().len() - 12) / 12;
+        for (i, slot) in corrupt[table_start..].chunks_mut(4).enumerate() {
+            slot.copy_from_slice(&(i as u32 % count as u32 + 1).to_be_bytes());
+        }
+        assert!(Signature::deserialize(corrupt).is_err());
+    }
+}
+
+#[test]
+fn test_append_fast_path() {
+    use rand::Rng;
+    let mut base = vec![0; 10_000];
+    rand::thread_rng().fill(&mut base[..]);
+    for hash_algorithm in [crate::HashAlgorithm::Md4, crate::HashAlgorithm::Blake3] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(64, 8, hash_algorithm),
+        );
+        let indexed = signature.index();
+        let mut appended = base.clone();
+        appended.extend_from_slice(b"appended");
+        let cases: [(&[u8], usize); 4] = [
+            (&base, 0),
+            (&appended, 8),
+            (&base[..5000], 5000 % 64),
+            (&base[..0], 0),
+        ];
+        for (data, literal) in cases {
+            let mut patch = vec![];
+            diff(&indexed, data, &mut patch).expect("diff error");
+            // magic, at most one copy and one literal, end
+            assert!(patch.len() <= 4 + 5 + 1 + literal + 1, "{}", patch.len());
+            let mut out = vec![];
+            apply(&base, &patch, &mut out).expect("apply error");
+            assert_eq!(data, out);
+        }
+    }
+}
+
+#[test]
+fn test_prefix_suffix_trimming() {
+    // Every full block of the base is identical, so only trimming yields few, long copies.
+    let base = vec![0; 10_000];
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(64, 8, crate::HashAlgorithm::Blake3),
+    );
+    let indexed = signature.index();
+    let mut changed = base.clone();
+    changed[5000] = 1;
+    let mut prepended = b"header".to_vec();
+    prepended.extend_from_slice(&base);
+    for data in [changed, prepended] {
+        let mut patch = vec![];
+        diff(&indexed, &data, &mut patch).expect("diff error");
+        assert!(patch.len() < 100, "{}", patch.len());
+        let mut out = vec![];
+        apply(&base, &patch, &mut out).expect("apply error");
+        assert_eq!(data, out);
+    }
+}
+
+#[test]
+fn test_diff_anchored() {
+    use rand::Rng;
+    // Unique blocks interleaved with zero-filled ones, with a region moved to the front.
+    let mut base = vec![0; 64 * 1024];
+    for block in base.chunks_mut(128) {
+        rand::thread_rng().fill(&mut block[..64]);
+    }
+    let mut data = base[20000..].to_vec();
+    data.extend_from_slice(&base[..20000]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(64, 8, crate::HashAlgorithm::Blake3),
+    );
+    let indexed = signature.index();
+    let mut plain = vec![];
+    diff(&indexed, &data, &mut plain).expect("diff error");
+    let mut anchored = vec![];
+    crate::diff_anchored(&indexed, &data, 4096, &mut anchored).expect("diff error");
+    // Both copy the moved region in long runs instead of scattering the zero-filled blocks.
+    for delta in [&plain, &anchored] {
+        assert!(delta.len() < 1024, "{}", delta.len());
+    }
+    let mut out = vec![];
+    apply(&base, &anchored, &mut out).expect("apply error");
+    assert_eq!(data, out);
+}
+
+#[test]
+fn test_hash_size_for() {
+    // 2 * 20 - 1 + 40 = 79 bits
+    assert_eq!(SignatureOptions::hash_size_for(1 << 20, 2f64.powi(-40)), 10);
+    assert_eq!(SignatureOptions::hash_size_for(1 << 20, 2f64.powi(-41)), 10);
+    assert_eq!(SignatureOptions::hash_size_for(1 << 20, 2f64.powi(-42)), 11);
+    assert_eq!(SignatureOptions::hash_size_for(0, 0.5), 1);
+    assert_eq!(SignatureOptions::hash_size_for(u64::MAX, 1e-30), 29);
+}
+
+#[test]
+fn test_extended_blake3_hash() {
+    let base: Vec<u8> = (0..20_000u32).map(|i| (i * 13 % 251) as u8).collect();
+    let data = [&base[..7_000], b"inserted", &base[7_000..]].concat();
+    let options = |crypto_hash_size|
+        SignatureOptions::new(256, crypto_hash_size, crate::HashAlgorithm::Blake3);
+    let regular = Signature::calculate(&base, options(32));
+    for crypto_hash_size in [48, 64] {
+        let signature = Signature::calculate(&base, options(crypto_hash_size));
+        // the regular hash is a prefix of the extended one
+        let entries = regular.serialized()[12..].chunks(36);
+        let extended = signature.serialized()[12..].chunks(4 + crypto_hash_size as usize);
+        assert_eq!(entries.len(), extended.len());
+        for (entry, extended) in entries.zip(extended) {
+            assert_eq!(entry, &extended[..36]);
+        }
+        let deserialized = Signature::deserialize(signature.serialized().to_vec()).unwrap();
+        assert_eq!(deserialized, signature);
+
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).expect("diff error");
+        assert!(delta.len() < 1000, "{}", delta.len());
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).expect("apply error");
+        assert_eq!(out, data);
+    }
+    assert_eq!(crate::HashAlgorithm::Blake3.max_hash_size(), 32);
+    assert_eq!(crate::HashAlgorithm::Blake3.max_xof_size(), 64);
+    assert_eq!(crate::HashAlgorithm::Md4.max_xof_size(), 16);
+}
+
+#[test]
+fn test_hash_algorithm_names() {
+    use crate::HashAlgorithm;
+    let algorithms = [
+        HashAlgorithm::Md4,
+        HashAlgorithm::Blake2,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Md5,
+    ];
+    for algorithm in algorithms {
+        assert_eq!(algorithm.to_string().parse::<HashAlgorithm>().unwrap(), algorithm);
+    }
+    assert_eq!("BLAKE3".parse::<HashAlgorithm>().unwrap(), HashAlgorithm::Blake3);
+    let error = "sha1".parse::<HashAlgorithm>().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "unknown hash algorithm, expected md4, blake2, blake3, sha256 or md5 (name=sha1)"
+    );
+}
+
+#[test]
+fn test_diff_range() {
+    use rand::Rng;
+
+    let mut base = vec![0; 4096];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base.clone();
+    data[2000..2010].fill(7);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(64, 8, crate::HashAlgorithm::Blake3),
+    );
+    let indexed = signature.index();
+    for range in [0..4096, 100..3000, 1999..2001, 33..97, 4096..4096] {
+        let mut delta = vec![];
+        crate::diff_range(&indexed, &data, range.clone(), &mut delta).expect("diff error");
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).expect("apply error");
+        assert_eq!(out, &data[range.clone()]);
+        // Only the part of the changed block within the range is sent as a literal.
+        let changed_block = 1984..2048;
+        let overlap = range
+            .end
+            .min(changed_block.end)
+            .saturating_sub(range.start.max(changed_block.start));
+        let literals = crate::analysis::heatmap(&delta, 1).expect("heatmap error")[0].changed;
+        assert_eq!(literals, overlap as u64, "range={range:?}");
+    }
+}
+
+#[test]
+fn test_apply_range() {
+    use rand::Rng;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[2000..].to_vec();
+    data.extend_from_slice(&[42; 300]);
+    data.extend_from_slice(&base[..500]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(100, 8, crate::HashAlgorithm::Blake3),
+    );
+    let mut delta = vec![];
+    diff(&signature.index(), &data, &mut delta).expect("diff error");
+    for range in [0..8800, 0..1, 7990..8010, 8050..8350, 8700..9000, 9000..9100] {
+        let mut out = vec![];
+        let written =
+            crate::apply_range(&base, &delta, range.clone(), &mut out).expect("apply error");
+        let expected = &data[range.start.min(8800) as usize..range.end.min(8800) as usize];
+        assert_eq!(out, expected);
+        assert_eq!(written, expected.len() as u64);
+    }
+    assert!(crate::apply_range(&base[..5000], &delta, 0..100, &mut vec![]).is_err());
+    let inverted = std::ops::Range { start: 100, end: 50 };
+    assert_eq!(crate::apply_range(&base, &delta, inverted, &mut vec![]).expect("apply error"), 0);
+
+    // Fingerprinted and compressed deltas are unpacked like apply() does.
+    let mut fingerprinted = vec![];
+    crate::fingerprint::diff_fingerprinted(&signature.index(), &data, &mut fingerprinted)
+        .expect("diff error");
+    let mut out = vec![];
+    crate::apply_range(&base, &fingerprinted, 8050..8350, &mut out).expect("apply error");
+    assert_eq!(out, &data[8050..8350]);
+    assert!(crate::apply_range(&data, &fingerprinted, 0..100, &mut vec![]).is_err());
+    #[cfg(feature = "zstd")]
+    {
+        let peer = crate::compress::Capabilities::supported();
+        let compressed = crate::compress::pack_delta(&delta, Some(&base), 3, peer).unwrap();
+        assert_ne!(compressed, delta);
+        let mut out = vec![];
+        crate::apply_range(&base, &compressed, 8050..8350, &mut out).expect("apply error");
+        assert_eq!(out, &data[8050..8350]);
+    }
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_apply_parallel() {
+    use rand::Rng;
+    let mut base = vec![0; 3 << 20];
+    rand::thread_rng().fill(&mut base[..]);
+    // Copies, literals and a repeat that span the segments reconstructed in parallel.
+    let mut data = base[(1 << 20) + 5..].to_vec();
+    data.extend_from_slice(&[7; 1 << 20]);
+    data.extend_from_slice(&base[..1 << 20]);
+    data[(1 << 20) - 3] ^= 1;
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(4096, 8, crate::HashAlgorithm::Blake3),
+    );
+    let mut delta = vec![];
+    crate::diff_v2(&signature.index(), &data, &mut delta).expect("diff error");
+    assert_eq!(crate::apply_parallel(&base, &delta, usize::MAX).expect("apply error"), data);
+    assert!(matches!(
+        crate::apply_parallel(&base, &delta, data.len() - 1),
+        Err(crate::ApplyError::OutputLimit { what: "output", .. })
+    ));
+
+    let mut out = vec![1; data.len() + 10];
+    let len = crate::apply_parallel_into(&base, &delta, &mut out).expect("apply error");
+    assert_eq!(&out[..len], &data[..]);
+    assert_eq!(out[len..], [1; 10]);
+    let mut out = vec![1; data.len() - 1];
+    assert!(crate::apply_parallel_into(&base, &delta, &mut out).is_err());
+    assert!(out.iter().all(|&byte| byte == 1));
+    assert!(crate::apply_parallel(&base[..1 << 20], &delta, usize::MAX).is_err());
+    assert_eq!(crate::apply_parallel(&base, &[114, 115, 2, 54, 0], 0).unwrap(), b"");
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_parallel_thread_pool() {
+    use crate::Parallel;
+    let base: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    let mut data = base.clone();
+    data.insert(1_500_000, 1);
+    let options = SignatureOptions::new(4096, 8, crate::HashAlgorithm::Blake3);
+    let signature = Signature::calculate_parallel(&base, options);
+    let mut expected = vec![];
+    diff_parallel(&signature.index(), &data, &mut expected).expect("parallel diff error");
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+    for parallel in [Parallel::with_max_threads(1).unwrap(), Parallel::with_pool(&pool)] {
+        assert!(parallel.threads() <= 3);
+        assert_eq!(parallel.signature(&base, options), signature);
+        let mut delta = vec![];
+        parallel.diff(&parallel.index(&signature), &data, &mut delta).expect("parallel diff error");
+        assert_eq!(delta, expected);
+        assert_eq!(parallel.apply(&base, &delta, usize::MAX).expect("apply error"), data);
+    }
+    assert_eq!(Parallel::with_max_threads(1).unwrap().threads(), 1);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_index_parallel() {
+    use rand::Rng;
+    // Duplicate blocks, so that the same block must win in both indexes.
+    let mut rng = rand::thread_rng();
+    let mut blocks = vec![[0; 16]; 1000];
+    for block in &mut blocks {
+        rng.fill(&mut block[..]);
+    }
+    let base: Vec<u8> = (0..200_000)
+        .flat_map(|_| blocks[rng.gen_range(0..blocks.len())])
+        .chain([1, 2, 3])
+        .collect();
+    let mut data = base.clone();
+    for _ in 0..100 {
+        let position = rng.gen_range(0..data.len());
+        data.insert(position, rng.gen());
+    }
+    for checksum_seed in [0, 5] {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(16, 8, crate::HashAlgorithm::Md4)
+                .with_checksum_seed(checksum_seed),
+        );
+        let mut expected = vec![];
+        diff(&signature.index(), &data, &mut expected).expect("diff error");
+        let mut delta = vec![];
+        diff(&signature.index_parallel(), &data, &mut delta).expect("diff error");
+        assert_eq!(delta, expected);
+    }
+}
+
+#[test]
+fn test_crc_collisions() {
+    // Bumping bytes by +1, -1, -1, +1 at offsets i, i + 1, j, j + 1 keeps the rolling CRC of
+    // every window that contains all four, so dozens of consecutive positions collide.
+    let base = vec![5; 4096];
+    let mut data = base.clone();
+    data[1000] += 1;
+    data[1001] -= 1;
+    data[1030] -= 1;
+    data[1031] += 1;
+    let algorithms = [
+        crate::HashAlgorithm::Md4,
+        crate::HashAlgorithm::Blake2,
+        crate::HashAlgorithm::Blake3,
+        crate::HashAlgorithm::Sha256,
+        crate::HashAlgorithm::Md5,
+    ];
+    for hash_algorithm in algorithms {
+        let signature = Signature::calculate(
+            &base,
+            SignatureOptions::new(64, 8, hash_algorithm),
+        );
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).expect("diff error");
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).expect("apply error");
+        assert_eq!(out, data);
+        let literals = crate::analysis::heatmap(&delta, 1).expect("heatmap error")[0].changed;
+        assert!(literals <= 128, "literals={literals}");
+    }
+}
+
+#[test]
+fn test_duplicate_blocks_make_runs() {
+    use rand::Rng;
+    // blocks: A Z Z Z B Z Z Z C, with Z all zeros
+    let mut base = vec![0; 9 * 16];
+    for block in [0, 4, 8] {
+        rand::thread_rng().fill(&mut base[block * 16..(block + 1) * 16]);
+    }
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(16, 8, crate::HashAlgorithm::Blake3),
+    );
+    // B Z Z Z continues the copy of B; Z Z C looks ahead to C
+    for (region, prefix) in [(4 * 16..8 * 16, &b"x"[..]), (6 * 16..9 * 16, &b"y"[..])] {
+        let mut data = prefix.to_vec();
+        data.extend_from_slice(&base[region.clone()]);
+        let mut delta = vec![];
+        diff(&signature.index(), &data, &mut delta).expect("diff error");
+        let mut out = vec![];
+        apply(&base, &delta, &mut out).expect("apply error");
+        assert_eq!(out, data);
+        let mut ops = crate::patch::OpReader::new(&delta).expect("invalid delta");
+        assert!(matches!(ops.next_op(), Ok(Some(crate::patch::Op::Literal(_)))));
+        assert!(matches!(
+            ops.next_op(),
+            Ok(Some(crate::patch::Op::Copy { offset, len }))
+                if offset == region.start as u64 && len == region.len() as u64
+        ));
+        assert!(matches!(ops.next_op(), Ok(None)));
+    }
+}
+
+#[test]
+fn test_diff_v2() {
+    use rand::Rng;
+    let mut base = vec![0; 10000];
+    rand::thread_rng().fill(&mut base[..]);
+    let mut data = base[..3000].to_vec();
+    data.extend_from_slice(&[0; 10000]);
+    data.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef].repeat(1000));
+    data.extend_from_slice(b"x");
+    data.extend_from_slice(&[0xff; 777]);
+    data.extend_from_slice(&base[3000..]);
+    let signature = Signature::calculate(
+        &base,
+        SignatureOptions::new(100, 8, crate::HashAlgorithm::Blake3),
+    );
+    let mut v1 = vec![];
+    diff(&signature.index(), &data, &mut v1).expect("diff error");
+    let mut delta = vec![];
+    crate::diff_v2(&signature.index(), &data, &mut delta).expect("diff error");
+    assert!(delta.len() < 100, "v1={}, v2={}", v1.len(), delta.len());
+    let mut out = vec![];
+    apply(&base, &delta, &mut out).expect("apply error");
+    assert_eq!(out, data);
+    for range in [0..2990, 2999..3001, 5000..13000, 16990..17100, 17700..20000] {
+        let mut out = vec![];
+        crate::apply_range(&base, &delta, range.clone(), &mut out).expect("apply error");
+        assert_eq!(out, &data[range.start as usize..range.end.min(data.len() as u64) as usize]);
+    }
+    let mut out = vec![];
+    let mut input = &delta[..];
+    crate::apply_with_scratch(
+        base.len() as u64,
+        |offset, buf| {
+            buf.copy_from_slice(&base[offset as usize..offset as usize + buf.len()]);
+            Ok(())
+        },
+        |buf| std::io::Read::read(&mut input, buf),
+        |chunk| {
+            out.extend_from_slice(chunk);
+            Ok(())
+        },
+        &mut [0; 7],
+    )
+    .expect("apply error");
+    assert_eq!(out, data);
+
+    // appended runs take the fast path
+    let mut appended = base.clone();
+    appended.extend_from_slice(&[0; 5000]);
+    let mut delta = vec![];
+    crate::diff_v2(&signature.index(), &appended, &mut delta).expect("diff error");
+    assert!(delta.len() < 20);
+    let mut out = vec![];
+    apply(&base, &delta, &mut out).expect("apply error");
+    assert_eq!(out, appended);
+
+    // repeats are only valid in v2 deltas, and never empty
+    use crate::consts::{DELTA_MAGIC, DELTA_V2_MAGIC, RS_OP_REPEAT_N1};
+    let mut v1 = DELTA_MAGIC.to_be_bytes().to_vec();
+    crate::ops::encode_repeat(&[1], 10, &mut v1).unwrap();
+    v1.push(0);
+    assert!(matches!(
+        apply(&base, &v1, &mut vec![]),
+        Err(crate::ApplyError::UnknownCommand { .. })
+    ));
+    let mut empty = DELTA_V2_MAGIC.to_be_bytes().to_vec();
+    empty.extend_from_slice(&[RS_OP_REPEAT_N1, 0, 1, 7, 0]);
+    assert!(matches!(
+        apply(&base, &empty, &mut vec![]),
+        Err(crate::ApplyError::RepeatZero)
+    ));
+}
+
+#[test]
+fn test_checksum_seed() {
+    use crate::consts::{SEEDED_MAGIC_FLAG, SEEDED_SIGNATURE_HEADER_SIZE, SIGNATURE_HEADER_SIZE};
+    use crate::{md4::md4, md5::md5, HashAlgorithm};
+
+    let base: Vec<u8> = (0..100_500u32).map(|i| (i * 7 % 251) as u8).collect();
+    let data = [&base[50_000..], b"new", &base[..49_999]].concat();
+    let seed = 0x5eed_u32;
+    // rsync hashes the seed after the block, except for MD5, where it comes first
+    let seeded = |hash_algorithm, block: &[u8]| -> [u8; 16] {
+        match hash_algorithm {
+            HashAlgorithm::Md4 => md4(&[block, &seed.to_le_bytes()].concat()),
+            _ => md5(&[&seed.to_le_bytes(), block].concat()),
+        }
+    };
+    for hash_algorithm in [HashAlgorithm::Md4, HashAlgorithm::Md5] {
+        let unseeded = Signature::calculate(&base, SignatureOptions::new(1000, 16, hash_algorithm));
+        let options = SignatureOptions::new(1000, 16, hash_algorithm).with_checksum_seed(seed);
+        let signature = Signature::calculate(&base, options);
+        assert_eq!(signature.checksum_seed(), seed);
+        let header = &signature.serialized()[..SEEDED_SIGNATURE_HEADER_SIZE];
+        let magic = u32::from_be_bytes(header[..4].try_into().unwrap());
+        assert_eq!(magic & SEEDED_MAGIC_FLAG, SEEDED_MAGIC_FLAG);
+        assert_eq!(header[12..], seed.to_be_bytes());
+
+        // the CRCs are unseeded, the strong hashes are seeded like rsync's
+        let entries = signature.serialized()[SEEDED_SIGNATURE_HEADER_SIZE..].chunks(20);
+        let unseeded_entries = unseeded.serialized()[SIGNATURE_HEADER_SIZE..].chunks(20);
+        assert_eq!(entries.len(), base.len().div_ceil(1000));
+        for ((entry, unseeded), block) in entries.zip(unseeded_entries).zip(base.chunks(1000)) {
+            assert_eq!(entry[..4], unseeded[..4]);
+            assert_ne!(entry[4..], unseeded[4..]);
+            assert_eq!(entry[4..], seeded(hash_algorithm, block));
+        }
+        #[cfg(feature = "parallel")]
+        assert_eq!(Signature::calculate_parallel(&base, options), signature);
+
+        for signature in [signature.clone(), signature.clone().with_index()] {
+            let mut delta = vec![];
+            diff(&signature.index(), &data, &mut delta).expect("diff error");
+            assert!(delta.len() < 2000, "{}", delta.len());
+            let mut out = vec![];
+            apply(&base, &delta, &mut out).expect("apply error");
+            assert_eq!(out, data);
+
+            let serialized = signature.serialized().to_vec();
+            assert_eq!(Signature::deserialize(serialized).unwrap(), signature);
+        }
+        let compact = signature.serialize_compact(crate::CompactOptions {
+            #[cfg(feature = "zstd")]
+            zstd_level: None,
+            delta_crcs: true,
+        });
+        assert_eq!(Signature::deserialize(compact).unwrap(), signature);
+
+        // a seeded header never holds seed 0
+        let mut serialized = signature.serialized().to_vec();
+        serialized[12..16].fill(0);
+        assert!(Signature::deserialize(serialized).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Reading files through io_uring on Linux.
+//!
+//! The synchronous read stage of a [Pipeline](crate::Pipeline) issues one `read(2)` at a time,
+//! which leaves a fast NVMe drive mostly idle. [read_stage] instead keeps as many chunk reads in
+//! flight as the pipeline's queue depth, at consecutive offsets, and hands the chunks on in order
+//! as they complete.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::SyncSender;
+
+use io_uring::{opcode, types, IoUring};
+
+/// A chunk being read.
+struct Slot {
+    buf: Vec<u8>,
+    filled: usize,
+    /// Whether the chunk is full or ends at the end of the file.
+    done: bool,
+}
+
+/// The reads in flight. Their buffers belong to the kernel until they complete, so dropping the
+/// reader waits for the outstanding ones.
+struct Reader<'a> {
+    file: &'a File,
+    ring: IoUring,
+    chunk_size: usize,
+    queue_depth: usize,
+    /// The chunks being read, the first of which is chunk number `first`.
+    slots: VecDeque<Slot>,
+    first: u64,
+    in_flight: usize,
+    eof: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(file: &'a File, chunk_size: usize, queue_depth: usize) -> io::Result<Self> {
+        let queue_depth = queue_depth.min(1 << 12);
+        Ok(Reader {
+            file,
+            ring: IoUring::new(queue_depth as u32)?,
+            chunk_size,
+            queue_depth,
+            slots: VecDeque::with_capacity(queue_depth),
+            first: 0,
+            in_flight: 0,
+            eof: false,
+        })
+    }
+
+    /// Queue a read of the rest of chunk number `chunk`.
+    fn push(&mut self, chunk: u64) -> io::Result<()> {
+        let slot = &mut self.slots[(chunk - self.first) as usize];
+        let len = (slot.buf.len() - slot.filled).min(u32::MAX as usize) as u32;
+        let offset = chunk * self.chunk_size as u64 + slot.filled as u64;
+        let entry = opcode::Read::new(
+            types::Fd(self.file.as_raw_fd()),
+            slot.buf[slot.filled..].as_mut_ptr(),
+            len,
+        )
+        .offset(offset)
+        .build()
+        .user_data(chunk);
+        // Safety: the buffer is owned by the slot, which is neither dropped nor reallocated while
+        // the read is in flight.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Wait for at least one read to complete, queueing the rest of any short read.
+    fn wait(&mut self) -> io::Result<()> {
+        self.ring.submit_and_wait(1)?;
+        let completed: Vec<_> = self
+            .ring
+            .completion()
+            .map(|entry| (entry.user_data(), entry.result()))
+            .collect();
+        self.in_flight -= completed.len();
+        for (chunk, result) in completed {
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            let slot = &mut self.slots[(chunk - self.first) as usize];
+            slot.filled += result as usize;
+            if result == 0 {
+                slot.done = true;
+                self.eof = true;
+            } else if slot.filled == slot.buf.len() {
+                slot.done = true;
+            } else {
+                self.push(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the next chunk of the file, or `None` at its end.
+    fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            while !self.eof && self.slots.len() < self.queue_depth {
+                self.slots.push_back(Slot {
+                    buf: vec![0; self.chunk_size],
+                    filled: 0,
+                    done: false,
+                });
+                self.push(self.first + self.slots.len() as u64 - 1)?;
+            }
+            match self.slots.front() {
+                None => return Ok(None),
+                Some(slot) if slot.done => {
+                    let mut slot = self.slots.pop_front().unwrap();
+                    self.first += 1;
+                    if slot.filled == 0 {
+                        return Ok(None);
+                    }
+                    slot.buf.truncate(slot.filled);
+                    return Ok(Some(slot.buf));
+                }
+                Some(_) => self.wait()?,
+            }
+        }
+    }
+}
+
+impl Drop for Reader<'_> {
+    fn drop(&mut self) {
+        while self.in_flight > 0 {
+            if self.ring.submit_and_wait(1).is_err() {
+                // The kernel may still write to the buffers, so they must outlive us.
+                std::mem::forget(std::mem::take(&mut self.slots));
+                return;
+            }
+            self.in_flight -= self.ring.completion().count();
+        }
+    }
+}
+
+/// Read `file` in chunks of `chunk_size` bytes with up to `queue_depth` reads in flight, sending
+/// the chunks to `output` in order.
+///
+/// Returns `None` if io_uring is unavailable (e.g. an older kernel, or blocked by seccomp), in
+/// which case nothing has been read yet.
+pub(crate) fn read_stage(
+    file: &File,
+    chunk_size: usize,
+    queue_depth: usize,
+    output: &SyncSender<Vec<u8>>,
+) -> Option<io::Result<()>> {
+    let reader = Reader::new(file, chunk_size, queue_depth).ok()?;
+    Some(send_chunks(reader, output))
+}
+
+fn send_chunks(mut reader: Reader<'_>, output: &SyncSender<Vec<u8>>) -> io::Result<()> {
+    while let Some(chunk) = reader.next_chunk()? {
+        if output.send(chunk).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+
+    use super::read_stage;
+    use crate::{HashAlgorithm, Pipeline, Signature, SignatureOptions};
+
+    #[test]
+    fn test_read_stage() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("superfast_rsync_uring_{}", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let file = File::open(&path).unwrap();
+        for (chunk_size, queue_depth) in [(1, 3), (4096, 1), (4096, 8), (10_000, 4), (1 << 20, 2)] {
+            let (tx, rx) = sync_channel(2);
+            let file = &file;
+            let read = thread::scope(|scope| {
+                let reader = scope.spawn(move || read_stage(file, chunk_size, queue_depth, &tx));
+                let chunks: Vec<Vec<u8>> = rx.iter().collect();
+                (reader.join().unwrap(), chunks)
+            });
+            let (result, chunks) = match read {
+                // io_uring is not available here.
+                (None, _) => break,
+                (Some(result), chunks) => (result, chunks),
+            };
+            result.unwrap();
+            assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() == chunk_size));
+            assert_eq!(chunks.concat(), data);
+        }
+
+        let options = SignatureOptions::new(128, 8, HashAlgorithm::Blake3);
+        let pipeline = Pipeline::new().chunk_size(4096).io_uring(true);
+        let signature = pipeline.signature_file(&path, options).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(signature, Signature::calculate(&data, options));
+    }
+}
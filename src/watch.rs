@@ -0,0 +1,184 @@
+//! Keeping signatures of a set of files up to date as they change.
+//!
+//! [SignatureWatcher] computes the signatures of the configured files once, then watches them
+//! with the platform's file notification API (via the `notify` crate) and recomputes a file's
+//! signature whenever it changes. The diff side of a continuous-sync daemon queries the current
+//! signatures through a [SignatureHandle] without ever waiting for a recomputation.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::signature::{Signature, SignatureOptions};
+
+type Signatures = RwLock<HashMap<PathBuf, Arc<Signature>>>;
+
+/// Watches a set of files and keeps their signatures up to date.
+///
+/// Dropping the watcher stops watching; handles keep returning the last known signatures.
+pub struct SignatureWatcher {
+    // Dropping the watcher closes the event channel, which stops the update thread.
+    _watcher: RecommendedWatcher,
+    signatures: Arc<Signatures>,
+}
+
+/// Read access to the signatures maintained by a [SignatureWatcher].
+#[derive(Clone)]
+pub struct SignatureHandle {
+    signatures: Arc<Signatures>,
+}
+
+impl SignatureWatcher {
+    /// Compute the signatures of `paths` and start watching them.
+    ///
+    /// Files that don't exist yet are picked up once they are created. The directory containing
+    /// each file is watched, so files replaced by a rename (as editors and atomic writers do)
+    /// are tracked too.
+    pub fn new(
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+        options: SignatureOptions,
+    ) -> io::Result<Self> {
+        let mut watched = HashSet::new();
+        let mut directories = HashSet::new();
+        for path in paths {
+            let path = absolute(path.as_ref())?;
+            let directory = path.parent().unwrap_or(&path).to_owned();
+            directories.insert(directory);
+            watched.insert(path);
+        }
+
+        let (tx, rx) = channel();
+        let mut watcher = notify::recommended_watcher(tx).map_err(notify_error)?;
+        for directory in &directories {
+            watcher
+                .watch(directory, RecursiveMode::NonRecursive)
+                .map_err(notify_error)?;
+        }
+
+        // Watch first, so that changes made during the initial pass aren't missed.
+        let signatures = Arc::new(RwLock::new(HashMap::new()));
+        for path in &watched {
+            update(&signatures, path, options);
+        }
+        let thread_signatures = signatures.clone();
+        thread::Builder::new()
+            .name("signature-watcher".into())
+            .spawn(move || run(rx, watched, thread_signatures, options))?;
+        Ok(SignatureWatcher {
+            _watcher: watcher,
+            signatures,
+        })
+    }
+
+    /// Get a handle for querying the signatures.
+    pub fn handle(&self) -> SignatureHandle {
+        SignatureHandle {
+            signatures: self.signatures.clone(),
+        }
+    }
+}
+
+impl SignatureHandle {
+    /// The current signature of the file at `path`, or `None` if it isn't watched or doesn't
+    /// exist.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<Arc<Signature>> {
+        let path = absolute(path.as_ref()).ok()?;
+        self.signatures.read().unwrap().get(&path).cloned()
+    }
+}
+
+fn run(
+    events: Receiver<notify::Result<Event>>,
+    watched: HashSet<PathBuf>,
+    signatures: Arc<Signatures>,
+    options: SignatureOptions,
+) {
+    let mut dirty = HashSet::new();
+    while let Ok(event) = events.recv() {
+        // Collect everything that's queued, so a burst of writes is handled with one update.
+        for event in std::iter::once(event).chain(events.try_iter()) {
+            match event {
+                Ok(event) => {
+                    dirty.extend(event.paths.into_iter().filter(|path| watched.contains(path)))
+                }
+                // The backend lost events; recompute everything to be safe.
+                Err(_) => dirty.extend(watched.iter().cloned()),
+            }
+        }
+        for path in dirty.drain() {
+            update(&signatures, &path, options);
+        }
+    }
+}
+
+/// Recompute the signature of `path`, or forget it if the file can't be read.
+fn update(signatures: &Signatures, path: &Path, options: SignatureOptions) {
+    match fs::read(path) {
+        Ok(data) => {
+            let signature = Arc::new(Signature::calculate(&data, options));
+            signatures.write().unwrap().insert(path.to_owned(), signature);
+        }
+        Err(_) => {
+            signatures.write().unwrap().remove(path);
+        }
+    }
+}
+
+/// Make `path` absolute, resolving symlinks in its directory, so it matches the paths in events.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok(fs::canonicalize(parent)?.join(name)),
+        _ => Ok(path),
+    }
+}
+
+fn notify_error(e: notify::Error) -> io::Error {
+    match e.kind {
+        notify::ErrorKind::Io(e) => e,
+        _ => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    use super::SignatureWatcher;
+    use crate::{HashAlgorithm, Signature, SignatureOptions};
+
+    #[test]
+    fn test_signature_watcher() {
+        let options = SignatureOptions::new(64, 8, HashAlgorithm::Blake3);
+        let dir = std::env::temp_dir().join(format!("superfast_rsync_watch_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("watched");
+        fs::write(&path, [1; 1000]).unwrap();
+
+        let watcher = SignatureWatcher::new([&path, &dir.join("missing")], options).unwrap();
+        let handle = watcher.handle();
+        assert_eq!(*handle.get(&path).unwrap(), Signature::calculate(&[1; 1000], options));
+        assert!(handle.get(dir.join("missing")).is_none());
+
+        fs::write(&path, [2; 3000]).unwrap();
+        let expected = Signature::calculate(&[2; 3000], options);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while handle.get(&path).as_deref() != Some(&expected) {
+            assert!(Instant::now() < deadline, "signature was not updated");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(watcher);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}